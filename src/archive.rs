@@ -0,0 +1,854 @@
+use std::cmp;
+use std::fs::{File, Metadata};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Component, Path};
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tar::{Archive, Builder, EntryType, Header};
+
+/// Tar header formats.
+///
+/// PAX can represent arbitrarily long names, arbitrarily large files and
+/// sub-second mtimes (using extended header records where the basic ustar
+/// fields fall short), and is required for sparse entries. GNU handles long
+/// names and large files with its own extensions. Plain ustar handles neither;
+/// entries it can't represent are rejected. Both GNU and ustar store mtimes to
+/// the second.
+#[derive(Clone,Copy,Debug,PartialEq,Deserialize)]
+pub enum TarFormat {
+	Pax,
+	Gnu,
+	Ustar,
+}
+
+/// Container formats for archives.
+///
+/// tar, compressed as chosen with `Compression`, keeps everything about a file
+/// that a backup needs. zip can be opened by Windows Explorer and other tools
+/// that don't read tar, but each file is compressed separately, with deflate
+/// (or stored), modification times are kept only to two seconds, only Unix
+/// permissions are kept of the file's metadata, and sparse files are stored
+/// densely.
+#[derive(Clone,Copy,Debug,PartialEq,Deserialize)]
+pub enum ArchiveFormat {
+	Tar,
+	Zip,
+}
+
+/// Check whether the start of a file is that of a zip archive: either its
+/// first local file header, or, if it's empty, its end of central directory
+/// record.
+pub fn is_zip(start: &[u8]) -> bool {
+	start.starts_with(b"PK\x03\x04") || start.starts_with(b"PK\x05\x06")
+}
+
+/// The name of the entry holding the checksums of a backup, when they're
+/// embedded at the start of its archive (see `append_checksums`).
+pub const CHECKSUMS_ENTRY: &str = "CHECKSUMS.txt";
+
+/// The PAX keyword with which `append_outcome` records a file which couldn't
+/// be archived.
+const FAILED_KEYWORD: &str = "BACKUP.failed";
+
+/// The PAX keyword with which `append_outcome` records a file checksummed
+/// again as it was archived, and its new checksum.
+const RECHECKSUMMED_KEYWORD: &str = "BACKUP.rechecksummed";
+
+/// Largest file size representable in the octal size field of a tar header.
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+/// Largest uid/gid representable in the octal id fields of a tar header.
+const MAX_OCTAL_ID: u64 = 0o7777777;
+
+/// Append a file to an archive under the given name, using the given header
+/// format.
+///
+/// In PAX format, if the file contains holes and the platform can report them
+/// (via `SEEK_DATA`/`SEEK_HOLE`), it is stored as a sparse entry (GNU sparse
+/// format 1.0) containing only its data extents; GNU tar and bsdtar recreate
+/// the holes on extraction. Otherwise, the file is stored densely.
+///
+/// As the file's contents are read, `on_read` is called with the number of
+/// bytes read each time, so progress through large files can be reported.
+///
+/// The entry always holds as many bytes as its header says, however the file
+/// changes while it's read: a file which grows is cut off at the size it had
+/// when its header was written, and one which shrinks is padded with zeros.
+/// Returns the number of bytes of padding, which is zero unless the file
+/// shrank.
+///
+/// # Errors
+///
+/// This function will return any I/O error encountered reading the file or
+/// writing the archive. If `name` is not a relative path, or the file can't be
+/// represented in the selected format (e.g. its name is too long for ustar),
+/// an `InvalidInput` error describing the problem is returned and nothing is
+/// written.
+pub fn append_file<W: Write>(
+		archive: &mut Builder<W>,
+		format: TarFormat,
+		name: &str,
+		file: &mut File,
+		on_read: &mut dyn FnMut(u64))
+		-> io::Result<u64> {
+	check_relative(name)?;
+	let meta = file.metadata()?;
+	if format == TarFormat::Pax {
+		if let Some(extents) = data_extents(file, meta.len())? {
+			trace!("Archiving {} sparsely ({} data extents)", name, extents.len());
+			return append_sparse(archive, name, file, &meta, &extents, on_read);
+		}
+	}
+
+	let mut header = match format {
+		TarFormat::Gnu => Header::new_gnu(),
+		TarFormat::Pax | TarFormat::Ustar => Header::new_ustar(),
+	};
+	header.set_metadata(&meta);
+	header.set_entry_type(EntryType::Regular);
+	let (uid, gid) = owner(&meta);
+	match format {
+		TarFormat::Pax => {
+			let mut pax = Vec::new();
+			if !set_header_path(&mut header, name)? {
+				pax.extend(pax_record("path", name));
+				set_fallback_path(&mut header, name)?;
+			}
+			if meta.len() > MAX_OCTAL_SIZE {
+				pax.extend(pax_record("size", &meta.len().to_string()));
+				header.set_size(0);
+			}
+			if uid > MAX_OCTAL_ID {
+				pax.extend(pax_record("uid", &uid.to_string()));
+				header.set_uid(0);
+			}
+			if gid > MAX_OCTAL_ID {
+				pax.extend(pax_record("gid", &gid.to_string()));
+				header.set_gid(0);
+			}
+			if let Some(mtime) = precise_mtime(&meta) {
+				pax.extend(pax_record("mtime", &mtime));
+			}
+			if !pax.is_empty() {
+				append_pax_header(archive, name, &meta, &pax)?;
+			}
+		},
+		TarFormat::Gnu => {
+			if !set_header_path(&mut header, name)? {
+				let mut long_name = name.as_bytes().to_vec();
+				long_name.push(0);
+				let mut long_header = Header::new_gnu();
+				long_header.set_path("././@LongLink")?;
+				long_header.set_size(long_name.len() as u64);
+				long_header.set_entry_type(EntryType::GNULongName);
+				long_header.set_cksum();
+				archive.append(&long_header, &long_name[..])?;
+				set_fallback_path(&mut header, name)?;
+			}
+			if meta.len() > MAX_OCTAL_SIZE {
+				base256_into(&mut header.as_old_mut().size, meta.len());
+			}
+			if uid > MAX_OCTAL_ID {
+				base256_into(&mut header.as_old_mut().uid, uid);
+			}
+			if gid > MAX_OCTAL_ID {
+				base256_into(&mut header.as_old_mut().gid, gid);
+			}
+		},
+		TarFormat::Ustar => {
+			if !set_header_path(&mut header, name)? {
+				return Err(unrepresentable(name, "name is too long"));
+			}
+			if meta.len() > MAX_OCTAL_SIZE {
+				return Err(unrepresentable(name, "file is larger than 8 GiB"));
+			}
+			if uid > MAX_OCTAL_ID || gid > MAX_OCTAL_ID {
+				return Err(unrepresentable(name, "owner id is too large"));
+			}
+		}
+	}
+	header.set_cksum();
+	let mut contents = PaddedReader::new(ReportingReader { inner: file, on_read }, meta.len());
+	archive.append(&header, &mut contents)?;
+	Ok(contents.padding)
+}
+
+/// Read the regular files in a (decompressed) archive, as written by
+/// `append_file`, passing each one's name and a reader over its contents to
+/// `each`. Sparse entries are read with their holes filled in, so the contents
+/// are always those of the original file.
+///
+/// # Errors
+///
+/// This function will return any I/O error encountered reading the archive or
+/// returned by `each`, or an `InvalidData` error if an entry's name isn't valid
+/// UTF-8 or a sparse entry is malformed.
+pub fn read_files<R: Read, F>(reader: R, mut each: F) -> io::Result<()>
+		where F: FnMut(&str, &mut dyn Read) -> io::Result<()> {
+	read_entries(reader, |name, _, contents| each(name, contents))
+}
+
+/// Read the regular files in a (decompressed) archive, as `read_files` does,
+/// also passing `each` each one's header, for its metadata (e.g. its mode and
+/// modification time).
+///
+/// # Errors
+///
+/// As for `read_files`.
+pub fn read_entries<R: Read, F>(reader: R, mut each: F) -> io::Result<()>
+		where F: FnMut(&str, &Header, &mut dyn Read) -> io::Result<()> {
+	let mut archive = Archive::new(reader);
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		if entry.header().entry_type() != EntryType::Regular {
+			continue
+		}
+		// Long names, and the names and sizes of sparse files, are in PAX
+		// records.
+		let mut name = None;
+		let mut sparse_size = None;
+		if let Some(extensions) = entry.pax_extensions()? {
+			for extension in extensions {
+				let extension = extension?;
+				match (extension.key(), extension.value()) {
+					(Ok("path"), Ok(value)) | (Ok("GNU.sparse.name"), Ok(value)) =>
+						name = Some(value.to_string()),
+					(Ok("GNU.sparse.realsize"), Ok(value)) =>
+						sparse_size = Some(value.parse::<u64>()
+							.map_err(|_| invalid_data("invalid sparse file size"))?),
+					_ => ()
+				}
+			}
+		}
+		let name = match name {
+			Some(name) => name,
+			None => match entry.path()?.to_str() {
+				Some(name) => name.to_string(),
+				None => return Err(invalid_data("entry name is not valid UTF-8"))
+			}
+		};
+		let header = entry.header().clone();
+		match sparse_size {
+			Some(size) => {
+				let extents = read_sparse_map(&mut entry)?;
+				let mut reader = HoleFillingReader {
+					inner: &mut entry,
+					extents,
+					current: 0,
+					position: 0,
+					size,
+				};
+				each(&name, &header, &mut reader)?;
+			},
+			None => each(&name, &header, &mut entry)?
+		}
+	}
+	Ok(())
+}
+
+/// Append the contents of a checksum file to an archive, as `CHECKSUMS_ENTRY`.
+/// This should be the archive's first entry, so that `read_checksums` finds
+/// it.
+///
+/// # Errors
+///
+/// This function will return any I/O error encountered writing the archive.
+pub fn append_checksums<W: Write>(archive: &mut Builder<W>, contents: &[u8]) -> io::Result<()> {
+	let mut header = Header::new_ustar();
+	header.set_path(CHECKSUMS_ENTRY)?;
+	header.set_mode(0o644);
+	header.set_mtime(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+	header.set_size(contents.len() as u64);
+	header.set_entry_type(EntryType::Regular);
+	header.set_cksum();
+	archive.append(&header, contents)
+}
+
+/// Append a PAX global header to an archive with checksums embedded at its
+/// start (see `append_checksums`), recording where the files archived differ
+/// from them: the names (as in the checksums) of files which couldn't be
+/// archived, or changed as they were (`failed`), and the names and new
+/// checksums of files checksummed again as they were archived
+/// (`rechecksummed`). That's only known once the files are written, so this
+/// goes after them. Tar tools other than `read_outcome` ignore it, as they do
+/// tags (see `write_tags`).
+///
+/// # Errors
+///
+/// This function will return any I/O error encountered writing the archive.
+pub fn append_outcome<W: Write>(
+		archive: &mut Builder<W>,
+		failed: &[String],
+		rechecksummed: &[(String, String)])
+		-> io::Result<()> {
+	let mut records = Vec::new();
+	for name in failed {
+		records.extend(pax_record(FAILED_KEYWORD, name));
+	}
+	for (name, checksum) in rechecksummed {
+		records.extend(pax_record(RECHECKSUMMED_KEYWORD, &format!("{} {}", checksum, name)));
+	}
+	archive.append(&global_header(records.len() as u64)?, &records[..])
+}
+
+/// Read what `append_outcome` recorded in a (decompressed) archive, or a part
+/// of one: the names of files which couldn't be archived, and the names and
+/// new checksums of those checksummed again, either of which is empty if
+/// there were none. As it's at the end, the whole archive is read. A global
+/// header at the very start holds tags (see `write_tags`), so isn't read.
+///
+/// # Errors
+///
+/// This function will return any I/O error encountered reading the archive, or
+/// an `InvalidData` error if a global header is malformed.
+pub fn read_outcome<R: Read>(reader: R) -> io::Result<Outcome> {
+	let mut failed = Vec::new();
+	let mut rechecksummed = Vec::new();
+	let mut archive = Archive::new(reader);
+	let mut first = true;
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		if mem::replace(&mut first, false)
+				|| entry.header().entry_type() != EntryType::XGlobalHeader {
+			continue
+		}
+		let mut records = Vec::new();
+		entry.read_to_end(&mut records)?;
+		for (key, value) in parse_pax_records(&records)? {
+			match &key[..] {
+				FAILED_KEYWORD => failed.push(value),
+				RECHECKSUMMED_KEYWORD => match value.find(' ') {
+					Some(i) => rechecksummed.push(
+						(value[i + 1 ..].to_string(), value[.. i].to_string())),
+					None => return Err(invalid_data("malformed PAX record"))
+				},
+				_ => ()
+			}
+		}
+	}
+	Ok((failed, rechecksummed))
+}
+
+/// Read the checksum file embedded in a (decompressed) archive by
+/// `append_checksums`, if its first regular file is `CHECKSUMS_ENTRY`.
+///
+/// # Errors
+///
+/// This function will return any I/O error encountered reading the archive.
+pub fn read_checksums<R: Read>(reader: R) -> io::Result<Option<Vec<u8>>> {
+	let mut archive = Archive::new(reader);
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		if entry.header().entry_type() != EntryType::Regular {
+			continue
+		}
+		if entry.path()?.to_str() != Some(CHECKSUMS_ENTRY) {
+			return Ok(None)
+		}
+		let mut contents = Vec::new();
+		entry.read_to_end(&mut contents)?;
+		return Ok(Some(contents))
+	}
+	Ok(None)
+}
+
+/// Write a PAX global header holding the given tags (user-defined metadata,
+/// e.g. `backup.host=myserver`), to go at the start of an archive.
+///
+/// # Errors
+///
+/// This function will return any I/O error encountered writing the header.
+pub fn write_tags<W: Write>(writer: &mut W, tags: &[(String, String)]) -> io::Result<()> {
+	let records : Vec<u8> = tags.iter()
+		.flat_map(|(key, value)| pax_record(key, value))
+		.collect();
+	writer.write_all(global_header(records.len() as u64)?.as_bytes())?;
+	writer.write_all(&records)?;
+	let padding = (512 - records.len() % 512) % 512;
+	writer.write_all(&vec![0; padding])
+}
+
+/// Build the header for a PAX global header of `size` bytes of records.
+fn global_header(size: u64) -> io::Result<Header> {
+	let mut header = Header::new_ustar();
+	header.set_path("pax_global_header")?;
+	header.set_mode(0o644);
+	header.set_mtime(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+	header.set_size(size);
+	header.set_entry_type(EntryType::XGlobalHeader);
+	header.set_cksum();
+	Ok(header)
+}
+
+/// What `append_outcome` recorded in an archive: the names of files which
+/// couldn't be archived, and the names and new checksums of those checksummed
+/// again.
+pub type Outcome = (Vec<String>, Vec<(String, String)>);
+
+/// Tags (user-defined metadata) from an archive's PAX global header, as key and
+/// value pairs.
+pub type Tags = Vec<(String, String)>;
+
+/// Read the tags from the PAX global header at the start of a (decompressed)
+/// archive, as written by `write_tags`.
+///
+/// Returns the tags, in the order they were written (none if the archive
+/// doesn't start with a global header), along with any bytes read from
+/// `reader` which belong to the rest of the archive.
+///
+/// # Errors
+///
+/// This function will return any I/O error encountered reading the archive, or
+/// an `InvalidData` error if the global header is malformed.
+pub fn read_tags<R: Read>(reader: &mut R) -> io::Result<(Tags, Vec<u8>)> {
+	let mut header = Header::new_old();
+	reader.read_exact(header.as_mut_bytes())?;
+	if header.entry_type() != EntryType::XGlobalHeader {
+		return Ok((Vec::new(), header.as_bytes().to_vec()));
+	}
+	let size = header.size()?;
+	let mut records = Vec::new();
+	reader.by_ref().take(size).read_to_end(&mut records)?;
+	if records.len() as u64 != size {
+		return Err(invalid_data("truncated global header"));
+	}
+	let padding = (512 - size % 512) % 512;
+	io::copy(&mut reader.by_ref().take(padding), &mut io::sink())?;
+	Ok((parse_pax_records(&records)?, Vec::new()))
+}
+
+/// Parse PAX extended header records (see `pax_record`) into key/value pairs.
+fn parse_pax_records(mut records: &[u8]) -> io::Result<Tags> {
+	let mut parsed = Vec::new();
+	while !records.is_empty() {
+		let space = records.iter().position(|&b| b == b' ')
+			.ok_or_else(|| invalid_data("malformed PAX record"))?;
+		let len = str::from_utf8(&records[.. space]).ok()
+			.and_then(|len| len.parse::<usize>().ok())
+			.ok_or_else(|| invalid_data("malformed PAX record"))?;
+		if len <= space + 1 || len > records.len() || records[len - 1] != b'\n' {
+			return Err(invalid_data("malformed PAX record"));
+		}
+		let record = str::from_utf8(&records[space + 1 .. len - 1])
+			.map_err(|_| invalid_data("PAX record is not valid UTF-8"))?;
+		match record.find('=') {
+			Some(i) => parsed.push((record[.. i].to_string(), record[i + 1 ..].to_string())),
+			None => return Err(invalid_data("malformed PAX record"))
+		}
+		records = &records[len ..];
+	}
+	Ok(parsed)
+}
+
+/// Read the sparse map at the start of a GNU 1.0 sparse entry's data (see
+/// `append_sparse`), returning the data extents and leaving `reader` at the
+/// start of their data.
+fn read_sparse_map<R: Read>(reader: &mut R) -> io::Result<Vec<(u64, u64)>> {
+	let mut read = 0;
+	let count = read_map_number(reader, &mut read)?;
+	let mut extents = Vec::new();
+	for _ in 0 .. count {
+		let offset = read_map_number(reader, &mut read)?;
+		let length = read_map_number(reader, &mut read)?;
+		extents.push((offset, length));
+	}
+	// The map is padded to a whole block.
+	let padding = (512 - read % 512) % 512;
+	io::copy(&mut reader.by_ref().take(padding), &mut io::sink())?;
+	Ok(extents)
+}
+
+/// Read a newline-terminated decimal number from a sparse map, adding the
+/// number of bytes read to `read`.
+fn read_map_number<R: Read>(reader: &mut R, read: &mut u64) -> io::Result<u64> {
+	let mut digits = String::new();
+	let mut byte = [0];
+	loop {
+		reader.read_exact(&mut byte)?;
+		*read += 1;
+		match byte[0] {
+			b'\n' => break,
+			b'0' ..= b'9' if digits.len() < 20 => digits.push(byte[0] as char),
+			_ => return Err(invalid_data("malformed sparse map"))
+		}
+	}
+	digits.parse().map_err(|_| invalid_data("malformed sparse map"))
+}
+
+/// An `InvalidData` error with the given message.
+fn invalid_data(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Ensure an entry name is a relative path without `..` components.
+fn check_relative(name: &str) -> io::Result<()> {
+	for component in Path::new(name).components() {
+		match component {
+			Component::Normal(_) | Component::CurDir => (),
+			_ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+				format!("{} is not a relative path", name)))
+		}
+	}
+	Ok(())
+}
+
+/// Build the error for an entry which the selected format can't represent.
+fn unrepresentable(name: &str, reason: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidInput,
+		format!("{} cannot be stored in ustar format: {}", name, reason))
+}
+
+/// Get the owner uid and gid of a file.
+#[cfg(unix)]
+fn owner(meta: &Metadata) -> (u64, u64) {
+	use std::os::unix::fs::MetadataExt;
+	(meta.uid() as u64, meta.gid() as u64)
+}
+
+#[cfg(not(unix))]
+fn owner(_meta: &Metadata) -> (u64, u64) {
+	(0, 0)
+}
+
+/// Format the mtime of a file as a PAX decimal timestamp, if it has sub-second
+/// precision which the basic header can't store.
+fn precise_mtime(meta: &Metadata) -> Option<String> {
+	meta.modified().ok()
+		.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+		.and_then(|d| if d.subsec_nanos() == 0 {
+			None
+		} else {
+			let fraction = format!("{:09}", d.subsec_nanos());
+			Some(format!("{}.{}", d.as_secs(), fraction.trim_end_matches('0')))
+		})
+}
+
+/// Write a number into a numeric header field using the GNU base-256
+/// extension.
+fn base256_into(field: &mut [u8], value: u64) {
+	let len = field.len();
+	for (i, byte) in field.iter_mut().enumerate() {
+		let shift = (len - 1 - i) * 8;
+		*byte = if shift < 64 { (value >> shift) as u8 } else { 0 };
+	}
+	field[0] |= 0x80;
+}
+
+/// Find the data extents of a file.
+///
+/// Returns a list of `(offset, length)` pairs covering all the data in the
+/// file, or `None` if the file has no holes or the filesystem can't tell us
+/// where they are (in which case it should be archived densely).
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn data_extents(file: &File, len: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+	use libc;
+	use std::os::unix::io::AsRawFd;
+
+	let fd = file.as_raw_fd();
+	let mut extents = Vec::new();
+	let mut offset = 0u64;
+	while offset < len {
+		let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+		if start < 0 {
+			let e = io::Error::last_os_error();
+			match e.raw_os_error() {
+				// No more data; the rest of the file is a hole.
+				Some(libc::ENXIO) => break,
+				// Hole-seeking isn't supported here.
+				Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => return Ok(None),
+				_ => return Err(e)
+			}
+		}
+		let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+		if end < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		extents.push((start as u64, (end - start) as u64));
+		offset = end as u64;
+	}
+	if unsafe { libc::lseek(fd, 0, libc::SEEK_SET) } < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	if len == 0 || extents == [(0, len)] {
+		return Ok(None);
+	}
+	// GNU tar expects the map to extend to the end of the file, so mark a
+	// trailing hole with an empty extent.
+	if extents.last().is_none_or(|&(o, l)| o + l < len) {
+		extents.push((len, 0));
+	}
+	Ok(Some(extents))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn data_extents(_file: &File, _len: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+	Ok(None)
+}
+
+/// Append a sparse file as a PAX extended header followed by a GNU 1.0 sparse
+/// entry (sparse map, then the data extents back-to-back).
+fn append_sparse<W: Write>(
+		archive: &mut Builder<W>,
+		name: &str,
+		file: &mut File,
+		meta: &Metadata,
+		extents: &[(u64, u64)],
+		on_read: &mut dyn FnMut(u64))
+		-> io::Result<u64> {
+	let mut map = format!("{}\n", extents.len());
+	for &(offset, length) in extents {
+		map.push_str(&format!("{}\n{}\n", offset, length));
+	}
+	let mut map = map.into_bytes();
+	let padded_len = map.len().div_ceil(512) * 512;
+	map.resize(padded_len, 0);
+	let data_len = extents.iter().fold(0, |acc, &(_, length)| acc + length);
+
+	let basename = Path::new(name).file_name()
+		.and_then(|n| n.to_str())
+		.unwrap_or("file");
+
+	let mut pax = Vec::new();
+	pax.extend(pax_record("GNU.sparse.major", "1"));
+	pax.extend(pax_record("GNU.sparse.minor", "0"));
+	pax.extend(pax_record("GNU.sparse.name", name));
+	pax.extend(pax_record("GNU.sparse.realsize", &meta.len().to_string()));
+	if let Some(mtime) = precise_mtime(meta) {
+		pax.extend(pax_record("mtime", &mtime));
+	}
+	let size = map.len() as u64 + data_len;
+	let mut header = Header::new_ustar();
+	set_synthetic_path(&mut header, "GNUSparseFile.0", basename)?;
+	header.set_metadata(meta);
+	header.set_entry_type(EntryType::Regular);
+	if size > MAX_OCTAL_SIZE {
+		pax.extend(pax_record("size", &size.to_string()));
+		header.set_size(0);
+	} else {
+		header.set_size(size);
+	}
+	header.set_cksum();
+	append_pax_header(archive, name, meta, &pax)?;
+	let extent_reader = ExtentReader { file, extents, current: 0, remaining: None };
+	let mut data = PaddedReader::new(ReportingReader { inner: extent_reader, on_read }, data_len);
+	archive.append(&header, Cursor::new(map).chain(&mut data))?;
+	Ok(data.padding)
+}
+
+/// Append a PAX extended header containing the given records, applying to the
+/// next entry.
+fn append_pax_header<W: Write>(
+		archive: &mut Builder<W>,
+		name: &str,
+		meta: &Metadata,
+		records: &[u8])
+		-> io::Result<()> {
+	let basename = Path::new(name).file_name()
+		.and_then(|n| n.to_str())
+		.unwrap_or("file");
+	let mut header = Header::new_ustar();
+	set_synthetic_path(&mut header, "PaxHeaders.0", basename)?;
+	header.set_metadata(meta);
+	header.set_size(records.len() as u64);
+	header.set_entry_type(EntryType::XHeader);
+	header.set_cksum();
+	archive.append(&header, records)
+}
+
+/// Store a name in a header's name field (and, for ustar headers, its prefix
+/// field), returning whether it fit.
+fn set_header_path(header: &mut Header, name: &str) -> io::Result<bool> {
+	let bytes = name.as_bytes();
+	if bytes.len() <= 100 {
+		header.set_path(name)?;
+		return Ok(true);
+	}
+	// Split long names between the prefix and name fields ourselves; the tar
+	// crate's own splitting mishandles names under 156 bytes.
+	let ustar = match header.as_ustar_mut() {
+		Some(ustar) => ustar,
+		None => return Ok(false)
+	};
+	let split = bytes.iter()
+		.enumerate()
+		.filter(|&(_, &b)| b == b'/')
+		.map(|(i, _)| i)
+		.find(|&i| i > 0 && bytes.len() - i - 1 <= ustar.name.len());
+	match split {
+		Some(i) if i <= ustar.prefix.len() && i + 1 < bytes.len() => {
+			ustar.prefix[.. i].copy_from_slice(&bytes[.. i]);
+			ustar.name[.. bytes.len() - i - 1].copy_from_slice(&bytes[i + 1 ..]);
+			Ok(true)
+		},
+		_ => Ok(false)
+	}
+}
+
+/// Set the path of a header whose real name is recorded elsewhere (in a PAX
+/// record or GNU long name entry) to something informational which fits.
+fn set_fallback_path(header: &mut Header, name: &str) -> io::Result<()> {
+	let basename = Path::new(name).file_name()
+		.and_then(|n| n.to_str())
+		.unwrap_or("file");
+	if !set_header_path(header, basename)? {
+		header.set_path("file")?;
+	}
+	Ok(())
+}
+
+/// Set the (informational) path of a synthetic header, falling back to a
+/// fixed name if the file's own name doesn't fit.
+fn set_synthetic_path(header: &mut Header, dir: &str, basename: &str) -> io::Result<()> {
+	if !set_header_path(header, &format!("{}/{}", dir, basename))? {
+		header.set_path(format!("{}/file", dir))?;
+	}
+	Ok(())
+}
+
+/// Format a single PAX extended header record (`"<len> <key>=<value>\n"`,
+/// where `<len>` counts the whole record including itself).
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+	let rest = format!(" {}={}\n", key, value);
+	let mut len = rest.len() + 1;
+	while len.to_string().len() + rest.len() > len {
+		len += 1;
+	}
+	format!("{}{}", len, rest).into_bytes()
+}
+
+/// Reader over the data extents of a sparse file, in order.
+struct ExtentReader<'a> {
+	file: &'a mut File,
+	extents: &'a [(u64, u64)],
+	current: usize,
+	remaining: Option<u64>,
+}
+
+impl<'a> Read for ExtentReader<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		loop {
+			if self.current >= self.extents.len() {
+				return Ok(0);
+			}
+			let (offset, length) = self.extents[self.current];
+			let remaining = match self.remaining {
+				Some(r) => r,
+				None => {
+					self.file.seek(SeekFrom::Start(offset))?;
+					length
+				}
+			};
+			if remaining == 0 {
+				self.current += 1;
+				self.remaining = None;
+				continue;
+			}
+			let want = cmp::min(remaining, buf.len() as u64) as usize;
+			// If the file shrank while being archived, the rest of the extent
+			// is padded by the `PaddedReader` around this.
+			let read_len = self.file.read(&mut buf[.. want])?;
+			if read_len == 0 {
+				return Ok(0);
+			}
+			self.remaining = Some(remaining - read_len as u64);
+			return Ok(read_len);
+		}
+	}
+}
+
+/// A reader which gives exactly `remaining` bytes: those of the inner reader,
+/// then zeros if it runs out first, so that an entry is as long as its header
+/// says even if its file shrinks while being archived.
+struct PaddedReader<R> {
+	inner: R,
+	remaining: u64,
+	/// The number of zeros given in place of the inner reader's bytes.
+	padding: u64,
+}
+
+impl<R: Read> PaddedReader<R> {
+	fn new(inner: R, len: u64) -> PaddedReader<R> {
+		PaddedReader { inner, remaining: len, padding: 0 }
+	}
+}
+
+impl<R: Read> Read for PaddedReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let want = cmp::min(self.remaining, buf.len() as u64) as usize;
+		if want == 0 {
+			return Ok(0);
+		}
+		// Once the inner reader has run out, it isn't read again, in case the
+		// file has grown back since.
+		let mut read_len = if self.padding == 0 { self.inner.read(&mut buf[.. want])? } else { 0 };
+		if read_len == 0 {
+			for byte in &mut buf[.. want] {
+				*byte = 0;
+			}
+			read_len = want;
+			self.padding += want as u64;
+		}
+		self.remaining -= read_len as u64;
+		Ok(read_len)
+	}
+}
+
+/// A reader which reports the number of bytes read by each call.
+struct ReportingReader<'a, R> {
+	inner: R,
+	on_read: &'a mut dyn FnMut(u64),
+}
+
+impl<'a, R: Read> Read for ReportingReader<'a, R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let read_len = self.inner.read(buf)?;
+		(self.on_read)(read_len as u64);
+		Ok(read_len)
+	}
+}
+
+/// Reader over the full contents of a sparse file, given its data extents and
+/// a reader over their data back-to-back (the reverse of `ExtentReader`).
+struct HoleFillingReader<R> {
+	inner: R,
+	extents: Vec<(u64, u64)>,
+	current: usize,
+	position: u64,
+	size: u64,
+}
+
+impl<R: Read> Read for HoleFillingReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		loop {
+			if buf.is_empty() || self.position >= self.size {
+				return Ok(0);
+			}
+			let (start, end) = match self.extents.get(self.current) {
+				Some(&(offset, length)) =>
+					(cmp::min(offset, self.size), cmp::min(offset.saturating_add(length), self.size)),
+				None => (self.size, self.size)
+			};
+			if self.position < start {
+				// In a hole
+				let len = cmp::min(buf.len() as u64, start - self.position) as usize;
+				for byte in &mut buf[.. len] {
+					*byte = 0;
+				}
+				self.position += len as u64;
+				return Ok(len);
+			}
+			if self.position >= end {
+				self.current += 1;
+				continue;
+			}
+			let len = cmp::min(buf.len() as u64, end - self.position) as usize;
+			let len = self.inner.read(&mut buf[.. len])?;
+			if len == 0 {
+				return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sparse entry is truncated"));
+			}
+			self.position += len as u64;
+			return Ok(len);
+		}
+	}
+}
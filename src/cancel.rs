@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use BackupError;
+
+/// Whether the run has been asked to stop, by SIGINT or SIGTERM.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Handle SIGINT and SIGTERM by asking the run to stop, which it does at the
+/// next check (see `check`), between files. A second signal exits immediately,
+/// with status 128 plus the signal's number, as the shell reports signals.
+///
+/// On platforms other than Unix, this does nothing, and signals kill the run
+/// as usual.
+#[cfg(unix)]
+pub fn install() {
+	use libc;
+	extern "C" fn handle(signal: libc::c_int) {
+		// Only async-signal-safe operations are allowed here.
+		if CANCELLED.swap(true, Ordering::SeqCst) {
+			unsafe { libc::_exit(128 + signal) };
+		}
+	}
+	let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+	unsafe {
+		libc::signal(libc::SIGINT, handler);
+		libc::signal(libc::SIGTERM, handler);
+	}
+}
+
+#[cfg(not(unix))]
+pub fn install() {
+}
+
+/// Whether the run has been asked to stop.
+pub fn cancelled() -> bool {
+	CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Check whether the run has been asked to stop, for long-running operations
+/// to call between files.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::Cancelled` if the run has been
+/// asked to stop.
+pub fn check() -> Result<(), BackupError> {
+	if cancelled() {
+		Err(BackupError::Cancelled)
+	} else {
+		Ok(())
+	}
+}
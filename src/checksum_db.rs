@@ -0,0 +1,192 @@
+use rusqlite::{Connection, Error, ErrorCode};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use BackupError;
+use operations::{format_timestamp, Normalization};
+
+/// The tables of a checksum database.
+///
+/// Each run which saves checksums is recorded in `runs`, along with any
+/// header values for it (as would be written at the top of a checksum file,
+/// e.g. its version name) in `run_headers`. `checksums` holds the checksums
+/// saved by the latest run, with the size and modification time of each file
+/// where known, and the run which last saw it.
+const SCHEMA: &str = "
+	CREATE TABLE IF NOT EXISTS runs (
+		id INTEGER PRIMARY KEY,
+		finished TEXT NOT NULL
+	);
+	CREATE TABLE IF NOT EXISTS run_headers (
+		run INTEGER NOT NULL REFERENCES runs(id),
+		name TEXT NOT NULL,
+		value TEXT NOT NULL
+	);
+	CREATE TABLE IF NOT EXISTS checksums (
+		path TEXT PRIMARY KEY,
+		hash TEXT NOT NULL,
+		size INTEGER,
+		mtime INTEGER,
+		last_seen_run INTEGER NOT NULL REFERENCES runs(id)
+	);
+";
+
+/// A SQLite database of checksums, used in place of old and new checksum
+/// files.
+///
+/// The database is locked exclusively from when it's opened until it's saved
+/// to or dropped, so that concurrent runs can't interleave their changes.
+pub struct ChecksumDb {
+	conn: Connection,
+	path: String,
+}
+
+impl ChecksumDb {
+	/// Open (creating if necessary) and lock the checksum database at `path`.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` with a descriptive
+	/// message if the database can't be opened or created, or is locked by
+	/// another run.
+	pub fn open(path: &str) -> Result<ChecksumDb, BackupError> {
+		let conn = Connection::open(path).map_err(|e| db_error(path, e))?;
+		// Fail immediately, rather than waiting, if another run holds the lock.
+		conn.busy_timeout(Duration::from_secs(0)).map_err(|e| db_error(path, e))?;
+		match conn.execute_batch("BEGIN EXCLUSIVE") {
+			Ok(()) => (),
+			Err(Error::SqliteFailure(ref e, _)) if e.code == ErrorCode::DatabaseBusy =>
+				return Err(BackupError::OtherError(format!(
+					"Checksum database {} is in use by another run", path))),
+			Err(e) => return Err(db_error(path, e))
+		}
+		conn.execute_batch(SCHEMA).map_err(|e| db_error(path, e))?;
+		Ok(ChecksumDb { conn, path: path.to_string() })
+	}
+
+	/// Load the checksums saved by the latest run, as `load_checksums` does
+	/// from a checksum file, applying `normalization` to filenames.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` with a descriptive
+	/// message if the checksums can't be read.
+	pub fn load(&self, normalization: Normalization) -> Result<HashMap<String, String>, BackupError> {
+		let mut statement = self.conn.prepare("SELECT path, hash FROM checksums")
+			.map_err(|e| db_error(&self.path, e))?;
+		let rows = statement.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+			.map_err(|e| db_error(&self.path, e))?;
+		let mut checksums = HashMap::new();
+		for row in rows {
+			let (filename, checksum) : (String, String) = row.map_err(|e| db_error(&self.path, e))?;
+			let filename = normalization.apply(filename);
+			trace!("Previous version checksum: {}\t{}", filename, checksum);
+			checksums.insert(filename, checksum);
+		}
+		Ok(checksums)
+	}
+
+	/// Load the sizes and modification times (in seconds since the epoch)
+	/// recorded for files by the latest run, where known, applying
+	/// `normalization` to filenames.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` with a descriptive
+	/// message if the database can't be read.
+	pub fn load_stats(&self, normalization: Normalization) -> Result<HashMap<String, (u64, i64)>, BackupError> {
+		let mut statement = self.conn.prepare(
+				"SELECT path, size, mtime FROM checksums WHERE size IS NOT NULL AND mtime IS NOT NULL")
+					.map_err(|e| db_error(&self.path, e))?;
+		let rows = statement.query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+			.map_err(|e| db_error(&self.path, e))?;
+		let mut stats = HashMap::new();
+		for row in rows {
+			let (filename, size, mtime) : (String, i64, i64) = row.map_err(|e| db_error(&self.path, e))?;
+			stats.insert(normalization.apply(filename), (size as u64, mtime));
+		}
+		Ok(stats)
+	}
+
+	/// The header values recorded by the latest run, in the order they were
+	/// given.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` with a descriptive
+	/// message if the headers can't be read.
+	pub fn headers(&self) -> Result<Vec<(String, String)>, BackupError> {
+		let mut statement = self.conn.prepare(concat!(
+				"SELECT name, value FROM run_headers ",
+				"WHERE run = (SELECT MAX(id) FROM runs) ORDER BY rowid"))
+					.map_err(|e| db_error(&self.path, e))?;
+		let rows = statement.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+			.map_err(|e| db_error(&self.path, e))?;
+		let mut headers = Vec::new();
+		for row in rows {
+			headers.push(row.map_err(|e| db_error(&self.path, e))?);
+		}
+		Ok(headers)
+	}
+
+	/// The version name recorded by the latest run, if any (see
+	/// `load_version`).
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` with a descriptive
+	/// message if the headers can't be read.
+	pub fn version(&self) -> Result<Option<String>, BackupError> {
+		Ok(self.headers()?.into_iter()
+			.find(|(name, _)| name == "version")
+			.map(|(_, value)| value))
+	}
+
+	/// Record a run, replacing the saved checksums with `checksums`, and
+	/// release the lock.
+	///
+	/// `header` is recorded for the run, as `save_checksums` writes it at the
+	/// top of a checksum file. The size and modification time of each file are
+	/// recorded as given by `stat`, if it returns them. Files not in
+	/// `checksums` are removed. Nothing is changed unless everything is saved.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` with a descriptive
+	/// message if the checksums can't be saved.
+	pub fn save<F>(self, checksums: &HashMap<String, String>, header: &[(&str, String)], mut stat: F)
+			-> Result<(), BackupError>
+			where F: FnMut(&str) -> Option<(u64, SystemTime)> {
+		let path = &self.path;
+		self.conn.execute("INSERT INTO runs (finished) VALUES (?1)",
+				(format_timestamp(SystemTime::now()),)).map_err(|e| db_error(path, e))?;
+		let run = self.conn.last_insert_rowid();
+		for &(name, ref value) in header {
+			self.conn.execute("INSERT INTO run_headers (run, name, value) VALUES (?1, ?2, ?3)",
+					(run, name, value)).map_err(|e| db_error(path, e))?;
+		}
+		{
+			let mut statement = self.conn.prepare(concat!(
+					"INSERT OR REPLACE INTO checksums (path, hash, size, mtime, last_seen_run) ",
+					"VALUES (?1, ?2, ?3, ?4, ?5)")).map_err(|e| db_error(path, e))?;
+			for (filename, checksum) in checksums {
+				let (size, mtime) = match stat(filename) {
+					Some((size, mtime)) => (Some(size as i64), mtime.duration_since(UNIX_EPOCH).ok()
+						.map(|d| d.as_secs() as i64)),
+					None => (None, None)
+				};
+				statement.execute((filename, checksum, size, mtime, run))
+					.map_err(|e| db_error(path, e))?;
+			}
+		}
+		self.conn.execute("DELETE FROM checksums WHERE last_seen_run != ?1", (run,))
+			.map_err(|e| db_error(path, e))?;
+		self.conn.execute_batch("COMMIT").map_err(|e| db_error(path, e))?;
+		Ok(())
+	}
+}
+
+/// Describe an error accessing a checksum database.
+fn db_error(path: &str, e: Error) -> BackupError {
+	BackupError::OtherError(format!("Error accessing checksum database {}: {}", path, e))
+}
@@ -0,0 +1,327 @@
+use flate2::{self, Decompress, Flush, GzBuilder, Status};
+use flate2::write::GzEncoder;
+use lz4_flex::frame::{BlockSize, FrameDecoder, FrameEncoder, FrameInfo};
+use std::io::{self, BufRead, Read, Write};
+
+use archive;
+
+/// The magic number starting an lz4 frame.
+const LZ4_MAGIC: u32 = 0x184D2204;
+
+/// The magic number starting an lz4 skippable frame, which readers pass over.
+/// An lz4 archive's comment is stored in one at its start.
+const LZ4_SKIPPABLE_MAGIC: u32 = 0x184D2A50;
+
+/// Compression formats for archives.
+///
+/// gzip compresses well, and can be read almost anywhere. lz4 compresses less
+/// well, but many times faster, for backups limited by the CPU rather than by
+/// the disk or network. Either way, an archive may be written as several
+/// concatenated gzip members or lz4 frames, which together are a valid file of
+/// the format.
+#[derive(Clone,Copy,Debug,PartialEq,Deserialize)]
+pub enum Compression {
+	Gzip,
+	Lz4,
+}
+
+impl Compression {
+	/// The name of this format, as given to `--compression` and in the
+	/// statistics report.
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Compression::Gzip => "gzip",
+			Compression::Lz4 => "lz4",
+		}
+	}
+
+	/// The compression level used, as given in the statistics report. lz4 has
+	/// only the one.
+	pub fn level(&self) -> u32 {
+		match *self {
+			Compression::Gzip => 9,
+			Compression::Lz4 => 1,
+		}
+	}
+
+	/// The usual extension for a tar file compressed in this format.
+	pub fn extension(&self) -> &'static str {
+		match *self {
+			Compression::Gzip => ".tar.gz",
+			Compression::Lz4 => ".tar.lz4",
+		}
+	}
+
+	/// Whether an entry for a file of `size` bytes is compressed, given the
+	/// threshold below which files are stored uncompressed. This only applies
+	/// to gzip, which can make small files larger; lz4 stores data which
+	/// doesn't compress as it is anyway.
+	pub fn compresses(&self, size: u64, threshold: u64) -> bool {
+		*self == Compression::Lz4 || size >= threshold
+	}
+
+	/// Start compressing a gzip member or lz4 frame to `writer`, with
+	/// `comment`, if given, in its header (gzip), or in a skippable frame
+	/// before it (lz4). If not `compressed`, gzip members are written at
+	/// compression level none (deflate's stored blocks).
+	///
+	/// # Errors
+	///
+	/// This function will return any I/O error encountered writing the
+	/// comment.
+	pub fn encoder<W: Write>(&self, mut writer: W, comment: Option<&str>, compressed: bool)
+			-> io::Result<Encoder<W>> {
+		let level = if compressed { flate2::Compression::Best } else { flate2::Compression::None };
+		Ok(match (*self, comment) {
+			(Compression::Gzip, Some(comment)) =>
+				Encoder::Gzip(GzBuilder::new().comment(comment.as_bytes()).write(writer, level)),
+			(Compression::Gzip, None) => Encoder::Gzip(GzEncoder::new(writer, level)),
+			(Compression::Lz4, comment) => {
+				if let Some(comment) = comment {
+					writer.write_all(&LZ4_SKIPPABLE_MAGIC.to_le_bytes())?;
+					writer.write_all(&(comment.len() as u32).to_le_bytes())?;
+					writer.write_all(comment.as_bytes())?;
+				}
+				// Archives are for keeping, so check their contents when read.
+				// Every frame has the same block size, as lz4_flex's decoder
+				// doesn't expect it to change between frames.
+				Encoder::Lz4(FrameEncoder::with_frame_info(
+					FrameInfo::new().block_size(BlockSize::Max256KB).content_checksum(true), writer))
+			}
+		})
+	}
+
+	/// Compress data as a complete gzip member or lz4 frame (see `encoder`).
+	pub fn compress(&self, data: &[u8], compressed: bool) -> io::Result<Vec<u8>> {
+		let mut encoder = self.encoder(Vec::new(), None, compressed)?;
+		encoder.write_all(data)?;
+		encoder.finish()
+	}
+}
+
+/// Writer compressing a gzip member or lz4 frame, as started by
+/// `Compression::encoder`.
+pub enum Encoder<W: Write> {
+	Gzip(GzEncoder<W>),
+	Lz4(FrameEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+	/// Finish the member or frame, returning the underlying writer.
+	///
+	/// # Errors
+	///
+	/// This function will return any I/O error encountered writing.
+	pub fn finish(self) -> io::Result<W> {
+		match self {
+			Encoder::Gzip(encoder) => encoder.finish(),
+			Encoder::Lz4(encoder) => encoder.finish().map_err(io::Error::from),
+		}
+	}
+}
+
+impl<W: Write> Write for Encoder<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match *self {
+			Encoder::Gzip(ref mut encoder) => encoder.write(buf),
+			Encoder::Lz4(ref mut encoder) => encoder.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match *self {
+			Encoder::Gzip(ref mut encoder) => encoder.flush(),
+			Encoder::Lz4(ref mut encoder) => encoder.flush(),
+		}
+	}
+}
+
+/// Reader decompressing an archive in either format, which it tells from the
+/// magic number at the start.
+pub enum Decoder<R: BufRead> {
+	Gzip(MultiGzDecoder<R>),
+	Lz4(FrameDecoder<R>, Option<String>),
+}
+
+impl<R: BufRead> Decoder<R> {
+	/// Start decompressing `reader`, reading an lz4 archive's comment, if it
+	/// has one. Anything which isn't lz4 is taken to be gzip (and fails to
+	/// read if it isn't), except for zip archives (see `archive::is_zip`),
+	/// which can't be read as a stream.
+	///
+	/// # Errors
+	///
+	/// This function will return any I/O error encountered reading the start
+	/// of the archive, or an `InvalidData` error for a zip archive.
+	pub fn new(mut reader: R) -> io::Result<Decoder<R>> {
+		let magic = {
+			let start = reader.fill_buf()?;
+			if start.len() < 4 {
+				None
+			} else {
+				Some(u32::from_le_bytes([start[0], start[1], start[2], start[3]]))
+			}
+		};
+		match magic {
+			Some(LZ4_MAGIC) => Ok(Decoder::Lz4(FrameDecoder::new(reader), None)),
+			Some(LZ4_SKIPPABLE_MAGIC) => {
+				let mut header = [0; 8];
+				reader.read_exact(&mut header)?;
+				let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+				let mut comment = Vec::new();
+				reader.by_ref().take(len as u64).read_to_end(&mut comment)?;
+				let comment = String::from_utf8_lossy(&comment).into_owned();
+				Ok(Decoder::Lz4(FrameDecoder::new(reader), Some(comment)))
+			},
+			_ if archive::is_zip(reader.fill_buf()?) => Err(io::Error::new(io::ErrorKind::InvalidData,
+				"this is a zip archive, which can only be listed")),
+			_ => Ok(Decoder::Gzip(MultiGzDecoder::new(reader)))
+		}
+	}
+
+	/// The archive's compression format.
+	pub fn compression(&self) -> Compression {
+		match *self {
+			Decoder::Gzip(_) => Compression::Gzip,
+			Decoder::Lz4(_, _) => Compression::Lz4,
+		}
+	}
+
+	/// The archive's comment, if it has one (and, for gzip, it has been read).
+	pub fn comment(&self) -> Option<&str> {
+		match *self {
+			Decoder::Gzip(ref decoder) => decoder.comment(),
+			Decoder::Lz4(_, ref comment) => comment.as_ref().map(|c| &c[..]),
+		}
+	}
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match *self {
+			Decoder::Gzip(ref mut decoder) => decoder.read(buf),
+			// lz4_flex's decoder stops at the end of each frame, so carry on
+			// into the next, if there is one.
+			Decoder::Lz4(ref mut decoder, _) => loop {
+				let read = decoder.read(buf)?;
+				if read > 0 || buf.is_empty() || decoder.get_mut().fill_buf()?.is_empty() {
+					return Ok(read);
+				}
+			},
+		}
+	}
+}
+
+/// Reader decompressing one or more concatenated gzip members.
+///
+/// flate2's `GzDecoder` stops after the first member (and may read past it),
+/// so this parses member headers itself and inflates their contents with
+/// `Decompress`. Members' CRCs aren't checked.
+pub struct MultiGzDecoder<R> {
+	inner: R,
+	inflate: Decompress,
+	in_member: bool,
+	members: usize,
+	comment: Option<String>,
+}
+
+impl<R> MultiGzDecoder<R> {
+	/// Start decompressing `inner`.
+	pub fn new(inner: R) -> MultiGzDecoder<R> {
+		MultiGzDecoder {
+			inner,
+			inflate: Decompress::new(false),
+			in_member: false,
+			members: 0,
+			comment: None,
+		}
+	}
+
+	/// The comment in the first member's header, if it has one (and has been
+	/// read).
+	pub fn comment(&self) -> Option<&str> {
+		self.comment.as_ref().map(|c| &c[..])
+	}
+}
+
+impl<R: BufRead> Read for MultiGzDecoder<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		loop {
+			if buf.is_empty() {
+				return Ok(0);
+			}
+			if !self.in_member {
+				if self.inner.fill_buf()?.is_empty() {
+					return Ok(0);
+				}
+				let comment = read_gzip_header(&mut self.inner)?;
+				if self.members == 0 {
+					self.comment = comment.map(|c| String::from_utf8_lossy(&c).into_owned());
+				}
+				self.members += 1;
+				self.inflate = Decompress::new(false);
+				self.in_member = true;
+			}
+			let (status, consumed, produced) = {
+				let input = self.inner.fill_buf()?;
+				if input.is_empty() {
+					return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+						"gzip stream is truncated"));
+				}
+				let total_in = self.inflate.total_in();
+				let total_out = self.inflate.total_out();
+				let status = self.inflate.decompress(input, buf, Flush::None)
+					.map_err(|_| io::Error::new(io::ErrorKind::InvalidData,
+						"corrupt deflate stream"))?;
+				(status,
+					(self.inflate.total_in() - total_in) as usize,
+					(self.inflate.total_out() - total_out) as usize)
+			};
+			self.inner.consume(consumed);
+			if let Status::StreamEnd = status {
+				// Skip the member's CRC and size.
+				self.inner.read_exact(&mut [0; 8])?;
+				self.in_member = false;
+			}
+			if produced > 0 {
+				return Ok(produced);
+			}
+		}
+	}
+}
+
+/// Read past a gzip member header (RFC 1952), returning its comment, if any.
+fn read_gzip_header<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+	const FHCRC: u8 = 0x02;
+	const FEXTRA: u8 = 0x04;
+	const FNAME: u8 = 0x08;
+	const FCOMMENT: u8 = 0x10;
+	let mut header = [0; 10];
+	reader.read_exact(&mut header)?;
+	if header[0] != 0x1f || header[1] != 0x8b || header[2] != 8 {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid gzip header"));
+	}
+	let flags = header[3];
+	if flags & FEXTRA != 0 {
+		let mut len = [0; 2];
+		reader.read_exact(&mut len)?;
+		let len = len[0] as u64 | (len[1] as u64) << 8;
+		io::copy(&mut reader.by_ref().take(len), &mut io::sink())?;
+	}
+	if flags & FNAME != 0 {
+		reader.read_until(0, &mut Vec::new())?;
+	}
+	let comment = if flags & FCOMMENT != 0 {
+		let mut comment = Vec::new();
+		reader.read_until(0, &mut comment)?;
+		comment.pop();
+		Some(comment)
+	} else {
+		None
+	};
+	if flags & FHCRC != 0 {
+		reader.read_exact(&mut [0; 2])?;
+	}
+	Ok(comment)
+}
+
@@ -0,0 +1,92 @@
+use std::cmp;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use compression::Compression;
+
+/// The most read from the start of each file to sample how well it
+/// compresses.
+const SAMPLE_PER_FILE: u64 = 64 << 10;
+
+/// The most sampled in total.
+const SAMPLE_TOTAL: usize = 16 << 20;
+
+/// The size of a tar header or block.
+const BLOCK: u64 = 512;
+
+/// An estimate of the size of an archive, built up from the files which would
+/// be written to it, for dry runs.
+///
+/// The start of each file is kept as a sample, until there's enough to get a
+/// fair idea of how well the files compress.
+pub struct ArchiveEstimate {
+	files: u64,
+	bytes: u64,
+	archive_bytes: u64,
+	sample: Vec<u8>,
+}
+
+impl Default for ArchiveEstimate {
+	fn default() -> ArchiveEstimate {
+		ArchiveEstimate::new()
+	}
+}
+
+impl ArchiveEstimate {
+	/// Create an estimate of an empty archive.
+	pub fn new() -> ArchiveEstimate {
+		ArchiveEstimate {
+			files: 0,
+			bytes: 0,
+			// The end-of-archive marker
+			archive_bytes: 2 * BLOCK,
+			sample: Vec::new(),
+		}
+	}
+
+	/// Add the file at `path` to the estimate, returning its size (zero if it
+	/// can't be read).
+	pub fn add(&mut self, path: &Path) -> u64 {
+		let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+		self.files += 1;
+		self.bytes += size;
+		// A header, then the contents padded to a whole block. Long names and
+		// PAX records take more, but not much.
+		self.archive_bytes += BLOCK + size.div_ceil(BLOCK) * BLOCK;
+		if self.sample.len() < SAMPLE_TOTAL {
+			let len = cmp::min(SAMPLE_PER_FILE, (SAMPLE_TOTAL - self.sample.len()) as u64);
+			if let Ok(file) = File::open(path) {
+				// A partial sample is as good as any.
+				let _ = file.take(len).read_to_end(&mut self.sample);
+			}
+		}
+		size
+	}
+
+	/// The number of files added.
+	pub fn files(&self) -> u64 {
+		self.files
+	}
+
+	/// The total size of the files added.
+	pub fn bytes(&self) -> u64 {
+		self.bytes
+	}
+
+	/// The size of the archive before compression, including tar headers.
+	pub fn archive_bytes(&self) -> u64 {
+		self.archive_bytes
+	}
+
+	/// The ratio of compressed to uncompressed size of the sample, compressed
+	/// with `compression`, or `None` if there's nothing in it.
+	pub fn sample_ratio(&self, compression: Compression) -> Option<f64> {
+		if self.sample.is_empty() {
+			return None;
+		}
+		// Compressing into memory can't fail.
+		let compressed = compression.compress(&self.sample, true).unwrap();
+		Some(compressed.len() as f64 / self.sample.len() as f64)
+	}
+}
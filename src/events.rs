@@ -0,0 +1,144 @@
+use serde_json;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+
+use BackupError;
+use stats::{Phase, Skip, Summary};
+
+/// A stream of events describing a run as it progresses, for tools wrapping
+/// this one, written as newline-delimited JSON (one object per line).
+///
+/// Every event has an `event` field naming it:
+///
+/// * `phase`: a phase started or ended, with `name` (`load_checksums`,
+///   `checksum`, `save_checksums` or `archive`) and `state` (`start` or `end`); `end` events also have `seconds`, the
+///   time taken. When archiving is done while checksumming, both phases start
+///   and end together.
+/// * `file_hashed`: a file was checksummed, with its `path` (relative to the
+///   source root) and size in `bytes`.
+/// * `file_archived`: a file was added to the archive, likewise.
+/// * `file_skipped`: a file was skipped without being checksummed, with its
+///   `path` and the `reason` (`unreadable`, `excluded` or `special`).
+/// * `file_unstable`: a changed file was changing as it was backed up, with
+///   its `path` and a `message` saying how.
+/// * `error`: a file couldn't be read (with its `path`), or the run failed
+///   (without one), with a `message`.
+/// * `summary`: the run finished, with the same fields as the `--stats-json`
+///   report. This is always the last event of a run; with `--interval`, each
+///   backup is a run, and its events follow the last one's summary.
+///
+/// Fields may be added to events, and new events added, without notice.
+pub struct Events {
+	writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for Events {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Events")
+	}
+}
+
+impl Events {
+	/// Write events to `writer`.
+	pub fn new(writer: Box<dyn Write + Send>) -> Events {
+		Events { writer: Mutex::new(writer) }
+	}
+
+	/// Write events to the open file descriptor `fd`.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` if `fd` isn't open,
+	/// or on platforms without file descriptors.
+	pub fn from_fd(fd: i32) -> Result<Events, BackupError> {
+		Ok(Events::new(Box::new(fd_file(fd)?)))
+	}
+
+	/// Record that a phase has started.
+	pub fn phase_started(&self, phase: Phase) {
+		self.emit(format!(r#"{{"event":"phase","name":"{}","state":"start"}}"#, phase.name()));
+	}
+
+	/// Record that a phase has ended, having taken `seconds`.
+	pub fn phase_ended(&self, phase: Phase, seconds: f64) {
+		self.emit(format!(r#"{{"event":"phase","name":"{}","state":"end","seconds":{}}}"#,
+			phase.name(), seconds));
+	}
+
+	/// Record that a file of the given size has been checksummed or archived.
+	pub fn file_done(&self, phase: Phase, path: &str, bytes: u64) {
+		let event = match phase {
+			Phase::Checksum => "file_hashed",
+			Phase::Archive => "file_archived",
+			// Checksums are loaded and saved in bulk, so there's nothing to
+			// report for each.
+			Phase::LoadChecksums | Phase::SaveChecksums => return,
+		};
+		self.emit(format!(r#"{{"event":"{}","path":{},"bytes":{}}}"#, event, string(path), bytes));
+	}
+
+	/// Record that a file was skipped without being checksummed.
+	pub fn file_skipped(&self, path: &str, reason: Skip) {
+		self.emit(format!(r#"{{"event":"file_skipped","path":{},"reason":"{}"}}"#,
+			string(path), reason.name()));
+	}
+
+	/// Record that a changed file was changing as it was backed up.
+	pub fn file_unstable(&self, path: &str, message: &str) {
+		self.emit(format!(r#"{{"event":"file_unstable","path":{},"message":{}}}"#,
+			string(path), string(message)));
+	}
+
+	/// Record an error, reading the file at `path` if given, or otherwise
+	/// stopping the run.
+	pub fn error(&self, path: Option<&str>, message: &str) {
+		match path {
+			Some(path) => self.emit(format!(r#"{{"event":"error","path":{},"message":{}}}"#,
+				string(path), string(message))),
+			None => self.emit(format!(r#"{{"event":"error","message":{}}}"#, string(message))),
+		}
+	}
+
+	/// Record the final statistics for the run.
+	pub fn summary(&self, summary: &Summary) {
+		// Splice the event name into the report's object.
+		let report = summary.to_json();
+		self.emit(format!(r#"{{"event":"summary",{}"#, &report[1 ..]));
+	}
+
+	/// Write an event, as a single line.
+	fn emit(&self, mut event: String) {
+		event.push('\n');
+		// A wrapper which has stopped listening shouldn't stop the run, so
+		// errors are ignored.
+		let mut writer = self.writer.lock().unwrap();
+		let _ = writer.write_all(event.as_bytes()).and_then(|()| writer.flush());
+	}
+}
+
+/// Format a string as a JSON string.
+fn string(s: &str) -> String {
+	// Encoding a string can't fail.
+	serde_json::to_string(s).unwrap()
+}
+
+#[cfg(unix)]
+fn fd_file(fd: i32) -> Result<File, BackupError> {
+	use libc;
+	use std::io;
+	use std::os::unix::io::FromRawFd;
+	if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+		return Err(BackupError::OtherError(format!(
+			"Invalid --events-fd {}: {}", fd, io::Error::last_os_error())));
+	}
+	// The file descriptor was handed to us to write events to, so it's ours
+	// to close.
+	Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn fd_file(_fd: i32) -> Result<File, BackupError> {
+	Err(BackupError::OtherError("--events-fd is not supported on this platform".to_string()))
+}
@@ -0,0 +1,178 @@
+use blake3;
+use crc32c;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1;
+use crypto::sha2;
+use std::fs::File;
+use std::io::Read;
+
+use BackupError;
+
+/// The length, in bytes, of a key for `HashAlgorithm::HmacSha256`.
+pub const HMAC_KEY_LEN: usize = 32;
+
+/// An incremental checksum computation.
+///
+/// Implement this to plug a custom checksum algorithm into
+/// `operations::checksum_directory`.
+pub trait Hasher {
+	/// Feed more data into the checksum.
+	fn update(&mut self, data: &[u8]);
+
+	/// Consume the hasher, returning the checksum as a lowercase hexadecimal
+	/// string.
+	fn finalize(self: Box<Self>) -> String;
+}
+
+/// Checksumming algorithms selectable from the commandline.
+#[derive(Clone,Copy,Debug,Deserialize)]
+pub enum HashAlgorithm {
+	Sha1,
+	Sha256,
+	Blake3,
+	Crc32c,
+	HmacSha256,
+}
+
+impl HashAlgorithm {
+	/// Create a new hasher for this algorithm, keyed with `key` if it is keyed
+	/// (see `is_keyed`).
+	///
+	/// # Panics
+	///
+	/// This function panics if the algorithm is keyed and no key is given.
+	pub fn new_hasher(&self, key: Option<&[u8]>) -> Box<dyn Hasher> {
+		match *self {
+			HashAlgorithm::Sha1 => Box::new(Sha1(sha1::Sha1::new())),
+			HashAlgorithm::Sha256 => Box::new(Sha256(sha2::Sha256::new())),
+			HashAlgorithm::Blake3 => Box::new(Blake3(blake3::Hasher::new())),
+			HashAlgorithm::Crc32c => Box::new(Crc32c(0)),
+			HashAlgorithm::HmacSha256 => Box::new(HmacSha256(Hmac::new(sha2::Sha256::new(),
+				key.expect("HMAC-SHA256 requires a key")))),
+		}
+	}
+
+	/// Whether this algorithm requires a key.
+	pub fn is_keyed(&self) -> bool {
+		matches!(*self, HashAlgorithm::HmacSha256)
+	}
+
+	/// The name of this algorithm, as given on the commandline.
+	pub fn name(&self) -> &'static str {
+		match *self {
+			HashAlgorithm::Sha1 => "sha1",
+			HashAlgorithm::Sha256 => "sha256",
+			HashAlgorithm::Blake3 => "blake3",
+			HashAlgorithm::Crc32c => "crc32c",
+			HashAlgorithm::HmacSha256 => "hmacsha256",
+		}
+	}
+}
+
+/// Load a key for a keyed algorithm from `fname`, which should contain exactly
+/// `HMAC_KEY_LEN` bytes (e.g. as written by
+/// `head -c 32 /dev/urandom > backup.key`).
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the file cannot be read or is the wrong length.
+pub fn load_key(fname: &str) -> Result<Vec<u8>, BackupError> {
+	let mut key = Vec::new();
+	File::open(fname)
+		.and_then(|mut file| file.read_to_end(&mut key))
+		.map_err(|e| BackupError::OtherError(format!("Error reading key file {}: {}", fname, e)))?;
+	if key.len() != HMAC_KEY_LEN {
+		return Err(BackupError::OtherError(format!(
+			"Key file {} is {} bytes long, but should be {}", fname, key.len(), HMAC_KEY_LEN)));
+	}
+	Ok(key)
+}
+
+/// SHA-1, as output by `sha1sum`.
+pub struct Sha1(sha1::Sha1);
+
+impl Hasher for Sha1 {
+	fn update(&mut self, data: &[u8]) {
+		self.0.input(data);
+	}
+
+	fn finalize(mut self: Box<Self>) -> String {
+		self.0.result_str()
+	}
+}
+
+/// SHA-256, as output by `sha256sum`.
+pub struct Sha256(sha2::Sha256);
+
+impl Hasher for Sha256 {
+	fn update(&mut self, data: &[u8]) {
+		self.0.input(data);
+	}
+
+	fn finalize(mut self: Box<Self>) -> String {
+		self.0.result_str()
+	}
+}
+
+/// BLAKE3, as output by `b3sum`.
+pub struct Blake3(blake3::Hasher);
+
+impl Hasher for Blake3 {
+	fn update(&mut self, data: &[u8]) {
+		self.0.update(data);
+	}
+
+	fn finalize(self: Box<Self>) -> String {
+		self.0.finalize().to_hex().to_string()
+	}
+}
+
+/// HMAC-SHA256, keyed with a secret, so that checksums can't be forged by
+/// anyone without the key (unlike plain checksums, which anyone who can change
+/// a file can recompute).
+pub struct HmacSha256(Hmac<sha2::Sha256>);
+
+impl Hasher for HmacSha256 {
+	fn update(&mut self, data: &[u8]) {
+		self.0.input(data);
+	}
+
+	fn finalize(mut self: Box<Self>) -> String {
+		self.0.result().code().iter().map(|b| format!("{:02x}", b)).collect()
+	}
+}
+
+/// CRC-32C (Castagnoli), as eight hexadecimal digits.
+///
+/// This is hardware-accelerated on most CPUs, and so much faster than the
+/// other algorithms, but it is not a cryptographic hash: it only detects
+/// accidental corruption, and files with the same CRC are easy to construct.
+/// Don't use it where security matters.
+pub struct Crc32c(u32);
+
+impl Hasher for Crc32c {
+	fn update(&mut self, data: &[u8]) {
+		self.0 = crc32c::crc32c_append(self.0, data);
+	}
+
+	fn finalize(self: Box<Self>) -> String {
+		format!("{:08x}", self.0)
+	}
+}
+
+/// Discards the data fed to it, for measuring how fast files can be read
+/// without checksumming them (see the `benchmark` subcommand). Its checksum is
+/// always empty.
+pub struct NullHasher;
+
+impl Hasher for NullHasher {
+	fn update(&mut self, _data: &[u8]) {
+	}
+
+	fn finalize(self: Box<Self>) -> String {
+		String::new()
+	}
+}
@@ -0,0 +1,34 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A reader which counts the bytes read through it.
+///
+/// The count is shared, so several readers (e.g. on different checksum
+/// threads) can add to the same count, and it can be read from another
+/// thread as reading goes on (see `progress::ThroughputMeter`).
+#[derive(Debug)]
+pub struct TrackingReader<R: Read> {
+	inner: R,
+	bytes_read: Arc<AtomicU64>,
+}
+
+impl<R: Read> TrackingReader<R> {
+	/// Wrap a reader, adding the bytes read through it to `bytes_read`.
+	pub fn new(inner: R, bytes_read: Arc<AtomicU64>) -> TrackingReader<R> {
+		TrackingReader { inner, bytes_read }
+	}
+
+	/// Get a reference to the underlying reader.
+	pub fn get_ref(&self) -> &R {
+		&self.inner
+	}
+}
+
+impl<R: Read> Read for TrackingReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let len = self.inner.read(buf)?;
+		self.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+		Ok(len)
+	}
+}
@@ -0,0 +1,111 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use BackupError;
+use cancel;
+use operations::format_timestamp;
+
+/// How often to try again for a lock held by another run, while waiting.
+const RETRY_INTERVAL_MS: u64 = 200;
+
+/// An exclusive advisory lock on a file, held for the whole of a backup, so
+/// concurrent runs against the same checksums and destination fail (or wait)
+/// rather than corrupting each other's output.
+///
+/// The lock file records the pid of the run holding it and when it started,
+/// for the error reported to other runs. The lock is held by the open file,
+/// so it's released however the run ends, even by a panic or a signal; the
+/// file itself is left in place, since removing it could let a waiting run
+/// and a new one lock different files of the same name.
+#[derive(Debug)]
+pub struct RunLock {
+	file: File,
+}
+
+impl RunLock {
+	/// Lock the file at `path`, creating it if necessary. If another run holds
+	/// the lock, wait up to `wait` for it to be released (checking for
+	/// interruption between tries), or fail at once if not given.
+	///
+	/// On platforms other than Unix, files can't be locked, so this only
+	/// records this run in the file.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` with a descriptive
+	/// message if the file can't be opened or written, or another run holds the
+	/// lock (and doesn't release it in time), or a `BackupError::Cancelled` if
+	/// the run is interrupted while waiting.
+	pub fn acquire(path: &str, wait: Option<Duration>) -> Result<RunLock, BackupError> {
+		let lock_error = |e: io::Error| BackupError::OtherError(
+			format!("Error locking {}: {}", path, e));
+		// The file is only truncated once it's locked, so the holder's details
+		// survive.
+		let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+			.open(path)
+			.map_err(&lock_error)?;
+		let started = Instant::now();
+		let mut logged = false;
+		while !try_lock(&file).map_err(&lock_error)? {
+			let holder = holder(&mut file);
+			match wait {
+				Some(wait) if started.elapsed() < wait => {
+					if !logged {
+						info!("Another backup is already running ({}); waiting up to {}s for it to finish",
+							holder, wait.as_secs());
+						logged = true;
+					}
+					cancel::check()?;
+					thread::sleep(Duration::from_millis(RETRY_INTERVAL_MS));
+				},
+				_ => return Err(BackupError::OtherError(format!(
+					"Another backup is already running ({}; lock file {})", holder, path)))
+			}
+		}
+		file.set_len(0)
+			.and_then(|()| file.seek(SeekFrom::Start(0)))
+			.and_then(|_| writeln!(file, "{} {}", process::id(), format_timestamp(SystemTime::now())))
+			.map_err(&lock_error)?;
+		debug!("Locked {}", path);
+		Ok(RunLock { file })
+	}
+}
+
+impl Drop for RunLock {
+	fn drop(&mut self) {
+		// Clear this run's details; the lock is released as the file closes.
+		let _ = self.file.set_len(0);
+	}
+}
+
+/// Describe the run holding a lock, from the lock file's contents.
+fn holder(file: &mut File) -> String {
+	let mut contents = String::new();
+	let _ = file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_string(&mut contents));
+	let mut fields = contents.split_whitespace();
+	match (fields.next(), fields.next()) {
+		(Some(pid), Some(started)) => format!("pid {}, started {}", pid, started),
+		_ => "pid unknown".to_string()
+	}
+}
+
+/// Try to take an exclusive lock on a file without blocking, returning
+/// whether it was taken.
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+	use libc;
+	use std::os::unix::io::AsRawFd;
+	if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+		return Ok(true);
+	}
+	let e = io::Error::last_os_error();
+	if e.kind() == io::ErrorKind::WouldBlock { Ok(false) } else { Err(e) }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> io::Result<bool> {
+	Ok(true)
+}
@@ -0,0 +1,301 @@
+use env_logger;
+use log::{self, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+use serde::Serialize;
+use serde_json::{self, Map, Value};
+use std::cell::RefCell;
+use std::cmp;
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use operations::{format_local_timestamp, format_timestamp};
+
+/// Syslog facilities, by name, with their codes (from RFC 5424).
+const FACILITIES: &[(&str, u8)] = &[
+	("kern", 0), ("user", 1), ("mail", 2), ("daemon", 3), ("auth", 4), ("syslog", 5),
+	("lpr", 6), ("news", 7), ("uucp", 8), ("cron", 9), ("authpriv", 10), ("ftp", 11),
+	("local0", 16), ("local1", 17), ("local2", 18), ("local3", 19),
+	("local4", 20), ("local5", 21), ("local6", 22), ("local7", 23),
+];
+
+/// Log message formats.
+#[derive(Clone,Copy,Debug,PartialEq,Deserialize)]
+pub enum LogFormat {
+	/// One line of text per message.
+	Human,
+	/// One JSON object per line, with `timestamp`, `level`, `module`, `file`,
+	/// `line` and `message` fields, along with any given to `log_fields!`.
+	Json,
+}
+
+/// When to color log messages written to standard error.
+#[derive(Clone,Copy,Debug,PartialEq,Deserialize)]
+pub enum ColorChoice {
+	/// Only if standard error is a terminal.
+	Auto,
+	Always,
+	Never,
+}
+
+impl ColorChoice {
+	/// Whether to color messages written to standard error.
+	pub fn enabled(self) -> bool {
+		match self {
+			ColorChoice::Auto => io::stderr().is_terminal(),
+			ColorChoice::Always => true,
+			ColorChoice::Never => false,
+		}
+	}
+}
+
+thread_local! {
+	/// The structured fields of the message being logged on this thread (see
+	/// `log_fields!`).
+	static FIELDS: RefCell<Vec<(&'static str, Value)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Log a message, as `log!` does, along with structured fields (e.g. `path`
+/// or `bytes`) to be included in JSON log lines; human-readable lines only
+/// include the message, so it should mention any fields of interest. The
+/// fields are only evaluated if the message is logged.
+///
+/// For example: `log_fields!(LogLevel::Trace, {path: name, bytes: size},
+/// "Archived {}", name)`.
+macro_rules! log_fields {
+	($level:expr, { $($key:ident: $value:expr),* }, $($arg:tt)+) => {{
+		let level = $level;
+		if log_enabled!(level) {
+			::logger::with_fields(vec![$((stringify!($key), ::logger::field_value(&$value))),*],
+				|| log!(level, $($arg)+));
+		}
+	}}
+}
+
+/// Where to send log messages, besides the console logger given to `init`.
+pub struct Sinks {
+	/// Whether to write to standard error (through the console logger). The
+	/// console logger's filter applies to syslog too, however.
+	pub stderr: bool,
+	/// A log file, opened for appending, and the level to write to it at.
+	pub file: Option<(File, LogLevelFilter)>,
+	/// A connection to the local syslog daemon.
+	pub syslog: Option<Syslog>,
+	/// Timestamp lines in the local time zone, rather than UTC (see
+	/// `timestamp`).
+	pub local_time: bool,
+	/// The format of lines written to the console and log file. Messages sent
+	/// to syslog are always text.
+	pub format: LogFormat,
+	/// Color human-readable lines written to the console (see `paint`). Lines
+	/// sent anywhere else are never colored.
+	pub color: bool,
+}
+
+/// A logger writing to the console (as configured by `env_logger`) and,
+/// optionally, appending to a log file at its own level and sending to syslog.
+///
+/// Each line is written to the log file as it's logged, rather than buffered,
+/// so nothing is lost if the run exits early.
+struct Logger {
+	console: env_logger::Logger,
+	stderr: bool,
+	file: Option<(Mutex<File>, LogLevelFilter)>,
+	syslog: Option<Syslog>,
+	local_time: bool,
+	format: LogFormat,
+}
+
+/// A connection to the local syslog daemon, sending messages with the syslog
+/// protocol (RFC 3164, as `logger` and the C library do) to its socket.
+pub struct Syslog {
+	facility: u8,
+	#[cfg(unix)]
+	socket: Mutex<::std::os::unix::net::UnixDatagram>,
+}
+
+/// Log whatever `log` does with the given structured fields, for
+/// `log_fields!`.
+pub fn with_fields<F>(fields: Vec<(&'static str, Value)>, log: F) where F: FnOnce() {
+	FIELDS.with(|f| *f.borrow_mut() = fields);
+	log();
+	FIELDS.with(|f| f.borrow_mut().clear());
+}
+
+/// Convert a structured field's value to JSON, for `log_fields!`.
+pub fn field_value<T: Serialize + ?Sized>(value: &T) -> Value {
+	// Fields are strings, numbers and the like, which always convert.
+	serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+/// Format a record as a line of text, timestamped as for `timestamp`.
+pub fn human_line(record: &LogRecord, local_time: bool) -> String {
+	format!("{} [{} {}:{}] [{}] {}",
+		timestamp(local_time),
+		record.location().module_path(),
+		record.location().file(),
+		record.location().line(),
+		record.level(),
+		record.args())
+}
+
+/// Format a record as a JSON object, with any structured fields it was logged
+/// with, timestamped as for `timestamp`.
+pub fn json_line(record: &LogRecord, local_time: bool) -> String {
+	let mut object = Map::new();
+	object.insert("timestamp".to_string(), Value::String(timestamp(local_time)));
+	object.insert("level".to_string(), Value::String(record.level().to_string()));
+	object.insert("module".to_string(), Value::String(record.location().module_path().to_string()));
+	object.insert("file".to_string(), Value::String(record.location().file().to_string()));
+	object.insert("line".to_string(), Value::from(record.location().line()));
+	object.insert("message".to_string(), Value::String(record.args().to_string()));
+	FIELDS.with(|fields| {
+		for &(key, ref value) in fields.borrow().iter() {
+			object.entry(key.to_string()).or_insert_with(|| value.clone());
+		}
+	});
+	Value::Object(object).to_string()
+}
+
+/// Color a human-readable console line for a record with ANSI escape codes:
+/// errors in red and warnings in yellow, and otherwise by the record's
+/// structured fields (see `log_fields!`), with a `change` of `added` in green
+/// and `changed` in cyan, and a `summary` in bold.
+pub fn paint(record: &LogRecord, line: String) -> String {
+	let code = match record.level() {
+		LogLevel::Error => Some("31"),
+		LogLevel::Warn => Some("33"),
+		_ => FIELDS.with(|fields| fields.borrow().iter()
+			.filter_map(|&(key, ref value)| match (key, value.as_str()) {
+				("change", Some("added")) => Some("32"),
+				("change", Some("changed")) => Some("36"),
+				("summary", _) => Some("1"),
+				_ => None
+			})
+			.next())
+	};
+	match code {
+		Some(code) => format!("\x1b[{}m{}\x1b[0m", code, line),
+		None => line
+	}
+}
+
+/// The current time, as an RFC 3339 timestamp in the local time zone if
+/// `local_time`, or otherwise in UTC, for log lines.
+fn timestamp(local_time: bool) -> String {
+	if local_time {
+		format_local_timestamp(SystemTime::now())
+	} else {
+		format_timestamp(SystemTime::now())
+	}
+}
+
+impl Log for Logger {
+	fn enabled(&self, metadata: &LogMetadata) -> bool {
+		self.console.enabled(metadata) || self.file_enabled(metadata)
+	}
+
+	fn log(&self, record: &LogRecord) {
+		if self.console.enabled(record.metadata()) {
+			if self.stderr {
+				self.console.log(record);
+			}
+			if let Some(ref syslog) = self.syslog {
+				syslog.send(record);
+			}
+		}
+		if let Some((ref file, _)) = self.file {
+			if self.file_enabled(record.metadata()) {
+				let line = match self.format {
+					LogFormat::Human => format!("{}\n", human_line(record, self.local_time)),
+					LogFormat::Json => format!("{}\n", json_line(record, self.local_time)),
+				};
+				// There's nowhere left to report a failure to log.
+				let _ = file.lock().unwrap().write_all(line.as_bytes());
+			}
+		}
+	}
+}
+
+impl Logger {
+	fn file_enabled(&self, metadata: &LogMetadata) -> bool {
+		match self.file {
+			Some((_, level)) => metadata.level() <= level,
+			None => false,
+		}
+	}
+}
+
+impl Syslog {
+	/// Find the code of a syslog facility from its name (e.g. `daemon` or
+	/// `local0`).
+	pub fn facility(name: &str) -> Option<u8> {
+		FACILITIES.iter().find(|&&(n, _)| n == name).map(|&(_, code)| code)
+	}
+
+	/// Connect to the local syslog daemon, to send messages with the given
+	/// facility code.
+	///
+	/// # Errors
+	///
+	/// This function will return a description of the problem if the syslog
+	/// daemon's socket can't be reached, or on platforms other than Unix.
+	#[cfg(unix)]
+	pub fn connect(facility: u8) -> Result<Syslog, String> {
+		use std::os::unix::net::UnixDatagram;
+		let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+		// Linux and the BSDs use /dev/log; macOS uses /var/run/syslog.
+		let mut errors = Vec::new();
+		for path in &["/dev/log", "/var/run/syslog"] {
+			match socket.connect(path) {
+				Ok(()) => return Ok(Syslog { facility, socket: Mutex::new(socket) }),
+				Err(e) => errors.push(format!("{}: {}", path, e))
+			}
+		}
+		Err(errors.join("; "))
+	}
+
+	#[cfg(not(unix))]
+	pub fn connect(_facility: u8) -> Result<Syslog, String> {
+		Err("syslog is only supported on Unix".to_string())
+	}
+
+	/// Send a log record, with the syslog severity corresponding to its level.
+	#[cfg(unix)]
+	fn send(&self, record: &LogRecord) {
+		use log::LogLevel;
+		use std::process;
+		let severity = match record.level() {
+			LogLevel::Error => 3,
+			LogLevel::Warn => 4,
+			LogLevel::Info => 6,
+			LogLevel::Debug | LogLevel::Trace => 7,
+		};
+		// The daemon adds the time and host.
+		let message = format!("<{}>backup[{}]: [{}] {}",
+			self.facility as u32 * 8 + severity, process::id(),
+			record.location().module_path(), record.args());
+		// There's nowhere left to report a failure to log.
+		let _ = self.socket.lock().unwrap().send(message.as_bytes());
+	}
+
+	#[cfg(not(unix))]
+	fn send(&self, _record: &LogRecord) {
+	}
+}
+
+/// Install a logger writing to `console` and the given other `sinks`.
+pub fn init(console: env_logger::Logger, sinks: Sinks) -> Result<(), SetLoggerError> {
+	log::set_logger(|max_level| {
+		let file_level = sinks.file.as_ref().map_or(LogLevelFilter::Off, |&(_, level)| level);
+		max_level.set(cmp::max(console.filter(), file_level));
+		Box::new(Logger {
+			console,
+			stderr: sinks.stderr,
+			file: sinks.file.map(|(file, level)| (Mutex::new(file), level)),
+			syslog: sinks.syslog,
+			local_time: sinks.local_time,
+			format: sinks.format,
+		})
+	})
+}
@@ -1,26 +1,81 @@
+extern crate blake3;
+extern crate crc32c;
 extern crate crypto;
 extern crate docopt;
 extern crate env_logger;
 extern crate flate2;
+extern crate libc;
 #[macro_use]
 extern crate log;
-extern crate rustc_serialize;
+extern crate lz4_flex;
+#[cfg(unix)]
+extern crate nix;
+extern crate rusqlite;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate tar;
+extern crate unicode_normalization;
 extern crate walkdir;
+extern crate zip;
 
+pub mod archive;
+pub mod cancel;
+pub mod checksum_db;
+pub mod compression;
+pub mod estimate;
+pub mod events;
+pub mod hash;
+pub mod io;
+pub mod lock;
+#[macro_use]
+pub mod logger;
+pub mod manifest;
 pub mod operations;
+pub mod progress;
+pub mod schedule;
+pub mod stats;
+pub mod status;
+pub mod systemd;
+pub mod throttle;
+pub mod watch;
 
+use archive::{ArchiveFormat, TarFormat};
+use checksum_db::ChecksumDb;
+use compression::Compression;
 use docopt::Docopt;
+use estimate::ArchiveEstimate;
+use events::Events;
 use env_logger::LogBuilder;
-use log::{LogLevel, LogRecord, SetLoggerError};
-use std::collections::HashMap;
+use hash::{HashAlgorithm, NullHasher};
+use lock::RunLock;
+use logger::{ColorChoice, LogFormat};
+use operations::{ArchiveOptions, SourceRoots, Stability, WalkOptions};
+use operations::ScanTotals;
+use progress::{human_bytes, human_duration, Progress};
+use stats::{CompressionSummary, Phase, Stats, Summary};
+use systemd::Notifier;
+use throttle::Throttle;
+use watch::{Change, ChangeTracker, Watcher};
+use log::{LogLevel, LogLevelFilter, LogRecord, SetLoggerError};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::cmp;
 use std::env;
-use std::path::PathBuf;
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::iter;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 
-pub const USAGE: &'static str = "
+pub const USAGE: &str = "
 Checksum-based incremental backup utility using standard tools and formats.
 
 This program checksums the files in the target directory, optionally compares
@@ -28,174 +83,2903 @@ them to a set of preexisting checksums, collects changed files in a tarball,
 and writes the new checksums.
 
 Usage:
-  backup [options] [--] <source>... <destination>
+  backup merge-checksums [options] [-v...] --output <file> <base> <incremental>...
+  backup compare [options] [-v...] [--checksums <file>] <root> <archive>
+  backup export-checksums [options] [-v...] --checksum-db <file> --output <file>
+  backup import-checksums [options] [-v...] --checksum-db <file> <checksum-file>
+  backup tag [options] [-v...] --archive <file> (--tag <key=value>)...
+  backup list [options] [-v...] <archive>
+  backup restore-chain [options] [-v...] --full <file> [--incremental <file>]... <destination>
+  backup benchmark [options] [-v...] [--map <mapping>]... [--exclude-by-owner <user>]... [--include-by-owner <user>]... [--follow-mount-points <mountpoint>]... [--] <source>...
+  backup watch [options] [-v...] [--map <mapping>]... [--exclude-by-owner <user>]... [--include-by-owner <user>]... [--follow-mount-points <mountpoint>]... [--] <source>... <destination>
+  backup daemon [options] [-v...] [--map <mapping>]... [--exclude-by-owner <user>]... [--include-by-owner <user>]... [--follow-mount-points <mountpoint>]... [--] <source>... <destination>
+  backup [options] [-v...] [--map <mapping>]... [--new-checksums <file>]... [--exclude-by-owner <user>]... [--include-by-owner <user>]... [--follow-mount-points <mountpoint>]... [--] <source>... <destination>
+  backup [options] [-v...] [--map <mapping>]... [--new-checksums <file>]... [--exclude-by-owner <user>]... [--include-by-owner <user>]... [--follow-mount-points <mountpoint>]... --files-from <file> [--] <destination>
   backup (-h | --help)
   backup --version
 
 Options:
   -h --help     Show this screen.
   --version     Show version.
+  -v, --verbose
+                Log more detail: debug messages, or given twice, trace messages
+                too. Logging defaults to informational messages and above. If
+                RUST_LOG is set and not empty (e.g.
+                RUST_LOG=backup::operations=trace), it takes precedence over
+                this and --quiet.
+  -q, --quiet   Only log warnings and errors. Takes precedence over --verbose.
+  --log-file <file>
+                Also append log messages, with timestamps, to the given file,
+                regardless of --verbose, --quiet and RUST_LOG. The run fails to
+                start if the file can't be opened.
+  --log-file-level <level>
+                The least severe messages to write to --log-file (error, warn,
+                info, debug or trace). [default: debug]
+  --log-local-time
+                Timestamp log messages in the local time zone, rather than in
+                UTC. Only supported on Unix.
+  --log-format <format>
+                The format of log messages written to standard error and the
+                log file: human, or json for one JSON object per line, with
+                timestamp, level, module, file, line and message fields, and
+                for some messages fields such as path, reason, phase and error.
+                [default: human]
+  --color <when>
+                When to color messages written to standard error: errors in
+                red, warnings in yellow, files added or changed in the dry-run
+                list in green or cyan, and the summary in bold. auto colors
+                them only on a terminal; always and never do as they say.
+                Lines written to the log file, syslog, events or standard
+                output are never colored. [default: auto]
+  --log-syslog  Also send log messages (at the same level as to standard
+                error) to the local syslog daemon, with severities matching
+                their levels. If syslog can't be reached, a warning is logged
+                and the backup carries on. Only supported on Unix.
+  --syslog-only
+                Like --log-syslog, but don't also log to standard error, unless
+                syslog can't be reached.
+  --syslog-facility <facility>
+                The syslog facility to log with (kern, user, mail, daemon, auth,
+                syslog, lpr, news, uucp, cron, authpriv, ftp, or local0 to
+                local7). [default: daemon]
+  --no-sd-notify
+                Don't notify systemd of the backup's state. By default, when
+                run as a Type=notify service (NOTIFY_SOCKET is set), a backup
+                reports itself ready once its commandline is parsed, updates
+                its status every few seconds with its phase and progress,
+                pings the watchdog (if WatchdogSec is set) while files are
+                being processed, and reports when it's stopping. Since the
+                watchdog is only pinged as files are started and finished,
+                WatchdogSec needs to be longer than reading the largest file
+                takes. Only supported on Linux.
   -r <dir>, --source-root <dir>
                 The root of the backup. This should be a prefix to the source
                 path. This prefix will be removed from file paths when
                 constructing the destination file. Default is the current
                 working directory.
+  --files-from <file>
+                Back up the sources listed in <file> (or standard input, if
+                it's -), one per line, as well as any given on the
+                commandline, which can then be left out. Like them, they're
+                relative to the source root, and directories are walked, so
+                list only files (e.g. with find -type f) to back up just
+                those. Sources listed which don't exist (e.g. because they
+                were deleted since the list was made) are skipped with a
+                warning, unless none of them exist.
+  --ignore-missing-sources
+                Skip sources given on the commandline which don't exist,
+                with a warning, rather than failing. The run then exits with
+                status 2.
+  -0, --null    With --files-from, the sources are separated by NUL
+                characters instead, as written by find -print0 (and read by
+                xargs -0 and tar --null), so they may contain newlines.
+  --verify-source <min-files>
+                Before starting, check that the source root directly contains
+                at least this many entries, and fail if it doesn't, e.g.
+                because the filesystem that should be mounted there isn't, and
+                the backup would otherwise find nothing to back up.
+  -m <mapping>, --map <mapping>
+                An additional source root, given as <root>=<prefix>. Files
+                beneath <root> are recorded under <prefix>/ followed by their
+                path relative to <root>, and sources starting with <prefix>/
+                are looked for beneath <root>. May be given more than once;
+                prefixes may not overlap.
   -c <file>, --old-checksums <file>
                 Checksums to compare against. If not specified, all target
                 files will be backed up; otherwise, all non-matching and new
-                files will be backed up. The format should be filename,
-                whitespace, hexadecimal checksum (as output by e.g. md5sum).
+                files will be backed up. The format should be hexadecimal
+                checksum, whitespace, filename (as output by e.g. sha1sum,
+                including its escaping of filenames containing backslashes or
+                newlines). If this is -, checksums are read from standard
+                input (and the version name isn't continued from them).
+  --since-archive <file>
+                Compare against the checksums embedded in a previous backup's
+                archive (written with --output-checksums-to-archive), rather
+                than a checksum file, continuing its version name. It can't be
+                combined with --old-checksums, --checksum-db or --low-memory.
   -n <file>, --new-checksums <file>
                 File to which to write checksums. The file will be overwritten
-                by filename, whitespace, hexadecimal checksum (as output by
-                e.g. sha1sum).
+                by hexadecimal checksum, tab, filename, with filenames escaped
+                as by sha1sum where necessary. If this is -, checksums are
+                written to standard output. May be given more than once, to
+                write the same checksums to several files (e.g. a local file
+                and one on a network share).
+  --checksum-db <file>
+                A SQLite database to use instead of old and new checksum files,
+                which is faster to load for huge trees. Checksums are loaded
+                from it in place of --old-checksums, and replaced by the new
+                checksums (with each file's size and modification time) once
+                the backup is complete, all or nothing. The database is locked
+                for the whole run, so concurrent runs fail rather than
+                interfering. export-checksums and import-checksums convert
+                between it and checksum files.
+  --no-checksum-verify
+                Only read and checksum files which look modified, reusing the
+                previous checksums of the rest, as rsync does by default. Files
+                look modified if their size or modification time has changed
+                (recorded with --checksum-db), or otherwise if they've been
+                modified since the old checksums were written. This is much
+                faster, but weaker: a file changed without changing its size
+                or modification time is not archived. It can't be combined
+                with --low-memory.
+  --lock-file <file>
+                Lock this file for the whole run, so another backup using the
+                same lock file fails at once (reporting the pid and start time
+                of the run holding it), rather than both corrupting the same
+                checksums and archive. Defaults to the first --new-checksums
+                file, or otherwise the destination, with .lock appended (for
+                watch and daemon, checksums.lock in the destination). The lock
+                is released however the run ends; the file is left in place.
+                Dry runs don't lock. Only supported on Unix.
+  --wait-for-lock <seconds>
+                If another backup holds the lock, wait up to this long for it
+                to finish, rather than failing at once.
+  --interval <duration>
+                Back up repeatedly until stopped: once straight away, then at
+                each whole multiple of this interval since the Unix epoch (e.g.
+                30m, 6h or 1d; a plain number is seconds). Times which pass
+                while a backup is still running are skipped. Each backup is
+                written to the destination with the time it started inserted
+                before the extension (e.g. backup-20240101T120000Z.tar.gz), and
+                compared against the checksums written by the one before (so
+                either a --new-checksums file or --checksum-db is required).
+                Each backup takes the lock (see --lock-file), and logs its own
+                summary and exit status, also written to the --stats-json file;
+                one failing doesn't stop the next. SIGINT or SIGTERM between
+                backups exits with status 0. For daemon, this is instead how
+                often to back up the changes (see below).
+  --interval-jitter <duration>
+                With --interval, delay each backup by a random time up to this
+                long, so machines sharing a schedule don't all start at once.
+                [default: 0]
   -x <algorithm>, --hash-algorithm <algorithm>
-                Checksumming algorithm to use. Available options are platform-
-                dependent. This option affects the interpretation of checksums
-                in the old-checksums and new-checksums files. BUG: At the
-                moment, this option is ignored. [default: sha1]
+                Checksumming algorithm to use. Available options are sha1,
+                sha256, blake3, crc32c, and hmacsha256. This option affects the
+                interpretation of checksums in the old-checksums and
+                new-checksums files. crc32c is very fast, but is only suitable
+                for detecting accidental changes (e.g. bit rot on a trusted
+                disk): it is trivial to make a different file with the same
+                CRC, so don't rely on it where anyone might do so deliberately.
+                hmacsha256 requires --hmac-key; without the key, nobody can
+                forge checksums to match files they've tampered with, even if
+                they can change the checksum file too. [default: sha1]
+  --hmac-key <file>
+                The key for --hash-algorithm hmacsha256: a file containing 32
+                random bytes (e.g. from head -c 32 /dev/urandom). Keep it
+                somewhere other than the files and checksums it protects.
+  -j <n>, --jobs <n>
+                Number of threads with which to checksum files. Defaults to the
+                number of CPUs, up to 4. With 0, files are checksummed on the
+                main thread, between archiving changed files.
+  --buffer-size <bytes>
+                Size of the buffer with which each thread reads files while
+                checksumming them, with an optional K, M, or G suffix. Larger
+                buffers may be faster on fast disks; smaller ones save memory.
+                For benchmark, a comma-separated list (e.g. 64K,1M,8M) reads
+                the files once with each size, to compare them. [default: 1M]
+  -f <format>, --tar-format <format>
+                Tar header format to write. Available options are pax, gnu,
+                and ustar. PAX can represent arbitrarily long names, large
+                files, sub-second mtimes, and sparse files; ustar cannot store
+                names over 255 bytes or files over 8 GiB, and refuses such
+                files. [default: pax]
+  --archive-comments <text>
+                A comment to store in the archive's header, for future
+                reference (e.g. pre-deployment snapshot). list shows it.
+  --compression <format>
+                Compression for the archive. Available options are gzip and
+                lz4. lz4 compresses less well but many times faster, for
+                backups limited by the CPU; its archives are tar files in lz4
+                frames (conventionally named .tar.lz4), which lz4 -d and
+                tar -I lz4 read. list, compare and tag read either kind.
+                [default: gzip]
+  --archive-format <format>
+                Container for the archive: tar (compressed as chosen with
+                the compression option), or zip, for recipients whose tools
+                (e.g. Windows Explorer) don't read tar. Zip files deflate each
+                file separately (storing those under --compress-threshold),
+                keep modification times to two seconds and only Unix
+                permissions, and ignore --tar-format. They can't be split
+                with --target-size, compressed on more than one thread,
+                written with --low-memory or to a device or named pipe, or
+                used with watch or daemon; list reads them, but compare and
+                tag don't. [default: tar]
+  --compress-threshold <bytes>
+                Store files smaller than this (with an optional K, M, or G
+                suffix, e.g. 4K) uncompressed in the archive, since small
+                files compress poorly and can even grow. The archive is still
+                a gzipped tar file, with the uncompressed entries in gzip
+                members of their own. Only applies to gzip. [default: 0]
+  --target-size <bytes>
+                Split the archive into parts of about this much compressed
+                data each (with an optional K, M, or G suffix, e.g. 4G), named
+                like the destination with _001, _002 and so on before its
+                extension. Each part is a complete gzipped tar file, and may
+                overshoot the target by up to one file. list, compare and tag
+                accept the destination's name for the whole set of parts. If
+                <destination> is a device (such as a tape drive) or a named
+                pipe, the archive is written to it directly, rather than to a
+                .partial file renamed into place, and can't be split.
+  --archive-threads <n>
+                Number of threads with which to compress the archive. With more
+                than one, each file is compressed separately and the results
+                concatenated, which is faster on multicore machines but
+                compresses slightly worse. With more than one, the archive is
+                written after all files are checksummed, as with --no-pipeline.
+                Either way, files are archived in order of name, so the same
+                files make the same archive. [default: 1]
+  --no-pipeline
+                Checksum all files before archiving any, rather than archiving
+                changed files as soon as they are checksummed (while
+                checksumming continues). This walks the source twice and is
+                slower, but nothing is written if checksumming fails. Implied
+                by --dry-run and --fail-on-case-collision, and by more than one
+                archive thread.
+  --low-memory  Keep memory use bounded however many files there are, by
+                sorting checksums in temporary files and comparing them with
+                the old checksums in order, rather than holding them all in
+                memory. The archive is written after all files are
+                checksummed, with one thread. Filenames differing only by case
+                aren't checked for, and this can't be combined with a minimum
+                percentage of changed files (--min-changed-pct) or with
+                embedding checksums in the archive
+                (--output-checksums-to-archive).
+  -o, --one-file-system
+                Don't back up anything on a different filesystem to the source
+                root (e.g. network shares or tmpfs mounted beneath it). Only
+                supported on Unix.
+  --follow-mount-points <mountpoint>
+                Back up the filesystem mounted at this path, even though it
+                differs from the source root's, while still skipping any other
+                filesystems. Implies --one-file-system; may be given more than
+                once.
+  -u <form>, --normalize-unicode <form>
+                Unicode normalization to apply to filenames when comparing them
+                to the old checksums and writing the new checksums (nfc, nfd,
+                or none), so the same file from macOS (which uses NFD) and Linux
+                (which usually uses NFC) is recognized as unchanged. Files are
+                still archived under their names as found on disk. Filenames
+                which are not valid UTF-8 are skipped regardless.
+                [default: none]
+  --min-depth <n>
+                Skip files fewer than this many directory levels below each
+                source. With 1, sources which are themselves files are skipped;
+                with 2, so are files directly within source directories.
+                Combined with --max-depth, only files between the two depths
+                (inclusive) are backed up; the minimum must not be greater than
+                the maximum.
+  --max-depth <n>
+                Descend at most this many directory levels below each source.
+                With 1, only files directly within source directories are backed
+                up; with 0, only sources which are themselves files.
+  -e <user>, --exclude-by-owner <user>
+                Skip files owned by the given user (a username or numeric uid).
+                May be given more than once. Only supported on Unix.
+  -i <user>, --include-by-owner <user>
+                Skip files not owned by the given user (a username or numeric
+                uid). May be given more than once, in which case files owned by
+                any of the users are included. Only supported on Unix.
+  --exclude-temp-files
+                Skip common temporary files and build outputs: files named
+                *.tmp, *.temp, *.swp, *.bak, *~, *.pyc, *.class, *.o, *.so or
+                *.a, and everything in directories named __pycache__ or target.
+  --no-canonicalize
+                Use the source root as given, rather than resolving it to its
+                canonical path. Use this if the root is reached through a
+                symlink and the symlinked view is intended.
+  --allow-outside-root
+                Allow sources which are not beneath the source root. Their files
+                are recorded under @outside-root/ followed by their absolute
+                path (e.g. @outside-root/tmp/other/file for /tmp/other/file).
+                Without this, such sources are an error.
+  --relative-paths
+                Archive files under their paths relative to the source root
+                (or beneath their mapped prefix), as recorded in the checksum
+                file. This is the default.
+  --absolute-paths
+                Archive files under their absolute paths, less the leading /,
+                as tar does. Such archives can't be checked with compare.
+  --strip-components <n>
+                Remove this many leading components from the path each file is
+                archived under, as tar --strip-components does. Files with no
+                more components than this aren't archived. Checksum files
+                are unaffected, so such archives can't be checked with compare.
+  --allow-network-source
+                Don't warn if a source root is on a network filesystem (e.g. NFS
+                or SMB), where checksumming may be slow and unreliable.
+  --drop-caches
+                Avoid disturbing the rest of the system: on Linux, read source
+                files without updating their access times (where permitted),
+                and drop their contents from the page cache once read.
+                Elsewhere, this does nothing.
+  --bwlimit <rate>
+                Limit the rate at which source files are read, in bytes per
+                second, with an optional K, M, G, or T suffix (e.g. 50M for 50
+                MiB/s), across all threads. 0 means no limit.
+  --nice
+                Run at low priority: with the lowest CPU priority on Unix, and
+                in the idle I/O scheduling class on Linux.
+  --nice-delay <ms>
+                Sleep for this many milliseconds after checksumming each file,
+                to leave gaps for other work.
+  --retries <n>
+                Try files which give transient errors (timeouts, I/O errors
+                and stale file handles, as network filesystems give when the
+                server is briefly unavailable) again up to this many times,
+                reopening them and checksumming them from the start, waiting
+                half a second before the first retry and twice as long before
+                each one after that. Files read after retrying are counted at
+                the end of the run. [default: 3]
+  --ignore-errors
+                If a file can't be read, log a warning and skip it (leaving it
+                out of the new checksums, so it will be archived by a later
+                run), rather than stopping. Files skipped are listed at the
+                end of the run.
+  --strict      Treat any file which can't be backed up (because it can't be
+                read, vanishes, can't be archived, or changes as it's
+                archived) as an error: stop, remove the partial archive and
+                checksum files, and exit with status 3, listing the files.
+                Can't be used with --ignore-errors.
+  --list-skipped
+                At the end of the run, log every file skipped without being
+                checksummed, and why: unreadable (with the error), not a
+                regular file, or excluded (by owner, by being on another
+                filesystem, or by having a name which isn't valid UTF-8).
+                Otherwise, most skipped files are only logged with -vv, and
+                the --stats-json report lists at most the first 1000.
+  --report-skipped <file>
+                At the end of the run, however it ended, write every file
+                skipped (as listed by --list-skipped) to <file>, one per line,
+                followed by a tab and why. Files skipped because permission
+                to read them was denied are otherwise only counted, with the
+                first few named, at the end of the run.
+  --skip-unstable
+                Leave out of the archive changed files whose size or
+                modification time changed after they were checksummed (e.g.
+                files being written to), rather than checksumming them again
+                and archiving them under their new checksums. Files are also
+                checked once they're archived, and their checksums aren't
+                saved if they changed meanwhile. Either way, these files are
+                archived by the next run, and listed at the end of this one.
+                With --low-memory, files are only checked once archived.
+  -l, --long-paths
+                On Windows, access files through extended-length (\\\\?\\) paths,
+                allowing paths longer than 260 characters. Filenames in the
+                checksum files and archive are unaffected. Has no effect on
+                other platforms.
+  --fail-on-case-collision
+                Fail if any filenames differ only by case (and so would collide
+                if restored to a case-insensitive filesystem), rather than just
+                warning. This is checked before anything is written, so this
+                implies --no-pipeline.
+  --version-name <name>
+                Record this backup's version name (e.g. v1.2.3) at the top of
+                the new checksum file. If not given, and the old checksum file
+                records a version name ending in a number, the number is
+                incremented (so v1.2.3 is followed by v1.2.4).
+  --min-changed-pct <n>
+                Don't write an archive (or copy or link unchanged files) if
+                fewer than this percentage of files have changed. Unchanged
+                files are still recorded in the new checksum file, but changed
+                and new files keep their old checksums or are left out, so
+                they're archived by a later run.
+  --always-write-archive
+                Write an archive however few files have changed (overriding
+                the minimum percentage given with --min-changed-pct).
+  --output-checksums-to-archive
+                Also embed the new checksums, as they'd be written to the
+                checksum file, in the archive as its first entry (named
+                CHECKSUMS.txt), so that the archive is self-contained and can be
+                checked with compare on its own. Changed files are then archived
+                once every file has been checksummed, rather than as they're
+                found. Files which then can't be archived, or change as they
+                are, are still listed in CHECKSUMS.txt, so they're also recorded
+                at the end of the archive, for compare, --since-archive and
+                restore-chain to leave out. In a zip archive, CHECKSUMS.txt is
+                the last entry, written once the files are, without them.
+  --check-disk-space
+                Before writing the archive, check that the destination's
+                filesystem has room for it, taking the total size of the
+                changed files as an upper bound, and stop if not. Changed
+                files are then archived once every file has been checksummed,
+                rather than as they're found. With --low-memory, which files
+                have changed isn't known in advance, so the total size of all
+                the files is used. Only supported on Unix.
+  --min-free-space <bytes>
+                Also require this much space (with an optional K, M, G, or T
+                suffix, e.g. 10G) to be left free on the destination's
+                filesystem once the archive is written. This implies the
+                check of --check-disk-space.
+  --checksum-header
+                Record the version of this program, the time, the source root
+                and the checksumming algorithm in comments at the top of the
+                new checksum file.
+  -b <dir>, --backup-dir <dir>
+                Copy unchanged files into this directory, in the same directory
+                structure as beneath the source root, so that it and the backup
+                file together hold a complete snapshot.
+  --hardlink-dest <dir>
+                With --backup-dir, hard link unchanged files into the backup
+                directory from the same place in <dir> (typically the previous
+                run's backup directory), rather than copying them from the
+                source, so that every run gets a complete snapshot using little
+                extra space. Files which can't be linked are copied.
+  --write-pid-file <file>
+                Write the PID of this process to the given file while it runs.
+                The file is removed on exit, including on errors.
+  -p, --progress
+                Show progress (files and bytes done out of the total) on stderr,
+                on a single line if it is a terminal, or otherwise as a log line
+                every few seconds. While checksumming, the rate files are being
+                read at over the last second is shown too, with the current
+                file, to show which files slow the backup down. Finding the
+                totals takes an extra walk of the source.
+  --pre-scan    Before checksumming, walk the source without reading anything,
+                and log how many files would be examined and their total size,
+                and roughly how many of them are new or modified since the
+                previous backup (from the recorded sizes and modification
+                times with --checksum-db, or otherwise the time the old
+                checksum file was written). This uses the same walk as finding
+                the totals for --progress.
+  --stats-json <file>
+                Write a report of the run to the given file (or standard output,
+                if this is -) as a JSON object, as well as logging a summary at
+                the end of the run. The report is written even if the run
+                fails. It includes the summary's statistics, start and finish
+                times, exit status and error, hash algorithm, compression
+                settings, and the files which were skipped or couldn't be read
+                (up to 1000 of each, with their counts). Its version field
+                is increased when fields are removed or change meaning.
+  --events-fd <fd>
+                Write events describing the run as it progresses (phases
+                starting and ending, each file checksummed, archived or
+                skipped, errors, and a final summary) to the given open file
+                descriptor, as newline-delimited JSON, for tools wrapping this
+                one. Use a descriptor other than 2 to keep them apart from the
+                log. See src/events.rs for the events and their fields.
+  --output <file>
+                For merge-checksums and export-checksums, the file to write
+                checksums to.
+  --checksums <file>
+                For compare, the checksum file written by the backup being
+                compared against. compare checks the files beneath <root> (e.g.
+                a restored copy) against these checksums and against the
+                copies of files in <archive>, lists files found in only one or
+                the other and files which differ, and fails if there are any.
+                If this isn't given, the checksums embedded in <archive> by the
+                backup (see --output-checksums-to-archive) are used.
+  --archive <file>
+                For tag, the archive to add tags to.
+  --tag <key=value>
+                For tag, a tag to add to the archive (e.g.
+                backup.host=myserver), replacing any existing tag with the same
+                key. Tags are stored in a PAX global header at the start of the
+                archive, and shown by list, which lists an archive's tags and
+                files. May be given more than once.
+  --full <file>
+                For restore-chain, the full backup to restore first.
+  --incremental <file>
+                For restore-chain, an incremental backup to apply after the
+                full backup. May be given more than once, oldest first.
+  --settle <seconds>
+                For watch, how long changes must stop for before they're backed
+                up. [default: 5]
+  --watch-interval <seconds>
+                For watch, don't watch for changes with inotify, but check
+                all the files again every this many seconds, backing up those
+                which have changed. For filesystems where inotify doesn't see
+                changes (e.g. NFS or SMB mounts) or isn't available (e.g. in
+                some containers, or on platforms other than Linux), at the
+                cost of checksumming every file each time.
   -d, --dry-run
                 Don't actually write any files, print what would be done
-                instead.
+                instead, including the files which would be archived with
+                their sizes, and an estimate of the archive's size.
+  --estimate-ratio <ratio>
+                For --dry-run, estimate the archive's size assuming it
+                compresses to this fraction of its original size (e.g. 0.5).
+                By default, the ratio is found by compressing a sample of the
+                files to be archived.
+
+watch backs up each <source> as the main command does, then watches them for
+changes (only on Linux, with inotify) and backs up the changed files whenever
+changes stop for --settle seconds, or with --watch-interval, checks every file
+again at that interval instead. <destination> is a directory: each backup is
+written to a new archive in it, named for when it was written (e.g.
+backup-20240101T120000Z.tar.gz, or .tar.lz4 with --compression lz4), and the
+checksums of all the files to the file checksums in it, which each backup is
+compared against. If the kernel's queue of changes overflows, everything is
+checksummed again. SIGINT or SIGTERM stops watching, interrupting any backup
+in progress, and exits with status 0; changes not yet backed up are backed up
+when watch is next started. Checksum files can't be used with watch, and nor
+can --checksum-db, --since-archive, --low-memory, --dry-run, --strict, a
+list of sources from --files-from, or --ignore-missing-sources.
+
+daemon is like watch, but rather than backing up changes once they stop, it
+keeps track of the paths which have changed, and backs up only those when
+asked to: on SIGHUP, or with --interval, at each whole multiple of the
+interval (plus up to --interval-jitter), whichever is first. If nothing has
+changed, nothing is written. Its destination, archives and checksums are as
+for watch, and the same options can't be used with it.
+
+benchmark reads every file in each <source>, as the main command would with
+the same options (e.g. --jobs, --buffer-size, --drop-caches and --bwlimit),
+but without checksumming or archiving anything, and reports how fast they
+were read. It also reports how fast --hash-algorithm checksums data already
+in memory, on one thread. If files are read much faster than they can be
+checksummed, backups are CPU-bound, and more --jobs (or a faster algorithm)
+will help; if not, they're I/O-bound, and a different --buffer-size or
+fewer --jobs may. To compare buffer sizes, give several to --buffer-size
+(e.g. 64K,1M,8M). Files read again may be read faster from the cache,
+which --drop-caches avoids.
+
+restore-chain restores a full backup to <destination> (a directory, created if
+need be), then applies each incremental backup after it in the order given:
+files in each replace the copies already restored, and files deleted since the
+previous backup are removed. Deleted files are found from the checksums each
+backup embeds (see --output-checksums-to-archive); an incremental backup
+without them is applied without removing anything. Files are restored with
+their permissions and modification times; names which would be outside
+<destination> are refused. Zip archives can't be restored.
+
+On Unix, sending a backup SIGUSR1 (e.g. kill -USR1 <pid>) writes a line to
+stderr describing what it's doing: the current phase and file, the files and
+bytes done in that phase, how long it and the whole run have taken, and the
+rate.
+
+Exit status:
+  0  Success: every file was checksummed, and changed files archived.
+  1  The commandline was invalid.
+  2  The backup completed, but some files couldn't be read (e.g. because of
+     their permissions, or because they vanished during the run) and were
+     skipped, changed as they were archived (see --skip-unstable), or
+     sources didn't exist (see --ignore-missing-sources); they're logged,
+     and listed in the --stats-json report. With --strict, the run fails
+     instead.
+  3  The run failed (or, for compare, the files differ from the backup).
+  4  The backup completed, but no files had changed.
+  5  Logging couldn't be set up.
+  6  The backup was interrupted by SIGINT or SIGTERM. It stops between
+     files, removes the partial archive and checksum files, and logs how
+     far it got; a second signal exits at once (with 128 plus the signal's
+     number). Archives and checksum files are written to <name>.partial
+     and only renamed into place once complete, so a previous file of the
+     same name is never damaged.
 ";
 
-#[derive(Debug,RustcDecodable)]
+/// Exit statuses, as documented in `USAGE`.
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_USAGE: i32 = 1;
+const EXIT_PARTIAL: i32 = 2;
+const EXIT_FAILURE: i32 = 3;
+const EXIT_UNCHANGED: i32 = 4;
+const EXIT_NO_LOG: i32 = 5;
+const EXIT_CANCELLED: i32 = 6;
+
+#[derive(Clone,Debug,Deserialize)]
 struct Args {
+	cmd_merge_checksums: bool,
+	arg_base: String,
+	arg_incremental: Vec<String>,
+	flag_output: Option<String>,
+	cmd_compare: bool,
+	arg_root: String,
+	arg_archive: String,
+	flag_checksums: Option<String>,
+	cmd_export_checksums: bool,
+	cmd_import_checksums: bool,
+	arg_checksum_file: String,
+	flag_checksum_db: Option<String>,
+	cmd_tag: bool,
+	flag_archive: Option<String>,
+	flag_tag: Vec<String>,
+	cmd_list: bool,
+	cmd_restore_chain: bool,
+	flag_full: Option<String>,
+	flag_incremental: Vec<String>,
+	cmd_benchmark: bool,
+	cmd_watch: bool,
+	flag_settle: u64,
+	flag_watch_interval: Option<u64>,
+	cmd_daemon: bool,
 	arg_source: Vec<String>,
 	arg_destination: String,
 	flag_source_root: Option<String>,
+	flag_files_from: Option<String>,
+	flag_null: bool,
+	/// The sources read from --files-from (see `operations::read_file_list`).
+	#[serde(skip)]
+	listed_sources: Vec<String>,
+	flag_ignore_missing_sources: bool,
+	flag_verify_source: Option<usize>,
+	flag_map: Vec<String>,
 	flag_old_checksums: Option<String>,
-	flag_new_checksums: Option<String>,
-	flag_hash_algorithm: String,
+	flag_since_archive: Option<String>,
+	flag_new_checksums: Vec<String>,
+	flag_hash_algorithm: HashAlgorithm,
+	flag_hmac_key: Option<String>,
+	flag_jobs: Option<usize>,
+	flag_buffer_size: String,
+	flag_tar_format: TarFormat,
+	flag_archive_comments: Option<String>,
+	flag_compression: Compression,
+	flag_archive_format: ArchiveFormat,
+	flag_compress_threshold: String,
+	flag_target_size: Option<String>,
+	flag_archive_threads: usize,
+	flag_normalize_unicode: operations::Normalization,
+	flag_no_pipeline: bool,
+	flag_low_memory: bool,
+	flag_backup_dir: Option<String>,
+	flag_hardlink_dest: Option<String>,
+	flag_one_file_system: bool,
+	flag_follow_mount_points: Vec<String>,
+	flag_min_depth: Option<usize>,
+	flag_max_depth: Option<usize>,
+	flag_exclude_by_owner: Vec<String>,
+	flag_include_by_owner: Vec<String>,
+	flag_exclude_temp_files: bool,
+	flag_long_paths: bool,
+	flag_no_canonicalize: bool,
+	flag_allow_outside_root: bool,
+	flag_relative_paths: bool,
+	flag_absolute_paths: bool,
+	flag_strip_components: Option<usize>,
+	flag_allow_network_source: bool,
+	flag_drop_caches: bool,
+	flag_bwlimit: Option<String>,
+	flag_nice: bool,
+	flag_nice_delay: Option<u64>,
+	flag_retries: u32,
+	flag_ignore_errors: bool,
+	flag_strict: bool,
+	flag_list_skipped: bool,
+	flag_report_skipped: Option<String>,
+	flag_skip_unstable: bool,
+	flag_no_checksum_verify: bool,
+	flag_lock_file: Option<String>,
+	flag_wait_for_lock: Option<u64>,
+	flag_fail_on_case_collision: bool,
+	flag_version_name: Option<String>,
+	flag_min_changed_pct: Option<f64>,
+	flag_always_write_archive: bool,
+	flag_output_checksums_to_archive: bool,
+	flag_check_disk_space: bool,
+	flag_min_free_space: Option<String>,
+	flag_checksum_header: bool,
+	flag_write_pid_file: Option<String>,
+	flag_progress: bool,
+	flag_pre_scan: bool,
+	flag_stats_json: Option<String>,
+	flag_events_fd: Option<i32>,
 	flag_dry_run: bool,
+	flag_verbose: usize,
+	flag_quiet: bool,
+	flag_log_file: Option<String>,
+	flag_log_file_level: String,
+	flag_log_local_time: bool,
+	flag_log_format: LogFormat,
+	flag_color: ColorChoice,
+	flag_log_syslog: bool,
+	flag_syslog_only: bool,
+	flag_syslog_facility: String,
+	flag_no_sd_notify: bool,
+	flag_interval: Option<String>,
+	flag_interval_jitter: String,
+	flag_estimate_ratio: Option<f64>,
 }
 
-/// Errors returned from main method.
+/// Errors returned from the backup and its subcommands.
 ///
 /// This serves to distinguish `DocoptError`s due to commandline parsing (which
 /// should result in usage being printed) from other errors (which should
-/// result in only a descriptive error being printed).
-pub enum MainError {
+/// result in only a descriptive error being printed), and from the run being
+/// interrupted (see `cancel`).
+#[derive(Debug)]
+pub enum BackupError {
 	DocoptError(docopt::Error),
 	OtherError(String),
+	Cancelled,
+}
+
+impl fmt::Display for BackupError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			BackupError::DocoptError(ref e) => write!(f, "{}", e),
+			BackupError::OtherError(ref message) => write!(f, "{}", message),
+			BackupError::Cancelled => write!(f, "Interrupted"),
+		}
+	}
+}
+
+impl error::Error for BackupError {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match *self {
+			BackupError::DocoptError(ref e) => Some(e),
+			_ => None
+		}
+	}
+}
+
+/// Check a source root is a directory, and resolve it as requested.
+fn prepare_root(source_root: PathBuf, canonicalize: bool, long_paths: bool)
+		-> Result<PathBuf, BackupError> {
+	if !source_root.is_dir() {
+		return Err(BackupError::OtherError(format!(
+			"Source root path {} is not a directory", source_root.as_path().display())
+			.to_string()));
+	}
+	let source_root = if canonicalize {
+		operations::canonicalize_root(&source_root)?
+	} else {
+		source_root
+	};
+	if long_paths {
+		operations::extended_length_path(&source_root).map_err(|e| BackupError::OtherError(format!(
+				"Couldn't convert source root {} to an extended-length path: {}",
+				source_root.as_path().display(), e)))
+	} else {
+		Ok(source_root)
+	}
+}
+
+/// Check a source root directly contains at least `min_files` entries, for
+/// `--verify-source`.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the directory can't be read or has too few entries.
+fn verify_source(source_root: &Path, min_files: usize) -> Result<(), BackupError> {
+	let read_error = |e| BackupError::OtherError(format!(
+		"Couldn't read source root {}: {}", source_root.display(), e));
+	let mut entries = 0;
+	for entry in fs::read_dir(source_root).map_err(&read_error)? {
+		entry.map_err(&read_error)?;
+		entries += 1;
+		if entries >= min_files {
+			break
+		}
+	}
+	if entries < min_files {
+		return Err(BackupError::OtherError(format!(
+			"Source root {} has only {} entries, fewer than the {} required by --verify-source (is it mounted?)",
+			source_root.display(), entries, min_files)));
+	}
+	debug!("Source root {} has at least {} entries", source_root.display(), min_files);
+	Ok(())
+}
+
+/// Load the key for the hash algorithm, if it's keyed.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the algorithm needs a key and none was given (or one was given
+/// but isn't needed), or the key can't be loaded.
+fn hash_key(args: &Args) -> Result<Option<Vec<u8>>, BackupError> {
+	match (args.flag_hash_algorithm.is_keyed(), args.flag_hmac_key.as_ref()) {
+		(true, Some(fname)) => Ok(Some(hash::load_key(fname)?)),
+		(false, None) => Ok(None),
+		(true, None) => Err(BackupError::OtherError(format!(
+			"--hash-algorithm {} requires --hmac-key", args.flag_hash_algorithm.name()))),
+		(false, Some(_)) => Err(BackupError::OtherError(format!(
+			"--hmac-key can't be used with --hash-algorithm {}", args.flag_hash_algorithm.name())))
+	}
+}
+
+/// Parse the size below which files are stored uncompressed.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the size is invalid.
+fn compress_threshold(args: &Args) -> Result<u64, BackupError> {
+	throttle::parse_size(&args.flag_compress_threshold).ok_or_else(|| BackupError::OtherError(
+		format!("Invalid --compress-threshold {} (expected e.g. 4K)", args.flag_compress_threshold)))
+}
+
+/// Parse the size of the parts into which to split the archive, if it's to be
+/// split.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the size is invalid or zero.
+fn target_size(args: &Args) -> Result<Option<u64>, BackupError> {
+	match args.flag_target_size {
+		Some(ref size) => match throttle::parse_size(size) {
+			Some(bytes) if bytes > 0 => Ok(Some(bytes)),
+			_ => Err(BackupError::OtherError(
+				format!("Invalid --target-size {} (expected e.g. 4G)", size)))
+		},
+		None => Ok(None)
+	}
+}
+
+/// Work out how to walk and read source files from the commandline, recording
+/// statistics in `stats`.
+fn walk_options(args: &Args, stats: Arc<Stats>) -> Result<WalkOptions, BackupError> {
+	let buffer_size = match throttle::parse_size(&args.flag_buffer_size) {
+		Some(size) if size > 0 && size <= usize::MAX as u64 => size as usize,
+		_ => return Err(BackupError::OtherError(
+			format!("Invalid --buffer-size {} (expected e.g. 1M)", args.flag_buffer_size)))
+	};
+	let throttle = match args.flag_bwlimit {
+		Some(ref rate) => match throttle::parse_size(rate) {
+			Some(0) => None,
+			Some(rate) => Some(Arc::new(Throttle::new(rate))),
+			None => return Err(BackupError::OtherError(
+				format!("Invalid --bwlimit rate {} (expected e.g. 50M)", rate)))
+		},
+		None => None
+	};
+	Ok(WalkOptions {
+		one_file_system: args.flag_one_file_system || !args.flag_follow_mount_points.is_empty(),
+		follow_devices: args.flag_follow_mount_points.iter()
+			.map(|p| operations::mount_point_device(p))
+			.collect::<Result<_, _>>()?,
+		min_depth: args.flag_min_depth,
+		max_depth: args.flag_max_depth,
+		exclude_owners: args.flag_exclude_by_owner.iter()
+			.map(|u| operations::resolve_user(u))
+			.collect::<Result<_, _>>()?,
+		include_owners: args.flag_include_by_owner.iter()
+			.map(|u| operations::resolve_user(u))
+			.collect::<Result<_, _>>()?,
+		exclude_patterns: if args.flag_exclude_temp_files {
+			operations::TEMP_FILE_PATTERNS.iter().map(|p| p.to_string()).collect()
+		} else {
+			Vec::new()
+		},
+		normalization: args.flag_normalize_unicode,
+		allow_outside_root: args.flag_allow_outside_root,
+		jobs: args.flag_jobs.unwrap_or_else(|| thread::available_parallelism()
+			.map(|n| cmp::min(n.get(), 4))
+			.unwrap_or(1)),
+		progress: None,
+		stats,
+		drop_caches: args.flag_drop_caches,
+		throttle,
+		buffer_size,
+		file_delay: args.flag_nice_delay.map(Duration::from_millis),
+		ignore_errors: args.flag_ignore_errors,
+		retries: args.flag_retries,
+		known_files: None,
+		stability: None,
+	})
+}
+
+/// Get the options for writing the archive from the commandline, reading files
+/// as for `walk_options`, and reporting progress and statistics to the same
+/// places.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the compression threshold or target size is invalid.
+fn archive_options(args: &Args, walk_options: &WalkOptions)
+		-> Result<ArchiveOptions, BackupError> {
+	Ok(ArchiveOptions {
+		format: args.flag_tar_format,
+		comment: args.flag_archive_comments.clone(),
+		compression: args.flag_compression,
+		compress_threshold: compress_threshold(args)?,
+		target_size: target_size(args)?,
+		threads: args.flag_archive_threads,
+		normalization: args.flag_normalize_unicode,
+		backup_dir: args.flag_backup_dir.as_ref().map(PathBuf::from),
+		link_dest: args.flag_hardlink_dest.as_ref().map(PathBuf::from),
+		progress: walk_options.progress.clone(),
+		stats: walk_options.stats.clone(),
+		drop_caches: walk_options.drop_caches,
+		throttle: walk_options.throttle.clone(),
+		stability: walk_options.stability.clone(),
+	})
+}
+
+/// Compare a directory against a backup, for the `compare` subcommand.
+fn compare(args: &Args) -> Result<(), BackupError> {
+	let root = prepare_root(
+		PathBuf::from(&args.arg_root), !args.flag_no_canonicalize, args.flag_long_paths)?;
+	let roots = SourceRoots::new(root);
+	let (checksums, fname) = match args.flag_checksums {
+		Some(ref fname) => {
+			debug!("Loading backed up checksums from {}...", fname);
+			(operations::load_checksums(fname, args.flag_normalize_unicode)?, &fname[..])
+		},
+		None => {
+			debug!("Loading backed up checksums embedded in {}...", args.arg_archive);
+			match operations::load_archived_checksums(&args.arg_archive, args.flag_normalize_unicode)? {
+				Some((checksums, _)) => (checksums, "embedded checksums"),
+				None => return Err(BackupError::OtherError(format!(
+					"Archive {} has no embedded checksums; give them with --checksums",
+					args.arg_archive)))
+			}
+		}
+	};
+	let walk_options = walk_options(args, Arc::new(Stats::new()))?;
+	let hash_algorithm = args.flag_hash_algorithm;
+	let hash_key = hash_key(args)?;
+	let hash_key = hash_key.as_ref().map(|k| &k[..]);
+	debug!("Comparing {} against {} and {}...", args.arg_root, fname, args.arg_archive);
+	let comparison = operations::compare_backup(
+		&[".".to_string()],
+		&roots,
+		&args.arg_archive,
+		&checksums,
+		&walk_options,
+		|| hash_algorithm.new_hasher(hash_key))?;
+	for fname in &comparison.only_in_source {
+		println!("Only in {}: {}", args.arg_root, fname);
+	}
+	for fname in &comparison.only_in_backup {
+		println!("Only in backup: {}", fname);
+	}
+	for fname in &comparison.differing {
+		println!("Differs: {}", fname);
+	}
+	let total = comparison.only_in_source.len() + comparison.only_in_backup.len() +
+		comparison.differing.len();
+	if total > 0 {
+		return Err(BackupError::OtherError(format!(
+			"{} files differ between {} and the backup ({} only in {}, {} only in the backup, {} changed)",
+			total, args.arg_root, comparison.only_in_source.len(), args.arg_root,
+			comparison.only_in_backup.len(), comparison.differing.len())));
+	}
+	info!("{} matches the backup", args.arg_root);
+	Ok(())
+}
+
+/// Add tags to an archive, for the `tag` subcommand.
+fn tag(args: &Args) -> Result<(), BackupError> {
+	// Docopt requires --archive for tag.
+	let fname = args.flag_archive.as_ref().unwrap();
+	let mut tags = HashMap::new();
+	for tag in &args.flag_tag {
+		match tag.find('=') {
+			Some(i) if i > 0 => tags.insert(tag[.. i].to_string(), tag[i + 1 ..].to_string()),
+			_ => return Err(BackupError::OtherError(format!(
+				"Invalid tag {} (expected <key>=<value>)", tag)))
+		};
+	}
+	if args.flag_dry_run {
+		info!("[dry-run] {} tags would be added to {}", tags.len(), fname);
+		return Ok(());
+	}
+	debug!("Adding {} tags to {}...", tags.len(), fname);
+	operations::tag_archive(fname, &tags)
+}
+
+/// Print the comment, tags and files in an archive, for the `list` subcommand.
+fn list(args: &Args) -> Result<(), BackupError> {
+	let listing = operations::list_archive(&args.arg_archive)?;
+	if let Some(comment) = listing.comment {
+		println!("Comment: {}", comment);
+	}
+	for (key, value) in listing.tags {
+		println!("Tag: {}={}", key, value);
+	}
+	for name in listing.files {
+		println!("{}", name);
+	}
+	Ok(())
+}
+
+/// Restore a full backup, then apply incremental backups to it in order, for
+/// the `restore-chain` subcommand.
+fn restore_chain(args: &Args) -> Result<(), BackupError> {
+	// Docopt requires --full for restore-chain.
+	let full = args.flag_full.as_ref().unwrap();
+	let destination = Path::new(&args.arg_destination);
+	let normalization = args.flag_normalize_unicode;
+
+	// Work out what each backup restores and removes before writing anything,
+	// so that nothing is touched if the backups don't fit together. The files
+	// restored so far are kept by key, with their names in the archives.
+	let mut restored : HashMap<String, String> = HashMap::new();
+	let mut steps = Vec::new();
+	for (index, fname) in iter::once(full).chain(&args.flag_incremental).enumerate() {
+		if operations::is_zip_archive(fname) {
+			return Err(BackupError::OtherError(format!(
+				"{} is a zip archive, which can't be restored from (extract it with unzip instead)", fname)));
+		}
+		let archived : HashMap<String, String> = archived_files(fname)?.into_iter()
+			.map(|name| (normalization.apply(name.clone()), name))
+			.collect();
+		// A file is deleted if it's in neither the backup's checksums nor
+		// the archive (which may hold files whose checksums weren't saved),
+		// and wasn't left out of the checksums because it couldn't be
+		// archived, in which case its copy from an earlier backup is kept.
+		let deletions : Vec<String> = match operations::load_archived_checksums(fname, normalization)? {
+			Some((checksums, outcome)) => {
+				let failed : HashSet<String> = outcome.failed.into_iter().collect();
+				check_restorable(fname, &archived, &checksums, &failed, &restored)?;
+				restored.iter()
+					.filter(|&(key, _)| !checksums.contains_key(key)
+						&& !archived.contains_key(key) && !failed.contains(key))
+					.map(|(_, name)| name.clone())
+					.collect()
+			},
+			None if index == 0 => Vec::new(),
+			None => {
+				warn!("{} has no embedded checksums (see --output-checksums-to-archive), so files deleted before it won't be removed",
+					fname);
+				Vec::new()
+			}
+		};
+		for name in &deletions {
+			restored.remove(&normalization.apply(name.clone()));
+		}
+		steps.push((fname, archived.len(), deletions));
+		restored.extend(archived);
+	}
+
+	for (fname, files, deletions) in steps {
+		if args.flag_dry_run {
+			info!("[dry-run] {} files from {} would be restored to {}, and {} deleted files removed",
+				files, fname, args.arg_destination, deletions.len());
+			for name in &deletions {
+				info!("[dry-run] Would remove {}", name);
+			}
+		} else {
+			debug!("Applying {} ({} files, {} deleted) to {}...",
+				fname, files, deletions.len(), args.arg_destination);
+			operations::apply_incremental(destination, fname, &deletions)?;
+		}
+	}
+	info!("{}{} files {} to {} from {} backups",
+		if args.flag_dry_run { "[dry-run] " } else { "" }, restored.len(),
+		if args.flag_dry_run { "would be restored" } else { "restored" },
+		args.arg_destination, args.flag_incremental.len() + 1);
+	Ok(())
+}
+
+/// Check that a backup's archive fits with its embedded checksums, and with
+/// the backups restored before it (`restored`, by key), so that the files it
+/// deleted can be found by comparing them.
+///
+/// Every file in the archive must be in the checksums, unless it's `failed`
+/// (it changed as it was archived, so was left out of them; see
+/// `operations::load_archived_checksums`): if not, the archive's names aren't
+/// the checksums' filenames (e.g. it was written with --strip-components or
+/// --absolute-paths), and every file restored would look deleted. Files in the
+/// checksums but not the archive are unchanged, so should have been restored
+/// already; if none of them were, the backups are out of order or from
+/// different sources.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the backup doesn't fit.
+fn check_restorable(
+		fname: &str,
+		archived: &HashMap<String, String>,
+		checksums: &HashMap<String, String>,
+		failed: &HashSet<String>,
+		restored: &HashMap<String, String>)
+		-> Result<(), BackupError> {
+	if let Some(name) = archived.iter()
+			.find(|&(key, _)| !checksums.contains_key(key) && !failed.contains(key))
+			.map(|(_, name)| name) {
+		return Err(BackupError::OtherError(format!(
+			concat!("{} holds {}, which isn't in its checksums, so its files aren't named as ",
+				"they were checksummed (e.g. it was written with --strip-components or ",
+				"--absolute-paths), and deleted files can't be found"),
+			fname, name)));
+	}
+	let unchanged : Vec<&String> = checksums.keys().filter(|key| !archived.contains_key(*key)).collect();
+	let missing = unchanged.iter().filter(|key| !restored.contains_key(**key)).count();
+	if missing > 0 && missing == unchanged.len() && !restored.is_empty() {
+		return Err(BackupError::OtherError(format!(
+			concat!("None of the {} unchanged files in {} were restored from the backups before it; ",
+				"are they out of order, or from different sources?"),
+			unchanged.len(), fname)));
+	}
+	if missing > 0 && !restored.is_empty() {
+		warn!("{} of the unchanged files in {} weren't restored from the backups before it",
+			missing, fname);
+	}
+	Ok(())
+}
+
+/// The names of the files in an archive, not counting its embedded checksums
+/// (see `archive::append_checksums`).
+fn archived_files(fname: &str) -> Result<Vec<String>, BackupError> {
+	let mut files = operations::list_archive(fname)?.files;
+	if files.first().map(|name| &name[..]) == Some(archive::CHECKSUMS_ENTRY) {
+		files.remove(0);
+	}
+	Ok(files)
+}
+
+/// Read the files in the sources without checksumming or archiving them, for
+/// the `benchmark` subcommand, and report how fast they were read, and how
+/// fast the selected algorithm checksums data in memory.
+///
+/// `--buffer-size` may be a comma-separated list of sizes, in which case the
+/// files are read once with each, and the largest is used in memory.
+fn benchmark(args: &Args) -> Result<(), BackupError> {
+	let (roots, sources) = source_roots(args, &Stats::new())?;
+	let args = &Args { arg_source: sources, ..args.clone() };
+	// Check every size before reading anything.
+	let passes = args.flag_buffer_size.split(',')
+		.map(str::trim)
+		.map(|buffer_size| {
+			let stats = Arc::new(Stats::new());
+			let args = &Args { flag_buffer_size: buffer_size.to_string(), ..args.clone() };
+			walk_options(args, stats.clone())
+				.map(|walk_options| (buffer_size, stats, walk_options))
+		})
+		.collect::<Result<Vec<_>, BackupError>>()?;
+	let mut largest_buffer = 0;
+	for (buffer_size, stats, walk_options) in passes {
+		largest_buffer = cmp::max(largest_buffer, walk_options.buffer_size);
+		debug!("Reading source files with --buffer-size {}...", buffer_size);
+		operations::checksum_directory(
+			&args.arg_source,
+			&roots,
+			&walk_options,
+			|| Box::new(NullHasher))?;
+		let read = stats.summary().checksum;
+		println!("Read {} files ({}) in {} with --jobs {} and --buffer-size {}: {}/s",
+			read.files, human_bytes(read.bytes),
+			human_duration(Duration::from_secs_f64(read.seconds)), walk_options.jobs,
+			buffer_size, human_bytes(read.rate(read.bytes) as u64));
+	}
+
+	// Only the algorithm's speed matters, not the key.
+	debug!("Checksumming {} in memory...", human_bytes(BENCHMARK_HASH_BYTES as u64));
+	let key = [0u8; hash::HMAC_KEY_LEN];
+	let mut hasher = args.flag_hash_algorithm.new_hasher(Some(&key));
+	let buf = vec![0u8; cmp::min(largest_buffer, BENCHMARK_HASH_BYTES)];
+	let started = Instant::now();
+	let mut hashed = 0;
+	while hashed < BENCHMARK_HASH_BYTES {
+		hasher.update(&buf);
+		hashed += buf.len();
+	}
+	hasher.finalize();
+	let seconds = started.elapsed().as_secs_f64();
+	println!("Checksummed {} in memory with {} in {} on 1 thread: {}/s",
+		human_bytes(hashed as u64), args.flag_hash_algorithm.name(),
+		human_duration(Duration::from_secs_f64(seconds)),
+		human_bytes(if seconds > 0.0 { (hashed as f64 / seconds) as u64 } else { 0 }));
+	Ok(())
+}
+
+/// How much data the `benchmark` subcommand checksums in memory.
+const BENCHMARK_HASH_BYTES: usize = 64 * 1024 * 1024;
+
+/// Write the checksums in a checksum database to a checksum file, for the
+/// `export-checksums` subcommand.
+fn export_checksums(args: &Args) -> Result<(), BackupError> {
+	// Docopt requires --checksum-db and --output for export-checksums.
+	let db_path = args.flag_checksum_db.as_ref().unwrap();
+	let fname = args.flag_output.as_ref().unwrap();
+	let db = ChecksumDb::open(db_path)?;
+	let checksums = db.load(args.flag_normalize_unicode)?;
+	let headers = db.headers()?;
+	let header : Vec<(&str, String)> = headers.iter()
+		.map(|(name, value)| (name.as_str(), value.clone()))
+		.collect();
+	if args.flag_dry_run {
+		info!("[dry-run] {} checksums from {} would be written to {}", checksums.len(), db_path, fname);
+		return Ok(());
+	}
+	debug!("Writing {} checksums from {} to {}...", checksums.len(), db_path, fname);
+	operations::save_checksums(&checksums, std::slice::from_ref(fname), &header)
+}
+
+/// Replace the checksums in a checksum database with those in a checksum file,
+/// for the `import-checksums` subcommand.
+fn import_checksums(args: &Args) -> Result<(), BackupError> {
+	// Docopt requires --checksum-db for import-checksums.
+	let db_path = args.flag_checksum_db.as_ref().unwrap();
+	let fname = &args.arg_checksum_file;
+	let db = ChecksumDb::open(db_path)?;
+	let checksums = operations::load_checksums(fname, args.flag_normalize_unicode)?;
+	let header : Vec<(&str, String)> = operations::load_version(fname)
+		.map(|version| ("version", version))
+		.into_iter()
+		.collect();
+	if args.flag_dry_run {
+		info!("[dry-run] {} checksums from {} would be saved to {}", checksums.len(), fname, db_path);
+		return Ok(());
+	}
+	debug!("Saving {} checksums from {} to {}...", checksums.len(), fname, db_path);
+	// Sizes and modification times aren't known for imported checksums.
+	db.save(&checksums, &header, |_| None)
+}
+
+/// Merge checksum files, for the `merge-checksums` subcommand.
+fn merge_checksums(args: &Args) -> Result<(), BackupError> {
+	debug!("Merging checksums from {} and {} later files...",
+		args.arg_base, args.arg_incremental.len());
+	let merged = operations::merge_checksums(
+		&args.arg_base, &args.arg_incremental, args.flag_normalize_unicode)?;
+	let header : Vec<(&str, String)> = args.arg_incremental.last()
+		.and_then(|fname| operations::load_version(fname))
+		.map(|version| ("version", version))
+		.into_iter()
+		.collect();
+	match (args.flag_dry_run, args.flag_output.as_ref()) {
+		(false, Some(fname)) => {
+			debug!("Writing {} merged checksums to {}...", merged.len(), fname);
+			operations::save_checksums(&merged, std::slice::from_ref(fname), &header)
+		},
+		(true, Some(fname)) => {
+			info!("[dry-run] {} merged checksums would be written to {}", merged.len(), fname);
+			Ok(())
+		},
+		// Docopt requires --output for merge-checksums.
+		(_, None) => unreachable!()
+	}
 }
 
-fn do_main() -> Result<(),MainError> {
+fn do_main() -> Result<i32, BackupError> {
 
 	// Parse commandline arguments
-	let args : Args = try!(Docopt::new(USAGE)
-		.and_then(|d| Ok(d.version(VERSION.and_then(|v| Some(v.to_string())))))
-		.and_then(|d| d.decode())
-		.or_else(|e| Err(MainError::DocoptError(e))));
+	let mut args : Args = Docopt::new(USAGE).map(|d| d.version(VERSION.map(|v| v.to_string())))
+		.and_then(|d| d.deserialize()).map_err(BackupError::DocoptError)?;
+
+	// Logging can only be set up once the commandline has been parsed.
+	let (sinks, syslog_error) = match log_sinks(&args) {
+		Ok(sinks) => sinks,
+		Err(BackupError::OtherError(e)) => {
+			writeln!(&mut std::io::stderr(), "{}", e).unwrap();
+			exit(EXIT_NO_LOG);
+		},
+		Err(e) => return Err(e),
+	};
+	// An empty RUST_LOG would turn logging off entirely; treat it as unset.
+	let spec = env::var("RUST_LOG").ok().filter(|spec| !spec.is_empty());
+	if let Err(e) = init_log(log_level(args.flag_verbose, args.flag_quiet), spec, sinks) {
+		writeln!(&mut std::io::stderr(), "Could not set logger: {}", e).unwrap();
+		exit(EXIT_NO_LOG);
+	}
+	if let Some(e) = syslog_error {
+		warn!("Couldn't connect to syslog ({}); logging to standard error instead", e);
+	}
 
 	if args.flag_dry_run {
 		info!("[dry-run] Dry-run specified, not writing anything.");
 	}
 
+	match args.flag_files_from.clone() {
+		Some(fname) => {
+			if fname == "-" && args.flag_old_checksums.as_ref().is_some_and(|f| f == "-") {
+				return Err(BackupError::OtherError(
+					"--files-from and --old-checksums can't both be read from standard input".to_string()));
+			}
+			args.listed_sources = operations::read_file_list(&fname, args.flag_null)?;
+			debug!("Read {} sources from {}", args.listed_sources.len(), fname);
+		},
+		None if args.flag_null =>
+			return Err(BackupError::OtherError("--null can only be used with --files-from".to_string())),
+		None => ()
+	}
+
+	if args.cmd_merge_checksums {
+		return merge_checksums(&args).map(|()| EXIT_SUCCESS);
+	}
+	if args.cmd_compare {
+		return compare(&args).map(|()| EXIT_SUCCESS);
+	}
+	if args.cmd_export_checksums {
+		return export_checksums(&args).map(|()| EXIT_SUCCESS);
+	}
+	if args.cmd_import_checksums {
+		return import_checksums(&args).map(|()| EXIT_SUCCESS);
+	}
+	if args.cmd_tag {
+		return tag(&args).map(|()| EXIT_SUCCESS);
+	}
+	if args.cmd_list {
+		return list(&args).map(|()| EXIT_SUCCESS);
+	}
+	if args.cmd_restore_chain {
+		return restore_chain(&args).map(|()| EXIT_SUCCESS);
+	}
+	if args.cmd_benchmark {
+		cancel::install();
+		return benchmark(&args).map(|()| EXIT_SUCCESS);
+	}
+
+	let events = match args.flag_events_fd {
+		Some(fd) => Some(Events::from_fd(fd)?),
+		None => None
+	};
+	let mut stats = Stats::with_events(events);
+	if args.flag_list_skipped || args.flag_report_skipped.is_some() {
+		stats.list_skipped();
+	}
+	if args.flag_strict {
+		stats.strict();
+	}
+	let stats = Arc::new(stats);
+	cancel::install();
+	status::install(stats.clone());
+	let notifier = if args.flag_no_sd_notify { None } else { Notifier::from_env().map(Arc::new) };
+	if let Some(ref notifier) = notifier {
+		notifier.ready();
+		Notifier::start_updates(notifier.clone(), stats.clone());
+	}
+	watch_interval(&args)?;
+	let result = match interval(&args)? {
+		Some((interval, jitter)) if !args.cmd_daemon => run_periodically(&args, &stats, interval, jitter),
+		_ => run(&args, &stats)
+	};
+	if let Some(ref notifier) = notifier {
+		notifier.stopping();
+	}
+	result
+}
+
+/// Run a backup (or watch or daemon), and report how it went, returning its exit
+/// status.
+///
+/// # Errors
+///
+/// This function will return an error if the run fails, or its statistics
+/// can't be written.
+fn run(args: &Args, stats: &Arc<Stats>) -> Result<i32, BackupError> {
+	// Held until the run ends. Until it's taken, the outputs may be another
+	// run's, so they mustn't be cleaned up.
+	let _lock = run_lock(args)?;
+	let result = if args.cmd_watch {
+		watch(args, stats.clone())
+	} else if args.cmd_daemon {
+		daemon(args, stats.clone())
+	} else {
+		backup(args, stats.clone())
+	};
+	if result.is_err() {
+		remove_partial_outputs(args);
+	}
+	let status = match result {
+		// Watching only ends when it's asked to stop.
+		Ok(()) if args.cmd_watch || args.cmd_daemon => EXIT_SUCCESS,
+		Ok(()) => exit_status(&stats.summary()),
+		Err(ref e) => error_status(e)
+	};
+	stats.finished(status, result.as_ref().err().map(|e| e.to_string()));
+	// An interrupted run reports how far it got.
+	let cancelled = matches!(result, Err(BackupError::Cancelled));
+	let reported = report_stats(args, stats, result.is_ok() || cancelled);
+	result?;
+	reported?;
+	Ok(status)
+}
+
+/// Get the interval at which watch checks all the files again, rather than
+/// watching for changes, from the commandline, if given (see `poll`).
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if it's zero, or given for anything but watch.
+fn watch_interval(args: &Args) -> Result<Option<Duration>, BackupError> {
+	match args.flag_watch_interval {
+		None => Ok(None),
+		Some(_) if args.cmd_daemon => Err(BackupError::OtherError(
+			"--watch-interval can't be used with daemon".to_string())),
+		Some(_) if !args.cmd_watch => Err(BackupError::OtherError(
+			"--watch-interval can only be used with watch".to_string())),
+		Some(0) => Err(BackupError::OtherError(
+			"--watch-interval must be at least 1 second".to_string())),
+		Some(seconds) => Ok(Some(Duration::from_secs(seconds))),
+	}
+}
+
+/// Get the interval at which to back up repeatedly, and the most by which to
+/// delay each backup, from the commandline, if given.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if either is invalid, or the options can't be used with
+/// --interval.
+fn interval(args: &Args) -> Result<Option<(Duration, Duration)>, BackupError> {
+	let interval = match args.flag_interval {
+		Some(ref interval) => match schedule::parse_duration(interval) {
+			Some(duration) if duration.as_secs() > 0 => duration,
+			_ => return Err(BackupError::OtherError(
+				format!("Invalid --interval {} (expected e.g. 6h)", interval)))
+		},
+		None => return Ok(None)
+	};
+	let jitter = schedule::parse_duration(&args.flag_interval_jitter)
+		.ok_or_else(|| BackupError::OtherError(format!(
+			"Invalid --interval-jitter {} (expected e.g. 10m)", args.flag_interval_jitter)))?;
+	// daemon backs up changes at the interval itself.
+	if args.cmd_daemon {
+		return Ok(Some((interval, jitter)));
+	}
+	if args.cmd_watch {
+		return Err(BackupError::OtherError("--interval can't be used with watch".to_string()));
+	}
+	if args.flag_since_archive.is_some() {
+		return Err(BackupError::OtherError(
+			"--interval can't be used with --since-archive".to_string()));
+	}
+	if args.flag_checksum_db.is_none() && !args.flag_new_checksums.iter().any(|fname| fname != "-") {
+		return Err(BackupError::OtherError(
+			"--interval requires a --new-checksums file or --checksum-db".to_string()));
+	}
+	Ok(Some((interval, jitter)))
+}
+
+/// Back up repeatedly, for --interval, until asked to stop between backups,
+/// then return success.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::Cancelled` if a backup is
+/// interrupted. Other errors are logged, and the next backup goes ahead.
+fn run_periodically(args: &Args, stats: &Arc<Stats>, interval: Duration, jitter: Duration)
+		-> Result<i32, BackupError> {
+	let mut args = args.clone();
+	let destination = args.arg_destination.clone();
+	let checksum_file = args.flag_new_checksums.iter().find(|fname| *fname != "-").cloned();
+	// Every backup takes the same lock, rather than one for its own archive.
+	args.flag_lock_file = Some(lock_path(&args));
+	let mut run_number = 0;
+	loop {
+		run_number += 1;
+		if run_number > 1 {
+			let next = schedule::next_boundary(SystemTime::now(), interval) + schedule::jitter(jitter);
+			info!("Next backup at {}", operations::format_timestamp(next));
+			if !schedule::sleep_until(next) {
+				info!("Stopped backing up every {}", args.flag_interval.as_ref().unwrap());
+				return Ok(EXIT_SUCCESS);
+			}
+			stats.restart();
+		}
+		// Each backup goes to its own file, or, for a device or pipe, in turn to
+		// the same one.
+		if operations::special_destination(&destination).is_none() {
+			args.arg_destination = operations::timestamped_path(&destination, SystemTime::now());
+		}
+		info!("Starting backup {} to {}...", run_number, args.arg_destination);
+		let status = match run(&args, stats) {
+			Ok(status) => status,
+			Err(BackupError::Cancelled) => return Err(BackupError::Cancelled),
+			Err(e) => {
+				error!("Backup {} failed: {}", run_number, e);
+				EXIT_FAILURE
+			}
+		};
+		info!("Backup {} finished with exit status {}", run_number, status);
+		// Later backups are compared against the latest checksums written.
+		if let Some(ref fname) = checksum_file {
+			if Path::new(fname).exists() {
+				args.flag_old_checksums = Some(fname.clone());
+			}
+		}
+	}
+}
+
+/// Lock the run against others writing the same checksums and destination
+/// (see `RunLock`), unless it's a dry run.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the lock can't be taken, or a `BackupError::Cancelled` if the run
+/// is interrupted while waiting for it.
+fn run_lock(args: &Args) -> Result<Option<RunLock>, BackupError> {
+	if args.flag_dry_run {
+		return Ok(None);
+	}
+	RunLock::acquire(&lock_path(args), args.flag_wait_for_lock.map(Duration::from_secs)).map(Some)
+}
+
+/// Get the file to lock for a run (see `--lock-file`).
+fn lock_path(args: &Args) -> String {
+	match args.flag_lock_file {
+		Some(ref path) => path.clone(),
+		None if args.cmd_watch || args.cmd_daemon => format!("{}.lock", watch_manifest(args)),
+		None => format!("{}.lock", args.flag_new_checksums.iter()
+			.find(|fname| *fname != "-")
+			.unwrap_or(&args.arg_destination))
+	}
+}
+
+/// Remove any partial archive and checksum files left by a backup which
+/// failed or was interrupted (see `operations::partial_path`).
+fn remove_partial_outputs(args: &Args) {
+	remove_partial_archive(&args.arg_destination, args.flag_target_size.is_some());
+	for fname in args.flag_new_checksums.iter().filter(|fname| *fname != "-") {
+		operations::remove_partial(fname);
+	}
+}
+
+/// Remove a partial archive, and if it's split into parts (see
+/// --target-size), any partial parts.
+fn remove_partial_archive(destination: &str, split: bool) {
+	operations::remove_partial(destination);
+	if split {
+		// Parts are only started once the previous ones are written.
+		let parts = (1 ..).map(|number| operations::part_path(destination, number))
+			.take_while(|part| Path::new(&operations::partial_path(part)).exists());
+		for part in parts {
+			operations::remove_partial(&part);
+		}
+	}
+}
+
+/// Work out the exit status for a backup which completed, from its
+/// statistics: files which couldn't be read or were changing as they were
+/// archived take precedence over there being no changes.
+fn exit_status(summary: &Summary) -> i32 {
+	if summary.skipped.unreadable > 0 {
+		warn!("{} files couldn't be read, and weren't backed up", summary.skipped.unreadable);
+		EXIT_PARTIAL
+	} else if summary.unstable_files_count > 0 || summary.missing_sources_count > 0 {
+		EXIT_PARTIAL
+	} else if summary.changed_files == 0 {
+		info!("No files have changed");
+		EXIT_UNCHANGED
+	} else {
+		EXIT_SUCCESS
+	}
+}
+
+/// Work out the exit status for a run which failed with `error`.
+fn error_status(error: &BackupError) -> i32 {
+	match *error {
+		BackupError::OtherError(_) => EXIT_FAILURE,
+		BackupError::DocoptError(_) => EXIT_USAGE,
+		BackupError::Cancelled => EXIT_CANCELLED
+	}
+}
+
+/// Work out the roots to back up files from: the source root, and any
+/// further roots mapped with --map, and the sources to back up, checking they
+/// exist and lie beneath them (see `operations::check_sources`). Sources
+/// skipped are recorded in `stats`.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if a root can't be used, a mapping is invalid, or the sources
+/// aren't valid.
+fn source_roots(args: &Args, stats: &Stats) -> Result<(SourceRoots, Vec<String>), BackupError> {
 	// Figure out source root. If not specified on the commandline, it's the
 	// current directory.
-	let source_root = try!(args.flag_source_root
-		.ok_or(())
-		.and_then(|d| Ok(PathBuf::from(d)))
-		.or_else(|_| env::current_dir()
-			.or_else(|e| Err(MainError::OtherError(
+	let source_root = (args.flag_source_root.as_ref()
+		.ok_or(()).map(PathBuf::from)
+		.or_else(|_| env::current_dir().map_err(|e| BackupError::OtherError(
 				format!("Couldn't use current directory as source root: {}", e)
 				.to_string()
-			)))
+			))
 		)
-	);
-	if !source_root.is_dir() {
-		return Err(MainError::OtherError(format!(
-			"Source root path {} is not a directory", source_root.as_path().display())
-			.to_string()));
+	)?;
+	let source_root = prepare_root(
+		source_root, !args.flag_no_canonicalize, args.flag_long_paths)?;
+	if let Some(min_files) = args.flag_verify_source {
+		verify_source(&source_root, min_files)?;
 	}
 	debug!("Using {} as source directory...", source_root.as_path().display());
+	let mut roots = operations::SourceRoots::new(source_root);
+	for mapping in &args.flag_map {
+		let (root, prefix) = match mapping.rfind('=') {
+			Some(i) => (&mapping[.. i], &mapping[i + 1 ..]),
+			None => return Err(BackupError::OtherError(format!(
+				"Invalid mapping {} (expected <root>=<prefix>)", mapping)))
+		};
+		let root = prepare_root(
+			PathBuf::from(root), !args.flag_no_canonicalize, args.flag_long_paths)?;
+		debug!("Using {} as source directory for {}...", root.as_path().display(), prefix);
+		roots.add_mapping(prefix, root)?;
+	}
+	if args.flag_relative_paths && args.flag_absolute_paths {
+		return Err(BackupError::OtherError(
+			"--relative-paths and --absolute-paths can't be given together".to_string()));
+	}
+	roots.set_archive_paths(args.flag_absolute_paths, args.flag_strip_components.unwrap_or(0));
+	let sources = operations::check_sources(&args.arg_source, &args.listed_sources, &roots,
+		args.flag_allow_outside_root, args.flag_ignore_missing_sources, stats)?;
+	if !args.flag_allow_network_source {
+		for root in roots.paths() {
+			if let Some(fs_type) = operations::network_filesystem(root) {
+				warn!(concat!("Source {} is on a network filesystem ({}); checksumming may be ",
+					"slow and unreliable (use --allow-network-source to silence this warning)"),
+					root.display(), fs_type);
+			}
+		}
+	}
+	Ok((roots, sources))
+}
+
+/// Back up files, for the main command, recording statistics for the run in
+/// `stats`.
+fn backup(args: &Args, stats: Arc<Stats>) -> Result<(), BackupError> {
+	// Lower our priority, if requested, before starting any threads
+	if args.flag_nice {
+		let applied = operations::lower_priority();
+		if applied.is_empty() {
+			warn!("Couldn't lower priority: not supported on this platform");
+		} else {
+			info!("Running with {}", applied.join(" and "));
+		}
+	}
+
+	// Record our PID, if requested. The file is removed when this goes out of
+	// scope, however we exit.
+	let _pid_file = match (args.flag_dry_run, args.flag_write_pid_file.as_ref()) {
+		(false, Some(fname)) => Some(operations::PidFile::create(fname)?),
+		(true, Some(fname)) => {
+			info!("[dry-run] PID would be written to {}", fname);
+			None
+		},
+		(_, None) => None
+	};
+
+	let (roots, sources) = source_roots(args, &stats)?;
+	// Only the sources which exist are walked.
+	let args = &Args { arg_source: sources, ..args.clone() };
 
-	// Load extant checksums
+	if let (Some(min), Some(max)) = (args.flag_min_depth, args.flag_max_depth) {
+		if min > max {
+			return Err(BackupError::OtherError(format!(
+				"Minimum depth {} is greater than maximum depth {}", min, max)));
+		}
+	}
+	if args.flag_checksum_db.is_some() {
+		if args.flag_old_checksums.is_some() || !args.flag_new_checksums.is_empty() {
+			return Err(BackupError::OtherError(
+				"--checksum-db can't be used with checksum files (see import-checksums)".to_string()));
+		}
+		if args.flag_low_memory {
+			return Err(BackupError::OtherError(
+				"--checksum-db can't be used with --low-memory".to_string()));
+		}
+	}
+	if args.flag_strict && args.flag_ignore_errors {
+		return Err(BackupError::OtherError("--strict can't be used with --ignore-errors".to_string()));
+	}
+	if args.flag_since_archive.is_some() {
+		if args.flag_old_checksums.is_some() || args.flag_checksum_db.is_some() {
+			return Err(BackupError::OtherError(
+				"--since-archive can't be used with --old-checksums or --checksum-db".to_string()));
+		}
+		if args.flag_low_memory {
+			return Err(BackupError::OtherError(
+				"--since-archive can't be used with --low-memory".to_string()));
+		}
+	}
+	if let Some(kind) = operations::special_destination(&args.arg_destination) {
+		if args.flag_target_size.is_some() {
+			return Err(BackupError::OtherError(format!(
+				"--target-size can't be used when the destination is a {}", kind)));
+		}
+		// Zip files are finished by going back to rewrite their headers.
+		if args.flag_archive_format == ArchiveFormat::Zip {
+			return Err(BackupError::OtherError(format!(
+				"--archive-format zip can't be used when the destination is a {}", kind)));
+		}
+		if !args.flag_dry_run {
+			warn!(concat!("Writing the archive directly to the {} {}, rather than to a temporary ",
+				"file moved into place once complete; if the backup fails, what's there is incomplete"),
+				kind, args.arg_destination);
+		}
+	}
+	if args.flag_archive_format == ArchiveFormat::Zip {
+		let conflicts = [
+			("--compression lz4", args.flag_compression == Compression::Lz4),
+			("--target-size", args.flag_target_size.is_some()),
+			("--archive-threads", args.flag_archive_threads > 1),
+			("--low-memory", args.flag_low_memory),
+		];
+		for &(option, given) in conflicts.iter() {
+			if given {
+				return Err(BackupError::OtherError(
+					format!("--archive-format zip can't be used with {}", option)));
+			}
+		}
+	}
+	if args.flag_low_memory {
+		if args.flag_archive_threads > 1 {
+			return Err(BackupError::OtherError(
+				"--low-memory can only be used with one archive thread".to_string()));
+		}
+		if args.flag_fail_on_case_collision {
+			return Err(BackupError::OtherError(
+				"--low-memory can't be used with --fail-on-case-collision".to_string()));
+		}
+		if args.flag_min_changed_pct.is_some() {
+			return Err(BackupError::OtherError(
+				"--low-memory can't be used with --min-changed-pct".to_string()));
+		}
+		if args.flag_output_checksums_to_archive {
+			return Err(BackupError::OtherError(
+				"--low-memory can't be used with --output-checksums-to-archive".to_string()));
+		}
+		if args.flag_no_checksum_verify {
+			return Err(BackupError::OtherError(
+				"--low-memory can't be used with --no-checksum-verify".to_string()));
+		}
+	}
+
+	// Open the checksum database, if any, locking it for the rest of the run
+	let checksum_db = match args.flag_checksum_db {
+		Some(ref path) => {
+			debug!("Opening checksum database {}...", path);
+			Some(ChecksumDb::open(path)?)
+		},
+		None => None
+	};
+
+	// Work out the version name for this backup: either as given, or following
+	// on from the previous version's
+	let old_version = match (checksum_db.as_ref(), args.flag_since_archive.as_ref()) {
+		(Some(db), _) => db.version()?,
+		(None, Some(archive)) => operations::load_archived_version(archive),
+		(None, None) => args.flag_old_checksums.as_ref().and_then(|fname| operations::load_version(fname))
+	};
+	let version = args.flag_version_name.clone()
+		.or_else(|| old_version.and_then(|old| operations::next_version(&old)));
+	if let Some(ref version) = version {
+		debug!("Backup version is {}", version);
+	}
+
+	// Work out the header for the new checksum file
+	let hash_algorithm = args.flag_hash_algorithm;
+	let hash_key = hash_key(args)?;
+	let hash_key = hash_key.as_ref().map(|k| &k[..]);
+	let compress_threshold = compress_threshold(args)?;
+	let mut header = Vec::new();
+	if let Some(ref version) = version {
+		header.push(("version", version.clone()));
+	}
+	if args.flag_checksum_header {
+		header.push(("backup", VERSION.unwrap_or("unknown").to_string()));
+		header.push(("created", operations::format_timestamp(SystemTime::now())));
+		header.push(("source-root", roots.root().display().to_string()));
+		header.push(("hash-algorithm", hash_algorithm.name().to_string()));
+	}
+	// Archives are always written at the format's best compression, apart
+	// from files under the threshold.
+	stats.settings(hash_algorithm.name(), CompressionSummary {
+		format: args.flag_compression.name().to_string(),
+		level: args.flag_compression.level(),
+		threshold: compress_threshold,
+		tar_format: format!("{:?}", args.flag_tar_format).to_lowercase(),
+	});
+
+	// Load extant checksums. With --low-memory, they're loaded sorted later.
 	let old_checksums = match args.flag_old_checksums {
-		Some(fname) => {
+		_ if checksum_db.is_some() => {
+			debug!("Loading previous version checksums from database...");
+			stats.time(Phase::LoadChecksums,
+				|| checksum_db.as_ref().unwrap().load(args.flag_normalize_unicode))?
+		},
+		_ if args.flag_since_archive.is_some() => {
+			let archive = args.flag_since_archive.as_ref().unwrap();
+			debug!("Loading previous version checksums embedded in {}...", archive);
+			stats.time(Phase::LoadChecksums,
+				|| operations::load_checksums_from_archive(archive, args.flag_normalize_unicode))?
+		},
+		Some(ref fname) if !args.flag_low_memory => {
 			debug!("Loading previous version checksums from {}...", fname);
-			try!(operations::load_checksums(&fname))
+			stats.time(Phase::LoadChecksums,
+				|| operations::load_checksums(fname, args.flag_normalize_unicode))?
 		},
-		None => HashMap::with_capacity(0)
+		_ => HashMap::with_capacity(0)
 	};
-	debug!("Loaded {} previous version checksums...", old_checksums.len());
+	if !args.flag_low_memory {
+		debug!("Loaded {} previous version checksums...", old_checksums.len());
+		stats.files_done(Phase::LoadChecksums, old_checksums.len() as u64, 0);
+	}
 
-	// Walk specified files in the source directory and checksum files
-	debug!("Walking/checking source directory...");
-	let new_checksums = operations::checksum_directory(&args.arg_source, &source_root);
+	// Walk specified files in the source directory and checksum files. If
+	// streaming, changed files are archived as we go.
+	let mut walk_options = walk_options(args, stats.clone())?;
+	if args.flag_no_checksum_verify {
+		walk_options.known_files = Some(Arc::new(known_files(args, &old_checksums,
+			checksum_db.as_ref())?));
+	}
+	// Checking files for changes before archiving them means keeping every
+	// file's size and modification time, which --low-memory avoids.
+	if !args.flag_low_memory {
+		walk_options.stability = Some(Arc::new(Stability::new(hash_algorithm, hash_key,
+			args.flag_skip_unstable)));
+	}
+	let totals = if args.flag_pre_scan || args.flag_progress {
+		Some(pre_scan(args, &roots, &walk_options, &old_checksums, checksum_db.as_ref())?)
+	} else {
+		None
+	};
+	let progress = match (args.flag_progress, totals) {
+		(true, Some(totals)) => {
+			let progress = Arc::new(Progress::new());
+			progress.start("Checksumming", totals.files, totals.bytes);
+			Some(progress)
+		},
+		_ => None
+	};
+	walk_options.progress = progress.clone();
+	stats.destination(&args.arg_destination, args.flag_dry_run);
+	let backup_dir = args.flag_backup_dir.as_ref().map(Path::new);
+	let link_dest = args.flag_hardlink_dest.as_ref().map(Path::new);
+	if link_dest.is_some() && backup_dir.is_none() {
+		return Err(BackupError::OtherError(
+			"--hardlink-dest can only be used with --backup-dir".to_string()));
+	}
+	// With --hardlink-dest, unchanged files are linked into the backup
+	// directory once the archive is written, rather than copied as we go (see
+	// `ArchiveOptions::link_dest`).
+	let archive_options = archive_options(args, &walk_options)?;
+	if args.flag_low_memory {
+		low_memory_backup(args, &roots, &walk_options, &archive_options, &header)?;
+		record_throttle(&walk_options);
+		debug!("Done!");
+		return Ok(());
+	}
+	// The archive can only be skipped if it isn't written as we go.
+	let min_changed_pct = if args.flag_always_write_archive { None } else { args.flag_min_changed_pct };
+	let streamed = !args.flag_no_pipeline && !args.flag_dry_run &&
+		!args.flag_fail_on_case_collision && args.flag_archive_threads <= 1 &&
+		min_changed_pct.is_none() && !checks_disk_space(args) &&
+		!args.flag_output_checksums_to_archive &&
+		args.flag_archive_format == ArchiveFormat::Tar;
+	let result = if streamed {
+		debug!("Walking/checking source directory and writing backup file to {}...",
+			args.arg_destination);
+		let mut archive = operations::create_archive(&args.arg_destination,
+			&archive_options)?;
+		let result = operations::stream_checksums(
+			&args.arg_source,
+			&roots,
+			&walk_options,
+			|| hash_algorithm.new_hasher(hash_key),
+			&old_checksums,
+			&mut archive,
+			&archive_options)?;
+		let output = archive.finish()
+			.map_err(|e| operations::archive_write_error(&args.arg_destination, None, e))?;
+		stats.check_strict()?;
+		output.commit()?;
+		stats.archive_written(output.size());
+		result
+	} else {
+		debug!("Walking/checking source directory...");
+		operations::checksum_directory(
+			&args.arg_source,
+			&roots,
+			&walk_options,
+			|| hash_algorithm.new_hasher(hash_key))?
+	};
+	// Files which couldn't be read are reported with the statistics, below,
+	// unless the run is strict.
+	stats.check_strict()?;
+	let new_checksums = result.checksums;
 
-	// Write new checksums
-	try!(match (args.flag_dry_run, args.flag_new_checksums) {
-		(false, Some(fname)) => {
+	if let Some(ref progress) = progress {
+		progress.finish();
+	}
+
+	// Check for filenames which would collide on case-insensitive filesystems
+	let collisions = operations::case_collisions(&new_checksums);
+	if !collisions.is_empty() {
+		let groups : Vec<String> = collisions.iter().map(|g| g.join(", ")).collect();
+		let message = format!(
+			concat!("Some filenames differ only by case, and would collide if restored to ",
+				"a case-insensitive filesystem:\n\t{}"),
+			groups.join("\n\t"));
+		if args.flag_fail_on_case_collision {
+			return Err(BackupError::OtherError(message))
+		}
+		warn!("{}", message);
+	}
+
+	let changed = operations::count_changed(&new_checksums, &old_checksums);
+	stats.compared(changed as u64, (new_checksums.len() - changed) as u64);
+
+	// Skip the archive if too few files have changed, leaving the changes for
+	// the next run
+	let skip_archive = match min_changed_pct {
+		Some(min_pct) => {
+			let changed_pct = if new_checksums.is_empty() {
+				0.0
+			} else {
+				changed as f64 * 100.0 / new_checksums.len() as f64
+			};
+			if changed_pct < min_pct {
+				info!("Only {} of {} files ({:.1}%) changed, fewer than {}%; not writing an archive",
+					changed, new_checksums.len(), changed_pct, min_pct);
+			}
+			changed_pct < min_pct
+		},
+		None => false
+	};
+	if !skip_archive && !args.flag_dry_run && checks_disk_space(args) {
+		check_disk_space(args, operations::changed_size(
+			&new_checksums, &old_checksums, &roots, args.flag_normalize_unicode))?;
+	}
+	let deferred;
+	let archived;
+	let mut saved_checksums = if skip_archive {
+		deferred = operations::defer_changes(&new_checksums, &old_checksums);
+		&deferred
+	} else {
+		&new_checksums
+	};
+
+	// Package altered files in source root into a tarball and write it to the destination
+	if skip_archive {
+		debug!("Not writing backup file to {}", args.arg_destination);
+	} else if streamed {
+		debug!("Backup file already written to {}", args.arg_destination);
+	} else if !args.flag_dry_run {
+		debug!("Writing backup file to {}...", args.arg_destination);
+		let report = if args.flag_archive_format == ArchiveFormat::Zip {
+			let embedded_checksums = if args.flag_output_checksums_to_archive {
+				Some(saved_checksums)
+			} else {
+				None
+			};
+			operations::write_zip_archive(
+					&new_checksums,
+					&old_checksums,
+					&roots,
+					&args.arg_destination,
+					embedded_checksums,
+					&header,
+					&archive_options)?
+		} else {
+			let embedded_checksums = if args.flag_output_checksums_to_archive {
+				Some(operations::format_checksums(saved_checksums, &header))
+			} else {
+				None
+			};
+			operations::write_archive(
+					&new_checksums,
+					&old_checksums,
+					&roots,
+					&args.arg_destination,
+					embedded_checksums.as_deref(),
+					&archive_options)?
+		};
+		if let Some(ref progress) = progress {
+			progress.finish();
+		}
+		// Files which couldn't be archived (or changed as they were) are left
+		// out of the new checksums, so the next run archives them, and files
+		// checksummed again as they were archived are saved with their new
+		// checksums
+		if report.changes_checksums() {
+			let mut checksums = saved_checksums.clone();
+			report.apply(&mut checksums);
+			archived = checksums;
+			saved_checksums = &archived;
+		}
+	} else {
+		info!("[dry-run] Output file would be written to {}", args.arg_destination);
+		match (backup_dir, link_dest) {
+			(Some(dir), Some(prev)) => info!(
+				"[dry-run] Unchanged files would be linked from {} (or copied) to {}",
+				prev.display(), dir.display()),
+			(Some(dir), None) => info!(
+				"[dry-run] Unchanged files would be copied to {}", dir.display()),
+			_ => ()
+		}
+		info!("[dry-run] Output would contain the following files (A added, M changed):");
+		let mut changed : Vec<(&String, &String)> = new_checksums.iter()
+			.filter(|&(fname, hash)| old_checksums.get(fname) != Some(hash))
+			.collect();
+		changed.sort();
+		let mut estimate = ArchiveEstimate::new();
+		for (fname, hash) in changed {
+			let size = estimate.add(&operations::source_file(&roots, fname, args.flag_normalize_unicode));
+			log_dry_run_file(fname, hash, size, !old_checksums.contains_key(fname));
+		}
+		log_estimate(&estimate, args.flag_estimate_ratio, args.flag_compression, &stats);
+	}
+
+	// Link unchanged files into the backup directory
+	if let (Some(prev), Some(dir), false, false) = (link_dest, backup_dir, args.flag_dry_run, skip_archive) {
+		debug!("Linking unchanged files from {} to {}...", prev.display(), dir.display());
+		operations::link_unchanged_files(
+				&new_checksums,
+				&old_checksums,
+				&roots,
+				args.flag_normalize_unicode,
+				prev,
+				dir)?;
+	}
+
+	// Write new checksums, only now the backup is complete, so that if it
+	// fails, the next run doesn't take the files to be backed up already
+	let new_fnames = &args.flag_new_checksums;
+	(match (args.flag_dry_run, new_fnames.is_empty()) {
+		(false, false) => {
 			debug!("Writing current version checksums...");
-			operations::save_checksums(&new_checksums, &fname)
+			stats.files_done(Phase::SaveChecksums, saved_checksums.len() as u64, 0);
+			stats.time(Phase::SaveChecksums,
+				|| operations::save_checksums(saved_checksums, new_fnames, &header))
 		},
-		(true, Some(fname)) => {
-			info!("[dry-run] Checksums would be written to {}", fname);
+		(true, false) => {
+			info!("[dry-run] Checksums would be written to {}", new_fnames.join(", "));
 			Ok(())
 		},
-		(_, None) => {
+		(_, true) => {
 			debug!(concat!("No current version checksum file specified, ",
 				"not writing current version checksums..."));
 			Ok(())
 		}
-	});
+	})?;
 
-	// Package altered files in source root into a tarball and write it to the destination
-	if !args.flag_dry_run {
-		debug!("Writing backup file to {}...", args.arg_destination);
-		try!(operations::write_archive(
+	// Record the new checksums in the database, now the backup is complete
+	if let Some(db) = checksum_db {
+		if args.flag_dry_run {
+			info!("[dry-run] Checksums would be saved to the checksum database");
+		} else {
+			debug!("Saving current version checksums to database...");
+			let normalization = args.flag_normalize_unicode;
+			stats.files_done(Phase::SaveChecksums, saved_checksums.len() as u64, 0);
+			stats.time(Phase::SaveChecksums, || db.save(saved_checksums, &header,
+				|key| operations::source_stat(&roots, key, normalization)))?;
+		}
+	}
+
+	record_throttle(&walk_options);
+
+	debug!("Done!");
+	Ok(())
+}
+
+/// Back up files, then back up changes to them as they happen, until asked to
+/// stop, for the `watch` subcommand, recording statistics for all the backups
+/// in `stats`.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the options can't be used with watch, the sources can't be
+/// watched, or a backup fails.
+fn watch(args: &Args, stats: Arc<Stats>) -> Result<(), BackupError> {
+	let (destination, roots, walk_options, sources) = watched_sources(args, &stats, "watch")?;
+	if let Some(interval) = watch_interval(args)? {
+		return poll(args, stats, &destination, &roots, &walk_options, interval);
+	}
+	let settle = Duration::from_secs(args.flag_settle);
+
+	// Everything is backed up to start with, and again if changes are lost.
+	// Until then, the paths which have changed since the last backup are
+	// gathered until changes stop for long enough.
+	let mut watcher = None;
+	let mut full = true;
+	let mut dirty = BTreeSet::new();
+	let mut last_change : Option<Instant> = None;
+	loop {
+		let settled = last_change.is_none_or(|last| last.elapsed() >= settle);
+		let result = if full && settled {
+			// Start watching first, so changes made while checksumming are
+			// noticed.
+			watcher = Some(watch_sources(&sources)?);
+			full = false;
+			dirty.clear();
+			info!("Backing up {} to {}...", args.arg_source.join(", "), destination.display());
+			watch_backup(args, &roots, &walk_options, &stats, &args.arg_source, None)
+		} else if !dirty.is_empty() && settled {
+			let (changed, replaced) = changed_sources(&roots, &dirty);
+			dirty.clear();
+			debug!("Backing up changes to {}...", replaced.join(", "));
+			watch_backup(args, &roots, &walk_options, &stats, &changed, Some(&replaced))
+		} else {
+			Ok(())
+		};
+		match result {
+			Err(BackupError::Cancelled) => (),
+			result => result?
+		}
+		if cancel::cancelled() {
+			info!("Stopped watching for changes");
+			return Ok(());
+		}
+
+		// Wake at least every second to check whether to stop.
+		let timeout = match last_change {
+			Some(last) if full || !dirty.is_empty() => settle.checked_sub(last.elapsed())
+				.map_or(Duration::from_secs(0), |left| cmp::min(left, Duration::from_secs(1))),
+			_ => Duration::from_secs(1)
+		};
+		let changes = watcher.as_mut().unwrap().wait(timeout)
+			.map_err(|e| BackupError::OtherError(format!("Error watching for changes: {}", e)))?;
+		for change in changes {
+			match change {
+				Change::Overflow => {
+					warn!("Too many changes to keep track of; checking all the files again");
+					full = true;
+					last_change = Some(Instant::now());
+				},
+				// Other files in the directories of sources which are files
+				// are watched too.
+				Change::Path(path) => if sources.iter().any(|source| path.starts_with(source)) {
+					trace!("Changed: {}", path.display());
+					dirty.insert(path);
+					last_change = Some(Instant::now());
+				}
+			}
+		}
+	}
+}
+
+/// Back up files, then check all of them again every `interval`, backing up
+/// those which have changed, until asked to stop, for watch with
+/// --watch-interval, recording statistics for all the backups in `stats`.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if a backup fails.
+fn poll(
+		args: &Args,
+		stats: Arc<Stats>,
+		destination: &Path,
+		roots: &SourceRoots,
+		walk_options: &WalkOptions,
+		interval: Duration)
+		-> Result<(), BackupError> {
+	info!("Backing up {} to {}...", args.arg_source.join(", "), destination.display());
+	loop {
+		// Each backup checksums every file, and replaces all the previous
+		// checksums, so files which have gone are dropped.
+		match watch_backup(args, roots, walk_options, &stats, &args.arg_source, None) {
+			Err(BackupError::Cancelled) => (),
+			result => result?
+		}
+		let next = SystemTime::now() + interval;
+		debug!("Checking for changes again at {}", operations::format_timestamp(next));
+		if !schedule::sleep_until(next) {
+			info!("Stopped checking for changes");
+			return Ok(());
+		}
+	}
+}
+
+/// Back up files, then keep track of changes to them, and back up those which
+/// have changed when asked to (by SIGHUP, or at each --interval), until asked
+/// to stop, for the `daemon` subcommand, recording statistics for all the
+/// backups in `stats`.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the options can't be used with daemon, the sources can't be
+/// watched, or a backup fails.
+fn daemon(args: &Args, stats: Arc<Stats>) -> Result<(), BackupError> {
+	let (destination, roots, walk_options, sources) = watched_sources(args, &stats, "daemon")?;
+	let interval = interval(args)?;
+	schedule::install_trigger();
+
+	// Start tracking changes first, so changes made while checksumming are
+	// backed up next time.
+	let tracker = ChangeTracker::start(watch_sources(&sources)?, sources);
+	info!("Backing up {} to {}...", args.arg_source.join(", "), destination.display());
+	let mut result = watch_backup(args, &roots, &walk_options, &stats, &args.arg_source, None);
+	loop {
+		match result {
+			Err(BackupError::Cancelled) => (),
+			result => result?
+		}
+		let next = interval.map(|(interval, jitter)|
+			schedule::next_boundary(SystemTime::now(), interval) + schedule::jitter(jitter));
+		match next {
+			Some(next) => debug!("Backing up changes at {}, or on SIGHUP", operations::format_timestamp(next)),
+			None => debug!("Backing up changes on SIGHUP")
+		}
+		if !schedule::wait_for_trigger(next) {
+			info!("Stopped keeping track of changes");
+			return Ok(());
+		}
+		let dirty : BTreeSet<PathBuf> = tracker.take().into_iter().collect();
+		result = if dirty.is_empty() {
+			debug!("No files have changed");
+			Ok(())
+		} else {
+			let (changed, replaced) = changed_sources(&roots, &dirty);
+			info!("Backing up changes to {}...", replaced.join(", "));
+			watch_backup(args, &roots, &walk_options, &stats, &changed, Some(&replaced))
+		};
+	}
+}
+
+/// Check the options for watch or daemon (named by `command`), and prepare to
+/// back up their sources: the destination directory, the source roots, the
+/// options for walking the sources, and the sources' paths, to watch.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the options can't be used with the command, or the destination
+/// isn't a directory, or is beneath a source.
+fn watched_sources(args: &Args, stats: &Arc<Stats>, command: &str)
+		-> Result<(PathBuf, SourceRoots, WalkOptions, Vec<PathBuf>), BackupError> {
+	let conflicts = [
+		("--old-checksums", args.flag_old_checksums.is_some()),
+		("--new-checksums", !args.flag_new_checksums.is_empty()),
+		("--checksum-db", args.flag_checksum_db.is_some()),
+		("--since-archive", args.flag_since_archive.is_some()),
+		("--low-memory", args.flag_low_memory),
+		("--dry-run", args.flag_dry_run),
+		("--archive-format zip", args.flag_archive_format == ArchiveFormat::Zip),
+		("--strict", args.flag_strict),
+		("--files-from", args.flag_files_from.is_some()),
+		("--ignore-missing-sources", args.flag_ignore_missing_sources),
+	];
+	for &(option, given) in conflicts.iter() {
+		if given {
+			return Err(BackupError::OtherError(format!("{} can't be used with {}", option, command)));
+		}
+	}
+	let destination = fs::canonicalize(&args.arg_destination)
+		.and_then(|path| if path.is_dir() {
+			Ok(path)
+		} else {
+			Err(std::io::Error::other("not a directory"))
+		}).map_err(|e| BackupError::OtherError(
+			format!("Can't write backups to {}: {}", args.arg_destination, e)))?;
+	let (roots, sources) = source_roots(args, stats)?;
+	let args = &Args { arg_source: sources, ..args.clone() };
+	let walk_options = walk_options(args, stats.clone())?;
+	let sources : Vec<PathBuf> = args.arg_source.iter()
+		.map(|source| operations::source_file(&roots, source, args.flag_normalize_unicode)
+			.components().collect())
+		.collect();
+	// Otherwise each backup would back up the last.
+	if let Some(source) = sources.iter().find(|source| destination.starts_with(source)) {
+		return Err(BackupError::OtherError(format!(
+			"Can't write backups to {}, beneath the source {}", args.arg_destination, source.display())));
+	}
+	stats.destination(&args.arg_destination, false);
+	Ok((destination, roots, walk_options, sources))
+}
+
+/// Watch the given sources for changes (see `Watcher`).
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if changes can't be watched for, or any source can't be watched.
+fn watch_sources(sources: &[PathBuf]) -> Result<Watcher, BackupError> {
+	let mut watcher = Watcher::new()
+		.map_err(|e| BackupError::OtherError(format!("Can't watch for changes: {}", e)))?;
+	for source in sources {
+		watcher.add(source).map_err(|e| BackupError::OtherError(
+				format!("Couldn't watch {} for changes: {}", source.display(), e)))?;
+	}
+	Ok(watcher)
+}
+
+/// Work out what to back up for a set of changed paths: the sources to
+/// checksum again, those of them which still exist, and the sources whose
+/// previous checksums are replaced, all of them. Paths beneath others which
+/// have changed are covered by them, and left out.
+fn changed_sources(roots: &SourceRoots, dirty: &BTreeSet<PathBuf>) -> (Vec<String>, Vec<String>) {
+	let mut changed = Vec::new();
+	let mut replaced = Vec::new();
+	for path in dirty {
+		if path.ancestors().skip(1).any(|dir| dirty.contains(dir)) {
+			continue
+		}
+		// Sources outside the source root are given by their absolute paths.
+		let source = match roots.source_name(path).or_else(|| path.to_str().map(|p| p.to_string())) {
+			Some(source) => source,
+			None => {
+				warn!("Skipping {} (filename is not valid Unicode)", path.display());
+				continue
+			}
+		};
+		if fs::symlink_metadata(path).is_ok() {
+			changed.push(source.clone());
+		}
+		replaced.push(source);
+	}
+	(changed, replaced)
+}
+
+/// Back up the files in `sources` which have changed since the last backup
+/// written by watch, to a new archive in its destination directory, and update
+/// the checksums there. The previous checksums for files in `replaced` (or all
+/// of them, if not given) are replaced by the new ones, so files which have
+/// gone are dropped.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the checksums can't be read or written, a file can't be read, or
+/// the archive can't be written, or a `BackupError::Cancelled` if the backup is
+/// interrupted, having removed any partial archive and checksum file.
+fn watch_backup(
+		args: &Args,
+		roots: &SourceRoots,
+		walk_options: &WalkOptions,
+		stats: &Stats,
+		sources: &[String],
+		replaced: Option<&[String]>)
+		-> Result<(), BackupError> {
+	let normalization = args.flag_normalize_unicode;
+	let manifest = watch_manifest(args);
+	let old_checksums = if Path::new(&manifest).exists() {
+		operations::load_checksums(&manifest, normalization)?
+	} else {
+		HashMap::new()
+	};
+	let hash_key = hash_key(args)?;
+	let hash_key = hash_key.as_ref().map(|k| &k[..]);
+	let mut walk_options = walk_options.clone();
+	walk_options.stability = Some(Arc::new(Stability::new(args.flag_hash_algorithm, hash_key,
+		args.flag_skip_unstable)));
+	let new_checksums = if sources.is_empty() {
+		HashMap::new()
+	} else {
+		operations::checksum_directory(sources, roots, &walk_options,
+			|| args.flag_hash_algorithm.new_hasher(hash_key))?.checksums
+	};
+
+	// Keep the previous checksums for files which haven't changed. Sources
+	// outside the source root are recorded beneath OUTSIDE_ROOT.
+	let mut checksums : HashMap<String, String> = match replaced {
+		Some(replaced) => {
+			let replaced : Vec<String> = replaced.iter()
+				.map(|source| normalization.apply(if source.starts_with('/') {
+					format!("{}{}", operations::OUTSIDE_ROOT, source)
+				} else {
+					source.clone()
+				}))
+				.collect();
+			old_checksums.iter()
+				.filter(|&(key, _)| !replaced.iter().any(|source| source == "."
+					|| key == source || key.starts_with(&format!("{}/", source))))
+				.map(|(key, value)| (key.clone(), value.clone()))
+				.collect()
+		},
+		None => HashMap::new()
+	};
+	checksums.extend(new_checksums.iter().map(|(key, value)| (key.clone(), value.clone())));
+	let changed = operations::count_changed(&new_checksums, &old_checksums);
+	let removed = old_checksums.keys().filter(|key| !checksums.contains_key(*key)).count();
+	stats.compared(changed as u64, (new_checksums.len() - changed) as u64);
+	if changed == 0 && removed == 0 {
+		debug!("No files have changed");
+		return Ok(());
+	}
+
+	let mut header = Vec::new();
+	if args.flag_checksum_header {
+		header.push(("backup", VERSION.unwrap_or("unknown").to_string()));
+		header.push(("created", operations::format_timestamp(SystemTime::now())));
+		header.push(("source-root", roots.root().display().to_string()));
+		header.push(("hash-algorithm", args.flag_hash_algorithm.name().to_string()));
+	}
+	if changed > 0 {
+		let destination = watch_archive(&args.arg_destination, args.flag_compression);
+		info!("Backing up {} changed files to {}...", changed, destination);
+		let embedded_checksums = if args.flag_output_checksums_to_archive {
+			Some(operations::format_checksums(&checksums, &header))
+		} else {
+			None
+		};
+		// watch doesn't keep a backup directory.
+		let archive_options = ArchiveOptions {
+			backup_dir: None,
+			link_dest: None,
+			..archive_options(args, &walk_options)?
+		};
+		let written = operations::write_archive(
 				&new_checksums,
 				&old_checksums,
-				&source_root,
-				&args.arg_destination));
+				roots,
+				&destination,
+				embedded_checksums.as_deref(),
+				&archive_options);
+		if written.is_err() {
+			remove_partial_archive(&destination, archive_options.target_size.is_some());
+		}
+		// As for backup, files which couldn't be archived are left out, and
+		// those checksummed again updated
+		written?.apply(&mut checksums);
+	}
+	if removed > 0 {
+		info!("{} files have gone since the last backup", removed);
+	}
+	let saved = operations::save_checksums(&checksums, std::slice::from_ref(&manifest), &header);
+	if saved.is_err() {
+		operations::remove_partial(&manifest);
+	}
+	saved
+}
+
+/// Get the file to which watch writes the checksums of all the files it's
+/// backed up, in its destination directory.
+fn watch_manifest(args: &Args) -> String {
+	Path::new(&args.arg_destination).join("checksums").display().to_string()
+}
+
+/// Choose the name for a new archive in watch's destination directory, from
+/// the current time (see `USAGE`).
+fn watch_archive(directory: &str, compression: Compression) -> String {
+	let timestamp = operations::file_timestamp(SystemTime::now());
+	(0 ..)
+		.map(|n| if n == 0 {
+			format!("backup-{}{}", timestamp, compression.extension())
+		} else {
+			format!("backup-{}-{}{}", timestamp, n, compression.extension())
+		})
+		.map(|name| Path::new(directory).join(name).display().to_string())
+		.find(|name| !Path::new(name).exists() && !Path::new(&operations::part_path(name, 1)).exists())
+		.unwrap()
+}
+
+/// Gather what's known about the files in the previous backup, for
+/// `--no-checksum-verify`: their sizes and modification times from the
+/// checksum database, if any, or otherwise when the old checksums were
+/// written.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the checksum database can't be read.
+fn known_files(args: &Args, old_checksums: &HashMap<String, String>, checksum_db: Option<&ChecksumDb>)
+		-> Result<operations::KnownFiles, BackupError> {
+	let stats = match checksum_db {
+		Some(db) => Some(db.load_stats(args.flag_normalize_unicode)?),
+		None => None
+	};
+	// Embedded checksums are in an archive's first part, written just after
+	// them.
+	let old_fname = match args.flag_since_archive {
+		Some(ref archive) => Some(operations::archive_parts(archive).remove(0)),
+		None => args.flag_old_checksums.clone()
+	};
+	let written = old_fname.and_then(|fname| fs::metadata(fname).and_then(|meta| meta.modified()).ok());
+	Ok(operations::KnownFiles::new(old_checksums.clone(), stats, written))
+}
+
+/// Count the files to be checksummed without reading them, for `--pre-scan`
+/// and `--progress`. With `--pre-scan`, the totals are logged, along with an
+/// estimate of how many files have changed if there's a previous backup to
+/// compare against.
+fn pre_scan(
+		args: &Args,
+		roots: &SourceRoots,
+		walk_options: &WalkOptions,
+		old_checksums: &HashMap<String, String>,
+		checksum_db: Option<&ChecksumDb>)
+		-> Result<ScanTotals, BackupError> {
+	if !args.flag_pre_scan {
+		return Ok(operations::scan_files(&args.arg_source, roots, walk_options, |_, _| false));
+	}
+	debug!("Scanning source directory...");
+	// An archive with embedded checksums was written just after them.
+	let old_fname = match args.flag_old_checksums {
+		Some(ref fname) if !args.flag_low_memory => Some(fname),
+		_ => args.flag_since_archive.as_ref()
+	};
+	let totals = if let Some(db) = checksum_db {
+		// Files have probably changed if their size or modification time has.
+		let stats = db.load_stats(args.flag_normalize_unicode)?;
+		operations::scan_files(&args.arg_source, roots, walk_options, |key, meta| {
+			let mtime = meta.modified().ok()
+				.and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+				.map(|mtime| mtime.as_secs() as i64);
+			stats.get(key).is_none_or(|&(size, old_mtime)| size != meta.len() || Some(old_mtime) != mtime)
+		})
+	} else if let Some(fname) = old_fname {
+		// Without recorded modification times, files have probably changed if
+		// they've been modified since the old checksums were written.
+		let written = fs::metadata(fname).and_then(|meta| meta.modified()).ok();
+		operations::scan_files(&args.arg_source, roots, walk_options, |key, meta| {
+			!old_checksums.contains_key(key) || match (written, meta.modified()) {
+				(Some(written), Ok(mtime)) => mtime > written,
+				_ => false
+			}
+		})
 	} else {
+		operations::scan_files(&args.arg_source, roots, walk_options, |_, _| true)
+	};
+	info!("Pre-scan: would examine {} files ({})", totals.files, human_bytes(totals.bytes));
+	if checksum_db.is_some() || old_fname.is_some() {
+		info!("Pre-scan: about {} of them ({}) are new or modified since the previous backup",
+			totals.candidate_files, human_bytes(totals.candidate_bytes));
+	}
+	Ok(totals)
+}
+
+/// Checksum and back up files using a bounded amount of memory, for
+/// `--low-memory`: checksums are sorted in temporary files, then compared with
+/// the old checksums in order of filename, archiving changed files and copying
+/// or linking unchanged ones as they're found.
+fn low_memory_backup(
+		args: &Args,
+		roots: &SourceRoots,
+		walk_options: &WalkOptions,
+		archive_options: &ArchiveOptions,
+		header: &[(&str, String)])
+		-> Result<(), BackupError> {
+	let old_checksums = match args.flag_old_checksums {
+		Some(ref fname) => {
+			debug!("Loading and sorting previous version checksums from {}...", fname);
+			Some(walk_options.stats.time(Phase::LoadChecksums,
+				|| operations::load_checksums_sorted(fname, args.flag_normalize_unicode))?)
+		},
+		None => None
+	};
+
+	debug!("Walking/checking source directory...");
+	let hash_algorithm = args.flag_hash_algorithm;
+	let hash_key = hash_key(args)?;
+	let hash_key = hash_key.as_ref().map(|k| &k[..]);
+	let (new_checksums, failed_files) = operations::checksum_directory_sorted(
+		&args.arg_source,
+		roots,
+		walk_options,
+		|| hash_algorithm.new_hasher(hash_key))?;
+	// Files which couldn't be read are left out of the new checksums, so the
+	// next run tries them again, and are reported with the statistics (making
+	// the run partial), as in `backup`; a strict run fails here, before the
+	// archive is started.
+	if !failed_files.is_empty() {
+		debug!("{} files couldn't be read, and won't be archived", failed_files.len());
+	}
+	walk_options.stats.check_strict()?;
+	if let Some(ref progress) = walk_options.progress {
+		progress.finish();
+	}
+
+	if args.flag_dry_run {
 		info!("[dry-run] Output file would be written to {}", args.arg_destination);
-		info!("[dry-run] Output would contain the following files:");
-		for (fname, hash) in &new_checksums {
-			let old_hash = &old_checksums.get(fname);
-			if old_hash.is_none() || old_hash.unwrap() != hash {
-				info!("[dry-run]\t{}\t{}", fname, hash);
+		info!("[dry-run] Output would contain the following files (A added, M changed):");
+		let mut estimate = ArchiveEstimate::new();
+		manifest::compare_sorted(new_checksums, old_checksums, |fname, hash, changed, added| {
+			if changed {
+				let size = estimate.add(&operations::source_file(roots, &fname, args.flag_normalize_unicode));
+				log_dry_run_file(&fname, &hash, size, added);
+				walk_options.stats.compared(1, 0);
+			} else {
+				walk_options.stats.compared(0, 1);
 			}
+			Ok(())
+		})?;
+		log_estimate(&estimate, args.flag_estimate_ratio, args.flag_compression,
+			&walk_options.stats);
+		if !args.flag_new_checksums.is_empty() {
+			info!("[dry-run] Checksums would be written to {}", args.flag_new_checksums.join(", "));
 		}
+		return Ok(());
 	}
 
-	debug!("Done!");
-	Ok(())
+	if checks_disk_space(args) {
+		// Changed files are only found as the archive is written.
+		let totals = operations::scan_files(&args.arg_source, roots, walk_options, |_, _| false);
+		check_disk_space(args, totals.bytes)?;
+	}
+
+	debug!("Writing backup file to {}...", args.arg_destination);
+	operations::write_sorted(
+		new_checksums,
+		old_checksums,
+		roots,
+		&args.arg_destination,
+		&args.flag_new_checksums,
+		header,
+		archive_options)
 }
 
-fn main() {
-	if let Err(e) = init_log() {
-		use std::io::Write;
-		writeln!(&mut std::io::stderr(), "Could not set logger: {}", e).unwrap();
-		exit(5);
+/// Log a file which would be archived in a dry run, marked as added (absent
+/// from the old checksums) or changed.
+fn log_dry_run_file(fname: &str, hash: &str, size: u64, added: bool) {
+	let (marker, change) = if added { ("A", "added") } else { ("M", "changed") };
+	log_fields!(LogLevel::Info, {path: fname, checksum: hash, change: change},
+		"[dry-run]\t{}\t{}\t{}\t{}", marker, fname, hash, human_bytes(size));
+}
+
+/// Whether to check for space for the archive before writing it.
+fn checks_disk_space(args: &Args) -> bool {
+	args.flag_check_disk_space || args.flag_min_free_space.is_some()
+}
+
+/// Check that the destination's filesystem has room for an archive of up to
+/// `required` bytes, leaving the `--min-free-space` free.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if there isn't enough space, or `--min-free-space` is invalid. If
+/// the free space can't be found, a warning is logged instead.
+fn check_disk_space(args: &Args, required: u64) -> Result<(), BackupError> {
+	let margin = match args.flag_min_free_space {
+		Some(ref size) => throttle::parse_size(size).ok_or_else(|| BackupError::OtherError(
+			format!("Invalid --min-free-space {} (expected e.g. 10G)", size)))?,
+		None => 0
+	};
+	if let Some(kind) = operations::special_destination(&args.arg_destination) {
+		warn!("Can't determine the free space on the {} {}; not checking it", kind, args.arg_destination);
+		return Ok(());
+	}
+	let dir = match Path::new(&args.arg_destination).parent() {
+		Some(dir) if !dir.as_os_str().is_empty() => dir,
+		_ => Path::new(".")
+	};
+	match operations::available_space(dir) {
+		Some(available) if available < required.saturating_add(margin) =>
+			Err(BackupError::OtherError(format!(
+				"Not enough space for {}: it may need up to {}{}, but only {} is available",
+				args.arg_destination, human_bytes(required),
+				if margin > 0 { format!(" with {} left free", human_bytes(margin)) } else { String::new() },
+				human_bytes(available)))),
+		Some(available) => {
+			debug!("{} available for {}, which may need up to {}",
+				human_bytes(available), args.arg_destination, human_bytes(required));
+			Ok(())
+		},
+		None => {
+			warn!("Can't determine the free space for {}; not checking it", args.arg_destination);
+			Ok(())
+		}
 	}
+}
 
-	if let Err(e) = do_main() {
-		match e {
-			MainError::OtherError(s) => {
-				error!("{}", s);
-				exit(3);
-			},
-			MainError::DocoptError(e) => e.exit(),
+/// Log the total size of the files which would be archived in a dry run, and
+/// an estimate of the archive's size, compressing to `ratio` if given or
+/// otherwise as well as the sampled files do with `compression`. The estimate is
+/// recorded in `stats` as though the archive had been written.
+fn log_estimate(estimate: &ArchiveEstimate, ratio: Option<f64>, compression: Compression,
+		stats: &Stats) {
+	stats.files_done(Phase::Archive, estimate.files(), estimate.bytes());
+	info!("[dry-run] {} files would be archived, totalling {} ({} with tar headers)",
+		estimate.files(), human_bytes(estimate.bytes()), human_bytes(estimate.archive_bytes()));
+	let (ratio, source) = match ratio {
+		Some(ratio) => (Some(ratio), "given"),
+		None => (estimate.sample_ratio(compression), "sampled")
+	};
+	let compressed = ratio.map_or(estimate.archive_bytes(), |ratio| (estimate.archive_bytes() as f64 * ratio) as u64);
+	if let Some(ratio) = ratio {
+		info!("[dry-run] The archive would be about {} compressed ({} compression ratio {:.2})",
+			human_bytes(compressed), source, ratio);
+	}
+	stats.archive_written(compressed);
+}
+
+/// Log a summary of the run if it succeeded, and write a report of it to the
+/// `--stats-json` file if given, however it ended.
+fn report_stats(args: &Args, stats: &Stats, succeeded: bool) -> Result<(), BackupError> {
+	let summary = stats.summary();
+	// A failed run's error is reported instead.
+	if succeeded {
+		summary.log();
+		if args.flag_list_skipped {
+			stats.log_skipped();
 		}
 	}
+	if let Some(ref fname) = args.flag_report_skipped {
+		File::create(fname)
+			.and_then(|file| stats.write_skipped(std::io::BufWriter::new(file)))
+			.map_err(|e| BackupError::OtherError(
+				format!("Error writing skipped files to {}: {}", fname, e)))?;
+	}
+	match args.flag_stats_json {
+		Some(ref fname) if fname == "-" => {
+			println!("{}", summary.to_json());
+			Ok(())
+		},
+		Some(ref fname) => File::create(fname)
+			.and_then(|mut file| writeln!(file, "{}", summary.to_json()))
+			.map_err(|e| BackupError::OtherError(
+				format!("Error writing statistics to {}: {}", fname, e))),
+		None => Ok(())
+	}
+}
+
+/// Record the limit on the rate at which files were read, if any, and the
+/// average rate achieved, in the statistics for the run.
+fn record_throttle(walk_options: &WalkOptions) {
+	if let Some(ref throttle) = walk_options.throttle {
+		walk_options.stats.throttled(throttle.rate(), throttle.average_rate());
+	}
+}
+
+fn main() {
+	match do_main() {
+		Ok(EXIT_SUCCESS) => (),
+		Ok(status) => exit(status),
+		Err(BackupError::OtherError(s)) => {
+			error!("{}", s);
+			exit(EXIT_FAILURE);
+		},
+		Err(BackupError::DocoptError(e)) => e.exit(),
+		Err(BackupError::Cancelled) => {
+			error!("Interrupted; partial output was removed");
+			exit(EXIT_CANCELLED);
+		},
+	}
+}
+
+/// The level to log at, unless overridden by `RUST_LOG`, given the number of
+/// times `--verbose` was given and whether `--quiet` was.
+fn log_level(verbose: usize, quiet: bool) -> LogLevelFilter {
+	match (quiet, verbose) {
+		(true, _) => LogLevelFilter::Warn,
+		(false, 0) => LogLevelFilter::Info,
+		(false, 1) => LogLevelFilter::Debug,
+		(false, _) => LogLevelFilter::Trace,
+	}
 }
 
-fn init_log() -> Result<(), SetLoggerError> {
+/// Open the `--log-file`, if given, along with the level to write to it at.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the level is invalid or the file can't be opened for appending.
+fn log_file(args: &Args) -> Result<Option<(File, LogLevelFilter)>, BackupError> {
+	let fname = match args.flag_log_file {
+		Some(ref fname) => fname,
+		None => return Ok(None)
+	};
+	let level = args.flag_log_file_level.parse().map_err(|()| BackupError::OtherError(
+		format!("Invalid --log-file-level {} (expected error, warn, info, debug or trace)",
+			args.flag_log_file_level)))?;
+	let file = fs::OpenOptions::new().create(true).append(true).open(fname)
+		.map_err(|e| BackupError::OtherError(format!("Couldn't open log file {}: {}", fname, e)))?;
+	Ok(Some((file, level)))
+}
+
+/// Work out where to log, besides the console: the `--log-file`, and syslog
+/// with `--log-syslog` or `--syslog-only`. If syslog can't be reached, logging
+/// goes to the console instead, and the reason is returned to be logged once
+/// logging is set up.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the log file can't be opened (see `log_file`), or the syslog
+/// facility is invalid.
+fn log_sinks(args: &Args) -> Result<(logger::Sinks, Option<String>), BackupError> {
+	let file = log_file(args)?;
+	let (syslog, error) = if args.flag_log_syslog || args.flag_syslog_only {
+		let facility = logger::Syslog::facility(&args.flag_syslog_facility).ok_or_else(||
+			BackupError::OtherError(format!("Invalid --syslog-facility {}", args.flag_syslog_facility)))?;
+		match logger::Syslog::connect(facility) {
+			Ok(syslog) => (Some(syslog), None),
+			Err(e) => (None, Some(e))
+		}
+	} else {
+		(None, None)
+	};
+	Ok((logger::Sinks {
+		stderr: !args.flag_syslog_only || syslog.is_none(),
+		file,
+		syslog,
+		local_time: args.flag_log_local_time,
+		format: args.flag_log_format,
+		color: args.flag_color.enabled(),
+	}, error))
+}
+
+/// Set up logging to the console, according to `spec` (in `RUST_LOG` syntax)
+/// if given, or otherwise at `level`, and to the other `sinks`. Console lines
+/// are formatted, timestamped and colored as `sinks` says.
+fn init_log(level: LogLevelFilter, spec: Option<String>, sinks: logger::Sinks)
+		-> Result<(), SetLoggerError> {
+	let local_time = sinks.local_time;
+	let format = sinks.format;
+	let color = sinks.color;
 	let mut builder = LogBuilder::new();
-	builder.format(|record: &LogRecord| {
-		format!("[{} {}:{}] [{}] {}",
-			record.location().module_path(),
-			record.location().file(),
-			record.location().line(),
-			record.level(),
-			record.args()) } );
-	match env::var("RUST_LOG") {
-		Ok(log_str) => { builder.parse(&log_str); },
-		Err(_) => { builder.filter(None, LogLevel::Info.to_log_level_filter()); }
-	}
-	try!(builder.init());
-	Ok(())
+	builder.format(move |record: &LogRecord| {
+		progress::clear_line();
+		match format {
+			LogFormat::Human => {
+				let line = logger::human_line(record, local_time);
+				if color { logger::paint(record, line) } else { line }
+			},
+			LogFormat::Json => logger::json_line(record, local_time),
+		} } );
+	match spec {
+		Some(spec) => { builder.parse(&spec); },
+		None => { builder.filter(None, level); }
+	}
+	logger::init(builder.build(), sinks)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Parse a commandline (without the program name).
+	fn args(argv: &[&str]) -> Args {
+		Docopt::new(USAGE)
+			.and_then(|d| d.argv(iter::once("backup").chain(argv.iter().cloned())).deserialize())
+			.unwrap()
+	}
+
+	#[test]
+	fn watch_intervals() {
+		assert_eq!(watch_interval(&args(&["watch", "src", "dest"])).unwrap(), None);
+		assert_eq!(watch_interval(&args(&["watch", "--watch-interval", "90", "src", "dest"])).unwrap(),
+			Some(Duration::from_secs(90)));
+		for (argv, error) in &[
+			(&["watch", "--watch-interval", "0", "src", "dest"][..], "at least 1 second"),
+			(&["daemon", "--watch-interval", "90", "src", "dest"][..], "can't be used with daemon"),
+			(&["--watch-interval", "90", "src", "dest"][..], "can only be used with watch"),
+		] {
+			let message = watch_interval(&args(argv)).unwrap_err().to_string();
+			assert!(message.contains(error), "{:?}: {}", argv, message);
+		}
+	}
+
+	#[test]
+	fn exit_status_from_summary() {
+		let summary = |f: &dyn Fn(&mut Summary)| {
+			let mut summary = Summary { changed_files: 1, ..Summary::default() };
+			f(&mut summary);
+			exit_status(&summary)
+		};
+		assert_eq!(summary(&|_| ()), EXIT_SUCCESS);
+		assert_eq!(summary(&|s| s.changed_files = 0), EXIT_UNCHANGED);
+		assert_eq!(summary(&|s| s.skipped.unreadable = 1), EXIT_PARTIAL);
+		assert_eq!(summary(&|s| s.unstable_files_count = 1), EXIT_PARTIAL);
+		assert_eq!(summary(&|s| s.missing_sources_count = 1), EXIT_PARTIAL);
+		// Files which weren't backed up matter more than there being no changes.
+		assert_eq!(summary(&|s| { s.changed_files = 0; s.skipped.unreadable = 1; }), EXIT_PARTIAL);
+		assert_eq!(summary(&|s| { s.changed_files = 0; s.missing_sources_count = 1; }), EXIT_PARTIAL);
+		assert_eq!((EXIT_SUCCESS, EXIT_PARTIAL, EXIT_UNCHANGED), (0, 2, 4));
+	}
+
+	#[test]
+	fn exit_status_from_error() {
+		assert_eq!(error_status(&BackupError::Cancelled), 6);
+		assert_eq!(error_status(&BackupError::OtherError("failed".to_string())), 3);
+		assert_eq!(error_status(&BackupError::DocoptError(docopt::Error::Argv("bad".to_string()))), 1);
+	}
+
+	#[test]
+	fn log_levels() {
+		assert_eq!(log_level(0, false), LogLevelFilter::Info);
+		assert_eq!(log_level(1, false), LogLevelFilter::Debug);
+		assert_eq!(log_level(2, false), LogLevelFilter::Trace);
+		assert_eq!(log_level(5, false), LogLevelFilter::Trace);
+		// --quiet wins, however many --verbose are given.
+		assert_eq!(log_level(0, true), LogLevelFilter::Warn);
+		assert_eq!(log_level(1, true), LogLevelFilter::Warn);
+		assert_eq!(log_level(2, true), LogLevelFilter::Warn);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn held_lock_fails_the_run() {
+		let path = env::temp_dir().join(format!("backup-test-{}.lock", std::process::id()));
+		let path = path.to_str().unwrap();
+		let held = RunLock::acquire(path, None).unwrap();
+		let error = RunLock::acquire(path, Some(Duration::from_millis(300))).unwrap_err();
+		assert!(error.to_string().contains("Another backup is already running"), "{}", error);
+		assert_eq!(error_status(&error), 3);
+		drop(held);
+		drop(RunLock::acquire(path, None).unwrap());
+		fs::remove_file(path).unwrap();
+	}
 }
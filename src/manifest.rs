@@ -0,0 +1,370 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{self, AtomicUsize};
+use std::vec;
+
+use BackupError;
+use operations::{format_checksum_line, parse_checksum_line};
+
+/// Roughly how much memory checksums may use before they're sorted and
+/// written out to a temporary file.
+const CHUNK_BYTES: usize = 64 << 20;
+
+/// An estimate of the memory used by each checksum beyond its strings.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Sorts checksums by filename using a bounded amount of memory.
+///
+/// Checksums are collected in memory until they reach `CHUNK_BYTES`, then
+/// sorted and written to a temporary file; once all checksums have been
+/// added, the sorted chunks are merged. If a filename is added more than once,
+/// the last checksum added wins, as for a map.
+pub struct ManifestSorter {
+	entries: Vec<(String, String)>,
+	bytes: usize,
+	/// How many bytes to collect before spilling them (`CHUNK_BYTES`, except
+	/// in tests).
+	chunk_bytes: usize,
+	chunks: Vec<(File, TempPath)>,
+}
+
+impl Default for ManifestSorter {
+	fn default() -> ManifestSorter {
+		ManifestSorter::new()
+	}
+}
+
+impl ManifestSorter {
+	/// Create an empty sorter.
+	pub fn new() -> ManifestSorter {
+		ManifestSorter::with_chunk_bytes(CHUNK_BYTES)
+	}
+
+	/// Create an empty sorter which spills checksums after collecting
+	/// `chunk_bytes` of them.
+	fn with_chunk_bytes(chunk_bytes: usize) -> ManifestSorter {
+		ManifestSorter { entries: Vec::new(), bytes: 0, chunk_bytes, chunks: Vec::new() }
+	}
+
+	/// Add a filename and checksum.
+	///
+	/// # Errors
+	///
+	/// This function will return any I/O error encountered writing checksums
+	/// to a temporary file.
+	pub fn push(&mut self, filename: String, checksum: String) -> io::Result<()> {
+		self.bytes += filename.len() + checksum.len() + ENTRY_OVERHEAD;
+		self.entries.push((filename, checksum));
+		if self.bytes >= self.chunk_bytes {
+			self.spill()?;
+		}
+		Ok(())
+	}
+
+	/// Sort the checksums collected in memory and write them to a temporary
+	/// file.
+	fn spill(&mut self) -> io::Result<()> {
+		// The sort is stable, so repeated filenames stay in the order added.
+		self.entries.sort_by(|a, b| a.0.cmp(&b.0));
+		let (mut file, path) = create_temp_file()?;
+		{
+			let mut writer = BufWriter::new(&mut file);
+			for (filename, checksum) in self.entries.drain(..) {
+				writer.write_all(format_checksum_line(&filename, &checksum).as_bytes())?;
+			}
+			writer.flush()?;
+		}
+		file.seek(SeekFrom::Start(0))?;
+		trace!("Wrote sorted checksums to {}", path.0.display());
+		self.chunks.push((file, path));
+		self.bytes = 0;
+		Ok(())
+	}
+
+	/// Finish adding checksums, returning them in order of filename.
+	///
+	/// # Errors
+	///
+	/// This function will return any I/O error encountered reading the
+	/// temporary files.
+	pub fn finish(mut self) -> io::Result<SortedManifest> {
+		self.entries.sort_by(|a, b| a.0.cmp(&b.0));
+		let mut sources = Vec::new();
+		for (file, path) in self.chunks {
+			sources.push(Source::Chunk { reader: BufReader::new(file), _path: path });
+		}
+		sources.push(Source::Memory(self.entries.into_iter()));
+		let mut manifest = SortedManifest { sources, heap: BinaryHeap::new() };
+		for index in 0 .. manifest.sources.len() {
+			manifest.refill(index)?;
+		}
+		Ok(manifest)
+	}
+}
+
+/// Checksums in order of filename, with each filename appearing once; see
+/// `ManifestSorter`.
+pub struct SortedManifest {
+	sources: Vec<Source>,
+	heap: BinaryHeap<Reverse<HeapEntry>>,
+}
+
+/// Somewhere sorted checksums are read from.
+enum Source {
+	// The file is closed before the path is dropped, removing it.
+	Chunk { reader: BufReader<File>, _path: TempPath },
+	Memory(vec::IntoIter<(String, String)>),
+}
+
+/// The next checksum from a source, ordered by filename, then by source (so
+/// that among repeated filenames, the last added comes last).
+struct HeapEntry {
+	filename: String,
+	source: usize,
+	checksum: String,
+}
+
+impl PartialEq for HeapEntry {
+	fn eq(&self, other: &HeapEntry) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+	fn partial_cmp(&self, other: &HeapEntry) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for HeapEntry {
+	fn cmp(&self, other: &HeapEntry) -> Ordering {
+		self.filename.cmp(&other.filename).then(self.source.cmp(&other.source))
+	}
+}
+
+impl SortedManifest {
+	/// Read the next checksum from the given source onto the heap.
+	fn refill(&mut self, index: usize) -> io::Result<()> {
+		let next = match self.sources[index] {
+			Source::Chunk { ref mut reader, .. } => {
+				let mut line = String::new();
+				if reader.read_line(&mut line)? == 0 {
+					None
+				} else {
+					// Lines were written by `format_checksum_line`, so they
+					// always parse.
+					parse_checksum_line(line.trim_end_matches('\n'))
+				}
+			},
+			Source::Memory(ref mut entries) => entries.next(),
+		};
+		if let Some((filename, checksum)) = next {
+			self.heap.push(Reverse(HeapEntry { filename, source: index, checksum }));
+		}
+		Ok(())
+	}
+
+	/// Take the next checksum, returning `None` once there are no more.
+	fn take(&mut self) -> io::Result<Option<(String, String)>> {
+		let mut entry = match self.heap.pop() {
+			Some(Reverse(entry)) => entry,
+			None => return Ok(None)
+		};
+		self.refill(entry.source)?;
+		// Skip all but the last of a repeated filename.
+		while self.heap.peek().is_some_and(|next| next.0.filename == entry.filename) {
+			let Reverse(next) = self.heap.pop().unwrap();
+			self.refill(next.source)?;
+			entry = next;
+		}
+		Ok(Some((entry.filename, entry.checksum)))
+	}
+}
+
+impl Iterator for SortedManifest {
+	type Item = io::Result<(String, String)>;
+
+	fn next(&mut self) -> Option<io::Result<(String, String)>> {
+		match self.take() {
+			Ok(Some(entry)) => Some(Ok(entry)),
+			Ok(None) => None,
+			Err(e) => Some(Err(e)),
+		}
+	}
+}
+
+/// Walk sorted new and old checksums together, passing each new filename and
+/// checksum to `each`, along with whether the checksum is absent from or
+/// different to the old checksums, and whether it's absent. If `each` returns
+/// an error, the walk stops and the error is returned.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the checksums can't be read back from temporary files.
+pub fn compare_sorted<F>(new_checksums: SortedManifest, old_checksums: Option<SortedManifest>, mut each: F)
+		-> Result<(), BackupError>
+		where F: FnMut(String, String, bool, bool) -> Result<(), BackupError> {
+	let read_error = |e: io::Error| BackupError::OtherError(
+		format!("Error reading sorted checksums: {}", e));
+	let mut old_checksums = old_checksums.map(|old| old.peekable());
+	for entry in new_checksums {
+		let (filename, checksum) = entry.map_err(&read_error)?;
+		let mut changed = true;
+		let mut added = true;
+		if let Some(ref mut old_checksums) = old_checksums {
+			loop {
+				let ordering = match old_checksums.peek() {
+					Some(&Ok((ref old_filename, ref old_checksum))) => {
+						let ordering = old_filename.cmp(&filename);
+						if ordering == Ordering::Equal {
+							changed = *old_checksum != checksum;
+							added = false;
+						}
+						ordering
+					},
+					Some(&Err(_)) => {
+						// Take the error to return it.
+						return Err(read_error(old_checksums.next().unwrap().unwrap_err()));
+					},
+					None => break
+				};
+				if ordering == Ordering::Greater {
+					break
+				}
+				old_checksums.next();
+				if ordering == Ordering::Equal {
+					break
+				}
+			}
+		}
+		if changed {
+			trace!("Mismatched hashes, archiving: {}\tnew: {}", filename, checksum);
+		}
+		each(filename, checksum, changed, added)?;
+	}
+	Ok(())
+}
+
+/// The path of a temporary file, which is removed when this is dropped.
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+	fn drop(&mut self) {
+		if let Err(e) = fs::remove_file(&self.0) {
+			warn!("Couldn't remove temporary file {}: {}", self.0.display(), e);
+		}
+	}
+}
+
+/// Create a new, empty temporary file, open for reading and writing.
+fn create_temp_file() -> io::Result<(File, TempPath)> {
+	static COUNTER: AtomicUsize = AtomicUsize::new(0);
+	let path = env::temp_dir().join(format!("backup-{}-{}.tmp",
+		process::id(), COUNTER.fetch_add(1, atomic::Ordering::SeqCst)));
+	let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+	Ok((file, TempPath(path)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sorted(sorter: ManifestSorter) -> Vec<(String, String)> {
+		sorter.finish().unwrap().map(Result::unwrap).collect()
+	}
+
+	#[test]
+	fn sorts_in_memory() {
+		let mut sorter = ManifestSorter::new();
+		for name in &["b", "c", "a"] {
+			sorter.push(name.to_string(), format!("{}-sum", name)).unwrap();
+		}
+		assert!(sorter.chunks.is_empty());
+		assert_eq!(sorted(sorter), vec![
+			("a".to_string(), "a-sum".to_string()),
+			("b".to_string(), "b-sum".to_string()),
+			("c".to_string(), "c-sum".to_string())]);
+	}
+
+	#[test]
+	fn memory_stays_bounded() {
+		const FILES: usize = 100_000;
+		const CHUNK: usize = 16 << 10;
+		let mut sorter = ManifestSorter::with_chunk_bytes(CHUNK);
+		let mut most_entries = 0;
+		// Add every file in a scrambled order, then every tenth file again
+		// with a new checksum, which should win.
+		for i in 0 .. FILES {
+			let file = i * 7919 % FILES;
+			sorter.push(format!("dir/file {:06}", file), format!("{:040}", file)).unwrap();
+			most_entries = most_entries.max(sorter.entries.len());
+		}
+		for file in (0 .. FILES).step_by(10) {
+			sorter.push(format!("dir/file {:06}", file), "changed".to_string()).unwrap();
+			most_entries = most_entries.max(sorter.entries.len());
+		}
+		// Only a chunk's worth is ever held in memory.
+		assert!(most_entries <= CHUNK / ENTRY_OVERHEAD + 1, "held {} entries", most_entries);
+		assert!(sorter.chunks.len() > 100);
+
+		let paths: Vec<PathBuf> = sorter.chunks.iter().map(|c| (c.1).0.clone()).collect();
+		let mut count = 0;
+		for (i, entry) in sorter.finish().unwrap().enumerate() {
+			let (filename, checksum) = entry.unwrap();
+			assert_eq!(filename, format!("dir/file {:06}", i));
+			if i % 10 == 0 {
+				assert_eq!(checksum, "changed");
+			} else {
+				assert_eq!(checksum, format!("{:040}", i));
+			}
+			count += 1;
+		}
+		assert_eq!(count, FILES);
+		// The temporary files are removed once the checksums are read.
+		assert!(paths.iter().all(|path| !path.exists()));
+	}
+
+	#[test]
+	fn awkward_names_survive_spilling() {
+		let names = ["tab\there", "new\nline", "back\\slash", "#hash", " space", "cr\r"];
+		let mut sorter = ManifestSorter::with_chunk_bytes(1);
+		for name in &names {
+			sorter.push(name.to_string(), "sum".to_string()).unwrap();
+		}
+		assert_eq!(sorter.chunks.len(), names.len());
+		let mut expected: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+		expected.sort();
+		let filenames: Vec<String> = sorted(sorter).into_iter().map(|e| e.0).collect();
+		assert_eq!(filenames, expected);
+	}
+
+	#[test]
+	fn compares_sorted_checksums() {
+		let manifest = |entries: &[(&str, &str)]| {
+			let mut sorter = ManifestSorter::with_chunk_bytes(100);
+			for &(name, sum) in entries {
+				sorter.push(name.to_string(), sum.to_string()).unwrap();
+			}
+			sorter.finish().unwrap()
+		};
+		let new = manifest(&[("same", "1"), ("changed", "2"), ("added", "3")]);
+		let old = manifest(&[("same", "1"), ("changed", "1"), ("deleted", "1")]);
+		let mut seen = Vec::new();
+		compare_sorted(new, Some(old), |name, _, changed, added| {
+			seen.push((name, changed, added));
+			Ok(())
+		}).unwrap();
+		assert_eq!(seen, vec![
+			("added".to_string(), true, true),
+			("changed".to_string(), true, false),
+			("same".to_string(), false, false)]);
+	}
+}
@@ -1,176 +1,4404 @@
-use crypto::digest::Digest;
-use crypto::sha1::Sha1;
-use flate2::Compression;
-use flate2::write::GzEncoder;
-use std::collections::HashMap;
+use log::LogLevel;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::path::PathBuf;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::cell::Cell;
+use std::mem;
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::process;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tar::Builder;
-use walkdir::WalkDir;
+use walkdir::{WalkDir, WalkDirIterator};
 
-use MainError;
+use BackupError;
+use archive;
+use archive::TarFormat;
+use cancel;
+use compression::{Compression, Decoder, Encoder};
+use hash::{HashAlgorithm, Hasher};
+use manifest::{self, ManifestSorter, SortedManifest};
+use io::TrackingReader;
+use progress::{human_bytes, human_duration, FileProgress, Progress, ThroughputMeter};
+use stats::{Phase, Skip, Stats};
+use throttle::Throttle;
+use unicode_normalization::UnicodeNormalization;
+use zip::{self, CompressionMethod, DateTime, ZipArchive, ZipWriter};
+use zip::write::SimpleFileOptions;
+
+/// Open a checksum file for reading, or standard input if `spec` is `-`.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the file can't be opened.
+pub fn open_checksum_input(spec: &str) -> Result<Box<dyn Read>, BackupError> {
+	if spec == "-" {
+		return Ok(Box::new(io::stdin()));
+	}
+	match File::open(spec) {
+		Ok(file) => Ok(Box::new(file)),
+		Err(e) => Err(BackupError::OtherError(format!("Couldn't open checksums file: {}", e)))
+	}
+}
+
+/// Read a list of sources from the file `spec`, or standard input if it's
+/// `-`, one per line, or with `null`, separated by NUL characters (as written
+/// by `find -print0`, so they may contain newlines). Empty and repeated
+/// entries are ignored, and entries which aren't valid UTF-8 are skipped with
+/// a warning.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the file can't be opened or read.
+pub fn read_file_list(spec: &str, null: bool) -> Result<Vec<String>, BackupError> {
+	let input : Box<dyn Read> = if spec == "-" {
+		Box::new(io::stdin())
+	} else {
+		Box::new(File::open(spec).map_err(|e| BackupError::OtherError(
+			format!("Couldn't open file list {}: {}", spec, e)))?)
+	};
+	let mut seen = HashSet::new();
+	let mut sources = Vec::new();
+	for entry in BufReader::new(input).split(if null { b'\0' } else { b'\n' }) {
+		let entry = entry.map_err(|e| BackupError::OtherError(
+			format!("Error reading file list {}: {}", spec, e)))?;
+		let source = match String::from_utf8(entry) {
+			Ok(source) => source,
+			Err(e) => {
+				warn!("Skipping {} in file list {} (filename is not valid UTF-8)",
+					String::from_utf8_lossy(e.as_bytes()), spec);
+				continue
+			}
+		};
+		if !source.is_empty() && seen.insert(source.clone()) {
+			sources.push(source);
+		}
+	}
+	Ok(sources)
+}
+
+/// Create a checksum file for writing, or write to standard output if `spec`
+/// is `-`. The file is written under its `partial_path`, and must be moved
+/// into place once complete (see `commit_checksum_files`).
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the file can't be created.
+pub fn open_checksum_output(spec: &str) -> Result<Box<dyn Write>, BackupError> {
+	if spec == "-" {
+		return Ok(Box::new(io::stdout()));
+	}
+	match File::create(partial_path(spec)) {
+		Ok(file) => Ok(Box::new(file)),
+		Err(e) => Err(BackupError::OtherError(
+			format!("Error creating checksum file {}: {}", spec, e)))
+	}
+}
+
+/// The name an output file (an archive or checksum file) is written under
+/// until it's complete, so that a run which fails or is interrupted never
+/// leaves a truncated file under the real name, or damages a previous file
+/// there.
+pub fn partial_path(path: &str) -> String {
+	format!("{}.partial", path)
+}
+
+/// Check whether `path` is a device (e.g. a tape drive) or named pipe, rather
+/// than a regular file, returning which kind of file it is.
+///
+/// Archives are written to such destinations directly, rather than to a
+/// `partial_path` moved into place once complete (see `ArchiveOutput`).
+#[cfg(unix)]
+pub fn special_destination(path: &str) -> Option<&'static str> {
+	use std::os::unix::fs::FileTypeExt;
+	let file_type = match fs::metadata(path) {
+		Ok(meta) => meta.file_type(),
+		Err(_) => return None,
+	};
+	if file_type.is_char_device() {
+		Some("character device")
+	} else if file_type.is_block_device() {
+		Some("block device")
+	} else if file_type.is_fifo() {
+		Some("named pipe")
+	} else {
+		None
+	}
+}
+
+#[cfg(not(unix))]
+pub fn special_destination(_path: &str) -> Option<&'static str> {
+	None
+}
+
+/// Move a completed output file from its `partial_path` into place,
+/// replacing any previous file.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the file can't be moved.
+pub fn commit_output(path: &str) -> Result<(), BackupError> {
+	let partial = partial_path(path);
+	fs::rename(&partial, path).map_err(|e| BackupError::OtherError(
+		format!("Error moving {} into place as {}: {}", partial, path, e)))
+}
+
+/// Move completed checksum files (see `open_checksum_output`) into place.
+/// Standard output (`-`) needs nothing done.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if a file can't be moved.
+pub fn commit_checksum_files(fnames: &[String]) -> Result<(), BackupError> {
+	for fname in fnames.iter().filter(|fname| *fname != "-") {
+		commit_output(fname)?;
+	}
+	Ok(())
+}
+
+/// Remove the partial output file for `path`, if there is one, as left by a
+/// run which failed or was interrupted before completing it.
+pub fn remove_partial(path: &str) {
+	let partial = partial_path(path);
+	match fs::remove_file(&partial) {
+		Ok(()) => info!("Removed partial output {}", partial),
+		Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+		Err(e) => warn!("Couldn't remove partial output {}: {}", partial, e)
+	}
+}
+
+/// Describe an error writing the archive to `destination`, while archiving
+/// the file `fname`, if given.
+///
+/// If the destination's filesystem is full, the message says how much of the
+/// archive (including any earlier parts) had been written, and what to do
+/// about it. A device which is full (e.g. a tape which has reached its end)
+/// is reported as such.
+pub fn archive_write_error(destination: &str, fname: Option<&str>, e: io::Error) -> BackupError {
+	let archiving = fname.map_or(String::new(), |fname| format!(" while archiving {}", fname));
+	if e.kind() != io::ErrorKind::StorageFull {
+		return BackupError::OtherError(
+			format!("Error writing to target file {}{}: {}", destination, archiving, e))
+	}
+	if let Some(kind) = special_destination(destination) {
+		return BackupError::OtherError(format!(
+			"Ran out of space on {} {}{} (e.g. at the end of a tape); the archive there is incomplete",
+			kind, destination, archiving))
+	}
+	let parts = (1 ..).map(|number| part_path(destination, number))
+		.take_while(|part| Path::new(&partial_path(part)).exists());
+	let written : u64 = Some(destination.to_string()).into_iter().chain(parts)
+		.filter_map(|part| fs::metadata(partial_path(&part)).ok())
+		.map(|meta| meta.len())
+		.sum();
+	BackupError::OtherError(format!(
+		concat!("Ran out of space writing to target file {}{}, after writing {} of the archive. ",
+			"Free up space on the destination or back up somewhere else; --check-disk-space ",
+			"can check for enough space before starting."),
+		destination, archiving, human_bytes(written)))
+}
+
+/// A writer which writes everything written to it to each of several named
+/// writers, e.g. to save the same checksums to several files. Errors are
+/// prefixed with the name of the writer which failed.
+pub struct MultiWriter<W: Write> {
+	writers: Vec<(String, W)>,
+}
+
+impl<W: Write> MultiWriter<W> {
+	/// Create a writer writing to each of `writers`, in order.
+	pub fn new(writers: Vec<(String, W)>) -> MultiWriter<W> {
+		MultiWriter { writers }
+	}
+}
+
+impl<W: Write> Write for MultiWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		// Each writer takes the whole buffer, so that they all stay in step.
+		for &mut (ref name, ref mut writer) in &mut self.writers {
+			writer.write_all(buf).map_err(|e| named_error(name, e))?;
+		}
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		for &mut (ref name, ref mut writer) in &mut self.writers {
+			writer.flush().map_err(|e| named_error(name, e))?;
+		}
+		Ok(())
+	}
+}
+
+/// Prefix an error with the name of what it happened to.
+fn named_error(name: &str, e: io::Error) -> io::Error {
+	io::Error::new(e.kind(), format!("{}: {}", name, e))
+}
 
 /// Load checksums from a given file.
 ///
-/// The file referenced by `fname` is opened and read. Each line is treated as
-/// a tab-separated filename/checksum pair and inserted into a map from
-/// filenames to sums. If a given file is repeated, the last iteration wins.
-/// Lines which cannot be read or parsed will be ignored (however, the parser
-/// is extremely lenient; this is essentially only lines with no whitespace).
+/// The file referenced by `fname` (or standard input, if it's `-`) is opened
+/// and read. Each line is treated as
+/// a checksum/filename pair (in the format described for `parse_checksum_line`)
+/// and inserted into a map from filenames to sums. Filenames are normalized
+/// with `normalization`. If a given file is repeated, the last iteration wins.
+/// Lines which cannot be read or parsed will be ignored.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if it experiences an I/O error.
+pub fn load_checksums(fname: &str, normalization: Normalization)
+		-> Result<HashMap<String, String>, BackupError> {
+	let checksums_file = open_checksum_input(fname)?;
+	Ok(read_checksums(BufReader::new(checksums_file), normalization))
+}
+
+/// Checksums loaded from an archive by `load_archived_checksums`, and the
+/// outcome of archiving recorded with them.
+pub type ArchivedChecksums = (HashMap<String, String>, ArchiveReport);
+
+/// Load the checksums embedded at the start of an archive (see
+/// `archive::append_checksums`), as `load_checksums` does from a checksum
+/// file, or `None` if the archive has none. For an archive split into parts
+/// (see `archive_parts`), they're in the first.
+///
+/// They're embedded before the files are archived, so they're corrected with
+/// the outcome of archiving recorded at the end of the archive (see
+/// `archive::append_outcome`), as the checksum file saved by the same run was:
+/// files which couldn't be archived are left out, and files checksummed again
+/// get their new checksums. That outcome is returned too (without a count of
+/// files archived). Reading it means reading the whole archive.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the archive can't be read.
+pub fn load_archived_checksums(archive_fname: &str, normalization: Normalization)
+		-> Result<Option<ArchivedChecksums>, BackupError> {
+	let parts = archive_parts(archive_fname);
+	let read_error = |fname: &str, e| BackupError::OtherError(
+		format!("Error reading archive {}: {}", fname, e));
+	let contents = match archive::read_checksums(open_archive(&parts[0])?)
+			.map_err(|e| read_error(&parts[0], e))? {
+		Some(contents) => contents,
+		None => return Ok(None)
+	};
+	let mut checksums = read_checksums(&contents[..], normalization);
+	let key = |name: String| normalization.apply(normalize_loaded_key(name));
+	let mut outcome = ArchiveReport::default();
+	for part in &parts {
+		let (failed, rechecksummed) = archive::read_outcome(open_archive(part)?)
+			.map_err(|e| read_error(part, e))?;
+		outcome.failed.extend(failed.into_iter().map(key));
+		outcome.rechecksummed.extend(rechecksummed.into_iter()
+			.map(|(name, checksum)| (key(name), checksum)));
+	}
+	if outcome.changes_checksums() {
+		debug!("{} files in {} couldn't be archived, and {} were checksummed again",
+			outcome.failed.len(), archive_fname, outcome.rechecksummed.len());
+		outcome.apply(&mut checksums);
+	}
+	Ok(Some((checksums, outcome)))
+}
+
+/// Load the checksums embedded at the start of an archive, as
+/// `load_archived_checksums` does, for use as the old checksums.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the archive can't be read or has no embedded checksums.
+pub fn load_checksums_from_archive(archive_path: &str, normalization: Normalization)
+		-> Result<HashMap<String, String>, BackupError> {
+	load_archived_checksums(archive_path, normalization)?
+		.map(|(checksums, _)| checksums)
+		.ok_or_else(|| BackupError::OtherError(format!(
+			"Archive {} has no embedded checksums (see --output-checksums-to-archive)",
+			archive_path)))
+}
+
+/// Read checksums in the format of a checksum file, as described for
+/// `load_checksums`.
+fn read_checksums<R: BufRead>(checksums_reader: R, normalization: Normalization)
+		-> HashMap<String, String> {
+	let mut checksums : HashMap<String, String> = HashMap::new();
+	for line in checksums_reader.lines() {
+		match line {
+			Ok(l) => {
+				let (filename, checksum) = match parse_checksum_line(&l) {
+					Some(f) => f,
+					None => continue
+				};
+				let filename = normalization.apply(normalize_loaded_key(filename));
+				log_fields!(LogLevel::Trace, {path: filename, checksum: checksum},
+					"Previous version checksum: {}\t{}", filename, checksum);
+				checksums.insert(filename, checksum);
+			},
+			Err(_) => continue
+		}
+	}
+	checksums.shrink_to_fit();
+	checksums
+}
+
+/// Merge checksum files, applying each in `incrementals` on top of `base`.
+///
+/// The result starts with the checksums in `base`; each later file's checksums
+/// are then added, replacing any for the same file. Each checksum file lists
+/// every file its run backed up, so files missing from a later file have been
+/// deleted (or couldn't be backed up) since, and are removed. This produces a
+/// combined checksum file suitable for use as the old checksums for the next
+/// run. Filenames are normalized with `normalization`, as by `load_checksums`.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if any of the files can't be read.
+pub fn merge_checksums(base: &str, incrementals: &[String], normalization: Normalization)
+		-> Result<HashMap<String, String>, BackupError> {
+	let mut merged = load_checksums(base, normalization)?;
+	for fname in incrementals {
+		let checksums = load_checksums(fname, normalization)?;
+		trace!("Merging {} checksums from {}", checksums.len(), fname);
+		let before = merged.len();
+		merged.retain(|key, _| checksums.contains_key(key));
+		trace!("Removing {} deleted files missing from {}", before - merged.len(), fname);
+		merged.extend(checksums);
+	}
+	Ok(merged)
+}
+
+/// Load checksums from a given file, as `load_checksums` does, but sorted by
+/// filename rather than in a map, using a bounded amount of memory (see
+/// `ManifestSorter`).
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the file can't be opened, or the checksums can't be sorted.
+pub fn load_checksums_sorted(fname: &str, normalization: Normalization)
+		-> Result<SortedManifest, BackupError> {
+	let checksums_file = open_checksum_input(fname)?;
+	let mut sorter = ManifestSorter::new();
+	for line in BufReader::new(checksums_file).lines() {
+		let l = match line {
+			Ok(l) => l,
+			Err(_) => continue
+		};
+		let (filename, checksum) = match parse_checksum_line(&l) {
+			Some(f) => f,
+			None => continue
+		};
+		let filename = normalization.apply(normalize_loaded_key(filename));
+		sorter.push(filename, checksum).map_err(sort_error)?;
+	}
+	sorter.finish().map_err(sort_error)
+}
+
+/// Describe an error sorting checksums.
+fn sort_error(e: io::Error) -> BackupError {
+	BackupError::OtherError(format!("Error sorting checksums in temporary files: {}", e))
+}
+
+/// Unwrap an `Option`, returning `None` from the enclosing function if it is
+/// `None`.
+macro_rules! try_opt {
+	($e:expr) => (match $e { Some(v) => v, None => return None })
+}
+
+/// Normalize a filename loaded from a checksum file.
+///
+/// Filenames are always written with forward slashes, but older versions wrote
+/// native separators; on Windows (where backslashes can't appear in filenames)
+/// these are converted. Elsewhere, backslashes are left alone, since they may
+/// legitimately be part of a filename, so checksum files written by older
+/// versions on Windows won't match on other platforms.
+///
+/// Filenames are also tidied with `normalize_key`, so they match those
+/// computed from sources however they were spelt.
+#[cfg(windows)]
+fn normalize_loaded_key(key: String) -> String {
+	normalize_key(&key.replace('\\', "/"))
+}
+
+#[cfg(not(windows))]
+fn normalize_loaded_key(key: String) -> String {
+	normalize_key(&key)
+}
+
+/// Tidy a filename, removing `.` components and duplicate or trailing slashes
+/// (so `./photos//a.jpg` becomes `photos/a.jpg`). A leading slash is kept.
+fn normalize_key(key: &str) -> String {
+	let parts : Vec<&str> = key.split('/')
+		.filter(|p| !p.is_empty() && *p != ".")
+		.collect();
+	if key.starts_with('/') {
+		format!("/{}", parts.join("/"))
+	} else {
+		parts.join("/")
+	}
+}
+
+/// The comment introducing a backup version name in a checksum file header.
+const VERSION_HEADER: &str = "# version: ";
+
+/// Read the backup version name recorded in a checksum file's header, if any
+/// (see `save_checksums`).
+///
+/// Returns `None` if the file can't be read or has no version recorded, or if
+/// `fname` is `-`: standard input can only be read once, by `load_checksums`.
+pub fn load_version(fname: &str) -> Option<String> {
+	if fname == "-" {
+		return None
+	}
+	let file = try_opt!(File::open(fname).ok());
+	read_version(BufReader::new(file))
+}
+
+/// Read the backup version name recorded in the header of the checksums
+/// embedded in an archive, if any (see `load_version`).
+///
+/// Returns `None` if the archive can't be read or has no embedded checksums.
+pub fn load_archived_version(archive_fname: &str) -> Option<String> {
+	let archive = try_opt!(open_archive(&archive_parts(archive_fname)[0]).ok());
+	let contents = try_opt!(archive::read_checksums(archive).ok().and_then(|c| c));
+	read_version(&contents[..])
+}
+
+/// Read the backup version name from the header of a checksum file.
+fn read_version<R: BufRead>(reader: R) -> Option<String> {
+	for line in reader.lines() {
+		let line = try_opt!(line.ok());
+		if !line.starts_with('#') {
+			break
+		}
+		if let Some(version) = line.strip_prefix(VERSION_HEADER) {
+			return Some(version.to_string());
+		}
+	}
+	None
+}
+
+/// Format a time as an RFC 3339 timestamp in the local time zone, to the
+/// second (e.g. `2017-03-14T11:09:26-04:00`).
+///
+/// The local time zone is only available on Unix; elsewhere, this is the same
+/// as `format_timestamp`.
+#[cfg(unix)]
+pub fn format_local_timestamp(time: SystemTime) -> String {
+	use libc;
+	let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as libc::time_t;
+	let mut tm : libc::tm = unsafe { mem::zeroed() };
+	if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+		return format_timestamp(time);
+	}
+	let offset = tm.tm_gmtoff / 60;
+	format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+		tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec,
+		if offset < 0 { '-' } else { '+' }, offset.abs() / 60, offset.abs() % 60)
+}
+
+#[cfg(not(unix))]
+pub fn format_local_timestamp(time: SystemTime) -> String {
+	format_timestamp(time)
+}
+
+/// Format a time as a compact ISO 8601 UTC timestamp, for filenames (e.g.
+/// `20170314T150926Z`). Colons would make tar take such a filename for a
+/// remote file.
+pub fn file_timestamp(time: SystemTime) -> String {
+	format_timestamp(time).replace("-", "").replace(":", "")
+}
+
+/// Format a time as an RFC 3339 UTC timestamp, to the second (e.g.
+/// `2017-03-14T15:09:26Z`).
+pub fn format_timestamp(time: SystemTime) -> String {
+	let (year, month, day, secs) = civil_date(time);
+	format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+		year, month, day, secs / 3600 % 24, secs / 60 % 60, secs % 60)
+}
+
+/// Find the UTC date of a time, as its year, month and day, with the seconds
+/// since the epoch (from which to find the time of day).
+fn civil_date(time: SystemTime) -> (u64, u64, u64, u64) {
+	let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+	// Convert days since the epoch to a civil date; see
+	// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+	let days = secs / 86400 + 719468;
+	let era = days / 146097;
+	let day_of_era = days % 146097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month_index = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+	let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+	(year, month, day, secs)
+}
+
+/// Work out the version name following a given one, by incrementing the
+/// number at its end (e.g. `v1.2.9` is followed by `v1.2.10`).
+///
+/// Returns `None` if the version name doesn't end in a number.
+pub fn next_version(version: &str) -> Option<String> {
+	let prefix = version.trim_end_matches(|c: char| c.is_ascii_digit());
+	let number : u64 = try_opt!(version[prefix.len() ..].parse().ok());
+	Some(format!("{}{}", prefix, number + 1))
+}
+
+/// Parse a line of a checksum file into a filename and checksum.
+///
+/// Lines consist of a checksum, a separator, and the filename, which runs to
+/// the end of the line (so may contain spaces and tabs). The separator is
+/// either a single tab (as written by `save_checksums`) or, as written by
+/// `sha1sum` and friends, two spaces or a space and an asterisk. As with GNU
+/// coreutils, a line starting with a backslash has backslashes, newlines, and
+/// carriage returns in its filename escaped as `\\`, `\n`, and `\r`.
+///
+/// Returns `None` if the line is blank, is a comment (starts with `#`), has no
+/// filename, or contains an invalid escape.
+pub fn parse_checksum_line(line: &str) -> Option<(String, String)> {
+	if line.starts_with('#') {
+		return None;
+	}
+	let (escaped, line) = match line.strip_prefix('\\') {
+		Some(line) => (true, line),
+		None => (false, line)
+	};
+	let sep = match line.find(char::is_whitespace) {
+		Some(i) if i > 0 => i,
+		_ => return None
+	};
+	let checksum = &line[.. sep];
+	let rest = &line[sep ..];
+	let filename = if rest.starts_with("  ") || rest.starts_with(" *") {
+		&rest[2 ..]
+	} else {
+		let mut chars = rest.chars();
+		chars.next();
+		chars.as_str()
+	};
+	if filename.is_empty() {
+		return None;
+	}
+	if !escaped {
+		return Some((filename.to_string(), checksum.to_string()));
+	}
+	let mut unescaped = String::with_capacity(filename.len());
+	let mut chars = filename.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			unescaped.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('\\') => unescaped.push('\\'),
+			Some('n') => unescaped.push('\n'),
+			Some('r') => unescaped.push('\r'),
+			_ => return None
+		}
+	}
+	Some((unescaped, checksum.to_string()))
+}
+
+/// Format a checksum and filename as a line of a checksum file, escaping the
+/// filename if necessary so that `parse_checksum_line` reads it back intact.
+pub fn format_checksum_line(filename: &str, checksum: &str) -> String {
+	if filename.contains(['\\', '\n', '\r']) {
+		let escaped = filename
+			.replace('\\', "\\\\")
+			.replace('\n', "\\n")
+			.replace('\r', "\\r");
+		format!("\\{}\t{}\n", checksum, escaped)
+	} else {
+		format!("{}\t{}\n", checksum, filename)
+	}
+}
+
+/// Checksum all the files in a given directory.
+///
+/// All the entries in `sources` are read. If they are directories, they are
+/// walked fully (subject to `options`), and all the files they contain are
+/// checksummed; if they are files, they are themselves checksummed. The
+/// filenames, relative to their root in
+/// `roots`, and checksums are inserted into a map from filenames to
+/// sums. Files which cannot be opened are skipped. Checksums always cover the
+/// full logical contents of files (including any holes in sparse files).
+///
+/// A fresh hasher is obtained from `make_hasher` for each file, so callers can
+/// supply any implementation of `Hasher`. Files are checksummed on
+/// `options.jobs` threads.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if a file cannot be read, unless `options.ignore_errors` is set, in
+/// which case the file is skipped and listed in the result's `failed_files`.
+///
+/// # Panics
+///
+/// Probably, if you have it walk something weird which is neither a directory
+/// nor a normal file.
+pub fn checksum_directory<F>(
+		sources: &[String],
+		roots: &SourceRoots,
+		options: &WalkOptions,
+		make_hasher: F)
+		-> Result<ChecksumResult, BackupError>
+		where F: Fn() -> Box<dyn Hasher> + Sync {
+	options.stats.phase_started(Phase::Checksum);
+	let started = Instant::now();
+	let mut checksums : HashMap<String, String> = HashMap::new();
+	let failed_files = walk_checksums(sources, roots, options, make_hasher, |_, key, value| {
+		checksums.insert(key, value);
+		Ok(())
+	})?;
+	options.stats.elapsed(Phase::Checksum, started.elapsed());
+	checksums.shrink_to_fit();
+	Ok(ChecksumResult { checksums, failed_files })
+}
+
+/// The results of checksumming the files in a directory.
+#[derive(Debug)]
+pub struct ChecksumResult {
+	/// A map from filenames to checksums.
+	pub checksums: HashMap<String, String>,
+	/// Files which couldn't be read, and so have no checksum (see
+	/// `WalkOptions::ignore_errors`).
+	pub failed_files: Vec<String>,
+}
+
+/// Checksum all the files in a given directory, as `checksum_directory` does,
+/// but returning the checksums sorted by filename rather than in a map, using
+/// a bounded amount of memory (see `ManifestSorter`), along with any files
+/// which couldn't be read.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if a file cannot be read (unless `options.ignore_errors` is set), or
+/// the checksums can't be sorted.
+pub fn checksum_directory_sorted<F>(
+		sources: &[String],
+		roots: &SourceRoots,
+		options: &WalkOptions,
+		make_hasher: F)
+		-> Result<(SortedManifest, Vec<String>), BackupError>
+		where F: Fn() -> Box<dyn Hasher> + Sync {
+	options.stats.phase_started(Phase::Checksum);
+	let started = Instant::now();
+	let mut sorter = ManifestSorter::new();
+	let failed_files = walk_checksums(sources, roots, options, make_hasher, |_, key, value| {
+		sorter.push(key, value).map_err(sort_error)
+	})?;
+	options.stats.elapsed(Phase::Checksum, started.elapsed());
+	let checksums = sorter.finish().map_err(sort_error)?;
+	Ok((checksums, failed_files))
+}
+
+/// Checksum all the files in a given directory, archiving changed files as
+/// they are found.
+///
+/// This behaves like `checksum_directory`, except that each file whose
+/// checksum is absent from or different to `old_checksums` is appended to
+/// `archive` (as set by `archive_options`) as soon as it has been
+/// checksummed, rather than in a second pass afterwards. Changed files which
+/// can't then be opened are left out of the archive and the returned checksums (see
+/// `write_archive`). This means each
+/// changed file is read twice in quick succession (so usually from cache), the
+/// source tree is only walked once, and there is no window between
+/// checksumming and archiving in which files can be deleted. The returned map
+/// contains the checksums of all files, changed or not. If
+/// `archive_options.backup_dir` is given, unchanged files are copied into it
+/// (see `ArchiveOptions`).
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if a changed file cannot be written to the archive, an unchanged
+/// file cannot be copied, or (unless `options.ignore_errors` is set) a file
+/// cannot be read.
+pub fn stream_checksums<F>(
+		sources: &[String],
+		roots: &SourceRoots,
+		options: &WalkOptions,
+		make_hasher: F,
+		old_checksums: &HashMap<String, String>,
+		archive: &mut ArchiveWriter,
+		archive_options: &ArchiveOptions)
+		-> Result<ChecksumResult, BackupError>
+		where F: Fn() -> Box<dyn Hasher> + Sync {
+	options.stats.phase_started(Phase::Checksum);
+	options.stats.phase_started(Phase::Archive);
+	let started = Instant::now();
+	let mut checksums : HashMap<String, String> = HashMap::new();
+	let failed_files = walk_checksums(sources, roots, options, make_hasher, |name, key, mut value| {
+		if is_changed(&key, &value, old_checksums) {
+			options.stats.file_started(Phase::Archive, name);
+			let appended = append_compressed(archive, roots, name, None, archive_options)?;
+			record_appended(&options.stats, roots, name, &appended);
+			match appended {
+				Appended::Archived(Some(checksum)) => value = checksum,
+				Appended::Archived(None) => (),
+				_ => return Ok(())
+			}
+		} else if let Some(dir) = archive_options.copy_dir() {
+			copy_unchanged(roots, dir, name)?;
+		}
+		checksums.insert(key, value);
+		Ok(())
+	})?;
+	// Archiving overlaps checksumming, so both take the whole walk.
+	options.stats.elapsed(Phase::Checksum, started.elapsed());
+	options.stats.elapsed(Phase::Archive, started.elapsed());
+	checksums.shrink_to_fit();
+	Ok(ChecksumResult { checksums, failed_files })
+}
+
+/// Resolve the source root to its canonical path, following any symlinks.
+///
+/// Walked paths are made relative to the source root to form filenames, so
+/// the root should be given in the same form as the paths beneath it; with a
+/// canonical root, it doesn't matter how the root was reached.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the path cannot be resolved (e.g. if it doesn't exist).
+pub fn canonicalize_root(source_root: &Path) -> Result<PathBuf, BackupError> {
+	fs::canonicalize(source_root).map_err(|e| BackupError::OtherError(format!(
+			"Couldn't canonicalize source root {}: {}", source_root.display(), e)))
+}
+
+/// Convert a path to a Windows extended-length (`\\?\`) path.
+///
+/// Extended-length paths are not subject to the 260-character `MAX_PATH`
+/// limit, so walking and opening files beneath such a root works however deep
+/// the tree goes. Since walked paths are made relative to the root before use
+/// as filenames, the prefix never reaches checksum files or archives. On other
+/// platforms, the path is returned unchanged.
+///
+/// # Errors
+///
+/// On Windows, this function will return any I/O error encountered resolving
+/// the path (e.g. if it doesn't exist).
+#[cfg(windows)]
+pub fn extended_length_path(path: &Path) -> io::Result<PathBuf> {
+	// Canonical paths on Windows are always in extended-length form.
+	fs::canonicalize(path)
+}
+
+#[cfg(not(windows))]
+pub fn extended_length_path(path: &Path) -> io::Result<PathBuf> {
+	Ok(path.to_path_buf())
+}
+
+/// Convert a path to a filename as used in checksum files and archives.
+///
+/// Components are always separated by forward slashes, whatever the platform.
+/// This is normally applied to paths relative to the source root, but may see
+/// absolute paths, in which case Windows drive letters are kept (`C:/...`),
+/// UNC prefixes become `//server/share/...`, and extended-length (`\\?\`)
+/// prefixes are dropped. Returns `None` if the path is not valid UTF-8.
+fn path_to_key(path: &Path) -> Option<String> {
+	let mut key = String::new();
+	for component in path.components() {
+		let part = match component {
+			Component::Prefix(prefix) => {
+				key.push_str(&match prefix.kind() {
+					Prefix::Disk(d) | Prefix::VerbatimDisk(d) => format!("{}:", d as char),
+					Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) =>
+						format!("//{}/{}", try_opt!(server.to_str()), try_opt!(share.to_str())),
+					Prefix::DeviceNS(device) => format!("//./{}", try_opt!(device.to_str())),
+					Prefix::Verbatim(p) => try_opt!(p.to_str()).to_string(),
+				});
+				continue
+			},
+			Component::RootDir => {
+				key.push('/');
+				continue
+			},
+			Component::CurDir => continue,
+			Component::ParentDir => "..",
+			Component::Normal(p) => try_opt!(p.to_str()),
+		};
+		if !key.is_empty() && !key.ends_with('/') {
+			key.push('/');
+		}
+		key.push_str(part);
+	}
+	Some(key)
+}
+
+/// Get the filename recorded for a file outside the source root from its
+/// absolute path: the path, less any leading slash, beneath `OUTSIDE_ROOT`.
+/// UNC paths (`\\server\share\...`) are recorded beneath `UNC`, as in
+/// Windows' extended-length form, so they can be told from the others.
+fn outside_root_key(path: &Path) -> Option<String> {
+	let key = path_to_key(path)?;
+	Some(match key.strip_prefix("//") {
+		Some(unc) => format!("{}/UNC/{}", OUTSIDE_ROOT, unc),
+		None => format!("{}/{}", OUTSIDE_ROOT, key.trim_start_matches('/')),
+	})
+}
+
+/// Convert a filename recorded beneath `OUTSIDE_ROOT` (less that prefix) back
+/// to the absolute path it came from (see `outside_root_key`).
+#[cfg(windows)]
+fn outside_root_path(rest: &str) -> PathBuf {
+	if let Some(unc) = rest.strip_prefix("UNC/") {
+		PathBuf::from(format!("\\\\{}", unc.replace('/', "\\")))
+	} else if rest.find(':') == Some(1) {
+		// Keys for other Windows paths start with a drive letter.
+		PathBuf::from(rest)
+	} else {
+		PathBuf::from(format!("/{}", rest))
+	}
+}
+
+#[cfg(not(windows))]
+fn outside_root_path(rest: &str) -> PathBuf {
+	PathBuf::from(format!("/{}", rest))
+}
+
+/// Convert a filename from a checksum file to a path beneath a directory,
+/// using the native separator.
+fn key_to_path(dir: &Path, key: &str) -> PathBuf {
+	let mut path = dir.to_path_buf();
+	for part in key.split('/').filter(|p| !p.is_empty()) {
+		path.push(part);
+	}
+	path
+}
+
+/// The directory under which files from sources outside the source root are
+/// recorded, when allowed.
+///
+/// Such a file's filename is its absolute path, less any leading slash, beneath
+/// this directory (e.g. `/tmp/other/file` becomes `@outside-root/tmp/other/file`;
+/// see `outside_root_key`), so it never becomes an absolute entry name in the
+/// archive.
+pub const OUTSIDE_ROOT: &str = "@outside-root";
+
+/// The directories from which files are backed up.
+///
+/// Files beneath the main source root are recorded under their paths relative
+/// to it. Further roots can be mapped to prefixes: their files are recorded
+/// under the prefix followed by their path relative to the root, and sources
+/// starting with the prefix are looked for beneath the root. A mapped prefix
+/// takes precedence over the same path beneath the main source root.
+///
+/// Files are archived under their recorded filenames, unless set otherwise
+/// with `set_archive_paths`.
+#[derive(Clone,Debug)]
+pub struct SourceRoots {
+	root: PathBuf,
+	mapped: Vec<(String, PathBuf)>,
+	absolute_paths: bool,
+	strip_components: usize,
+}
+
+impl SourceRoots {
+	/// Create a set of roots containing just the main source root.
+	pub fn new(root: PathBuf) -> SourceRoots {
+		SourceRoots { root, mapped: Vec::new(), absolute_paths: false, strip_components: 0 }
+	}
+
+	/// Set how files are named in the archive: under their absolute paths
+	/// (less any leading slash, as tar does) if `absolute_paths`, or otherwise
+	/// their recorded filenames, either way with the first `strip_components`
+	/// components removed (as `tar --strip-components` does).
+	pub fn set_archive_paths(&mut self, absolute_paths: bool, strip_components: usize) {
+		self.absolute_paths = absolute_paths;
+		self.strip_components = strip_components;
+	}
+
+	/// Get the main source root.
+	pub fn root(&self) -> &PathBuf {
+		&self.root
+	}
+
+	/// Get all the roots, starting with the main source root.
+	pub fn paths(&self) -> Vec<&PathBuf> {
+		let mut paths = vec![&self.root];
+		paths.extend(self.mapped.iter().map(|(_, root)| root));
+		paths
+	}
+
+	/// Get the source naming a path beneath one of the roots, as it would be
+	/// given on the commandline, or `None` if the path isn't beneath any of
+	/// them (or isn't valid Unicode).
+	pub fn source_name(&self, path: &Path) -> Option<String> {
+		for (prefix, root) in &self.mapped {
+			if let Ok(rest) = path.strip_prefix(root) {
+				return path_to_key(rest).map(|rest| if rest.is_empty() {
+					prefix.clone()
+				} else {
+					format!("{}/{}", prefix, rest)
+				});
+			}
+		}
+		path.strip_prefix(&self.root).ok()
+			.and_then(path_to_key)
+			.map(|rest| if rest.is_empty() { ".".to_string() } else { rest })
+	}
+
+	/// Map a further root to a prefix.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` with a descriptive
+	/// message if the prefix is empty or reserved, or if it is the same as,
+	/// within, or contains another mapped prefix.
+	pub fn add_mapping(&mut self, prefix: &str, root: PathBuf) -> Result<(), BackupError> {
+		let prefix = normalize_key(prefix).trim_start_matches('/').to_string();
+		if prefix.is_empty() || prefix.split('/').any(|p| p == ".." || p == OUTSIDE_ROOT) {
+			return Err(BackupError::OtherError(format!("Invalid prefix for {}: {}",
+				root.display(), prefix)));
+		}
+		for (other, other_root) in &self.mapped {
+			if prefix == *other || prefix.starts_with(&format!("{}/", other))
+					|| other.starts_with(&format!("{}/", prefix)) {
+				return Err(BackupError::OtherError(format!(
+					"Prefixes for {} ({}) and {} ({}) collide",
+					other_root.display(), other, root.display(), prefix)));
+			}
+		}
+		self.mapped.push((prefix, root));
+		Ok(())
+	}
+
+	/// Split a filename (or source) into the root it lies beneath, the prefix
+	/// that root is mapped to (if any), and the rest of the filename.
+	fn split<'a>(&self, key: &'a str) -> (&PathBuf, Option<&str>, &'a str) {
+		for (prefix, root) in &self.mapped {
+			if key == prefix {
+				return (root, Some(prefix), "");
+			}
+			if key.starts_with(prefix.as_str()) && key[prefix.len() ..].starts_with('/') {
+				return (root, Some(prefix), &key[prefix.len() + 1 ..]);
+			}
+		}
+		(&self.root, None, key)
+	}
+
+	/// Convert a filename from a checksum file back to a path beneath the
+	/// appropriate root. Filenames beneath `OUTSIDE_ROOT` are converted back to
+	/// the absolute paths they came from.
+	fn path(&self, key: &str) -> PathBuf {
+		if let Some(rest) = key.strip_prefix(OUTSIDE_ROOT).and_then(|k| k.strip_prefix('/')) {
+			return outside_root_path(rest)
+		}
+		let (root, _, rest) = self.split(key);
+		key_to_path(root, rest)
+	}
+
+	/// Get the name to archive a file under, given its filename from a
+	/// checksum file (see `set_archive_paths`), or `None` if stripping
+	/// components leaves nothing of it.
+	fn archive_name(&self, key: &str) -> Option<String> {
+		let name = if self.absolute_paths {
+			path_to_key(&self.path(key)).unwrap_or_else(|| key.to_string())
+		} else {
+			key.to_string()
+		};
+		let parts : Vec<&str> = name.split('/')
+			.filter(|p| !p.is_empty())
+			.skip(self.strip_components)
+			.collect();
+		if parts.is_empty() {
+			None
+		} else {
+			Some(parts.join("/"))
+		}
+	}
+}
+
+/// Prepend a root's prefix, if it has one, to a filename relative to the root.
+fn join_key(prefix: Option<&str>, key: &str) -> String {
+	match prefix {
+		Some(prefix) if key.is_empty() => prefix.to_string(),
+		Some(prefix) => format!("{}/{}", prefix, key),
+		None => key.to_string(),
+	}
+}
+
+/// Make a path absolute (relative to the current directory) and remove any
+/// `.` and `..` components, without touching the filesystem.
+fn absolute_path(path: &Path) -> PathBuf {
+	let path = env::current_dir().map(|d| d.join(path)).unwrap_or_else(|_| path.to_path_buf());
+	let mut normalized = PathBuf::new();
+	for component in path.components() {
+		match component {
+			Component::CurDir => (),
+			Component::ParentDir => { normalized.pop(); },
+			c => normalized.push(c.as_os_str()),
+		}
+	}
+	normalized
+}
+
+/// Get the path to walk for a source, if it lies beneath its root.
+///
+/// Sources starting with a mapped prefix are resolved against the mapped root,
+/// and others against the main source root. The returned path is the root
+/// joined with the source's path relative to it, with any `.` and `..`
+/// components resolved, so that filenames derived from it are always relative
+/// paths within the root; the root and its prefix are returned alongside.
+/// Returns `None` if the source (e.g. an absolute path, or one with too many
+/// `..` components) is not beneath its root.
+fn source_path<'a>(roots: &'a SourceRoots, source: &str)
+		-> Option<(PathBuf, &'a PathBuf, Option<&'a str>)> {
+	let source = normalize_key(source);
+	let (root, prefix, rest) = roots.split(&source);
+	let absolute_root = absolute_path(root);
+	let absolute_source = absolute_path(&root.join(rest));
+	absolute_source.strip_prefix(&absolute_root).ok()
+		.map(|relative| (root.join(relative), root, prefix))
+}
+
+/// Check that the given sources exist and lie beneath their roots, returning
+/// those to walk: the `sources` and then the `listed` sources (e.g. read with
+/// `read_file_list`) which exist.
+///
+/// Sources which don't exist are skipped if `ignore_missing` is set, and listed
+/// sources which don't exist are always skipped, unless none of them exist.
+/// Sources skipped are logged, and recorded in `stats`.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` listing the absolute
+/// paths of any sources which don't exist (unless they're skipped), or
+/// naming the sources which are not beneath their roots unless
+/// `allow_outside_root` is set (in which case they are recorded beneath
+/// `OUTSIDE_ROOT`).
+pub fn check_sources(
+		sources: &[String],
+		listed: &[String],
+		roots: &SourceRoots,
+		allow_outside_root: bool,
+		ignore_missing: bool,
+		stats: &Stats)
+		-> Result<Vec<String>, BackupError> {
+	let source_root = roots.root();
+	let resolve = |source: &str| source_path(roots, source)
+		.map(|(path, _, _)| path)
+		.unwrap_or_else(|| absolute_path(&source_root.join(source)));
+	let exists = |source: &&String| fs::symlink_metadata(resolve(source)).is_ok();
+	let (found, missing) : (Vec<&String>, Vec<&String>) = sources.iter().partition(&exists);
+	if !missing.is_empty() && !ignore_missing {
+		let paths : Vec<String> = missing.iter().map(|s| resolve(s).display().to_string()).collect();
+		return Err(BackupError::OtherError(format!(
+			"Sources do not exist (use --ignore-missing-sources to skip them): {}", paths.join(", "))));
+	}
+	let (found_listed, missing_listed) : (Vec<&String>, Vec<&String>) = listed.iter().partition(&exists);
+	if found_listed.is_empty() && !missing_listed.is_empty() {
+		return Err(BackupError::OtherError(format!(
+			"None of the {} sources listed with --files-from exist (e.g. {})",
+			missing_listed.len(), resolve(missing_listed[0]).display())));
+	}
+	for source in missing.iter().chain(missing_listed.iter()) {
+		let path = resolve(source).display().to_string();
+		log_fields!(LogLevel::Warn, {path: path, reason: "missing"}, "Skipping source {} (does not exist)", path);
+		stats.source_missing(&path);
+	}
+	let sources : Vec<String> = found.into_iter().chain(found_listed).cloned().collect();
+	let outside : Vec<&str> = sources.iter()
+		.filter(|s| source_path(roots, s).is_none())
+		.map(|s| s.as_str())
+		.collect();
+	if outside.is_empty() {
+		Ok(sources)
+	} else if allow_outside_root {
+		debug!("Recording sources outside the source root under {}: {}",
+			OUTSIDE_ROOT, outside.join(", "));
+		Ok(sources)
+	} else {
+		Err(BackupError::OtherError(format!(
+			concat!("Sources must be beneath the source root {} (use --allow-outside-root ",
+				"to back them up anyway): {}"),
+			source_root.display(), outside.join(", "))))
+	}
+}
+
+/// Unicode normalization forms which can be applied to filenames.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Deserialize)]
+pub enum Normalization {
+	/// Canonical composition, as used by most Linux and Windows software.
+	Nfc,
+	/// Canonical decomposition, as used by macOS filesystems.
+	Nfd,
+	/// Leave filenames exactly as they are.
+	#[default]
+	None,
+}
+
+impl Normalization {
+	/// Normalize a filename.
+	pub fn apply(&self, key: String) -> String {
+		match *self {
+			Normalization::Nfc => key.nfc().collect(),
+			Normalization::Nfd => key.nfd().collect(),
+			Normalization::None => key,
+		}
+	}
+}
+
+/// Get the path of the file with the given normalized filename beneath its
+/// root (see `on_disk_name`).
+pub fn source_file(roots: &SourceRoots, key: &str, normalization: Normalization) -> PathBuf {
+	roots.path(&on_disk_name(roots, key, normalization))
+}
+
+/// Get the size and modification time of the file with the given normalized
+/// filename beneath its root, if it can be found.
+pub fn source_stat(roots: &SourceRoots, key: &str, normalization: Normalization)
+		-> Option<(u64, SystemTime)> {
+	fs::metadata(source_file(roots, key, normalization)).ok()
+		.and_then(|meta| meta.modified().ok().map(|mtime| (meta.len(), mtime)))
+}
+
+/// Find the filename on disk corresponding to a normalized filename.
+///
+/// If there is no file at `key` beneath its root, each component is looked for
+/// in turn, falling back to searching its directory for an entry which
+/// normalizes to it. If nothing matches, `key` is returned as is.
+fn on_disk_name(roots: &SourceRoots, key: &str, normalization: Normalization) -> String {
+	if normalization == Normalization::None || roots.path(key).exists() {
+		return key.to_string()
+	}
+	let (root, prefix, rest) = roots.split(key);
+	let mut path = root.clone();
+	let mut parts = Vec::new();
+	for part in rest.split('/') {
+		if part.is_empty() {
+			parts.push(String::new());
+			continue
+		}
+		let name = if path.join(part).exists() {
+			Some(part.to_string())
+		} else {
+			fs::read_dir(&path).ok().and_then(|entries| entries
+				.filter_map(|e| e.ok())
+				.filter_map(|e| e.file_name().into_string().ok())
+				.find(|name| normalization.apply(name.clone()) == part))
+		};
+		match name {
+			Some(name) => {
+				path.push(&name);
+				parts.push(name);
+			},
+			None => return key.to_string()
+		}
+	}
+	join_key(prefix, &parts.join("/"))
+}
+
+/// Options controlling how the sources are walked and checksummed.
+#[derive(Clone,Debug,Default)]
+pub struct WalkOptions {
+	/// Skip everything (including whole directories) which is not on the same
+	/// filesystem as the source root.
+	pub one_file_system: bool,
+	/// With `one_file_system`, also descend into these filesystems (given by
+	/// device ID; see `mount_point_device`).
+	pub follow_devices: Vec<u64>,
+	/// Skip everything less than this many levels below each source (so `1`
+	/// skips only the sources themselves, and `2` also their direct children).
+	pub min_depth: Option<usize>,
+	/// Descend at most this many levels below each source (so `0` visits only
+	/// the sources themselves, and `1` their direct children).
+	pub max_depth: Option<usize>,
+	/// Normalize filenames to this form for use as checksum keys. Filenames
+	/// which are not valid UTF-8 are never normalized (they are skipped).
+	pub normalization: Normalization,
+	/// Skip files owned by any of these uids.
+	pub exclude_owners: Vec<u32>,
+	/// If not empty, skip files not owned by any of these uids.
+	pub include_owners: Vec<u32>,
+	/// Skip files matching any of these glob patterns (see
+	/// `matching_pattern`).
+	pub exclude_patterns: Vec<String>,
+	/// Walk sources which are not beneath the source root, recording their
+	/// files beneath `OUTSIDE_ROOT`. Otherwise, such sources are skipped.
+	pub allow_outside_root: bool,
+	/// Checksum files on this many worker threads. With zero, files are
+	/// checksummed on the calling thread as they are found.
+	pub jobs: usize,
+	/// Report each file checksummed here.
+	pub progress: Option<Arc<Progress>>,
+	/// Record statistics about checksumming here.
+	pub stats: Arc<Stats>,
+	/// Avoid updating files' access times, and drop their contents from the
+	/// page cache once they've been read (see `open_source` and
+	/// `drop_cached`).
+	pub drop_caches: bool,
+	/// Limit the rate at which files are read with this.
+	pub throttle: Option<Arc<Throttle>>,
+	/// Read files in chunks of this many bytes. Each checksumming thread has
+	/// its own buffer, and large files are read into several (see
+	/// `hash_threaded`).
+	pub buffer_size: usize,
+	/// Sleep for this long after checksumming each file.
+	pub file_delay: Option<Duration>,
+	/// Skip files which can't be read, rather than stopping with an error.
+	pub ignore_errors: bool,
+	/// Reuse the previous checksums of files which look unchanged by their
+	/// size and modification time, rather than reading them (see
+	/// `KnownFiles`).
+	pub known_files: Option<Arc<KnownFiles>>,
+	/// Try files which give transient errors again this many times before
+	/// giving up on them (see `hash_file`).
+	pub retries: u32,
+	/// Record each file's size and modification time as it's checksummed, to
+	/// check it hasn't changed when it's archived (see `Stability`).
+	pub stability: Option<Arc<Stability>>,
+}
+
+impl WalkOptions {
+	/// Check whether a file should be skipped because of its owner.
+	fn skip_owner(&self, meta: &fs::Metadata) -> bool {
+		if self.exclude_owners.is_empty() && self.include_owners.is_empty() {
+			return false
+		}
+		match owner_id(meta) {
+			Some(uid) => self.exclude_owners.contains(&uid) ||
+				(!self.include_owners.is_empty() && !self.include_owners.contains(&uid)),
+			None => false
+		}
+	}
+}
+
+/// The files recorded by the previous backup, for reusing their checksums
+/// without reading them again if their size and modification time are
+/// unchanged (as rsync does by default).
+///
+/// This trusts files' metadata: a file changed without changing its size or
+/// modification time (e.g. with the time set back afterwards) keeps its old
+/// checksum, and isn't archived.
+#[derive(Debug)]
+pub struct KnownFiles {
+	checksums: HashMap<String, String>,
+	/// Each file's size and modification time, in seconds since the epoch, if
+	/// recorded (i.e. in a checksum database).
+	stats: Option<HashMap<String, (u64, i64)>>,
+	/// Otherwise, when the previous checksums were written, after which files
+	/// are taken to have changed if they've been modified.
+	written: Option<SystemTime>,
+}
+
+impl KnownFiles {
+	/// Use the previous `checksums`, with files' sizes and modification times
+	/// from `stats`, if given, or otherwise the time the checksums were
+	/// `written`.
+	pub fn new(
+			checksums: HashMap<String, String>,
+			stats: Option<HashMap<String, (u64, i64)>>,
+			written: Option<SystemTime>)
+			-> KnownFiles {
+		KnownFiles { checksums, stats, written }
+	}
+
+	/// The previous checksum of the file with normalized filename `key`, if it
+	/// looks unchanged by its metadata, `meta`.
+	fn unchanged(&self, key: &str, meta: &fs::Metadata) -> Option<&str> {
+		let checksum = try_opt!(self.checksums.get(key));
+		let mtime = try_opt!(meta.modified().ok());
+		let unchanged = match self.stats {
+			Some(ref stats) => stats.get(key).is_some_and(|&(size, old_mtime)| {
+				let secs = mtime.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).ok();
+				size == meta.len() && secs == Some(old_mtime)
+			}),
+			None => self.written.is_some_and(|written| mtime <= written)
+		};
+		if unchanged { Some(checksum) } else { None }
+	}
+}
+
+/// A file's size and modification time, to tell whether it has changed.
+#[derive(Clone,Copy,Debug,PartialEq)]
+struct FileState {
+	size: u64,
+	modified: Option<SystemTime>,
+}
+
+impl FileState {
+	fn of(meta: &fs::Metadata) -> FileState {
+		FileState { size: meta.len(), modified: meta.modified().ok() }
+	}
+}
+
+/// Catches changed files which are still changing as they're backed up (e.g.
+/// logs or databases being written to), whose archived contents might not
+/// match their checksums.
+///
+/// Each file's size and modification time are recorded as it's checksummed,
+/// and checked again just before it's archived. If they differ, the file is
+/// checksummed again, and archived under its new checksum, or, with `skip`,
+/// left out of the archive. Files are also checked once they've been archived
+/// (see `append_changed`).
+pub struct Stability {
+	/// Each file's state when it was checksummed, by its name on disk.
+	states: Mutex<HashMap<String, FileState>>,
+	algorithm: HashAlgorithm,
+	key: Option<Vec<u8>>,
+	skip: bool,
+}
+
+// Not derived, to keep the key out of debugging output.
+impl fmt::Debug for Stability {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Stability {{ algorithm: {:?}, skip: {} }}", self.algorithm, self.skip)
+	}
+}
+
+impl Stability {
+	/// Check files for changes between checksumming and archiving them,
+	/// checksumming those which have changed again with `algorithm` (keyed with
+	/// `key`, if given), or skipping them if `skip` is set.
+	pub fn new(algorithm: HashAlgorithm, key: Option<&[u8]>, skip: bool) -> Stability {
+		Stability {
+			states: Mutex::new(HashMap::new()),
+			algorithm,
+			key: key.map(|key| key.to_vec()),
+			skip,
+		}
+	}
+
+	/// Record the state of the file `name` as it's checksummed.
+	fn checksummed(&self, name: &str, meta: &fs::Metadata) {
+		self.states.lock().unwrap().insert(name.to_string(), FileState::of(meta));
+	}
+
+	/// Check whether the file `name`, now in state `now`, has changed since it
+	/// was checksummed. Files which weren't checksummed with this recording
+	/// their state are taken to be unchanged.
+	fn changed(&self, name: &str, now: FileState) -> bool {
+		self.states.lock().unwrap().get(name).is_some_and(|&state| state != now)
+	}
+}
+
+/// Resolve a user, given as a username or numeric uid, to a uid.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if there is no such user, or on platforms other than Unix.
+#[cfg(unix)]
+pub fn resolve_user(user: &str) -> Result<u32, BackupError> {
+	use nix::unistd::User;
+	if let Ok(uid) = user.parse() {
+		return Ok(uid)
+	}
+	match User::from_name(user) {
+		Ok(Some(u)) => Ok(u.uid.as_raw()),
+		Ok(None) => Err(BackupError::OtherError(format!("No such user: {}", user))),
+		Err(e) => Err(BackupError::OtherError(format!("Couldn't look up user {}: {}", user, e)))
+	}
+}
+
+#[cfg(not(unix))]
+pub fn resolve_user(user: &str) -> Result<u32, BackupError> {
+	Err(BackupError::OtherError(
+		format!("Can't filter by owner ({}): file owners are only supported on Unix", user)))
+}
+
+/// Get the uid of the owner of a file.
+///
+/// This is only available on Unix; elsewhere, `None` is returned.
+#[cfg(unix)]
+fn owner_id(meta: &fs::Metadata) -> Option<u32> {
+	use std::os::unix::fs::MetadataExt;
+	Some(meta.uid())
+}
+
+#[cfg(not(unix))]
+fn owner_id(_meta: &fs::Metadata) -> Option<u32> {
+	None
+}
+
+/// Get the ID of the device containing a file.
+///
+/// This is only available on Unix; elsewhere, `None` is returned.
+#[cfg(unix)]
+fn device_id(meta: &fs::Metadata) -> Option<u64> {
+	use std::os::unix::fs::MetadataExt;
+	Some(meta.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_meta: &fs::Metadata) -> Option<u64> {
+	None
+}
+
+/// Get the ID of the device for the filesystem mounted at `path`, for
+/// `WalkOptions::follow_devices`.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if `path` can't be read, or on platforms other than Unix.
+pub fn mount_point_device(path: &str) -> Result<u64, BackupError> {
+	let meta = fs::metadata(path).map_err(|e| BackupError::OtherError(
+		format!("Couldn't read mount point {}: {}", path, e)))?;
+	device_id(&meta).ok_or_else(|| BackupError::OtherError(
+		format!("Can't follow mount point {}: filesystems are only distinguished on Unix", path)))
+}
+
+/// Find out whether a path is on a network filesystem, returning the type of
+/// filesystem if so.
+///
+/// This is only available on Linux, macOS, and FreeBSD; elsewhere, or if the
+/// filesystem can't be queried, `None` is returned.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn network_filesystem(path: &Path) -> Option<&'static str> {
+	use libc;
+	use std::ffi::CString;
+	use std::os::unix::ffi::OsStrExt;
+	let c_path = try_opt!(CString::new(path.as_os_str().as_bytes()).ok());
+	let mut buf : libc::statfs = unsafe { mem::zeroed() };
+	if unsafe { libc::statfs(c_path.as_ptr(), &mut buf) } != 0 {
+		return None;
+	}
+	// Filesystem magic numbers, from statfs(2)
+	match buf.f_type as u32 {
+		0x6969 => Some("nfs"),
+		0x517b => Some("smb"),
+		0xfe534d42 => Some("smb2"),
+		0xff534d42 => Some("cifs"),
+		0x73757245 => Some("coda"),
+		0x5346414f => Some("afs"),
+		0x564c => Some("ncp"),
+		0x6b414653 => Some("afs"),
+		_ => None,
+	}
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub fn network_filesystem(path: &Path) -> Option<&'static str> {
+	use libc;
+	use std::ffi::{CStr, CString};
+	use std::os::unix::ffi::OsStrExt;
+	let c_path = try_opt!(CString::new(path.as_os_str().as_bytes()).ok());
+	let mut buf : libc::statfs = unsafe { mem::zeroed() };
+	if unsafe { libc::statfs(c_path.as_ptr(), &mut buf) } != 0 {
+		return None;
+	}
+	let name = unsafe { CStr::from_ptr(buf.f_fstypename.as_ptr()) };
+	match name.to_bytes() {
+		b"nfs" => Some("nfs"),
+		b"smbfs" => Some("smbfs"),
+		b"cifs" => Some("cifs"),
+		b"afpfs" => Some("afpfs"),
+		b"webdav" => Some("webdav"),
+		_ => None,
+	}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos",
+	target_os = "freebsd")))]
+pub fn network_filesystem(_path: &Path) -> Option<&'static str> {
+	None
+}
+
+/// Find the space available to this process on the filesystem containing
+/// `path`, in bytes.
+///
+/// This is only available on Unix; elsewhere, or if the filesystem can't be
+/// queried, `None` is returned.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Option<u64> {
+	use libc;
+	use std::ffi::CString;
+	use std::os::unix::ffi::OsStrExt;
+	let c_path = try_opt!(CString::new(path.as_os_str().as_bytes()).ok());
+	let mut buf : libc::statvfs = unsafe { mem::zeroed() };
+	if unsafe { libc::statvfs(c_path.as_ptr(), &mut buf) } != 0 {
+		return None;
+	}
+	Some(buf.f_bavail as u64 * buf.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> Option<u64> {
+	None
+}
+
+/// Lower this process's priority, so it only uses otherwise idle resources:
+/// its CPU priority is set as low as possible (on Unix) and, on Linux, its I/O
+/// is put in the idle scheduling class.
+///
+/// This must be called before any threads are started, since on Linux the
+/// settings apply to the calling thread and are inherited by threads it
+/// starts. Returns a description of each setting which took effect; those
+/// which fail are logged.
+pub fn lower_priority() -> Vec<&'static str> {
+	let mut applied = Vec::new();
+	if lower_cpu_priority() {
+		applied.push("lowest CPU priority");
+	}
+	if lower_io_priority() {
+		applied.push("idle I/O scheduling class");
+	}
+	applied
+}
+
+#[cfg(unix)]
+fn lower_cpu_priority() -> bool {
+	use libc;
+	if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } == 0 {
+		true
+	} else {
+		warn!("Couldn't lower CPU priority: {}", io::Error::last_os_error());
+		false
+	}
+}
+
+#[cfg(not(unix))]
+fn lower_cpu_priority() -> bool {
+	false
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn lower_io_priority() -> bool {
+	use libc;
+	// From linux/ioprio.h
+	const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+	const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+	const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+	if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0,
+			IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT) } == 0 {
+		true
+	} else {
+		warn!("Couldn't set idle I/O scheduling class: {}", io::Error::last_os_error());
+		false
+	}
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn lower_io_priority() -> bool {
+	false
+}
+
+/// Open a source file for reading.
+///
+/// With `drop_caches`, on Linux, the file is opened without updating its
+/// access time where that's permitted (only the file's owner or root may do
+/// so); otherwise, it's opened normally.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn open_source(path: &Path, drop_caches: bool) -> io::Result<File> {
+	use libc;
+	use std::fs::OpenOptions;
+	use std::os::unix::fs::OpenOptionsExt;
+	if drop_caches {
+		match OpenOptions::new().read(true).custom_flags(libc::O_NOATIME).open(path) {
+			Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => (),
+			result => return result,
+		}
+	}
+	File::open(path)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn open_source(path: &Path, _drop_caches: bool) -> io::Result<File> {
+	File::open(path)
+}
+
+/// Advise the kernel that a file's contents won't be needed again soon, so
+/// they can be dropped from the page cache.
+///
+/// This is only done on Linux; elsewhere, it does nothing.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn drop_cached(file: &File) {
+	use libc;
+	use std::os::unix::io::AsRawFd;
+	// This is only advice, so it doesn't matter if it fails.
+	unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn drop_cached(_file: &File) {
+}
+
+/// Walk the given sources and checksum the files found.
+///
+/// Each file's filename (relative to its root in `roots`) as found on disk, its
+/// filename normalized per `options` for use as a key, and its checksum are
+/// passed to `each` as soon as they are computed. If `each` returns an error,
+/// the walk stops and the error is returned.
+///
+/// If `options.jobs` is not zero, the walk runs on its own thread and files are
+/// checksummed by that many worker threads, so `each` sees files in no
+/// particular order, and checksumming continues while it runs; it is always
+/// called on the calling thread.
+///
+/// The walk stops with a `BackupError::Cancelled` between files if the run is
+/// interrupted (see `cancel::check`), or with a `BackupError::OtherError` if a
+/// file couldn't be read and the run is strict (see `Stats::check_strict`).
+fn walk_checksums<F, G>(
+		sources: &[String],
+		roots: &SourceRoots,
+		options: &WalkOptions,
+		make_hasher: F,
+		mut each: G)
+		-> Result<Vec<String>, BackupError>
+		where F: Fn() -> Box<dyn Hasher> + Sync, G: FnMut(&str, String, String) -> Result<(), BackupError> {
+	// Reads in `hash_file` are counted to show the current read rate with
+	// the progress.
+	let _meter = options.progress.as_ref().map(|progress| ThroughputMeter::start(progress.clone()));
+	let mut failed_files = Vec::new();
+	let mut checksummed = |name: String, result: io::Result<String>| match result {
+		Ok(value) => {
+			let key = options.normalization.apply(name.clone());
+			log_fields!(LogLevel::Trace, {path: key, checksum: value}, "Current version checksum: {}\t{}", key, value);
+			each(&name, key, value)
+		},
+		Err(e) => {
+			if !options.ignore_errors {
+				return Err(BackupError::OtherError(format!("Error reading {}: {}", name, e)));
+			}
+			log_fields!(LogLevel::Warn, {path: name, reason: "unreadable", error: e.to_string()},
+				"Skipping {} (error reading: {})", name, e);
+			options.stats.file_failed(&name, &e.to_string());
+			if e.kind() == io::ErrorKind::PermissionDenied {
+				options.stats.file_denied(&name);
+			}
+			failed_files.push(name);
+			Ok(())
+		}
+	};
+
+	if options.jobs == 0 {
+		let mut buf = vec![0u8; options.buffer_size];
+		walk_files(sources, roots, options, |path, name| {
+			cancel::check()?;
+			options.stats.check_strict()?;
+			match hash_file(path, &name, &make_hasher, &mut buf, options) {
+				Some(result) => checksummed(name, result),
+				None => Ok(())
+			}
+		})?;
+		return Ok(failed_files);
+	}
+
+	thread::scope(|scope| {
+		let (job_tx, job_rx) = mpsc::sync_channel::<(PathBuf, String)>(options.jobs * 4);
+		let job_rx = Arc::new(Mutex::new(job_rx));
+		let (result_tx, result_rx) = mpsc::channel();
+		for _ in 0 .. options.jobs {
+			let job_rx = job_rx.clone();
+			let result_tx = result_tx.clone();
+			let make_hasher = &make_hasher;
+			scope.spawn(move || {
+				let mut buf = vec![0u8; options.buffer_size];
+				loop {
+					let job = job_rx.lock().unwrap().recv();
+					let (path, name) = match job {
+						Ok(job) => job,
+						Err(_) => break
+					};
+					if let Some(result) = hash_file(&path, &name, make_hasher, &mut buf, options) {
+						if result_tx.send((name, result)).is_err() {
+							break
+						}
+					}
+				}
+			});
+		}
+		drop(job_rx);
+		drop(result_tx);
+		// The walk only fails if the workers have gone, i.e. if `each` failed
+		// or a file couldn't be read, in which case that error is returned
+		// below.
+		scope.spawn(move || walk_files(sources, roots, options, |path, name| {
+			job_tx.send((path.to_path_buf(), name))
+				.map_err(|_| BackupError::OtherError("Checksum threads exited".to_string()))
+		}));
+		for (name, result) in result_rx {
+			cancel::check()?;
+			options.stats.check_strict()?;
+			checksummed(name, result)?;
+		}
+		Ok(())
+	}).map(|_| failed_files)
+}
+
+/// Files at least this large are read on a separate thread while they are
+/// checksummed; for smaller files, the thread isn't worth it.
+const THREADED_READ_MIN: u64 = 8 << 20;
+
+/// The number of buffers used to read a file on a separate thread.
+const THREADED_READ_BUFFERS: usize = 4;
+
+/// Checksum a single file, using `buf` as scratch space, returning the
+/// checksum, or any error reading the file. `name` is the file's name as
+/// reported to `options.progress`, if given; the file is also recorded in
+/// `options.stats`.
+///
+/// Files which can't be opened (e.g. because they've been deleted since they
+/// were found, or aren't readable) are skipped, returning `None`. Bytes read
+/// are counted towards the current read rate shown with `options.progress`
+/// (see `ThroughputMeter`).
+///
+/// Large files are read on a separate thread (see `hash_threaded`). With
+/// `options.known_files`, files which look unchanged aren't read at all, and
+/// their previous checksums are returned. With `options.stability`, each
+/// file's size and modification time are recorded before it's read.
+///
+/// Files which give transient errors (see `transient_error`) opening or
+/// reading them are tried again from the start, reopening them, up to
+/// `options.retries` times, waiting longer before each retry (see
+/// `back_off`). Retries, and files read after retrying, are counted in
+/// `options.stats`.
+fn hash_file<F>(path: &Path, name: &str, make_hasher: &F, buf: &mut [u8], options: &WalkOptions)
+		-> Option<io::Result<String>>
+		where F: Fn() -> Box<dyn Hasher> {
+	if let Some(ref known) = options.known_files {
+		if let Ok(meta) = fs::metadata(path) {
+			if let Some(checksum) = known.unchanged(&options.normalization.apply(name.to_string()), &meta) {
+				log_fields!(LogLevel::Trace, {path: name, checksum: checksum},
+					"Size and modification time unchanged, not checksumming: {}", name);
+				if let Some(ref progress) = options.progress {
+					progress.file_done(name, meta.len());
+				}
+				return Some(Ok(checksum.to_string()))
+			}
+		}
+	}
+	let mut retries = 0;
+	let mut started = false;
+	loop {
+		let file = match open_source(path, options.drop_caches) {
+			Ok(file) => file,
+			Err(ref e) if retries < options.retries && transient_error(e) => {
+				back_off(name, e, retries, options);
+				retries += 1;
+				continue
+			},
+			Err(e) => {
+				//TODO: There are probably some cases where we should abort here.
+				log_fields!(LogLevel::Warn, {path: name, reason: "unreadable", error: e.to_string()},
+					"Skipping {} (couldn't open: {})", path.display(), e);
+				options.stats.file_skipped(name, Skip::Unreadable, &format!("couldn't open: {}", e));
+				if e.kind() == io::ErrorKind::PermissionDenied {
+					options.stats.file_denied(name);
+				}
+				return None
+			}
+		};
+		if !mem::replace(&mut started, true) {
+			options.stats.file_started(Phase::Checksum, name);
+		}
+		match hash_contents(file, name, make_hasher, buf, options) {
+			Err(ref e) if retries < options.retries && transient_error(e) => {
+				back_off(name, e, retries, options);
+				retries += 1;
+			},
+			result => {
+				if retries > 0 && result.is_ok() {
+					log_fields!(LogLevel::Info, {path: name, retries: retries},
+						"Read {} after {} retries", name, retries);
+					options.stats.file_retried();
+				}
+				if let Some(delay) = options.file_delay {
+					thread::sleep(delay);
+				}
+				return Some(result)
+			}
+		}
+	}
+}
+
+/// Checksum the contents of an open file, for `hash_file`.
+fn hash_contents<F>(file: File, name: &str, make_hasher: &F, buf: &mut [u8], options: &WalkOptions)
+		-> io::Result<String>
+		where F: Fn() -> Box<dyn Hasher> {
+	let mut hasher = make_hasher();
+	let meta = file.metadata();
+	if let (Some(stability), Ok(meta)) = (options.stability.as_ref(), meta.as_ref()) {
+		stability.checksummed(name, meta);
+	}
+	let size = meta.map(|m| m.len()).unwrap_or(0);
+	let mut file_progress = FileProgress::new(name, size, options.progress.as_deref());
+	let mut file = TrackingReader::new(file,
+		options.progress.as_ref().map_or_else(Default::default, |p| p.bytes_read()));
+	let throttle = options.throttle.as_deref();
+	let len = if size >= THREADED_READ_MIN {
+		hash_threaded(&mut file, &mut *hasher, buf.len(), &mut file_progress, throttle)?
+	} else {
+		let mut len = 0;
+		let mut read_len: usize = 1;
+		while read_len > 0 {
+			read_len = read_retrying(&mut file, buf)?;
+			if let Some(throttle) = throttle {
+				throttle.consume(read_len as u64);
+			}
+			hasher.update(&buf[0 .. read_len]);
+			len += read_len as u64;
+		}
+		len
+	};
+	file_progress.done(len);
+	options.stats.file_done(Phase::Checksum, name, len);
+	if options.drop_caches {
+		drop_cached(file.get_ref());
+	}
+	Ok(hasher.finalize())
+}
+
+/// The wait before retrying a file after its first transient error (see
+/// `transient_error`), doubling for each retry after that, up to
+/// `RETRY_BACKOFF_MAX_MS`.
+const RETRY_BACKOFF_MS: u64 = 500;
+
+/// The longest wait between retries.
+const RETRY_BACKOFF_MAX_MS: u64 = 30_000;
+
+/// Log a transient error checksumming a file, and wait before the given retry
+/// (counting from zero), for `hash_file`. The retry is recorded in
+/// `options.stats`.
+fn back_off(name: &str, e: &io::Error, retry: u32, options: &WalkOptions) {
+	let wait = Duration::from_millis(cmp::min(RETRY_BACKOFF_MS << cmp::min(retry, 16), RETRY_BACKOFF_MAX_MS));
+	log_fields!(LogLevel::Warn, {path: name, error: e.to_string(), retry: retry + 1},
+		"Error reading {}: {}; retrying in {} ({} of {})",
+		name, e, human_duration(wait), retry + 1, options.retries);
+	options.stats.read_retried();
+	thread::sleep(wait);
+}
+
+/// Check whether an error reading a file might go away if it's tried again:
+/// timeouts, and (on Unix) I/O errors and stale handles, as network
+/// filesystems give when the server is briefly unavailable.
+#[cfg(unix)]
+fn transient_error(e: &io::Error) -> bool {
+	use libc;
+	match e.kind() {
+		io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => true,
+		_ => matches!(e.raw_os_error(), Some(libc::EIO) | Some(libc::ESTALE) | Some(libc::ETIMEDOUT))
+	}
+}
+
+#[cfg(not(unix))]
+fn transient_error(e: &io::Error) -> bool {
+	match e.kind() {
+		io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => true,
+		_ => false
+	}
+}
+
+/// Read from a file, retrying reads which are interrupted.
+fn read_retrying<R: Read>(file: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+	loop {
+		match file.read(buf) {
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			result => return result
+		}
+	}
+}
+
+/// Checksum a file, reading it on a separate thread.
+///
+/// A fixed pool of buffers of `buf_size` bytes is passed back and forth
+/// between the reader thread, which fills them, and this thread, which
+/// checksums their contents, so that reading each part of the file overlaps
+/// checksumming the last, and memory use is bounded whatever the size of the
+/// file. Returns the number of bytes read, which are also reported to
+/// `file_progress` as they're checksummed, or any error reading the file.
+/// Reading is limited by `throttle`, if given.
+fn hash_threaded<R: Read + Send>(
+		file: &mut R,
+		hasher: &mut dyn Hasher,
+		buf_size: usize,
+		file_progress: &mut FileProgress,
+		throttle: Option<&Throttle>)
+		-> io::Result<u64> {
+	let (empty_tx, empty_rx) = mpsc::channel::<Vec<u8>>();
+	let (full_tx, full_rx) = mpsc::sync_channel::<io::Result<(Vec<u8>, usize)>>(THREADED_READ_BUFFERS);
+	for _ in 0 .. THREADED_READ_BUFFERS {
+		empty_tx.send(vec![0u8; buf_size]).unwrap();
+	}
+	let mut len = 0;
+	thread::scope(|scope| {
+		scope.spawn(move || {
+			for mut buf in empty_rx {
+				let read_len = match read_retrying(file, &mut buf) {
+					Ok(read_len) => read_len,
+					Err(e) => {
+						let _ = full_tx.send(Err(e));
+						break
+					}
+				};
+				if let Some(throttle) = throttle {
+					throttle.consume(read_len as u64);
+				}
+				if full_tx.send(Ok((buf, read_len))).is_err() || read_len == 0 {
+					break
+				}
+			}
+		});
+		for result in full_rx {
+			let (buf, read_len) = result?;
+			if read_len == 0 {
+				break
+			}
+			hasher.update(&buf[0 .. read_len]);
+			len += read_len as u64;
+			file_progress.read(read_len as u64);
+			// This only fails if the reader has stopped, in which case we're
+			// about to stop too.
+			let _ = empty_tx.send(buf);
+		}
+		Ok(len)
+	})
+}
+
+/// Totals found by `scan_files`.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct ScanTotals {
+	/// The number of files to be checksummed.
+	pub files: u64,
+	/// Their total size.
+	pub bytes: u64,
+	/// The number of those files which are likely to have changed.
+	pub candidate_files: u64,
+	/// Their total size.
+	pub candidate_bytes: u64,
+}
+
+/// Count the files to be checksummed in the given sources, and their total
+/// size, without reading them.
+///
+/// The walk is the same as for checksumming, so the totals are exact unless
+/// files change in the meantime. Files for which `is_candidate` returns true,
+/// given their normalized filenames and metadata, are also counted as likely
+/// to have changed (e.g. because they've been modified since the last backup).
+pub fn scan_files<C>(sources: &[String], roots: &SourceRoots, options: &WalkOptions, mut is_candidate: C)
+		-> ScanTotals
+		where C: FnMut(&str, &fs::Metadata) -> bool {
+	let mut totals = ScanTotals::default();
+	// Files skipped here will be counted again when they're checksummed.
+	let options = &WalkOptions { stats: Arc::new(Stats::new()), ..options.clone() };
+	// Counting can't fail, so neither can the walk.
+	let _ = walk_files(sources, roots, options, |path, name| {
+		let meta = match fs::metadata(path) {
+			Ok(meta) => meta,
+			Err(_) => return Ok(())
+		};
+		totals.files += 1;
+		totals.bytes += meta.len();
+		if is_candidate(&options.normalization.apply(name), &meta) {
+			totals.candidate_files += 1;
+			totals.candidate_bytes += meta.len();
+		}
+		Ok(())
+	});
+	totals
+}
+
+/// Walk the given sources, passing each file to be checksummed to `found`
+/// along with its filename (relative to its root in `roots`) as found on disk.
+/// If `found` returns an error, the walk stops and the error is returned.
+fn walk_files<H>(
+		sources: &[String],
+		roots: &SourceRoots,
+		options: &WalkOptions,
+		mut found: H)
+		-> Result<(), BackupError>
+		where H: FnMut(&Path, String) -> Result<(), BackupError> {
+	for source in sources {
+		let (source_path, root, prefix) = match source_path(roots, source) {
+			Some(found) => found,
+			None if options.allow_outside_root =>
+				(absolute_path(&roots.root().join(source)), roots.root(), None),
+			None => {
+				warn!("Skipping {} (not beneath the source root)", source);
+				continue
+			}
+		};
+		let allowed_devices = if options.one_file_system {
+			match fs::metadata(root).ok().and_then(|m| device_id(&m)) {
+				Some(device) => {
+					let mut devices: HashSet<u64> = options.follow_devices.iter().cloned().collect();
+					devices.insert(device);
+					Some(devices)
+				},
+				None => {
+					warn!("Can't determine the filesystem of {}; crossing filesystems",
+						root.display());
+					None
+				}
+			}
+		} else {
+			None
+		};
+		let mut walker = WalkDir::new(&source_path);
+		if let Some(depth) = options.min_depth {
+			walker = walker.min_depth(depth);
+		}
+		if let Some(depth) = options.max_depth {
+			walker = walker.max_depth(depth);
+		}
+		let walker = walker.into_iter()
+			.filter_entry(|e| {
+				// Excluded directories aren't walked at all.
+				let pattern = if e.depth() > 0 && e.file_type().is_dir() {
+					e.file_name().to_str().and_then(|name| matching_pattern(&options.exclude_patterns, name, true))
+				} else {
+					None
+				};
+				match pattern {
+					Some(pattern) => {
+						log_fields!(LogLevel::Trace, {path: e.path().display().to_string(), reason: "excluded"},
+							"Skipping {} (matches {})", e.path().display(), pattern);
+						options.stats.file_skipped(&e.path().display().to_string(), Skip::Excluded,
+							&format!("matches {}", pattern));
+						false
+					},
+					None => true
+				}
+			})
+			.filter_entry(|e| allowed_devices.as_ref().is_none_or(|allowed| {
+				let same = e.metadata().ok().and_then(|m| device_id(&m)).is_none_or(|d| allowed.contains(&d));
+				if !same {
+					log_fields!(LogLevel::Trace, {path: e.path().display().to_string(), reason: "excluded"},
+						"Skipping {} (on a different filesystem)", e.path().display());
+					options.stats.file_skipped(&e.path().display().to_string(), Skip::Excluded,
+						"on a different filesystem");
+				}
+				same
+			}));
+		for entry in walker {
+			let entry = match entry {
+				Ok(entry) => entry,
+				Err(e) => {
+					let path = e.path().map_or(String::new(), |p| p.display().to_string());
+					log_fields!(LogLevel::Warn, {path: path, reason: "unreadable", error: e.to_string()},
+						"Skipping unreadable entry: {}", e);
+					options.stats.file_skipped(&path, Skip::Unreadable, &e.to_string());
+					options.stats.walk_error();
+					if io::Error::from(e).kind() == io::ErrorKind::PermissionDenied {
+						options.stats.file_denied(&path);
+					}
+					continue
+				}
+			};
+			let path = entry.path();
+			if entry.file_type().is_dir() {
+				continue
+			}
+			if !path.is_file() {
+				log_fields!(LogLevel::Trace, {path: path.display().to_string(), reason: "special"},
+					"Skipping {} (not a file)", path.display());
+				options.stats.file_skipped(&path.display().to_string(), Skip::Special, "not a file");
+				continue
+			}
+			if fs::metadata(path).map(|m| options.skip_owner(&m)).unwrap_or(false) {
+				log_fields!(LogLevel::Trace, {path: path.display().to_string(), reason: "excluded"},
+					"Skipping {} (excluded by owner)", path.display());
+				options.stats.file_skipped(&path.display().to_string(), Skip::Excluded,
+					"excluded by owner");
+				continue
+			}
+			let name = match path.strip_prefix(root) {
+				Ok(relative) => path_to_key(relative).map(|k| join_key(prefix, &k)),
+				Err(_) => outside_root_key(path),
+			};
+			let name = match name {
+				Some(name) => name,
+				None => {
+					log_fields!(LogLevel::Warn, {path: path.display().to_string(), reason: "excluded"},
+						"Skipping {} (filename is not valid UTF-8)", path.display());
+					options.stats.file_skipped(&path.display().to_string(), Skip::Excluded,
+						"filename is not valid UTF-8");
+					continue
+				}
+			};
+			if let Some(pattern) = matching_pattern(&options.exclude_patterns, &name, false) {
+				log_fields!(LogLevel::Trace, {path: path.display().to_string(), reason: "excluded"},
+					"Skipping {} (matches {})", path.display(), pattern);
+				options.stats.file_skipped(&path.display().to_string(), Skip::Excluded,
+					&format!("matches {}", pattern));
+				continue
+			}
+			found(path, name)?;
+		}
+	}
+	Ok(())
+}
+
+/// Glob patterns matching common temporary files and build outputs, skipped
+/// with `--exclude-temp-files`.
+pub const TEMP_FILE_PATTERNS: &[&str] = &[
+	"*.tmp", "*.temp", "*.swp", "*.bak", "*~", "*.pyc", "__pycache__/**", "*.class",
+	"*.o", "*.so", "*.a", "target/**",
+];
+
+/// Find the first of the glob `patterns` which matches the file (or, if
+/// `is_dir`, directory) with filename `key`, relative to its root.
+///
+/// A pattern ending in `/**` matches everything beneath a directory whose name
+/// matches the rest of it (so `target/**` matches `target/debug/backup` and
+/// `src/target/x`); any other pattern matches files whose name matches it (so
+/// `*.tmp` matches `a/b.tmp`). Directories are matched by their own names
+/// against the first kind of pattern only.
+fn matching_pattern<'a>(patterns: &'a [String], key: &str, is_dir: bool) -> Option<&'a str> {
+	if patterns.is_empty() {
+		return None
+	}
+	let mut components : Vec<&str> = key.split('/').collect();
+	let name = if is_dir { None } else { components.pop() };
+	patterns.iter().map(|pattern| &pattern[..]).find(|pattern| match pattern.strip_suffix("/**") {
+		Some(dir) => components.iter().any(|component| glob_match(dir, component)),
+		None => match name {
+			Some(name) => glob_match(pattern, name),
+			None => false
+		},
+	})
+}
+
+/// Check whether `text` matches the glob `pattern`, in which `*` matches any
+/// run of characters and `?` any single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+	let pattern : Vec<char> = pattern.chars().collect();
+	let text : Vec<char> = text.chars().collect();
+	let (mut p, mut t) = (0, 0);
+	// Where the last `*` was, and where in `text` it's matched up to, to
+	// backtrack to if the rest of the pattern doesn't match.
+	let mut star = None;
+	while t < text.len() {
+		if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+			p += 1;
+			t += 1;
+		} else if p < pattern.len() && pattern[p] == '*' {
+			star = Some((p, t));
+			p += 1;
+		} else if let Some((star_p, star_t)) = star {
+			p = star_p + 1;
+			t = star_t + 1;
+			star = Some((star_p, star_t + 1));
+		} else {
+			return false
+		}
+	}
+	pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Find filenames which differ only by case.
+///
+/// Returns groups of filenames which would collide on a case-insensitive
+/// filesystem (e.g. `README.md` and `Readme.md`), each sorted, in sorted order.
+pub fn case_collisions(checksums: &HashMap<String, String>) -> Vec<Vec<String>> {
+	let mut folded : HashMap<String, Vec<String>> = HashMap::new();
+	for key in checksums.keys() {
+		folded.entry(key.to_lowercase()).or_default().push(key.clone());
+	}
+	let mut collisions : Vec<Vec<String>> = folded.into_values().map(|mut group| { group.sort(); group })
+		.filter(|group| group.len() > 1)
+		.collect();
+	collisions.sort();
+	collisions
+}
+
+/// Save checksums to the given files.
+///
+/// Each file is written with tab-separated checksum/filename pairs, one
+/// per line, with filenames escaped where necessary (see
+/// `parse_checksum_line`), sorted by filename so the same checksums always
+/// produce the same file. Filenames use forward slashes as separators on all
+/// platforms.
+///
+/// Each `(name, value)` pair in `header` is recorded in a `# name: value`
+/// comment at the top of the file; a `version` recorded this way is read back
+/// by `load_version`. If a filename is `-`, the checksums are written to
+/// standard output.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if any of the output files cannot be created or written to.
+pub fn save_checksums(checksums: &HashMap<String, String>, fnames: &[String], header: &[(&str, String)])
+		-> Result<(), BackupError> {
+	let mut file = create_checksum_file(fnames, header)?;
+	let mut keys : Vec<&String> = checksums.keys().collect();
+	keys.sort();
+	for key in keys {
+		let value = &checksums[key];
+		file.write_all(
+			&(format_checksum_line(key, value).into_bytes())).map_err(checksum_write_error)?;
+	}
+	file.flush().map_err(checksum_write_error)?;
+	drop(file);
+	commit_checksum_files(fnames)?;
+	trace!("Wrote {} current version checksums to {}...",
+		checksums.len(), fnames.join(", "));
+	Ok(())
+}
+
+/// Format checksums and a header as `save_checksums` writes them to a file.
+pub fn format_checksums(checksums: &HashMap<String, String>, header: &[(&str, String)]) -> Vec<u8> {
+	let mut contents = String::new();
+	for &(name, ref value) in header {
+		contents.push_str(&format!("# {}: {}\n", name, value));
+	}
+	let mut keys : Vec<&String> = checksums.keys().collect();
+	keys.sort();
+	for key in keys {
+		contents.push_str(&format_checksum_line(key, &checksums[key]));
+	}
+	contents.into_bytes()
+}
+
+/// Create checksum files (see `open_checksum_output`), and write the given
+/// header to them, as described for `save_checksums`, returning a writer to
+/// write to all of them.
+fn create_checksum_file(fnames: &[String], header: &[(&str, String)])
+		-> Result<MultiWriter<Box<dyn Write>>, BackupError> {
+	let mut writers = Vec::new();
+	for fname in fnames {
+		writers.push((fname.clone(), open_checksum_output(fname)?));
+	}
+	let mut file = MultiWriter::new(writers);
+	for &(name, ref value) in header {
+		file.write_all(format!("# {}: {}\n", name, value).as_bytes())
+			.map_err(checksum_write_error)?;
+	}
+	Ok(file)
+}
+
+/// Describe an error writing to a checksum file written through a
+/// `MultiWriter`, which names the file.
+fn checksum_write_error(e: io::Error) -> BackupError {
+	BackupError::OtherError(format!("Error writing to checksum file {}", e))
+}
+
+/// A file containing the PID of this process, removed when dropped.
+///
+/// Dropping happens however the owning scope is left, including by panicking,
+/// so this can be used by monitoring scripts to see whether a backup is
+/// running.
+pub struct PidFile {
+	path: PathBuf,
+}
+
+impl PidFile {
+	/// Write the PID of this process to the given file.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` with a descriptive
+	/// message if the file cannot be created or written to.
+	pub fn create(fname: &str) -> Result<PidFile, BackupError> {
+		let mut file = File::create(fname).map_err(|e| BackupError::OtherError(
+				format!("Error creating PID file {}: {}", fname, e)))?;
+		writeln!(file, "{}", process::id())
+			.or_else(|e| Err(BackupError::OtherError(
+				format!("Error writing to PID file {}: {}", fname, e))))?;
+		debug!("Wrote PID {} to {}", process::id(), fname);
+		Ok(PidFile { path: PathBuf::from(fname) })
+	}
+}
+
+impl Drop for PidFile {
+	fn drop(&mut self) {
+		if let Err(e) = fs::remove_file(&self.path) {
+			warn!("Couldn't remove PID file {}: {}", self.path.display(), e);
+		}
+	}
+}
+
+/// Create a new tar file to which to write changed files, compressed as set
+/// by `options`, with its comment, if any, in its header, storing files smaller
+/// than its `compress_threshold` uncompressed (see `ConditionalCompressor`),
+/// and split into parts of about its `target_size`, if given (see
+/// `ArchiveWriter`). The archive is written under its `partial_path`, and must
+/// be moved into place once complete (see `ArchiveOutput::commit`).
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the output file cannot be created.
+pub fn create_archive(destination: &str, options: &ArchiveOptions)
+		-> Result<ArchiveWriter, BackupError> {
+	let comment = options.comment.as_deref();
+	let mut output = ArchiveOutput::new(destination, options.target_size);
+	let compressor = output.next_file()
+		.and_then(|file| ConditionalCompressor::new(file, options.compression, comment,
+			options.compress_threshold))
+		.map_err(|e| BackupError::OtherError(e.to_string()))?;
+	Ok(ArchiveWriter {
+		compressor: Some(compressor),
+		output,
+		comment: options.comment.clone(),
+		compression: options.compression,
+		threshold: options.compress_threshold,
+		entries: 0,
+		pending: None,
+	})
+}
+
+/// The name of part `number` (counting from 1) of an archive split into parts
+/// with `--target-size`: the destination with `_001`, `_002`, and so on
+/// inserted before a `.tar.gz`, `.tgz` or `.tar.lz4` extension, or appended if
+/// it has none of them.
+pub fn part_path(destination: &str, number: usize) -> String {
+	insert_before_extension(destination, &format!("_{:03}", number))
+}
+
+/// The destination for a backup started at `time` with `--interval`: the
+/// destination with the time (see `file_timestamp`) inserted before a
+/// `.tar.gz`, `.tgz` or `.tar.lz4` extension, or appended if it has none of
+/// them (so `backup.tar.gz` becomes e.g. `backup-20240101T120000Z.tar.gz`).
+pub fn timestamped_path(destination: &str, time: SystemTime) -> String {
+	insert_before_extension(destination, &format!("-{}", file_timestamp(time)))
+}
+
+/// Insert `suffix` into an archive's name before a `.tar.gz`, `.tgz` or
+/// `.tar.lz4` extension, or append it if it has none of them.
+fn insert_before_extension(destination: &str, suffix: &str) -> String {
+	for extension in &[".tar.gz", ".tgz", ".tar.lz4"] {
+		if let Some(base) = destination.strip_suffix(extension) {
+			return format!("{}{}{}", base, suffix, extension);
+		}
+	}
+	format!("{}{}", destination, suffix)
+}
+
+/// The files making up an archive: the file itself if it exists, or otherwise
+/// its parts (see `part_path`), if it was split and they exist.
+pub fn archive_parts(fname: &str) -> Vec<String> {
+	if Path::new(fname).exists() {
+		return vec![fname.to_string()];
+	}
+	let parts : Vec<String> = (1 ..)
+		.map(|number| part_path(fname, number))
+		.take_while(|part| Path::new(part).exists())
+		.collect();
+	if parts.is_empty() { vec![fname.to_string()] } else { parts }
+}
+
+/// The file or files an archive is written to.
+///
+/// Without a target size, this is just the destination. With one, the archive
+/// is split into parts (see `part_path`), each a complete gzipped tar file,
+/// and bytes written to the current part are counted so the writer can move
+/// on to the next once it reaches the target (see `full`). Files are written
+/// under their `partial_path`s until `commit` is called.
+pub struct ArchiveOutput {
+	destination: String,
+	target_size: Option<u64>,
+	parts: Vec<String>,
+	written: Rc<Cell<u64>>,
+	/// Write straight to the destination, as it's a device or named pipe (see
+	/// `special_destination`).
+	direct: bool,
+}
+
+impl ArchiveOutput {
+	/// Prepare to write an archive to `destination`, split into parts of about
+	/// `target_size` bytes, if given. If `destination` is a device or named
+	/// pipe, the archive is written to it directly, and can't be split.
+	pub fn new(destination: &str, target_size: Option<u64>) -> ArchiveOutput {
+		let direct = special_destination(destination).is_some();
+		ArchiveOutput {
+			destination: destination.to_string(),
+			target_size: if direct { None } else { target_size },
+			parts: Vec::new(),
+			written: Rc::new(Cell::new(0)),
+			direct,
+		}
+	}
+
+	/// Create the next file of the archive (the first being the destination
+	/// itself, unless the archive is split), returning a writer to it which
+	/// counts the bytes written.
+	///
+	/// # Errors
+	///
+	/// This function will return an I/O error with a descriptive message if the
+	/// file cannot be created.
+	pub fn next_file(&mut self) -> io::Result<CountingWriter<File>> {
+		let name = match self.target_size {
+			Some(_) => part_path(&self.destination, self.parts.len() + 1),
+			None => self.destination.clone()
+		};
+		let partial = if self.direct { name.clone() } else { partial_path(&name) };
+		let file = File::create(&partial).map_err(|e| io::Error::new(e.kind(),
+				format!("Error creating target file {}: {}", partial, e)))?;
+		if self.target_size.is_some() {
+			debug!("Writing archive part {}", name);
+		}
+		self.parts.push(name);
+		// The previous file's writer keeps its own count.
+		self.written = Rc::new(Cell::new(0));
+		Ok(CountingWriter { inner: file, written: self.written.clone() })
+	}
+
+	/// Whether the current file has reached the target size, if any.
+	pub fn full(&self) -> bool {
+		self.target_size.is_some_and(|target| self.written.get() >= target)
+	}
+
+	/// The names of the files written so far.
+	pub fn parts(&self) -> &[String] {
+		&self.parts
+	}
+
+	/// The total size of the files written, once they're complete.
+	pub fn size(&self) -> u64 {
+		// Devices and pipes don't have a size of their own.
+		if self.direct {
+			return self.written.get()
+		}
+		self.parts.iter().map(|part| fs::metadata(part).map(|m| m.len()).unwrap_or(0)).sum()
+	}
+
+	/// Move the completed files into place (see `commit_output`). Archives
+	/// written directly to a device or pipe are already in place.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` with a descriptive
+	/// message if a file can't be moved.
+	pub fn commit(&self) -> Result<(), BackupError> {
+		if self.direct {
+			return Ok(())
+		}
+		for part in &self.parts {
+			commit_output(part)?;
+		}
+		Ok(())
+	}
+}
+
+/// Writer counting the bytes written through it, for `ArchiveOutput`.
+pub struct CountingWriter<W: Write> {
+	inner: W,
+	written: Rc<Cell<u64>>,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let len = self.inner.write(buf)?;
+		self.written.set(self.written.get() + len as u64);
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl<W: Write + Seek> Seek for CountingWriter<W> {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		self.inner.seek(pos)
+	}
+}
+
+/// Writer for a compressed tar archive, as created by `create_archive`, which
+/// moves on to a new part between entries once the current one reaches the
+/// target size, if any (see `ArchiveOutput`).
+///
+/// The size of a part is only known as compressed data reaches it, so parts
+/// end up somewhat larger than the target, by up to the compressed size of
+/// the entry which crossed it. Each part is a complete archive, with its own
+/// end-of-archive marker, and `comment` in its header.
+///
+/// As for `ConditionalCompressor`, tar entries are appended with a `Builder`
+/// over the writer for each entry, after calling `start_entry`. The next part
+/// is only started once the entry is written to, so one which fails before
+/// writing anything (see `discard_entry`) doesn't leave an empty part.
+pub struct ArchiveWriter {
+	compressor: Option<ConditionalCompressor<CountingWriter<File>>>,
+	output: ArchiveOutput,
+	comment: Option<String>,
+	compression: Compression,
+	threshold: u64,
+	/// The number of entries started in the current part.
+	entries: u64,
+	/// The size of the entry `start_entry` was last called for, until it's
+	/// written to or discarded.
+	pending: Option<u64>,
+}
+
+impl ArchiveWriter {
+	/// Prepare to write the tar entry for a file of `size` bytes, first
+	/// finishing the current part and starting the next if it's full, as soon
+	/// as the entry is written to.
+	///
+	/// # Errors
+	///
+	/// Errors finishing a part or creating the next are returned by the first
+	/// write to the entry, so this currently always succeeds.
+	pub fn start_entry(&mut self, size: u64) -> io::Result<()> {
+		self.pending = Some(size);
+		Ok(())
+	}
+
+	/// Forget the entry last started with `start_entry`, which turned out not
+	/// to be written (e.g. as its file couldn't be opened).
+	pub fn discard_entry(&mut self) {
+		self.pending = None;
+	}
+
+	/// Actually start the entry `start_entry` was last called for, if any, as
+	/// it's about to be written to.
+	fn begin_pending_entry(&mut self) -> io::Result<()> {
+		let size = match self.pending.take() {
+			Some(size) => size,
+			None => return Ok(())
+		};
+		if self.entries > 0 && self.output.full() {
+			self.compressor.take().unwrap().finish()?;
+			let file = self.output.next_file()?;
+			let comment = self.comment.as_ref().map(|c| &c[..]);
+			self.compressor = Some(ConditionalCompressor::new(file, self.compression, comment,
+				self.threshold)?);
+			self.entries = 0;
+		}
+		self.entries += 1;
+		self.compressor.as_mut().unwrap().start_entry(size)
+	}
+
+	/// Finish writing the archive, returning its output files, which then need
+	/// to be moved into place (see `ArchiveOutput::commit`).
+	///
+	/// # Errors
+	///
+	/// This function will return any I/O error encountered writing.
+	pub fn finish(mut self) -> io::Result<ArchiveOutput> {
+		self.compressor.take().unwrap().finish()?;
+		Ok(self.output)
+	}
+}
+
+impl Write for ArchiveWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.begin_pending_entry()?;
+		self.compressor.as_mut().unwrap().write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.compressor.as_mut().unwrap().flush()
+	}
+}
+
+/// Writer for a compressed tar archive which compresses some entries and
+/// stores others uncompressed.
+///
+/// Small files don't compress well, and deflate's overhead can make them
+/// larger, so with gzip, entries for files smaller than the threshold are
+/// written in gzip members at compression level none (deflate's stored
+/// blocks). A new member is started whenever the level changes between
+/// entries; a sequence of gzip members is itself a valid gzip file (see
+/// `write_archive_parallel`). With a threshold of zero, or with lz4 (see
+/// `Compression::compresses`), every entry is compressed, in a single member
+/// or frame.
+///
+/// Tar entries are appended with a `Builder` over the compressor for each
+/// entry, after calling `start_entry`, and the end-of-archive marker is written
+/// by `finish`.
+pub struct ConditionalCompressor<W: Write> {
+	encoder: Option<Encoder<W>>,
+	compression: Compression,
+	compressed: bool,
+	threshold: u64,
+}
+
+impl<W: Write> ConditionalCompressor<W> {
+	/// Start writing a tar file compressed with `compression` to `writer`,
+	/// with `comment`, if given, in its (first member's) header.
+	///
+	/// # Errors
+	///
+	/// This function will return any I/O error encountered writing the
+	/// comment.
+	pub fn new(writer: W, compression: Compression, comment: Option<&str>, threshold: u64)
+			-> io::Result<ConditionalCompressor<W>> {
+		Ok(ConditionalCompressor {
+			encoder: Some(compression.encoder(writer, comment, true)?),
+			compression,
+			compressed: true,
+			threshold,
+		})
+	}
+
+	/// Prepare to write the tar entry for a file of `size` bytes, starting a
+	/// new member if it's to be compressed differently to the last.
+	///
+	/// # Errors
+	///
+	/// This function will return any I/O error encountered finishing the
+	/// previous member.
+	pub fn start_entry(&mut self, size: u64) -> io::Result<()> {
+		let compressed = self.compression.compresses(size, self.threshold);
+		if compressed != self.compressed {
+			let writer = self.encoder.take().unwrap().finish()?;
+			self.encoder = Some(self.compression.encoder(writer, None, compressed)?);
+			self.compressed = compressed;
+		}
+		Ok(())
+	}
+
+	/// Write the end-of-archive marker and finish the last member,
+	/// returning the underlying writer.
+	///
+	/// # Errors
+	///
+	/// This function will return any I/O error encountered writing.
+	pub fn finish(mut self) -> io::Result<W> {
+		self.write_all(&[0; 1024])?;
+		self.encoder.take().unwrap().finish()
+	}
+}
+
+impl<W: Write> Write for ConditionalCompressor<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.encoder.as_mut().unwrap().write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.encoder.as_mut().unwrap().flush()
+	}
+}
+
+/// Open a compressed tar file, as written by `create_archive` (or by
+/// `write_archive_parallel`, as several concatenated members), returning a
+/// reader over the decompressed tar data.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the file cannot be opened.
+pub fn open_archive(fname: &str) -> Result<Decoder<BufReader<File>>, BackupError> {
+	File::open(fname)
+		.and_then(|file| Decoder::new(BufReader::new(file))).map_err(|e| BackupError::OtherError(
+			format!("Error opening archive {}: {}", fname, e)))
+}
+
+/// Add tags (user-defined metadata) to an archive, in a PAX global header at
+/// its start, or to each of its parts, if it was split (see `archive_parts`).
+///
+/// Tags already in the archive are kept, unless replaced by one with the same
+/// key in `tags`, as is its comment. The archive is rewritten (recompressed as a single gzip
+/// member or lz4 frame) to a temporary file beside it, which then replaces it.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the archive cannot be read or rewritten; the original is left in
+/// place (though earlier parts may already have been tagged).
+pub fn tag_archive(archive_path: &str, tags: &HashMap<String, String>) -> Result<(), BackupError> {
+	for part in archive_parts(archive_path) {
+		tag_archive_file(&part, tags)?;
+	}
+	Ok(())
+}
+
+/// Add tags to a single archive file, as described for `tag_archive`.
+fn tag_archive_file(archive_path: &str, tags: &HashMap<String, String>) -> Result<(), BackupError> {
+	let mut reader = open_archive(archive_path)?;
+	let read_error = |e| BackupError::OtherError(format!("Error reading archive {}: {}", archive_path, e));
+	let (old_tags, rest) = archive::read_tags(&mut reader).map_err(&read_error)?;
+	let mut merged : BTreeMap<String, String> = old_tags.into_iter().collect();
+	merged.extend(tags.iter().map(|(key, value)| (key.clone(), value.clone())));
+	let merged : Vec<(String, String)> = merged.into_iter().collect();
+	let comment = reader.comment().map(|c| c.to_string());
+	let compression = reader.compression();
+
+	let temp_path = format!("{}.tmp", archive_path);
+	let result = File::create(&temp_path)
+		.map_err(|e| BackupError::OtherError(format!("Error creating {}: {}", temp_path, e)))
+		.and_then(|file| {
+			let write_error = |e| BackupError::OtherError(format!("Error writing {}: {}", temp_path, e));
+			let mut encoder = compression.encoder(file, comment.as_ref().map(|c| &c[..]), true)
+				.map_err(&write_error)?;
+			archive::write_tags(&mut encoder, &merged).map_err(&write_error)?;
+			encoder.write_all(&rest).map_err(&write_error)?;
+			io::copy(&mut reader, &mut encoder).map_err(read_error)?;
+			encoder.finish().map_err(write_error)?;
+			fs::rename(&temp_path, archive_path).map_err(|e| BackupError::OtherError(
+				format!("Error replacing {} with {}: {}", archive_path, temp_path, e)))
+		});
+	if result.is_err() {
+		let _ = fs::remove_file(&temp_path);
+	}
+	result
+}
+
+/// The contents of an archive, as found by `list_archive`.
+pub struct ArchiveListing {
+	/// The comment in the archive's header, if any.
+	pub comment: Option<String>,
+	/// The archive's tags (see `tag_archive`).
+	pub tags: archive::Tags,
+	/// The names of the files in the archive, in the order they appear.
+	pub files: Vec<String>,
+}
+
+/// List the comment, tags and files in an archive, or in all its parts, if it
+/// was split (see `archive_parts`), taking the comment and tags from the
+/// first. Zip archives (see `archive::is_zip`) are recognized and listed too,
+/// though they have no tags.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the archive cannot be read.
+pub fn list_archive(fname: &str) -> Result<ArchiveListing, BackupError> {
+	if is_zip_archive(fname) {
+		return list_zip_archive(fname)
+	}
+	let mut listing = ArchiveListing { comment: None, tags: Vec::new(), files: Vec::new() };
+	for (index, part) in archive_parts(fname).iter().enumerate() {
+		let mut reader = open_archive(part)?;
+		let read_error = |e| BackupError::OtherError(format!("Error reading archive {}: {}", part, e));
+		let (tags, rest) = archive::read_tags(&mut reader).map_err(&read_error)?;
+		if index == 0 {
+			listing.comment = reader.comment().map(|c| c.to_string());
+			listing.tags = tags;
+		}
+		let files = &mut listing.files;
+		archive::read_files(io::Cursor::new(rest).chain(reader), |name, _| {
+			files.push(name.to_string());
+			Ok(())
+		}).map_err(read_error)?;
+	}
+	Ok(listing)
+}
+
+/// The outcome of `write_archive`.
+#[derive(Debug, Default)]
+pub struct ArchiveReport {
+	/// The number of changed files archived.
+	pub archived: usize,
+	/// Filenames (as in the checksums) of changed files whose checksums
+	/// shouldn't be saved, so that the next backup archives them again: those
+	/// which couldn't be opened to archive, and so aren't in the archive, and
+	/// those which were changing as they were archived (see `Stability`).
+	pub failed: Vec<String>,
+	/// Filenames and new checksums of changed files which were checksummed
+	/// again as they were archived, because they'd changed since they were
+	/// first checksummed.
+	pub rechecksummed: Vec<(String, String)>,
+}
+
+impl ArchiveReport {
+	/// Record what became of the changed file with key `key`.
+	fn record(&mut self, key: &str, appended: Appended) {
+		match appended {
+			Appended::Archived(checksum) => {
+				self.archived += 1;
+				if let Some(checksum) = checksum {
+					self.rechecksummed.push((key.to_string(), checksum));
+				}
+			},
+			Appended::Torn(_) => {
+				self.archived += 1;
+				self.failed.push(key.to_string());
+			},
+			Appended::Failed(_) | Appended::Unstable(_) => self.failed.push(key.to_string()),
+		}
+	}
+
+	/// Whether `apply` would change any checksums.
+	pub fn changes_checksums(&self) -> bool {
+		!self.failed.is_empty() || !self.rechecksummed.is_empty()
+	}
+
+	/// Update the new checksums with the outcome of archiving: leaving out
+	/// files in `failed`, and replacing the checksums of files checksummed
+	/// again.
+	pub fn apply(&self, checksums: &mut HashMap<String, String>) {
+		for key in &self.failed {
+			checksums.remove(key);
+		}
+		for (key, checksum) in &self.rechecksummed {
+			checksums.insert(key.clone(), checksum.clone());
+		}
+	}
+}
+
+/// What became of a changed file appended to an archive (see
+/// `append_changed`). Each holds why, except `Archived`.
+#[derive(Debug)]
+enum Appended {
+	/// The file was archived, and checksummed again, giving this checksum, if
+	/// it had changed since it was checksummed.
+	Archived(Option<String>),
+	/// The file couldn't be opened, and nothing was written.
+	Failed(io::Error),
+	/// The file had changed since it was checksummed, and nothing was written
+	/// (with `Stability::skip`, or if it changed again as it was checksummed
+	/// again).
+	Unstable(String),
+	/// The file changed while it was archived, so its entry may hold a mix of
+	/// its old and new contents.
+	Torn(String),
+}
+
+impl Appended {
+	/// Whether anything was written for the file.
+	fn written(&self) -> bool {
+		match *self {
+			Appended::Archived(_) | Appended::Torn(_) => true,
+			Appended::Failed(_) | Appended::Unstable(_) => false,
+		}
+	}
+}
+
+/// List the comment and files in a zip archive, for `list_archive`.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the archive cannot be read.
+fn list_zip_archive(fname: &str) -> Result<ArchiveListing, BackupError> {
+	let read_error = |e: zip::result::ZipError| BackupError::OtherError(
+		format!("Error reading archive {}: {}", fname, e));
+	let file = File::open(fname)
+		.map_err(|e| BackupError::OtherError(format!("Error opening archive {}: {}", fname, e)))?;
+	let archive = ZipArchive::new(BufReader::new(file)).map_err(&read_error)?;
+	let comment = if archive.comment().is_empty() {
+		None
+	} else {
+		Some(String::from_utf8_lossy(archive.comment()).into_owned())
+	};
+	let files = archive.file_names()
+		.map(|name| name.map(|name| name.into_owned()))
+		.collect::<Result<Vec<String>, _>>()
+		.map_err(&read_error)?;
+	Ok(ArchiveListing { comment, tags: Vec::new(), files })
+}
+
+/// Options controlling how archives of changed files are written (see
+/// `write_archive`).
+#[derive(Clone,Debug)]
+pub struct ArchiveOptions {
+	/// Write tar headers in this format.
+	pub format: TarFormat,
+	/// Write this comment in the archive's header.
+	pub comment: Option<String>,
+	/// Compress the archive with this.
+	pub compression: Compression,
+	/// Store files smaller than this many bytes uncompressed (see
+	/// `ConditionalCompressor`).
+	pub compress_threshold: u64,
+	/// Split the archive into parts of about this many bytes (see
+	/// `ArchiveWriter`).
+	pub target_size: Option<u64>,
+	/// Compress files on this many threads (see `write_archive_parallel`).
+	pub threads: usize,
+	/// The form filenames in the checksums are normalized to (see
+	/// `WalkOptions::normalization`).
+	pub normalization: Normalization,
+	/// Copy unchanged files into this directory, in the same directory
+	/// structure as beneath the source root, so that together with the
+	/// archive it holds a complete snapshot.
+	pub backup_dir: Option<PathBuf>,
+	/// With `backup_dir`, link unchanged files into it from this previous
+	/// backup directory (see `link_unchanged_files`) instead of copying them.
+	/// Only `write_sorted` links them itself; otherwise, they're left for the
+	/// caller to link once the archive is written.
+	pub link_dest: Option<PathBuf>,
+	/// Report each file archived here.
+	pub progress: Option<Arc<Progress>>,
+	/// Record the files archived, the time taken, and the size of the archive
+	/// here.
+	pub stats: Arc<Stats>,
+	/// Read files as described for `WalkOptions::drop_caches`.
+	pub drop_caches: bool,
+	/// Limit the rate at which files are read with this.
+	pub throttle: Option<Arc<Throttle>>,
+	/// Check files for changes since they were checksummed (see `Stability`).
+	pub stability: Option<Arc<Stability>>,
+}
+
+impl ArchiveOptions {
+	/// The directory to copy unchanged files into as they're found, if any:
+	/// `backup_dir`, unless they're to be linked from `link_dest` instead.
+	fn copy_dir(&self) -> Option<&Path> {
+		match self.link_dest {
+			Some(_) => None,
+			None => self.backup_dir.as_deref()
+		}
+	}
+}
+
+/// Copy changed files to the given archive file.
+///
+/// The given file is written with a gzipped tar file containing all files in
+/// `new_checksums` with checksums absent or different from those in
+/// `old_checksums`, relative to their roots in `roots`, using the tar header format
+/// `format`. Sparse files are stored as sparse entries where the format and
+/// platform allow.
+///
+/// Files are archived in order of filename. If `options.threads` is greater
+/// than one, they're compressed in parallel (see `write_archive_parallel`), and
+/// written in the same order. Files are archived under their names as found on
+/// disk.
+///
+/// Unchanged files are copied into `options.backup_dir`, if given (see
+/// `ArchiveOptions`). `checksums`, if given, are embedded as the archive's
+/// first entry (see `archive::append_checksums`). The archive is otherwise
+/// written, and files read, as set by `options`.
+///
+/// Changed files which can't be opened (e.g. because they were deleted after
+/// being checksummed) are left out of the archive, recorded in `stats` as
+/// failed, and listed in the returned report, so that their checksums can be
+/// left out when saving, and they're archived as new by the next backup. The
+/// same goes for files which change as they're archived. With
+/// `options.stability`, files are also checked for changes since they were
+/// checksummed, and the report lists the new checksums of those checksummed
+/// again (see `Stability`). Embedded `checksums` are written first, so still
+/// include these files; the report is recorded after the files (see
+/// `append_outcome`), for `load_archived_checksums` to correct them.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the output file cannot be created or written to, if a file
+/// cannot be represented in the selected format or read once archiving it has
+/// begun, if an unchanged file cannot be copied, or if a file can't be
+/// archived and the run is strict (see `Stats::check_strict`), or a
+/// `BackupError::Cancelled` if the run is interrupted between files.
+pub fn write_archive(
+		new_checksums: &HashMap<String, String>,
+		old_checksums: &HashMap<String, String>,
+		roots: &SourceRoots,
+		destination: &str,
+		checksums: Option<&[u8]>,
+		options: &ArchiveOptions)
+		-> Result<ArchiveReport, BackupError> {
+	let changed = start_archive(new_checksums, old_checksums, roots, options)?;
+	let stats = &options.stats;
+	stats.phase_started(Phase::Archive);
+	let started = Instant::now();
+	let mut report = ArchiveReport::default();
+	let output = if options.threads > 1 {
+		let (output, parallel_report) = write_archive_parallel(&changed, roots, destination,
+			checksums, options)?;
+		report = parallel_report;
+		output
+	} else {
+		let write_error = |e: io::Error| archive_write_error(destination, None, e);
+		let mut archive = create_archive(destination, options)?;
+		if let Some(checksums) = checksums {
+			archive.start_entry(checksums.len() as u64).map_err(&write_error)?;
+			let mut builder = Builder::new(&mut archive);
+			let result = archive::append_checksums(&mut builder, checksums);
+			// Dropping the builder would write the end-of-archive marker.
+			mem::forget(builder);
+			result.map_err(&write_error)?;
+		}
+		for (fname, name) in &changed {
+			cancel::check()?;
+			stats.check_strict()?;
+			stats.file_started(Phase::Archive, name);
+			let appended = append_compressed(&mut archive, roots, name,
+				options.progress.as_deref(), options)?;
+			record_appended(stats, roots, name, &appended);
+			report.record(fname, appended);
+		}
+		// In the current part, however full, as a global header starting a part
+		// would be taken for tags. The entries are discarded for any files which
+		// couldn't be archived, so that part isn't one started just for them.
+		if checksums.is_some() {
+			append_outcome(&mut archive, &report).map_err(&write_error)?;
+		}
+		archive.finish().map_err(&write_error)?
+	};
+	stats.check_strict()?;
+	output.commit()?;
+	stats.elapsed(Phase::Archive, started.elapsed());
+	stats.archive_written(output.size());
+	Ok(report)
+}
+
+/// Copy changed files to the given zip file.
+///
+/// This is the counterpart of `write_archive` for zip archives (see
+/// `archive::ArchiveFormat`): the same files are archived, in the same order
+/// and under the same names, unchanged files are copied into
+/// `options.backup_dir`, if given, and changed files which can't be opened are
+/// left out and reported. Each file is deflated, or stored if it's smaller than
+/// `options.compress_threshold` bytes, with its modification time (in UTC) and,
+/// on Unix, its permissions. The comment, if any, is the zip file's comment.
+/// `checksums`, if given, are formatted with `header` as by
+/// `format_checksums` and stored as `archive::CHECKSUMS_ENTRY`. Zip archives
+/// are read through the directory at their end, so rather than going first,
+/// as in a tar archive, this goes last, once the files are written, with the
+/// outcome of archiving applied (see `ArchiveReport::apply`), so that it
+/// matches the files archived. Files are read as for `write_archive`, and the
+/// tar format, compression, target size and threads are ignored. The file is
+/// written under its `partial_path`, and moved into place once it's complete.
 ///
 /// # Errors
 ///
-/// This function will return a `MainError::OtherError` with a descriptive
-/// message if it experiences an I/O error.
-pub fn load_checksums(fname: &str) -> Result<HashMap<String, String>, MainError> {
-	match File::open(fname) {
-		Ok(checksums_file) => {
-			let mut checksums : HashMap<String, String> = HashMap::new();
-			let checksums_reader = BufReader::new(&checksums_file);
-			for line in checksums_reader.lines() {
-				match line {
-					Ok(l) => {
-						let mut fields = l.split_whitespace();
-						let checksum = match fields.next() {
-							Some(f) => f,
-							None => continue
-						};
-						let filename = match fields.next() {
-							Some(f) => f,
-							None => continue
-						};
-						trace!("Previous version checksum: {}\t{}", filename, checksum);
-						checksums.insert(filename.to_string(), checksum.to_string());
-					},
-					Err(_) => continue
-				}
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the output file cannot be created or written to, if a file
+/// cannot be read once archiving it has begun, or if an unchanged file cannot
+/// be copied, or a `BackupError::Cancelled` if the run is interrupted between
+/// files.
+pub fn write_zip_archive(
+		new_checksums: &HashMap<String, String>,
+		old_checksums: &HashMap<String, String>,
+		roots: &SourceRoots,
+		destination: &str,
+		checksums: Option<&HashMap<String, String>>,
+		header: &[(&str, String)],
+		options: &ArchiveOptions)
+		-> Result<ArchiveReport, BackupError> {
+	let changed = start_archive(new_checksums, old_checksums, roots, options)?;
+	let stats = &options.stats;
+	stats.phase_started(Phase::Archive);
+	let started = Instant::now();
+	let write_error = |e: io::Error| archive_write_error(destination, None, e);
+	let mut output = ArchiveOutput::new(destination, None);
+	let mut zip = ZipWriter::new(output.next_file()
+		.map_err(|e| BackupError::OtherError(e.to_string()))?);
+	if let Some(ref comment) = options.comment {
+		zip.set_comment(comment.as_str()).map_err(|e| write_error(e.into()))?;
+	}
+	let mut report = ArchiveReport::default();
+	let mut buf = vec![0u8; ZIP_BUFFER_SIZE];
+	for (fname, name) in &changed {
+		cancel::check()?;
+		stats.check_strict()?;
+		stats.file_started(Phase::Archive, name);
+		let appended = append_zip_file(&mut zip, destination, roots, name, &mut buf,
+			options)?;
+		record_appended(stats, roots, name, &appended);
+		report.record(fname, appended);
+	}
+	if let Some(checksums) = checksums {
+		let mut checksums = checksums.clone();
+		report.apply(&mut checksums);
+		let options = SimpleFileOptions::default()
+			.compression_method(CompressionMethod::Deflated);
+		zip.start_file(archive::CHECKSUMS_ENTRY, options).map_err(|e| write_error(e.into()))?;
+		zip.write_all(&format_checksums(&checksums, header)).map_err(&write_error)?;
+	}
+	drop(zip.finish().map_err(|e| write_error(e.into()))?);
+	stats.check_strict()?;
+	output.commit()?;
+	stats.elapsed(Phase::Archive, started.elapsed());
+	stats.archive_written(output.size());
+	Ok(report)
+}
+
+/// The size of the buffer through which files are copied into zip archives.
+const ZIP_BUFFER_SIZE: usize = 64 << 10;
+
+/// Append a changed file to a zip archive being written to `destination`, as
+/// `append_changed` does for tar archives, deflating it unless it's smaller
+/// than `options.compress_threshold` bytes, and copying it through `buf`.
+fn append_zip_file<W: Write + Seek>(
+		zip: &mut ZipWriter<W>,
+		destination: &str,
+		roots: &SourceRoots,
+		fname: &str,
+		buf: &mut [u8],
+		options: &ArchiveOptions)
+		-> Result<Appended, BackupError> {
+	let drop_caches = options.drop_caches;
+	let throttle = options.throttle.as_deref();
+	let name = match roots.archive_name(fname) {
+		Some(name) => name,
+		None => {
+			log_fields!(LogLevel::Warn, {path: fname}, "Not archiving {} (--strip-components leaves nothing of its path)", fname);
+			return Ok(Appended::Archived(None))
+		}
+	};
+	let mut file = match open_source(&roots.path(fname), drop_caches) {
+		Ok(file) => file,
+		Err(e) => return Ok(Appended::Failed(e))
+	};
+	let stability = options.stability.as_deref();
+	let checksum = match check_unchanged(&mut file, fname, stability, throttle)? {
+		Ok((_, checksum)) => checksum,
+		Err(detail) => return Ok(Appended::Unstable(detail))
+	};
+	let read_error = |e: io::Error| BackupError::OtherError(format!("Error archiving {}: {}", fname, e));
+	let meta = file.metadata().map_err(&read_error)?;
+	let entry_options = SimpleFileOptions::default()
+		.large_file(meta.len() >= zip::ZIP64_BYTES_THR)
+		.last_modified_time(meta.modified().map(zip_time).unwrap_or_default());
+	let entry_options = if meta.len() < options.compress_threshold {
+		entry_options.compression_method(CompressionMethod::Stored)
+	} else {
+		entry_options.compression_method(CompressionMethod::Deflated)
+			.compression_level(Some(Compression::Gzip.level() as i64))
+	};
+	zip.start_file(name, zip_permissions(entry_options, &meta))
+		.map_err(|e| archive_write_error(destination, Some(fname), e.into()))?;
+	let mut file_progress = FileProgress::new(fname, meta.len(), options.progress.as_deref());
+	let mut copied = 0;
+	loop {
+		let len = read_retrying(&mut file, buf).map_err(&read_error)?;
+		if len == 0 {
+			break
+		}
+		if let Some(throttle) = throttle {
+			throttle.consume(len as u64);
+		}
+		file_progress.read(len as u64);
+		zip.write_all(&buf[.. len]).map_err(|e| archive_write_error(destination, Some(fname), e))?;
+		copied += len as u64;
+	}
+	file_progress.done(meta.len());
+	if drop_caches {
+		drop_cached(&file);
+	}
+	// Zip entries record their own length, so a file which grew or shrank
+	// leaves the archive readable, but its contents may still be torn.
+	Ok(match changed_while_archived(&file, FileState::of(&meta), meta.len().saturating_sub(copied)) {
+		Some(detail) => Appended::Torn(detail),
+		None => Appended::Archived(checksum)
+	})
+}
+
+/// Convert a time to a zip timestamp, in UTC (zip files don't say which time
+/// zone they use), or the earliest time zip can represent (the start of 1980)
+/// if it's out of range.
+fn zip_time(time: SystemTime) -> DateTime {
+	let (year, month, day, secs) = civil_date(time);
+	DateTime::from_date_and_time(cmp::min(year, u16::MAX as u64) as u16, month as u8, day as u8,
+			(secs / 3600 % 24) as u8, (secs / 60 % 60) as u8, (secs % 60) as u8)
+		.unwrap_or_default()
+}
+
+/// Record a file's permissions in the options for its zip entry.
+#[cfg(unix)]
+fn zip_permissions(options: SimpleFileOptions, meta: &fs::Metadata) -> SimpleFileOptions {
+	use std::os::unix::fs::PermissionsExt;
+	options.unix_permissions(meta.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn zip_permissions(options: SimpleFileOptions, _meta: &fs::Metadata) -> SimpleFileOptions {
+	options
+}
+
+/// Find the changed files to archive, for `write_archive` or
+/// `write_zip_archive`, as pairs of their keys and names on disk.
+///
+/// Files are archived in order of name, so that the same files always make
+/// the same archive, however many threads compress it. The archiving phase is
+/// started in `options.progress`, if given, and unchanged files are copied into
+/// `options.backup_dir`, if given (see `ArchiveOptions`).
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if an unchanged file cannot be copied.
+fn start_archive(
+		new_checksums: &HashMap<String, String>,
+		old_checksums: &HashMap<String, String>,
+		roots: &SourceRoots,
+		options: &ArchiveOptions)
+		-> Result<Vec<(String, String)>, BackupError> {
+	let normalization = options.normalization;
+	let mut changed : Vec<(String, String)> = new_checksums.iter()
+		.filter(|&(fname, hash)| is_changed(fname, hash, old_checksums))
+		.map(|(fname, _)| (fname.clone(), on_disk_name(roots, fname, normalization)))
+		.collect();
+	changed.sort();
+	if let Some(ref progress) = options.progress {
+		let size = changed.iter()
+			.map(|(_, name)| fs::metadata(roots.path(name)).map(|m| m.len()).unwrap_or(0))
+			.sum();
+		progress.start("Archiving", changed.len() as u64, size);
+	}
+	for (fname, hash) in new_checksums {
+		if old_checksums.get(fname) == Some(hash) {
+			log_fields!(LogLevel::Trace, {path: fname, checksum: hash}, "Matched hashes, not archiving: {}\t{}", fname, hash);
+			if let Some(dir) = options.copy_dir() {
+				copy_unchanged(roots, dir, &on_disk_name(roots, fname, normalization))?;
+			}
+		}
+	}
+	Ok(changed)
+}
+
+/// Log and record in `stats` what became of a changed file appended to an
+/// archive, found beneath its root in `roots`.
+fn record_appended(stats: &Stats, roots: &SourceRoots, name: &str, appended: &Appended) {
+	let done = || stats.file_done(Phase::Archive, name,
+		fs::metadata(roots.path(name)).map(|m| m.len()).unwrap_or(0));
+	match *appended {
+		Appended::Archived(_) => done(),
+		Appended::Failed(ref error) => {
+			log_fields!(LogLevel::Warn, {path: name, error: error.to_string()},
+				"Couldn't archive {}, leaving it for the next backup: {}", name, error);
+			stats.file_failed(name, &error.to_string());
+			if error.kind() == io::ErrorKind::PermissionDenied {
+				stats.file_denied(name);
 			}
-			checksums.shrink_to_fit();
-			Ok(checksums)
 		},
-		Err(e) => Err(MainError::OtherError(format!("Couldn't open checksums file: {}", e)))
+		Appended::Unstable(ref detail) => {
+			log_fields!(LogLevel::Warn, {path: name, reason: "unstable", detail: detail},
+				"Not archiving {}, leaving it for the next backup: it {}", name, detail);
+			stats.file_unstable(name, detail);
+		},
+		Appended::Torn(ref detail) => {
+			done();
+			log_fields!(LogLevel::Warn, {path: name, reason: "unstable", detail: detail},
+				"Archived {}, but it {}, so the next backup will archive it again", name, detail);
+			stats.file_unstable(name, detail);
+		},
 	}
 }
 
-/// Checksum all the files in a given directory.
+/// Copy an unchanged file from beneath its root to the same place beneath
+/// `backup_dir`, creating directories as needed.
+fn copy_unchanged(roots: &SourceRoots, backup_dir: &Path, fname: &str)
+		-> Result<(), BackupError> {
+	let target = key_to_path(backup_dir, fname);
+	log_fields!(LogLevel::Trace, {path: fname, target: target.display().to_string()},
+		"Copying unchanged file {} to {}", fname, target.display());
+	if let Some(parent) = target.parent() {
+		fs::create_dir_all(parent).map_err(|e| BackupError::OtherError(
+				format!("Error creating directory {}: {}", parent.display(), e)))?;
+	}
+	fs::copy(roots.path(fname), &target)
+		.map(|_| ()).map_err(|e| BackupError::OtherError(
+			format!("Error copying {} to {}: {}", fname, target.display(), e)))
+}
+
+/// Hard link unchanged files into a backup directory from a previous one.
 ///
-/// All the entries in `sources` are read. If they are directories, they are
-/// walked fully, and all the files they contain are checksummed; if they are
-/// files, they are themselves checksummed. The filenames, relative to
-/// `source_root`, and checksums are inserted into a map from filenames to
-/// sums. Files which cannot be opened are skipped.
+/// Each file in `new_checksums` whose checksum is the same as in
+/// `old_checksums` is linked from the same place beneath `prev_dir` to beneath
+/// `dest_dir`, creating directories as needed. If that fails (e.g. the file
+/// isn't in `prev_dir`, or the directories are on different filesystems), the
+/// file is copied from beneath its root in `roots` instead. Filenames are taken
+/// to be normalized with `normalization`, and files are placed under their
+/// names as found on disk.
 ///
-/// # Panics
+/// # Errors
 ///
-/// Probably, if you have it walk something weird which is neither a directory
-/// nor a normal file.
-pub fn checksum_directory(sources: &[String], source_root: &PathBuf)
-		-> HashMap<String, String> {
-	let mut checksums : HashMap<String, String> = HashMap::new();
-	//TODO: Make this runtime-swappable
-	let mut sha1 = Sha1::new();
-	//NOTE: Consider making this runtime-configurable? 
-	let mut buf = [0u8; 1<<20];
-	for source in sources {
-		let mut source_path = source_root.clone();
-		source_path.push(source);
-		for entry in WalkDir::new(&source_path).into_iter().filter_map(|e| e.ok()) {
-			let path = entry.path();
-			if !path.is_file() {
-				trace!("Skipping {} (not a file)", path.display());
-				continue
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if a file can be neither linked nor copied.
+pub fn link_unchanged_files(
+		new_checksums: &HashMap<String, String>,
+		old_checksums: &HashMap<String, String>,
+		roots: &SourceRoots,
+		normalization: Normalization,
+		prev_dir: &Path,
+		dest_dir: &Path)
+		-> Result<(), BackupError> {
+	for (fname, hash) in new_checksums {
+		if is_changed(fname, hash, old_checksums) {
+			continue
+		}
+		cancel::check()?;
+		link_unchanged(roots, prev_dir, dest_dir, &on_disk_name(roots, fname, normalization))?;
+	}
+	Ok(())
+}
+
+/// Hard link an unchanged file from beneath `prev_dir` to the same place
+/// beneath `dest_dir`, or copy it from beneath its root if that fails (see
+/// `link_unchanged_files`).
+fn link_unchanged(roots: &SourceRoots, prev_dir: &Path, dest_dir: &Path, name: &str)
+		-> Result<(), BackupError> {
+	let source = key_to_path(prev_dir, name);
+	let target = key_to_path(dest_dir, name);
+	if let Some(parent) = target.parent() {
+		fs::create_dir_all(parent).map_err(|e| BackupError::OtherError(
+				format!("Error creating directory {}: {}", parent.display(), e)))?;
+	}
+	match fs::hard_link(&source, &target) {
+		Ok(()) => log_fields!(LogLevel::Trace, {path: name, source: source.display().to_string()},
+			"Linked unchanged file {} from {}", name, source.display()),
+		Err(e) => {
+			log_fields!(LogLevel::Trace, {path: name, source: source.display().to_string(), error: e.to_string()},
+				"Couldn't link {} from {} ({}); copying", name, source.display(), e);
+			copy_unchanged(roots, dest_dir, name)?;
+		}
+	}
+	Ok(())
+}
+
+/// Write the archive and new checksum file from sorted checksums, without
+/// holding all the checksums in memory.
+///
+/// This has the same results as `write_archive` with one thread, then
+/// `link_unchanged_files` (if `options.link_dest` is given), then
+/// `save_checksums` (if any `checksums_fnames` are given), but compares
+/// `new_checksums` against `old_checksums` one file at a time, in order of
+/// filename (see `manifest::compare_sorted`), archiving, recording, and
+/// copying or linking each file as it goes. The checksum files are only moved into place once the
+/// archive has been written. Changed files which can't be opened are left out
+/// of both the archive and the checksum files (see `write_archive`), so
+/// they're archived as new by the next backup. The archive is written, and
+/// files read, as set by `options`, but with one thread, and without reporting
+/// progress.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the archive or checksum file cannot be created or written to, if
+/// a file cannot be archived, copied, or linked, or if the sorted checksums
+/// can't be read.
+pub fn write_sorted(
+		new_checksums: SortedManifest,
+		old_checksums: Option<SortedManifest>,
+		roots: &SourceRoots,
+		destination: &str,
+		checksums_fnames: &[String],
+		header: &[(&str, String)],
+		options: &ArchiveOptions)
+		-> Result<(), BackupError> {
+	options.stats.phase_started(Phase::Archive);
+	let started = Instant::now();
+	let mut checksums_file = if checksums_fnames.is_empty() {
+		None
+	} else {
+		Some(create_checksum_file(checksums_fnames, header)?)
+	};
+	let mut archive = create_archive(destination, options)?;
+	manifest::compare_sorted(new_checksums, old_checksums, |key, mut value, changed, _| {
+		cancel::check()?;
+		options.stats.check_strict()?;
+		let name = on_disk_name(roots, &key, options.normalization);
+		if changed {
+			options.stats.file_started(Phase::Archive, &name);
+			options.stats.compared(1, 0);
+			let appended = append_compressed(&mut archive, roots, &name, None, options)?;
+			record_appended(&options.stats, roots, &name, &appended);
+			match appended {
+				Appended::Archived(Some(checksum)) => value = checksum,
+				Appended::Archived(None) => (),
+				_ => return Ok(())
+			}
+		} else {
+			options.stats.compared(0, 1);
+			match (options.backup_dir.as_deref(), options.link_dest.as_deref()) {
+				(Some(dir), Some(prev)) => link_unchanged(roots, prev, dir, &name)?,
+				(Some(dir), None) => copy_unchanged(roots, dir, &name)?,
+				_ => ()
+			}
+		}
+		if let Some(ref mut file) = checksums_file {
+			file.write_all(format_checksum_line(&key, &value).as_bytes())
+				.map_err(checksum_write_error)?;
+		}
+		Ok(())
+	})?;
+	let output = archive.finish().map_err(|e| archive_write_error(destination, None, e))?;
+	options.stats.check_strict()?;
+	output.commit()?;
+	if let Some(mut file) = checksums_file {
+		file.flush().map_err(checksum_write_error)?;
+		drop(file);
+		commit_checksum_files(checksums_fnames)?;
+	}
+	options.stats.elapsed(Phase::Archive, started.elapsed());
+	options.stats.archive_written(output.size());
+	Ok(())
+}
+
+/// Files larger than this are compressed on the writing thread rather than
+/// buffered in memory by a worker.
+const PARALLEL_MAX_BUFFERED: u64 = 64 << 20;
+
+/// Write the given files to a tar file compressed with `compression`,
+/// compressing them on `threads` worker threads.
+///
+/// Each file's tar entry is compressed independently, as a separate gzip
+/// member or lz4 frame, and the members are written to the destination in
+/// order, followed by a final member containing the end-of-archive marker. A
+/// sequence of gzip members is itself a valid gzip file, which gunzip and tar
+/// read as a single stream, and likewise for lz4 frames. Compression is
+/// slightly worse than for a single stream, since each member starts with an
+/// empty dictionary. Each file is recorded in `stats` as it's written. The
+/// header's comment and any embedded checksums (see `write_archive`) go in a
+/// first member of their own, and the outcome of archiving, if it doesn't
+/// match them, in a last one. Files smaller than `options.compress_threshold`
+/// bytes are stored uncompressed.
+///
+/// To bound memory use, at most two files per thread are in flight at once,
+/// and files larger than `PARALLEL_MAX_BUFFERED` are compressed directly to
+/// the destination when their turn comes.
+///
+/// With `options.target_size`, the archive is split into parts as described for
+/// `ArchiveWriter`, moving on to the next part between members. `changed`
+/// holds each file's key (as in the checksums) and name on disk. Returns the
+/// output files, which need to be moved into place (see
+/// `ArchiveOutput::commit`), and what became of the files (see
+/// `write_archive`).
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the output file cannot be created or written to, or if a file
+/// cannot be archived.
+fn write_archive_parallel(
+		changed: &[(String, String)],
+		roots: &SourceRoots,
+		destination: &str,
+		checksums: Option<&[u8]>,
+		options: &ArchiveOptions)
+		-> Result<(ArchiveOutput, ArchiveReport), BackupError> {
+	let stats = &options.stats;
+	let compression = options.compression;
+	let comment = options.comment.as_deref();
+	let threads = options.threads;
+	let mut output = ArchiveOutput::new(destination, options.target_size);
+	let mut file = output.next_file().map_err(|e| BackupError::OtherError(e.to_string()))?;
+	let write_error = |e: io::Error| archive_write_error(destination, None, e);
+	// The comment goes in the first member, as readers only look at the first
+	// member's header.
+	if comment.is_some() || checksums.is_some() {
+		let mut encoder = compression.encoder(&mut file, comment, true).map_err(&write_error)?;
+		if let Some(checksums) = checksums {
+			let mut archive = Builder::new(&mut encoder);
+			archive::append_checksums(&mut archive, checksums).map_err(&write_error)?;
+			// Dropping the builder would write the end-of-archive marker.
+			mem::forget(archive);
+		}
+		encoder.finish().map_err(&write_error)?;
+	}
+	let end = compression.compress(&[0; 1024], true).map_err(&write_error)?;
+	let mut written_in_part = 0;
+
+	let (job_tx, job_rx) = mpsc::channel::<(usize, String)>();
+	let job_rx = Arc::new(Mutex::new(job_rx));
+	let (result_tx, result_rx) = mpsc::channel();
+	for _ in 0 .. threads {
+		let job_rx = job_rx.clone();
+		let result_tx = result_tx.clone();
+		let roots = roots.clone();
+		let destination = destination.to_string();
+		let options = options.clone();
+		thread::spawn(move || {
+			loop {
+				let job = job_rx.lock().unwrap().recv();
+				let (index, fname) = match job {
+					Ok(job) => job,
+					Err(_) => break
+				};
+				let member = compress_entry(&roots, &destination, &fname, &options);
+				if result_tx.send((index, Some(member))).is_err() {
+					break
+				}
+			}
+		});
+	}
+
+	let mut next_job = 0;
+	let mut next_write = 0;
+	let mut pending = BTreeMap::new();
+	let mut report = ArchiveReport::default();
+	while next_write < changed.len() {
+		cancel::check()?;
+		stats.check_strict()?;
+		while next_job < changed.len() && next_job - next_write < threads * 2 {
+			let fname = &changed[next_job].1;
+			let size = fs::metadata(roots.path(fname)).map(|m| m.len()).unwrap_or(0);
+			if size > PARALLEL_MAX_BUFFERED {
+				pending.insert(next_job, None);
+			} else {
+				// Workers only exit once this sender is dropped, so this can't fail.
+				job_tx.send((next_job, fname.clone())).unwrap();
+			}
+			next_job += 1;
+		}
+		if !pending.contains_key(&next_write) {
+			match result_rx.recv() {
+				Ok((index, member)) => { pending.insert(index, member); },
+				Err(_) => return Err(BackupError::OtherError(
+					"Archive compression threads exited unexpectedly".to_string()))
 			}
-			let open_result = File::open(path);
-			match open_result {
-				Ok(mut file) => {
-					let mut read_len: usize = 1;
-					while read_len > 0 {
-						read_len = file.read(&mut buf).unwrap();
-						sha1.input(&buf[0 .. read_len]);
+		}
+		while let Some(member) = pending.remove(&next_write) {
+			let (ref key, ref fname) = changed[next_write];
+			next_write += 1;
+			stats.file_started(Phase::Archive, fname);
+			let member = match member {
+				Some(member) => {
+					let (appended, member) = member?;
+					if !appended.written() {
+						record_appended(stats, roots, fname, &appended);
+						report.record(key, appended);
+						continue
 					}
-					let key = path.strip_prefix(&source_root)
-						.and_then(|p| Ok(p.to_str().unwrap().to_string()))
-						.unwrap_or(path.to_str().unwrap().to_string());
-					let value = sha1.result_str().to_string();
-					trace!("Current version checksum: {}\t{}", key, value);
-					checksums.insert(key, value);
-					sha1.reset();
+					Some((appended, member))
 				},
-				Err(e) => {
-					//TODO: There are probably some cases where we should abort here.
-					trace!("Skipping {} ({})", path.display(), e);
-					continue
+				None => None
+			};
+			if written_in_part > 0 && output.full() {
+				file.write_all(&end).map_err(&write_error)?;
+				drop(file);
+				file = output.next_file().map_err(|e| BackupError::OtherError(e.to_string()))?;
+				if comment.is_some() {
+					compression.encoder(&mut file, comment, true)
+						.and_then(|encoder| encoder.finish())
+						.map_err(&write_error)?;
 				}
+				written_in_part = 0;
 			}
+			written_in_part += 1;
+			let appended = match member {
+				Some((appended, member)) => {
+					file.write_all(&member).map_err(&write_error)?;
+					if let Some(ref progress) = options.progress {
+						progress.file_done(fname, fs::metadata(roots.path(fname)).map(|m| m.len()).unwrap_or(0));
+					}
+					appended
+				},
+				None => {
+					log_fields!(LogLevel::Trace, {path: fname}, "Compressing {} on the writing thread (too large to buffer)", fname);
+					let size = fs::metadata(roots.path(fname)).map(|m| m.len()).unwrap_or(0);
+					let compresses = compression.compresses(size,
+						options.compress_threshold);
+					let mut encoder = compression.encoder(&mut file, None, compresses)
+						.map_err(&write_error)?;
+					let appended = {
+						let mut archive = Builder::new(&mut encoder);
+						let appended = append_changed(&mut archive, destination, roots, fname,
+							options.progress.as_deref(), options);
+						// Dropping the builder would write the end-of-archive marker.
+						mem::forget(archive);
+						appended?
+					};
+					// An empty member is harmless if nothing was written.
+					encoder.finish().map_err(&write_error)?;
+					appended
+				}
+			};
+			record_appended(stats, roots, fname, &appended);
+			report.record(key, appended);
 		}
 	}
-	checksums.shrink_to_fit();
-	checksums
+	if checksums.is_some() && report.changes_checksums() {
+		let mut encoder = compression.encoder(&mut file, None, true).map_err(&write_error)?;
+		append_outcome(&mut encoder, &report).map_err(&write_error)?;
+		encoder.finish().map_err(&write_error)?;
+	}
+	file.write_all(&end).map_err(&write_error)?;
+	Ok((output, report))
+}
+
+/// Record the outcome of archiving after the files in an archive with embedded
+/// checksums, if they don't all match them (see `archive::append_outcome`).
+///
+/// # Errors
+///
+/// This function will return any I/O error encountered writing.
+fn append_outcome<W: Write>(writer: W, report: &ArchiveReport) -> io::Result<()> {
+	if !report.changes_checksums() {
+		return Ok(())
+	}
+	let mut archive = Builder::new(writer);
+	let result = archive::append_outcome(&mut archive, &report.failed, &report.rechecksummed);
+	// Dropping the builder would write the end-of-archive marker.
+	mem::forget(archive);
+	result
+}
+
+/// Build the tar entry for a changed file and compress it as a gzip member or
+/// lz4 frame (or store it, if the file is smaller than
+/// `options.compress_threshold` bytes; see `Compression::compresses`),
+/// returning what became of it (as for `append_changed`) along with the
+/// member, which is empty if nothing was written.
+fn compress_entry(
+		roots: &SourceRoots,
+		destination: &str,
+		fname: &str,
+		options: &ArchiveOptions)
+		-> Result<(Appended, Vec<u8>), BackupError> {
+	let mut archive = Builder::new(Vec::new());
+	let appended = append_changed(&mut archive, destination, roots, fname, None, options)?;
+	if !appended.written() {
+		return Ok((appended, Vec::new()))
+	}
+	let mut entry = archive.into_inner().map_err(|e| BackupError::OtherError(
+			format!("Error archiving {}: {}", fname, e)))?;
+	// `into_inner` appends the end-of-archive marker, which is written only
+	// once, at the very end.
+	let len = entry.len() - 1024;
+	entry.truncate(len);
+	let size = fs::metadata(roots.path(fname)).map(|m| m.len()).unwrap_or(0);
+	let compression = options.compression;
+	compression.compress(&entry, compression.compresses(size, options.compress_threshold))
+		.map(|member| (appended, member)).map_err(|e| BackupError::OtherError(
+			format!("Error compressing {}: {}", fname, e)))
+}
+
+/// Count the files in `new_checksums` with checksums absent from or different
+/// to those in `old_checksums`.
+pub fn count_changed(new_checksums: &HashMap<String, String>, old_checksums: &HashMap<String, String>)
+		-> usize {
+	new_checksums.iter().filter(|&(fname, hash)| is_changed(fname, hash, old_checksums)).count()
+}
+
+/// Find the total size of the files in `new_checksums` with checksums absent
+/// from or different to those in `old_checksums`, as they are now on disk.
+/// Files which can't be found are counted as empty.
+pub fn changed_size(
+		new_checksums: &HashMap<String, String>,
+		old_checksums: &HashMap<String, String>,
+		roots: &SourceRoots,
+		normalization: Normalization)
+		-> u64 {
+	new_checksums.iter()
+		.filter(|&(fname, hash)| is_changed(fname, hash, old_checksums))
+		.filter_map(|(fname, _)| source_stat(roots, fname, normalization))
+		.map(|(size, _)| size)
+		.sum()
 }
 
-/// Save checksums to a given file.
+/// Leave changes to be picked up by a later run.
 ///
-/// The given file is written with tab-separated filename/checksum pairs.
+/// Returns `new_checksums` with each changed file's checksum replaced by its
+/// checksum from `old_checksums`, and with new files left out, so that saving
+/// the result records the files which haven't changed without losing track of
+/// those which have.
+pub fn defer_changes(new_checksums: &HashMap<String, String>, old_checksums: &HashMap<String, String>)
+		-> HashMap<String, String> {
+	new_checksums.keys().filter_map(|fname| old_checksums.get(fname).map(|old| (fname.clone(), old.clone())))
+		.collect()
+}
+
+/// The differences between a source directory and a backup of it, found by
+/// `compare_backup`. Each list is sorted.
+#[derive(Debug)]
+pub struct Comparison {
+	/// Files in the source but not the backup.
+	pub only_in_source: Vec<String>,
+	/// Files in the backup but not the source.
+	pub only_in_backup: Vec<String>,
+	/// Files in both, whose contents differ.
+	pub differing: Vec<String>,
+}
+
+/// Compare the files in a source directory against a backup of them: a
+/// checksum file listing every file backed up, and an archive containing some
+/// of them (e.g. those changed since the previous backup).
+///
+/// Files are checksummed as by `checksum_directory`, and files in the archive
+/// (opened with `open_archive`) with hashers from `make_hasher`. A file
+/// differs if its checksum doesn't match `checksums`, or its contents don't
+/// match the archive's copy. Files found in neither `checksums` nor the archive
+/// are only in the source; files in either but not found in the source are only
+/// in the backup. Names in the archive are normalized with
+/// `options.normalization`, like filenames in the source. Checksums embedded in
+/// the archive (see `archive::append_checksums`) aren't compared. Files which couldn't
+/// be read (see `WalkOptions::ignore_errors`) are left out of the comparison.
 ///
 /// # Errors
 ///
-/// This function will return a `MainError::OtherError` with a descriptive
-/// message if it the output file cannot be created or written to.
-pub fn save_checksums(checksums: &HashMap<String, String>, fname:&str)
-		-> Result<(), MainError> {
-	match File::create(fname) {
-		Ok(mut file) => {
-			for (key, value) in checksums {
-				try!(file.write_all(
-					&(format!("{}\t{}\n", value, key).into_bytes()))
-					.or_else(|e| Err(MainError::OtherError(
-						format!("Error writing to checksum file {}: {}", fname, e)))));
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the archive cannot be read, or a file cannot be read unless
+/// `options.ignore_errors` is set.
+pub fn compare_backup<F>(
+		sources: &[String],
+		roots: &SourceRoots,
+		archive_fname: &str,
+		checksums: &HashMap<String, String>,
+		options: &WalkOptions,
+		make_hasher: F)
+		-> Result<Comparison, BackupError>
+		where F: Fn() -> Box<dyn Hasher> + Sync {
+	let result = checksum_directory(sources, roots, options, &make_hasher)?;
+	let live = result.checksums;
+	let failed : HashSet<String> = result.failed_files.into_iter()
+		.map(|name| options.normalization.apply(name))
+		.collect();
+	let mut only_in_backup = BTreeSet::new();
+	let mut differing = BTreeSet::new();
+	let mut compare = |key: &String, checksum: &str| match live.get(key) {
+		Some(live_checksum) if live_checksum != checksum => {
+			log_fields!(LogLevel::Trace, {path: key, checksum: live_checksum, old_checksum: checksum},
+				"Mismatched hashes: {}\tbackup: {}\tsource: {}", key, checksum, live_checksum);
+			differing.insert(key.clone());
+		},
+		Some(_) => (),
+		None if failed.contains(key) => (),
+		None => {
+			only_in_backup.insert(key.clone());
+		}
+	};
+
+	// Compare the archive's copies of files
+	let mut archived = HashSet::new();
+	let mut buf = vec![0u8; options.buffer_size];
+	let mut first = true;
+	for part in archive_parts(archive_fname) {
+		archive::read_files(open_archive(&part)?, |name, contents| {
+			if mem::replace(&mut first, false) && name == archive::CHECKSUMS_ENTRY {
+				return Ok(())
+			}
+			let key = options.normalization.apply(name.to_string());
+			let mut hasher = make_hasher();
+			loop {
+				match contents.read(&mut buf) {
+					Ok(0) => break,
+					Ok(len) => hasher.update(&buf[.. len]),
+					Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+					Err(e) => return Err(e)
+				}
 			}
-			trace!("Wrote {} current version checksums to {}...",
-				checksums.len(), fname);
+			compare(&key, &hasher.finalize());
+			archived.insert(key);
 			Ok(())
-		},
-		Err(e) => Err(MainError::OtherError(
-			format!("Error creating checksum file {}: {}", fname, e)))
+		}).map_err(|e| BackupError::OtherError(
+			format!("Error reading archive {}: {}", part, e)))?;
 	}
+
+	// Compare the checksums of all files backed up
+	for (key, checksum) in checksums {
+		compare(key, checksum);
+	}
+
+	let only_in_source = live.keys()
+		.filter(|key| !checksums.contains_key(*key) && !archived.contains(*key))
+		.cloned()
+		.collect::<BTreeSet<_>>();
+	Ok(Comparison {
+		only_in_source: only_in_source.into_iter().collect(),
+		only_in_backup: only_in_backup.into_iter().collect(),
+		differing: differing.into_iter().collect(),
+	})
 }
 
-/// Copy changed files to the given archive file.
+/// Whether the file `fname` is a zip archive (see `archive::is_zip`), rather
+/// than a tar archive. Files which can't be read aren't.
+pub fn is_zip_archive(fname: &str) -> bool {
+	let mut start = [0; 4];
+	File::open(fname).and_then(|mut file| file.read_exact(&mut start)).is_ok()
+		&& archive::is_zip(&start)
+}
+
+/// Apply an incremental archive to a directory restored from earlier backups:
+/// remove the files in `deletions` (names as in the archive, i.e. relative to
+/// `base_dir`), then extract every file in the archive (opened with
+/// `open_archive`, in each of its parts) over `base_dir`, replacing older
+/// copies. Checksums embedded in the archive (see `archive::append_checksums`)
+/// aren't extracted. Files get the mode (on Unix) and modification time
+/// recorded in the archive, and missing parent directories are created.
 ///
-/// The given file is written with a gzipped tar file containing all files in
-/// `new_checksums` with checksums absent or different from those in
-/// `old_checksums`, relative to `source_root`.
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the archive is a zip archive or cannot be read, a name in it or
+/// in `deletions` would be outside `base_dir`, or a file cannot be removed or
+/// written.
+pub fn apply_incremental(base_dir: &Path, archive: &str, deletions: &[String])
+		-> Result<(), BackupError> {
+	if is_zip_archive(archive) {
+		return Err(BackupError::OtherError(format!(
+			"{} is a zip archive, which can't be restored from (extract it with unzip instead)", archive)));
+	}
+	for name in deletions {
+		let path = restore_path(base_dir, name)?;
+		log_fields!(LogLevel::Debug, {path: name}, "Removing deleted file {}", name);
+		match fs::remove_file(&path) {
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+			result => result.map_err(|e| BackupError::OtherError(
+				format!("Error removing {}: {}", path.display(), e)))?,
+		}
+	}
+
+	let mut first = true;
+	for part in archive_parts(archive) {
+		let mut failure = None;
+		let result = archive::read_entries(open_archive(&part)?, |name, header, contents| {
+			if mem::replace(&mut first, false) && name == archive::CHECKSUMS_ENTRY {
+				return Ok(())
+			}
+			let path = match restore_path(base_dir, name) {
+				Ok(path) => path,
+				Err(e) => {
+					failure = Some(e);
+					return Err(io::Error::new(io::ErrorKind::InvalidData, "unsafe name"))
+				}
+			};
+			log_fields!(LogLevel::Debug, {path: name}, "Restoring {}", name);
+			extract_file(&path, header, contents).map_err(|e| {
+				failure = Some(BackupError::OtherError(
+					format!("Error restoring {}: {}", path.display(), e)));
+				e
+			})
+		});
+		if let Some(e) = failure {
+			return Err(e)
+		}
+		result.map_err(|e| BackupError::OtherError(
+			format!("Error reading archive {}: {}", part, e)))?;
+	}
+	Ok(())
+}
+
+/// Where to restore the file named `name` in an archive, under `base_dir`. A
+/// leading `/` is dropped, as tar does; names with `..`, or a drive or UNC
+/// prefix, are refused, rather than written outside `base_dir`.
 ///
 /// # Errors
 ///
-/// This function will return a `MainError::OtherError` with a descriptive
-/// message if the output file cannot be created or written to.
-pub fn write_archive(
-		new_checksums: &HashMap<String, String>,
-		old_checksums: &HashMap<String, String>,
-		source_root: &PathBuf,
-		destination: &str)
-		-> Result<(), MainError> {
-	match File::create(destination) {
-		Ok(file) => {
-			//TODO: We probably don't always want to gzip this.
-			let mut archive = Builder::new(GzEncoder::new(file, Compression::Best));
-			for (fname, hash) in new_checksums {
-				let old_hash = old_checksums.get(fname);
-				if old_hash.map_or(true, |h| h != hash) {
-					trace!("Mismatched hashes, archiving: {}\told: {}\tnew: {}",
-						fname, old_hash.unwrap_or(&"<none>".to_string()), hash);
-					let mut full_fname = source_root.clone();
-					full_fname.push(fname);
-					archive.append_file(fname, &mut File::open(full_fname).unwrap()).unwrap();
-				} else {
-					trace!("Matched hashes, not archiving: {}\t{}", fname, hash);
+/// This function will return a `BackupError::OtherError` if `name` would be
+/// outside `base_dir`.
+fn restore_path(base_dir: &Path, name: &str) -> Result<PathBuf, BackupError> {
+	let mut path = base_dir.to_path_buf();
+	for component in Path::new(name).components() {
+		match component {
+			Component::Normal(part) => path.push(part),
+			Component::RootDir | Component::CurDir => (),
+			Component::ParentDir | Component::Prefix(_) => return Err(BackupError::OtherError(
+				format!("Refusing to restore {}, which is outside the destination", name))),
+		}
+	}
+	if path == base_dir {
+		return Err(BackupError::OtherError(format!("Refusing to restore {}, which has no filename", name)));
+	}
+	Ok(path)
+}
+
+/// Write a file read from an archive to `path`, replacing any file already
+/// there, with the mode and modification time in `header`.
+fn extract_file(path: &Path, header: &tar::Header, contents: &mut dyn Read) -> io::Result<()> {
+	if let Some(parent) = path.parent() {
+		fs::create_dir_all(parent)?;
+	}
+	match fs::remove_file(path) {
+		Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+		result => result?,
+	}
+	let mut file = File::create(path)?;
+	io::copy(contents, &mut file)?;
+	if let Ok(mtime) = header.mtime() {
+		file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+	}
+	set_mode(&file, header)
+}
+
+/// Give a restored file the permissions recorded in its archive header.
+#[cfg(unix)]
+fn set_mode(file: &File, header: &tar::Header) -> io::Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+	match header.mode() {
+		Ok(mode) => file.set_permissions(fs::Permissions::from_mode(mode & 0o7777)),
+		Err(_) => Ok(())
+	}
+}
+
+#[cfg(not(unix))]
+fn set_mode(_file: &File, _header: &tar::Header) -> io::Result<()> {
+	Ok(())
+}
+
+/// Check whether a file's checksum is absent from or different to the old
+/// checksums.
+fn is_changed(fname: &str, hash: &str, old_checksums: &HashMap<String, String>) -> bool {
+	let old_hash = old_checksums.get(fname);
+	if old_hash.is_none_or(|h| h != hash) {
+		log_fields!(LogLevel::Trace, {path: fname, checksum: hash, old_checksum: old_hash.cloned()},
+			"Mismatched hashes, archiving: {}\told: {}\tnew: {}", fname, old_hash.map_or("<none>", |h| h), hash);
+		true
+	} else {
+		false
+	}
+}
+
+/// Append a changed file to an archive being written by an `ArchiveWriter`,
+/// compressed or not according to its size, as for `append_changed`.
+fn append_compressed(
+		archive: &mut ArchiveWriter,
+		roots: &SourceRoots,
+		fname: &str,
+		progress: Option<&Progress>,
+		options: &ArchiveOptions)
+		-> Result<Appended, BackupError> {
+	let size = fs::metadata(roots.path(fname)).map(|m| m.len()).unwrap_or(0);
+	let destination = archive.output.destination.clone();
+	archive.start_entry(size).map_err(|e| archive_write_error(&destination, Some(fname), e))?;
+	let mut builder = Builder::new(&mut *archive);
+	let result = append_changed(&mut builder, &destination, roots, fname, progress, options);
+	// Dropping the builder would write the end-of-archive marker.
+	mem::forget(builder);
+	if let Ok(Appended::Failed(_)) = result {
+		archive.discard_entry();
+	}
+	result
+}
+
+/// Append a changed file, relative to its root in `roots`, to the archive
+/// being written to `destination` (named as set by
+/// `SourceRoots::set_archive_paths`), in the tar format set by `options`,
+/// reporting it to `progress`, if given. The file is read as set by `options`
+/// (see `ArchiveOptions::drop_caches` and `ArchiveOptions::throttle`).
+///
+/// The file is closed before this returns, so archiving only ever has one
+/// source file open at a time (per thread, when compressing in parallel),
+/// however many files are changed.
+///
+/// If the file can't be opened (e.g. it was deleted or had its permissions
+/// changed after it was checksummed), nothing is written, and
+/// `Appended::Failed` is returned for the caller to record; the rest of the
+/// archive is unaffected.
+///
+/// With `options.stability`, the file is checked for changes since it was
+/// checksummed before it's archived (see `check_unchanged`). Whether or not
+/// it's given, the file is checked again once it's archived, and
+/// `Appended::Torn` is returned if it changed meanwhile. Its entry is always as long as its
+/// header says, so the archive stays readable (see `archive::append_file`).
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the file cannot be written to the archive (including if it
+/// can't be read once some of it has been written; see
+/// `archive_write_error` for running out of space).
+fn append_changed<W: Write>(
+		archive: &mut Builder<W>,
+		destination: &str,
+		roots: &SourceRoots,
+		fname: &str,
+		progress: Option<&Progress>,
+		options: &ArchiveOptions)
+		-> Result<Appended, BackupError> {
+	let throttle = options.throttle.as_deref();
+	let name = match roots.archive_name(fname) {
+		Some(name) => name,
+		None => {
+			log_fields!(LogLevel::Warn, {path: fname}, "Not archiving {} (--strip-components leaves nothing of its path)", fname);
+			return Ok(Appended::Archived(None))
+		}
+	};
+	let full_fname = roots.path(fname);
+	let mut file = match open_source(&full_fname, options.drop_caches) {
+		Ok(file) => file,
+		Err(e) => return Ok(Appended::Failed(e))
+	};
+	let stability = options.stability.as_deref();
+	let (state, checksum) = match check_unchanged(&mut file, fname, stability, throttle)? {
+		Ok(checked) => checked,
+		Err(detail) => return Ok(Appended::Unstable(detail))
+	};
+	let size = state.size;
+	let mut file_progress = FileProgress::new(fname, size, progress);
+	let padding = archive::append_file(
+			archive,
+			options.format,
+			&name,
+			&mut file,
+			&mut |bytes| {
+				file_progress.read(bytes);
+				if let Some(throttle) = throttle {
+					throttle.consume(bytes);
 				}
-			}
-			Ok(())
-		},
-		Err(e) => Err(MainError::OtherError(
-			format!("Error creating target file {}: {}", destination, e)))
+			}).map_err(|e| if e.kind() == io::ErrorKind::StorageFull {
+			archive_write_error(destination, Some(fname), e)
+		} else {
+			BackupError::OtherError(format!("Error archiving {}: {}", fname, e))
+		})?;
+	file_progress.done(size);
+	if options.drop_caches {
+		drop_cached(&file);
+	}
+	Ok(match changed_while_archived(&file, state, padding) {
+		Some(detail) => Appended::Torn(detail),
+		None => Appended::Archived(checksum)
+	})
+}
+
+/// The size of the buffer through which files which have changed since they
+/// were checksummed are checksummed again.
+const RECHECKSUM_BUFFER_SIZE: usize = 64 << 10;
+
+/// Check a changed file, opened to be archived, for changes since it was
+/// checksummed (see `Stability`). Returns its state now, to check it against
+/// once it's archived (see `changed_while_archived`), along with its new
+/// checksum if it had changed and was checksummed again, or, in `Ok(Err(..))`,
+/// why it should be left out of the archive. The file is left at its start.
+///
+/// # Errors
+///
+/// This function will return a `BackupError::OtherError` with a descriptive
+/// message if the file cannot be read.
+fn check_unchanged(file: &mut File, fname: &str, stability: Option<&Stability>, throttle: Option<&Throttle>)
+		-> Result<Result<(FileState, Option<String>), String>, BackupError> {
+	let read_error = |e: io::Error| BackupError::OtherError(format!("Error archiving {}: {}", fname, e));
+	let state = FileState::of(&file.metadata().map_err(&read_error)?);
+	let stability = match stability {
+		Some(stability) if stability.changed(fname, state) => stability,
+		_ => return Ok(Ok((state, None)))
+	};
+	if stability.skip {
+		return Ok(Err("changed after it was checksummed".to_string()))
+	}
+	log_fields!(LogLevel::Info, {path: fname}, "{} changed after it was checksummed; checksumming it again", fname);
+	let mut hasher = stability.algorithm.new_hasher(stability.key.as_ref().map(|k| &k[..]));
+	let mut buf = vec![0u8; RECHECKSUM_BUFFER_SIZE];
+	let mut len = 0;
+	loop {
+		let read_len = read_retrying(file, &mut buf).map_err(&read_error)?;
+		if read_len == 0 {
+			break
+		}
+		if let Some(throttle) = throttle {
+			throttle.consume(read_len as u64);
+		}
+		hasher.update(&buf[.. read_len]);
+		len += read_len as u64;
+	}
+	file.seek(SeekFrom::Start(0)).map_err(&read_error)?;
+	let now = FileState::of(&file.metadata().map_err(&read_error)?);
+	if now != state || len != state.size {
+		return Ok(Err("kept changing while it was checksummed again".to_string()))
+	}
+	Ok(Ok((state, Some(hasher.finalize()))))
+}
+
+/// Check whether a file changed while it was archived, given its state just
+/// before, and the number of bytes by which it fell short of its size then
+/// (see `archive::append_file`). Returns how it changed, if it did.
+fn changed_while_archived(file: &File, state: FileState, short: u64) -> Option<String> {
+	if short > 0 {
+		return Some(format!("shrank by {} while it was archived (the rest of its archived copy is zeros)",
+			human_bytes(short)))
+	}
+	match file.metadata() {
+		Ok(ref meta) if FileState::of(meta) == state => None,
+		_ => Some("changed while it was archived".to_string())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Create an empty scratch directory for a test.
+	fn temp_dir(name: &str) -> PathBuf {
+		let dir = env::temp_dir().join(format!("backup-test-{}-{}", name, process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	fn archive_options(threads: usize) -> ArchiveOptions {
+		ArchiveOptions {
+			format: TarFormat::Pax,
+			comment: None,
+			compression: Compression::Gzip,
+			compress_threshold: 0,
+			target_size: None,
+			threads,
+			normalization: Normalization::None,
+			backup_dir: None,
+			link_dest: None,
+			progress: None,
+			stats: Arc::new(Stats::new()),
+			drop_caches: false,
+			throttle: None,
+			stability: None,
+		}
+	}
+
+	#[test]
+	fn files_deleted_before_archiving_fail() {
+		for &threads in &[1, 3] {
+			let dir = temp_dir(&format!("deleted-before-archiving-{}", threads));
+			fs::create_dir(dir.join("src")).unwrap();
+			fs::write(dir.join("src/kept"), "kept").unwrap();
+			let mut new_checksums = HashMap::new();
+			new_checksums.insert("kept".to_string(), "1".to_string());
+			// Checksummed, then deleted.
+			new_checksums.insert("gone".to_string(), "2".to_string());
+
+			let options = archive_options(threads);
+			let destination = dir.join("out.tar.gz");
+			let report = write_archive(&new_checksums, &HashMap::new(),
+				&SourceRoots::new(dir.join("src")), destination.to_str().unwrap(), None, &options)
+				.unwrap();
+			assert_eq!(report.archived, 1);
+			assert_eq!(report.failed, vec!["gone".to_string()]);
+			assert_eq!(options.stats.summary().failed_files, vec!["gone".to_string()]);
+
+			// The next backup archives it again, if it comes back.
+			report.apply(&mut new_checksums);
+			assert_eq!(new_checksums.keys().collect::<Vec<_>>(), vec!["kept"]);
+			fs::remove_dir_all(&dir).unwrap();
+		}
+	}
+
+	#[test]
+	fn embedded_checksums_leave_out_failed_files() {
+		for &(threads, target_size) in &[(1, None), (3, None), (1, Some(1)), (3, Some(1))] {
+			let dir = temp_dir(&format!("embedded-failed-{}-{:?}", threads, target_size));
+			fs::create_dir(dir.join("src")).unwrap();
+			fs::write(dir.join("src/a"), "a").unwrap();
+			fs::write(dir.join("src/b"), "b").unwrap();
+			let mut new_checksums = HashMap::new();
+			new_checksums.insert("a".to_string(), "1".to_string());
+			new_checksums.insert("b".to_string(), "2".to_string());
+			// Checksummed, then deleted.
+			new_checksums.insert("gone".to_string(), "3".to_string());
+
+			let options = ArchiveOptions { target_size, ..archive_options(threads) };
+			let destination = dir.join("out.tar.gz").to_str().unwrap().to_string();
+			let embedded = format_checksums(&new_checksums, &[]);
+			write_archive(&new_checksums, &HashMap::new(), &SourceRoots::new(dir.join("src")),
+				&destination, Some(&embedded), &options).unwrap();
+
+			let (checksums, outcome) = load_archived_checksums(&destination,
+				Normalization::None).unwrap().unwrap();
+			let mut keys : Vec<&String> = checksums.keys().collect();
+			keys.sort();
+			assert_eq!(keys, vec!["a", "b"], "{} threads, {:?}", threads, target_size);
+			assert_eq!(outcome.failed, vec!["gone".to_string()]);
+			// The record isn't a file.
+			assert_eq!(list_archive(&destination).unwrap().files,
+				vec![archive::CHECKSUMS_ENTRY.to_string(), "a".to_string(), "b".to_string()]);
+			fs::remove_dir_all(&dir).unwrap();
+		}
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn append_changed_closes_files() {
+		const FILES: usize = 500;
+		let open_files = || fs::read_dir("/proc/self/fd").unwrap().count();
+		let dir = temp_dir("append-changed-closes-files");
+		for i in 0 .. FILES {
+			fs::write(dir.join(format!("file {}", i)), i.to_string()).unwrap();
+		}
+		let roots = SourceRoots::new(dir.clone());
+		let options = archive_options(1);
+		let mut archive = Builder::new(Vec::new());
+		let before = open_files();
+		let mut most = before;
+		for i in 0 .. FILES {
+			let appended = append_changed(&mut archive, "out.tar", &roots, &format!("file {}", i),
+				None, &options).unwrap();
+			assert!(matches!(appended, Appended::Archived(None)), "{:?}", appended);
+			most = most.max(open_files());
+		}
+		// Other tests running alongside may have a few files open, but a file
+		// left open for each one archived would add hundreds.
+		assert!(most < before + 20, "{} files open before, and up to {} while archiving", before, most);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn sources_outside_the_root() {
+		let dir = temp_dir("outside-root");
+		fs::create_dir_all(dir.join("home/me/photos")).unwrap();
+		fs::create_dir_all(dir.join("tmp/other")).unwrap();
+		let roots = SourceRoots::new(dir.join("home/me"));
+		let other = dir.join("tmp/other").to_str().unwrap().to_string();
+		let check = |source: &str, allow: bool| check_sources(&[source.to_string()], &[], &roots,
+			allow, false, &Stats::new());
+
+		assert_eq!(check("photos", false).unwrap(), vec!["photos".to_string()]);
+		for source in &[&other[..], "../../tmp/other", "photos/../../../tmp/other"] {
+			let error = check(source, false).unwrap_err().to_string();
+			assert!(error.contains("--allow-outside-root") && error.contains(source), "{}", error);
+			assert_eq!(check(source, true).unwrap(), vec![source.to_string()]);
+		}
+
+		// Their files are recorded beneath OUTSIDE_ROOT, and found again from
+		// there.
+		let file = dir.join("tmp/other/file");
+		let key = format!("{}/{}/file", OUTSIDE_ROOT, other.trim_start_matches('/'));
+		assert_eq!(outside_root_key(&file), Some(key.clone()));
+		assert_eq!(roots.path(&key), file);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[cfg(not(windows))]
+	#[test]
+	fn unix_keys() {
+		let roots = SourceRoots::new(PathBuf::from("/home/me"));
+		assert_eq!(path_to_key(Path::new("photos/./a.jpg")).unwrap(), "photos/a.jpg");
+		for &(path, key) in &[
+			("/tmp/other/file", "@outside-root/tmp/other/file"),
+			// Only Windows paths start with drive letters.
+			("/c:/file", "@outside-root/c:/file"),
+		] {
+			assert_eq!(outside_root_key(Path::new(path)).unwrap(), key);
+			assert_eq!(roots.path(key), Path::new(path));
+		}
+		// Backslashes are part of the filename.
+		assert_eq!(normalize_loaded_key("dir\\file".to_string()), "dir\\file");
+	}
+
+	#[cfg(windows)]
+	#[test]
+	fn windows_keys() {
+		let roots = SourceRoots::new(PathBuf::from(r"D:\root"));
+		assert_eq!(path_to_key(Path::new(r"photos\a.jpg")).unwrap(), "photos/a.jpg");
+		assert_eq!(roots.path("photos/a.jpg"), Path::new(r"D:\root\photos\a.jpg"));
+		for &(path, key, outside, reversed) in &[
+			(r"C:\x\y", "C:/x/y", "@outside-root/C:/x/y", r"C:\x\y"),
+			(r"\\server\share\x\y", "//server/share/x/y", "@outside-root/UNC/server/share/x/y",
+				r"\\server\share\x\y"),
+			// Extended-length prefixes are dropped.
+			(r"\\?\C:\x\y", "C:/x/y", "@outside-root/C:/x/y", r"C:\x\y"),
+			(r"\\?\UNC\server\share\x\y", "//server/share/x/y",
+				"@outside-root/UNC/server/share/x/y", r"\\server\share\x\y"),
+		] {
+			assert_eq!(path_to_key(Path::new(path)).unwrap(), key, "{}", path);
+			assert_eq!(outside_root_key(Path::new(path)).unwrap(), outside, "{}", path);
+			// Keys survive being saved and loaded again.
+			let loaded = normalize_loaded_key(outside.to_string());
+			assert_eq!(loaded, outside);
+			assert_eq!(roots.path(&loaded), Path::new(reversed), "{}", path);
+		}
+		// Older versions wrote native separators.
+		assert_eq!(normalize_loaded_key(r".\photos\a.jpg".to_string()), "photos/a.jpg");
+	}
+
+	/// On Windows, files beneath an extended-length root can be walked and
+	/// archived however deep they are; elsewhere, there's no such limit anyway.
+	#[test]
+	fn paths_beyond_max_path() {
+		const PART: &str = "a directory with quite a long name";
+		let dir = temp_dir("long-paths");
+		let root = extended_length_path(&dir).unwrap();
+		#[cfg(windows)]
+		assert!(root.to_str().unwrap().starts_with(r"\\?\"), "{}", root.display());
+		// Well beyond the 260 characters of Windows' MAX_PATH.
+		let mut deep = root.clone();
+		let mut key = String::new();
+		while deep.as_os_str().len() < 400 {
+			deep.push(PART);
+			key.push_str(PART);
+			key.push('/');
+		}
+		key.push_str("file");
+		fs::create_dir_all(&deep).unwrap();
+		fs::write(deep.join("file"), "deep").unwrap();
+
+		let roots = SourceRoots::new(root.clone());
+		let options = WalkOptions { buffer_size: 64 << 10, ..WalkOptions::default() };
+		let result = checksum_directory(&[PART.to_string()], &roots, &options,
+			|| HashAlgorithm::Sha1.new_hasher(None)).unwrap();
+		assert!(result.failed_files.is_empty(), "{:?}", result.failed_files);
+		assert_eq!(result.checksums.keys().collect::<Vec<_>>(), vec![&key]);
+
+		// The file is found again from its key to archive it.
+		let destination = dir.join("out.tar.gz");
+		let report = write_archive(&result.checksums, &HashMap::new(), &roots,
+			destination.to_str().unwrap(), None, &archive_options(1)).unwrap();
+		assert_eq!((report.archived, report.failed.len()), (1, 0));
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn checksum_lines_round_trip() {
+		let names = [
+			"plain",
+			"with space",
+			"with\ttab",
+			"with\nnewline",
+			"with\rreturn",
+			"with\\backslash",
+			"\\n, not a newline",
+			"#leading hash",
+			" leading space",
+			"  two leading spaces",
+			" *leading space and asterisk",
+			"trailing space ",
+			"dir/\u{e9}t\u{e9}",
+		];
+		for name in &names {
+			let line = format_checksum_line(name, "0123abcd");
+			assert!(line.ends_with('\n'));
+			assert_eq!(line.matches('\n').count(), 1, "{:?}", line);
+			assert_eq!(parse_checksum_line(line.trim_end_matches('\n')),
+				Some((name.to_string(), "0123abcd".to_string())), "{:?}", line);
+		}
+	}
+
+	#[test]
+	fn only_awkward_names_are_escaped() {
+		assert_eq!(format_checksum_line("a b\tc", "sum"), "sum\ta b\tc\n");
+		assert_eq!(format_checksum_line("a\\b\nc\rd", "sum"), "\\sum\ta\\\\b\\nc\\rd\n");
+	}
+
+	#[test]
+	fn parses_sha1sum_lines() {
+		let parsed = |name: &str| Some((name.to_string(), "0123abcd".to_string()));
+		assert_eq!(parse_checksum_line("0123abcd  text file"), parsed("text file"));
+		assert_eq!(parse_checksum_line("0123abcd *binary file"), parsed("binary file"));
+		assert_eq!(parse_checksum_line("0123abcd   leading space"), parsed(" leading space"));
+		assert_eq!(parse_checksum_line("\\0123abcd  new\\nline"), parsed("new\nline"));
+		assert_eq!(parse_checksum_line("\\0123abcd *back\\\\slash"), parsed("back\\slash"));
+	}
+
+	#[test]
+	fn merging_removes_deleted_files() {
+		let dir = temp_dir("merge-checksums");
+		let write = |name: &str, lines: &[(&str, &str)]| {
+			let fname = dir.join(name).to_str().unwrap().to_string();
+			let contents : String = lines.iter()
+				.map(|&(key, checksum)| format_checksum_line(key, checksum))
+				.collect();
+			fs::write(&fname, contents).unwrap();
+			fname
+		};
+		let base = write("base", &[("kept", "1"), ("changed", "2"), ("deleted", "3")]);
+		let first = write("first", &[("kept", "1"), ("changed", "4"), ("added", "5")]);
+		let second = write("second", &[("kept", "1"), ("changed", "4"), ("added", "5"),
+			("deleted", "6")]);
+		let merge = |incrementals: &[String]| {
+			let mut merged : Vec<(String, String)> =
+				merge_checksums(&base, incrementals, Normalization::None).unwrap()
+				.into_iter().collect();
+			merged.sort();
+			merged
+		};
+		let pairs = |lines: &[(&str, &str)]| -> Vec<(String, String)> {
+			lines.iter().map(|&(k, c)| (k.to_string(), c.to_string())).collect()
+		};
+
+		assert_eq!(merge(std::slice::from_ref(&first)),
+			pairs(&[("added", "5"), ("changed", "4"), ("kept", "1")]));
+		// A file deleted and then recreated is back.
+		assert_eq!(merge(&[first, second]),
+			pairs(&[("added", "5"), ("changed", "4"), ("deleted", "6"), ("kept", "1")]));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn keys_are_tidied() {
+		for key in &["photos/a.jpg", "./photos/a.jpg", "photos//a.jpg", "photos/./a.jpg",
+				"./photos/a.jpg/", "././photos///a.jpg"] {
+			assert_eq!(normalize_key(key), "photos/a.jpg", "{:?}", key);
+			assert_eq!(normalize_loaded_key(key.to_string()), "photos/a.jpg", "{:?}", key);
+		}
+		assert_eq!(normalize_key("/photos/./a.jpg"), "/photos/a.jpg");
+		assert_eq!(normalize_key("//photos"), "/photos");
+		// `..` is a real component, unlike `.`.
+		assert_eq!(normalize_key("photos/../a.jpg"), "photos/../a.jpg");
+	}
+
+	#[test]
+	fn rejects_bad_lines() {
+		for line in &[
+			"",
+			"# a comment\tfile",
+			"0123abcd",
+			"0123abcd\t",
+			"\tfile",
+			"\\0123abcd\tbad \\x escape",
+			"\\0123abcd\ttrailing backslash\\",
+		] {
+			assert_eq!(parse_checksum_line(line), None, "{:?}", line);
+		}
 	}
 }
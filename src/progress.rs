@@ -0,0 +1,332 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Whether a progress line is currently displayed on stderr.
+static DISPLAYED: AtomicBool = AtomicBool::new(false);
+
+/// How often to redraw the progress line on a terminal.
+const TTY_INTERVAL_MS: u64 = 200;
+
+/// How often to write a progress line when stderr is not a terminal.
+const PLAIN_INTERVAL_MS: u64 = 10000;
+
+/// The longest current filename displayed on a terminal.
+const MAX_NAME_LEN: usize = 40;
+
+/// Files at least this large have their progress reported as they are read,
+/// rather than only once they're done.
+const LARGE_FILE_MIN: u64 = 64 << 20;
+
+/// How often to log progress through a large file when progress isn't being
+/// displayed.
+const LARGE_FILE_LOG_INTERVAL_MS: u64 = 10000;
+
+/// How often a `ThroughputMeter` measures the current read rate.
+const THROUGHPUT_INTERVAL_MS: u64 = 1000;
+
+/// Progress reporting for a run, shared between threads.
+///
+/// On a terminal, progress is shown on a single line of stderr, redrawn a few
+/// times a second; otherwise, a plain line is logged every few seconds.
+#[derive(Debug)]
+pub struct Progress {
+	tty: bool,
+	state: Mutex<State>,
+	bytes_read: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+struct State {
+	phase: &'static str,
+	files_total: u64,
+	bytes_total: u64,
+	files_done: u64,
+	bytes_done: u64,
+	current: String,
+	started: Instant,
+	last_report: Option<Instant>,
+	current_rate: Option<u64>,
+}
+
+impl Default for Progress {
+	fn default() -> Progress {
+		Progress::new()
+	}
+}
+
+impl Progress {
+	/// Create a progress reporter, detecting whether stderr is a terminal.
+	pub fn new() -> Progress {
+		Progress {
+			tty: io::stderr().is_terminal(),
+			state: Mutex::new(State {
+				phase: "",
+				files_total: 0,
+				bytes_total: 0,
+				files_done: 0,
+				bytes_done: 0,
+				current: String::new(),
+				started: Instant::now(),
+				last_report: None,
+				current_rate: None,
+			}),
+			bytes_read: Arc::new(AtomicU64::new(0)),
+		}
+	}
+
+	/// Get the count of bytes read which a `ThroughputMeter` measures, for
+	/// readers to add to (see `io::TrackingReader`).
+	pub fn bytes_read(&self) -> Arc<AtomicU64> {
+		self.bytes_read.clone()
+	}
+
+	/// Start a new phase of the run, expected to cover the given number of
+	/// files and bytes.
+	pub fn start(&self, phase: &'static str, files: u64, bytes: u64) {
+		let mut state = self.state.lock().unwrap();
+		state.phase = phase;
+		state.files_total = files;
+		state.bytes_total = bytes;
+		state.files_done = 0;
+		state.bytes_done = 0;
+		state.current.clear();
+		state.started = Instant::now();
+		state.last_report = None;
+		state.current_rate = None;
+	}
+
+	/// Record that a file of the given size has been processed.
+	pub fn file_done(&self, name: &str, bytes: u64) {
+		let mut state = self.state.lock().unwrap();
+		state.files_done += 1;
+		state.bytes_done += bytes;
+		state.current.clear();
+		state.current.push_str(name);
+		self.report(&mut state, false);
+	}
+
+	/// Record that a file is being processed, to show as the current file
+	/// until the next is started or done.
+	pub fn file_started(&self, name: &str) {
+		let mut state = self.state.lock().unwrap();
+		state.current.clear();
+		state.current.push_str(name);
+	}
+
+	/// Record that some of a file has been processed, before it's done.
+	pub fn advance(&self, name: &str, bytes: u64) {
+		let mut state = self.state.lock().unwrap();
+		state.bytes_done += bytes;
+		if state.current != name {
+			state.current.clear();
+			state.current.push_str(name);
+		}
+		self.report(&mut state, false);
+	}
+
+	/// Record how fast data is currently being read, in bytes per second,
+	/// redrawing the progress line on a terminal.
+	fn throughput(&self, rate: u64) {
+		let mut state = self.state.lock().unwrap();
+		state.current_rate = Some(rate);
+		if self.tty {
+			self.report(&mut state, true);
+		}
+	}
+
+	/// Finish the current phase, reporting final progress and clearing the
+	/// progress line.
+	pub fn finish(&self) {
+		let mut state = self.state.lock().unwrap();
+		self.report(&mut state, true);
+		if self.tty {
+			clear_line();
+		}
+	}
+
+	/// Display progress, if it hasn't been displayed recently (or `force` is
+	/// set).
+	fn report(&self, state: &mut State, force: bool) {
+		let interval = Duration::from_millis(
+			if self.tty { TTY_INTERVAL_MS } else { PLAIN_INTERVAL_MS });
+		let now = Instant::now();
+		if !force && state.last_report.is_some_and(|last| now.duration_since(last) < interval) {
+			return
+		}
+		state.last_report = Some(now);
+		let mut summary = format!("{}: {}/{} files, {}/{}",
+			state.phase, state.files_done, state.files_total,
+			human_bytes(state.bytes_done), human_bytes(state.bytes_total));
+		let elapsed = now.duration_since(state.started).as_secs_f64();
+		if state.bytes_done > 0 && elapsed > 0.0 {
+			let rate = state.bytes_done as f64 / elapsed;
+			let remaining = state.bytes_total.saturating_sub(state.bytes_done) as f64 / rate;
+			summary.push_str(&format!(", {}/s, ETA {}",
+				human_bytes(rate as u64), human_duration(Duration::from_secs_f64(remaining))));
+		}
+		if let Some(rate) = state.current_rate {
+			summary.push_str(&format!(" (now {}/s)", human_bytes(rate)));
+		}
+		if self.tty {
+			let name = truncate_name(&state.current);
+			let _ = write!(io::stderr(), "\r\x1b[K{} {}", summary, name);
+			DISPLAYED.store(true, Ordering::SeqCst);
+		} else {
+			info!("{}", summary);
+		}
+	}
+}
+
+/// Progress through reading a single file.
+///
+/// For large files, bytes read are added to the overall progress as they are
+/// read, if there is any; otherwise, progress through the file is logged at
+/// debug level every few seconds. Small files aren't reported.
+pub struct FileProgress<'a> {
+	name: &'a str,
+	size: u64,
+	read: u64,
+	progress: Option<&'a Progress>,
+	last_log: Instant,
+}
+
+impl<'a> FileProgress<'a> {
+	/// Start reporting progress through the named file of the given size.
+	pub fn new(name: &'a str, size: u64, progress: Option<&'a Progress>) -> FileProgress<'a> {
+		if let Some(progress) = progress {
+			progress.file_started(name);
+		}
+		FileProgress {
+			name,
+			size,
+			read: 0,
+			progress,
+			last_log: Instant::now(),
+		}
+	}
+
+	/// Record that more of the file has been read.
+	pub fn read(&mut self, bytes: u64) {
+		if self.size < LARGE_FILE_MIN {
+			return
+		}
+		self.read += bytes;
+		match self.progress {
+			Some(progress) => progress.advance(self.name, bytes),
+			None => {
+				let now = Instant::now();
+				if now.duration_since(self.last_log) >= Duration::from_millis(LARGE_FILE_LOG_INTERVAL_MS) {
+					self.last_log = now;
+					debug!("{}: {} of {} ({}%)", self.name, human_bytes(self.read),
+						human_bytes(self.size), self.read * 100 / self.size);
+				}
+			}
+		}
+	}
+
+	/// Record that the file is done, given its total size, adding whatever
+	/// hasn't already been reported to the overall progress.
+	pub fn done(self, bytes: u64) {
+		if let Some(progress) = self.progress {
+			progress.file_done(self.name, bytes.saturating_sub(self.read));
+		}
+	}
+}
+
+/// Measures how fast data is being read while it exists.
+///
+/// Once a second, a background thread takes the bytes read since the last
+/// time from the count shared by `Progress::bytes_read`, and shows the rate
+/// with the overall progress. Unlike the average rate, this drops as soon as
+/// reading slows (e.g. on reaching a large file on a slow disk), and with the
+/// current filename, shows which files are holding up the backup. On a
+/// terminal, the progress line is redrawn each time; otherwise, the rate is
+/// included in the next log line.
+pub struct ThroughputMeter {
+	stop: mpsc::Sender<()>,
+	thread: Option<JoinHandle<()>>,
+}
+
+impl ThroughputMeter {
+	/// Start measuring the rate at which bytes are added to `progress`'s
+	/// count of bytes read.
+	pub fn start(progress: Arc<Progress>) -> ThroughputMeter {
+		let (stop, stopped) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			progress.bytes_read.store(0, Ordering::Relaxed);
+			let mut last = Instant::now();
+			let interval = Duration::from_millis(THROUGHPUT_INTERVAL_MS);
+			while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+				let now = Instant::now();
+				let bytes = progress.bytes_read.swap(0, Ordering::Relaxed);
+				let elapsed = now.duration_since(last).as_secs_f64();
+				last = now;
+				progress.throughput((bytes as f64 / elapsed) as u64);
+			}
+		});
+		ThroughputMeter { stop, thread: Some(thread) }
+	}
+}
+
+impl Drop for ThroughputMeter {
+	fn drop(&mut self) {
+		let _ = self.stop.send(());
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+/// Clear the progress line, if one is displayed, so something else can be
+/// written to stderr.
+///
+/// Returns whether a line was cleared.
+pub fn clear_line() -> bool {
+	if DISPLAYED.swap(false, Ordering::SeqCst) {
+		let _ = write!(io::stderr(), "\r\x1b[K");
+		true
+	} else {
+		false
+	}
+}
+
+/// Shorten a filename for display, keeping its end.
+fn truncate_name(name: &str) -> String {
+	let len = name.chars().count();
+	if len <= MAX_NAME_LEN {
+		name.to_string()
+	} else {
+		let tail : String = name.chars().skip(len - (MAX_NAME_LEN - 3)).collect();
+		format!("...{}", tail)
+	}
+}
+
+/// Format a number of bytes for people to read.
+pub fn human_bytes(bytes: u64) -> String {
+	const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+	if bytes < 1024 {
+		return format!("{} B", bytes);
+	}
+	let mut value = bytes as f64 / 1024.0;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Format a duration for people to read.
+pub fn human_duration(duration: Duration) -> String {
+	let secs = duration.as_secs();
+	if secs >= 3600 {
+		format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+	} else if secs >= 60 {
+		format!("{}m{:02}s", secs / 60, secs % 60)
+	} else {
+		format!("{:.1}s", duration.as_secs_f64())
+	}
+}
@@ -0,0 +1,105 @@
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cancel;
+
+/// How often to check for interruption while waiting for the next run.
+const CHECK_INTERVAL_MS: u64 = 1000;
+
+/// Whether a backup has been asked for, by SIGHUP, since last checked.
+static TRIGGERED: AtomicBool = AtomicBool::new(false);
+
+/// Parse a length of time: a number of seconds, or a number followed by `s`,
+/// `m`, `h` or `d` for seconds, minutes, hours or days (so `6h` is six hours).
+///
+/// Returns `None` if `duration` isn't of this form.
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+	let duration = duration.trim();
+	let (number, multiplier) = match duration.chars().last().map(|c| c.to_ascii_lowercase()) {
+		Some('s') => (&duration[.. duration.len() - 1], 1),
+		Some('m') => (&duration[.. duration.len() - 1], 60),
+		Some('h') => (&duration[.. duration.len() - 1], 60 * 60),
+		Some('d') => (&duration[.. duration.len() - 1], 24 * 60 * 60),
+		_ => (duration, 1),
+	};
+	number.parse::<u64>().ok()
+		.and_then(|n| n.checked_mul(multiplier))
+		.map(Duration::from_secs)
+}
+
+/// The first time after `now` which is a whole number of `interval`s since
+/// the Unix epoch, so runs with the same interval line up however long each
+/// takes, and across restarts.
+pub fn next_boundary(now: SystemTime, interval: Duration) -> SystemTime {
+	let interval = cmp::max(interval.as_secs(), 1);
+	let since_epoch = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+	UNIX_EPOCH + Duration::from_secs((since_epoch / interval + 1) * interval)
+}
+
+/// A random length of time up to `max`, to spread out runs on machines
+/// sharing a schedule.
+pub fn jitter(max: Duration) -> Duration {
+	if max.as_secs() == 0 {
+		return Duration::from_secs(0);
+	}
+	// Each RandomState is randomly seeded, which is random enough for this.
+	let random = RandomState::new().build_hasher().finish();
+	Duration::from_secs(random % (max.as_secs() + 1))
+}
+
+/// Sleep until `time`, checking for interruption (see `cancel`) every second,
+/// and returning `false` if interrupted, or `true` once it's time.
+pub fn sleep_until(time: SystemTime) -> bool {
+	loop {
+		if cancel::cancelled() {
+			return false;
+		}
+		match time.duration_since(SystemTime::now()) {
+			Ok(left) if left > Duration::from_secs(0) =>
+				thread::sleep(cmp::min(left, Duration::from_millis(CHECK_INTERVAL_MS))),
+			_ => return true
+		}
+	}
+}
+
+/// Handle SIGHUP by asking for a backup (see `wait_for_trigger`), for daemon.
+///
+/// On platforms other than Unix, this does nothing.
+#[cfg(unix)]
+pub fn install_trigger() {
+	use libc;
+	extern "C" fn handle(_: libc::c_int) {
+		// Only async-signal-safe operations are allowed here.
+		TRIGGERED.store(true, Ordering::SeqCst);
+	}
+	let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+	unsafe { libc::signal(libc::SIGHUP, handler) };
+}
+
+#[cfg(not(unix))]
+pub fn install_trigger() {
+}
+
+/// Sleep until `time`, if given, or until a backup is asked for (see
+/// `install_trigger`), whichever is first, checking every second. Returns
+/// `false` if interrupted (see `cancel`), or `true` once it's time.
+pub fn wait_for_trigger(time: Option<SystemTime>) -> bool {
+	loop {
+		if cancel::cancelled() {
+			return false;
+		}
+		if TRIGGERED.swap(false, Ordering::SeqCst) {
+			return true;
+		}
+		let check = Duration::from_millis(CHECK_INTERVAL_MS);
+		match time.map(|time| time.duration_since(SystemTime::now())) {
+			Some(Ok(left)) if left > Duration::from_secs(0) => thread::sleep(cmp::min(left, check)),
+			Some(_) => return true,
+			None => thread::sleep(check)
+		}
+	}
+}
@@ -0,0 +1,719 @@
+use BackupError;
+use events::Events;
+use log::LogLevel;
+use operations::format_timestamp;
+use progress::{human_bytes, human_duration};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The version of the JSON report's schema (see `Summary`). This is increased
+/// whenever fields are removed or their meaning changes; fields may be added
+/// without changing it.
+pub const REPORT_VERSION: u32 = 1;
+
+/// The most paths kept in each list of failed or skipped files.
+const MAX_LISTED_FILES: usize = 1000;
+
+/// The number of files skipped due to permissions named in the summary.
+const DENIED_LISTED_FILES: usize = 5;
+
+/// The number of files skipped due to permissions from which the summary
+/// suggests how to back them up.
+const DENIED_HINT_FILES: u64 = 10;
+
+/// A phase of a run, for which statistics are gathered separately.
+#[derive(Clone,Copy,Debug)]
+pub enum Phase {
+	LoadChecksums,
+	Checksum,
+	SaveChecksums,
+	Archive,
+}
+
+impl Phase {
+	/// The name of this phase, as given in events.
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Phase::LoadChecksums => "load_checksums",
+			Phase::Checksum => "checksum",
+			Phase::SaveChecksums => "save_checksums",
+			Phase::Archive => "archive",
+		}
+	}
+
+	/// A description of this phase, for the log.
+	fn description(&self) -> &'static str {
+		match *self {
+			Phase::LoadChecksums => "loading checksums",
+			Phase::Checksum => "walking and checksumming",
+			Phase::SaveChecksums => "saving checksums",
+			Phase::Archive => "writing the archive",
+		}
+	}
+}
+
+/// Reasons files are skipped without being checksummed.
+#[derive(Clone,Copy,Debug)]
+pub enum Skip {
+	/// The file couldn't be opened or read.
+	Unreadable,
+	/// The file was excluded by owner, by being on another filesystem, or by
+	/// having a name which isn't valid UTF-8.
+	Excluded,
+	/// The file isn't a regular file (e.g. a device, socket, or broken
+	/// symlink).
+	Special,
+}
+
+impl Skip {
+	/// The name of this reason, as given in the JSON report.
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Skip::Unreadable => "unreadable",
+			Skip::Excluded => "excluded",
+			Skip::Special => "special",
+		}
+	}
+}
+
+/// Timing and throughput statistics for a run, shared between threads.
+///
+/// Statistics can also be reported as they're gathered, as a stream of
+/// `Events`. The start and end of each phase, and the total time taken, are
+/// logged at info level. What the run is doing at any moment can be described
+/// with `status`.
+#[derive(Debug)]
+pub struct Stats {
+	summary: Mutex<Summary>,
+	current: Mutex<Current>,
+	/// Increased whenever a phase or file starts or a file is done (see
+	/// `activity`).
+	activity: AtomicUsize,
+	/// Every file skipped, with why, if they're to be listed (see
+	/// `list_skipped`).
+	skipped: Option<Mutex<Vec<(String, String)>>>,
+	/// Fail the run as soon as any file can't be backed up (see
+	/// `check_strict`).
+	strict: bool,
+	events: Option<Events>,
+	started: Mutex<Instant>,
+}
+
+/// What a run is doing right now, for `Stats::status`.
+#[derive(Debug,Default)]
+struct Current {
+	/// The phase of the file most recently started, or of the phase most
+	/// recently started, if it's later.
+	phase: Option<Phase>,
+	/// The file most recently started.
+	file: String,
+	/// When each phase (indexed by `Phase as usize`) last started.
+	started: [Option<Instant>; 4],
+}
+
+/// The statistics gathered for a run.
+///
+/// This is also the schema of the JSON report written by `to_json`, with one
+/// field for each field here (and objects for the nested structures), so its
+/// fields are documented for consumers of the report as well.
+#[derive(Clone,Debug,Default,Serialize)]
+pub struct Summary {
+	/// The version of this schema (see `REPORT_VERSION`).
+	pub version: u32,
+	/// When the run started and finished, as RFC 3339 UTC timestamps.
+	pub started: String,
+	pub finished: String,
+	/// The process's exit status: zero if the run succeeded.
+	pub exit_status: i32,
+	/// The error which stopped the run, if it failed.
+	pub error: Option<String>,
+	/// The time the run took, in seconds.
+	pub total_seconds: f64,
+	/// The checksumming algorithm used (as given to `--hash-algorithm`).
+	pub hash_algorithm: String,
+	/// How the archive was written.
+	pub compression: CompressionSummary,
+	/// Old checksums loaded.
+	pub load_checksums: PhaseSummary,
+	/// Files read and checksummed.
+	pub checksum: PhaseSummary,
+	/// New checksums saved.
+	pub save_checksums: PhaseSummary,
+	/// Changed files read and archived.
+	pub archive: PhaseSummary,
+	/// The size of the archive written, after compression.
+	pub archive_bytes_written: u64,
+	/// The limit on the rate at which files were read, in bytes per second,
+	/// or zero if there was none.
+	pub bwlimit: u64,
+	/// The average rate at which files were read under that limit.
+	pub bwlimit_average: f64,
+	/// Files skipped because they couldn't be read, up to the first
+	/// `MAX_LISTED_FILES` of them.
+	pub failed_files: Vec<String>,
+	/// The number of files skipped because they couldn't be read, including
+	/// any left out of `failed_files`.
+	pub failed_files_count: u64,
+	/// Changed files which were changing as they were backed up (between being
+	/// checksummed and archived, or while being archived), and so need backing
+	/// up again, up to the first `MAX_LISTED_FILES` of them.
+	pub unstable_files: Vec<String>,
+	/// The number of unstable files, including any left out of
+	/// `unstable_files`.
+	pub unstable_files_count: u64,
+	/// Files skipped because permission to read them (or, for directories,
+	/// list them) was denied, up to the first `MAX_LISTED_FILES` of them.
+	/// These are also in `failed_files`.
+	pub denied_files: Vec<String>,
+	/// The number of files skipped because permission was denied, including
+	/// any left out of `denied_files`.
+	pub denied_files_count: u64,
+	/// Sources which didn't exist, and were skipped (see
+	/// `operations::check_sources`), by their absolute paths, up to the first
+	/// `MAX_LISTED_FILES` of them.
+	pub missing_sources: Vec<String>,
+	/// The number of sources skipped because they didn't exist, including any
+	/// left out of `missing_sources`.
+	pub missing_sources_count: u64,
+	/// The number of errors walking the sources (e.g. directories which
+	/// couldn't be listed, or filesystem loops), each of which may have left
+	/// out everything beneath a directory. These are also in `failed_files`.
+	pub walk_errors: u64,
+	/// Files checksummed after being retried because of transient errors
+	/// reading them (see `--retries`).
+	pub retried_files: u64,
+	/// The number of retries, including those of files which still couldn't
+	/// be read.
+	pub read_retries: u64,
+	/// Files whose checksums match the old checksums.
+	pub unchanged_files: u64,
+	/// Files whose checksums are absent from or different to the old
+	/// checksums.
+	pub changed_files: u64,
+	/// Files skipped without being checksummed, by reason.
+	pub skipped: SkipSummary,
+	/// Files skipped without being checksummed (other than those in
+	/// `failed_files`), up to the first `MAX_LISTED_FILES` of them.
+	pub skipped_files: Vec<SkippedFile>,
+	/// The number of files skipped without being checksummed (other than those
+	/// counted in `failed_files_count`), including any left out of
+	/// `skipped_files`.
+	pub skipped_files_count: u64,
+	/// Where the archive was written (or would have been, in a dry run).
+	pub destination: String,
+	/// Whether this was a dry run, in which case the archive figures are for
+	/// the archive which would have been written, and its compressed size is
+	/// an estimate.
+	pub dry_run: bool,
+}
+
+/// The number of files skipped for each reason (see `Skip`).
+#[derive(Clone,Debug,Default,Serialize)]
+pub struct SkipSummary {
+	pub unreadable: u64,
+	pub excluded: u64,
+	pub special: u64,
+}
+
+/// A file skipped without being checksummed, and why.
+#[derive(Clone,Debug,Serialize)]
+pub struct SkippedFile {
+	pub path: String,
+	/// The reason it was skipped (see `Skip::name`).
+	pub reason: String,
+}
+
+/// How an archive is compressed and formatted.
+#[derive(Clone,Debug,Default,Serialize)]
+pub struct CompressionSummary {
+	/// The compression format (as given to `--compression`).
+	pub format: String,
+	/// The compression level.
+	pub level: u32,
+	/// Files smaller than this many bytes are stored uncompressed (see
+	/// `--compress-threshold`).
+	pub threshold: u64,
+	/// The tar header format (as given to `--tar-format`).
+	pub tar_format: String,
+}
+
+/// The statistics gathered for one phase of a run.
+#[derive(Clone,Debug,Default,Serialize)]
+pub struct PhaseSummary {
+	pub files: u64,
+	pub bytes: u64,
+	pub seconds: f64,
+}
+
+impl PhaseSummary {
+	/// The rate at which the given number of bytes were processed over this
+	/// phase, in bytes per second.
+	pub fn rate(&self, bytes: u64) -> f64 {
+		if self.seconds > 0.0 { bytes as f64 / self.seconds } else { 0.0 }
+	}
+}
+
+impl Default for Stats {
+	fn default() -> Stats {
+		Stats::new()
+	}
+}
+
+impl Stats {
+	/// Create an empty set of statistics.
+	pub fn new() -> Stats {
+		Stats::with_events(None)
+	}
+
+	/// Create an empty set of statistics, reporting them to `events` as
+	/// they're gathered, if given.
+	pub fn with_events(events: Option<Events>) -> Stats {
+		Stats {
+			summary: Mutex::new(Summary {
+				version: REPORT_VERSION,
+				started: format_timestamp(SystemTime::now()),
+				.. Default::default()
+			}),
+			current: Mutex::new(Current::default()),
+			activity: AtomicUsize::new(0),
+			skipped: None,
+			strict: false,
+			events,
+			started: Mutex::new(Instant::now()),
+		}
+	}
+
+	/// Start the statistics afresh for another run by the same process (see
+	/// `--interval`), still reporting them to the same events, and listing
+	/// skipped files if they were.
+	pub fn restart(&self) {
+		*self.summary.lock().unwrap() = Summary {
+			version: REPORT_VERSION,
+			started: format_timestamp(SystemTime::now()),
+			.. Default::default()
+		};
+		*self.current.lock().unwrap() = Current::default();
+		if let Some(ref skipped) = self.skipped {
+			skipped.lock().unwrap().clear();
+		}
+		*self.started.lock().unwrap() = Instant::now();
+	}
+
+	/// Record that a phase has started.
+	pub fn phase_started(&self, phase: Phase) {
+		log_fields!(LogLevel::Info, {phase: phase.name(), state: "start"}, "Started {}", phase.description());
+		if let Some(ref events) = self.events {
+			events.phase_started(phase);
+		}
+		self.activity.fetch_add(1, Ordering::Relaxed);
+		let mut current = self.current.lock().unwrap();
+		current.phase = Some(phase);
+		current.started[phase as usize] = Some(Instant::now());
+	}
+
+	/// Record that the file `name` is being processed in a phase.
+	pub fn file_started(&self, phase: Phase, name: &str) {
+		self.activity.fetch_add(1, Ordering::Relaxed);
+		let mut current = self.current.lock().unwrap();
+		current.phase = Some(phase);
+		current.file.clear();
+		current.file.push_str(name);
+	}
+
+	/// Record that the file `name`, of the given size, has been processed in a
+	/// phase.
+	pub fn file_done(&self, phase: Phase, name: &str, bytes: u64) {
+		if let Some(ref events) = self.events {
+			events.file_done(phase, name, bytes);
+		}
+		self.activity.fetch_add(1, Ordering::Relaxed);
+		let mut summary = self.summary.lock().unwrap();
+		let phase = summary.phase(phase);
+		phase.files += 1;
+		phase.bytes += bytes;
+	}
+
+	/// Record that a number of files, of the given total size, have been
+	/// processed in a phase.
+	pub fn files_done(&self, phase: Phase, files: u64, bytes: u64) {
+		let mut summary = self.summary.lock().unwrap();
+		let phase = summary.phase(phase);
+		phase.files += files;
+		phase.bytes += bytes;
+	}
+
+	/// Keep every file skipped (without the limit on those in the summary),
+	/// for `log_skipped`.
+	pub fn list_skipped(&mut self) {
+		self.skipped = Some(Mutex::new(Vec::new()));
+	}
+
+	/// Treat any file which can't be backed up as an error (see
+	/// `check_strict`).
+	pub fn strict(&mut self) {
+		self.strict = true;
+	}
+
+	/// If the run is strict, check that every file has been backed up so far:
+	/// none were unreadable (including vanishing or failing to be archived)
+	/// or changed as they were archived, and no sources were missing. Long-running operations call this
+	/// between files, as they do `cancel::check`, and before moving their
+	/// output into place.
+	///
+	/// # Errors
+	///
+	/// This function will return a `BackupError::OtherError` listing the files
+	/// which couldn't be backed up, if the run is strict and there are any.
+	pub fn check_strict(&self) -> Result<(), BackupError> {
+		if !self.strict {
+			return Ok(())
+		}
+		let summary = self.summary.lock().unwrap();
+		let count = summary.failed_files_count + summary.unstable_files_count + summary.missing_sources_count;
+		if count == 0 {
+			return Ok(())
+		}
+		let files : Vec<&str> = summary.missing_sources.iter()
+			.chain(summary.failed_files.iter())
+			.chain(summary.unstable_files.iter())
+			.map(|f| &f[..]).collect();
+		let more = count - files.len() as u64;
+		Err(BackupError::OtherError(format!("{} files couldn't be backed up (with --strict):\n\t{}{}",
+			count, files.join("\n\t"),
+			if more > 0 { format!("\n\t(and {} more)", more) } else { String::new() })))
+	}
+
+	/// Record that a file was skipped because it couldn't be read, with the
+	/// error reading it.
+	pub fn file_failed(&self, name: &str, error: &str) {
+		if let Some(ref events) = self.events {
+			events.error(Some(name), error);
+		}
+		if let Some(ref skipped) = self.skipped {
+			skipped.lock().unwrap().push((name.to_string(), format!("error reading: {}", error)));
+		}
+		let mut summary = self.summary.lock().unwrap();
+		if summary.failed_files.len() < MAX_LISTED_FILES {
+			summary.failed_files.push(name.to_string());
+		}
+		summary.failed_files_count += 1;
+		summary.skipped.unreadable += 1;
+	}
+
+	/// Record that a file which couldn't be read (already recorded with
+	/// `file_failed` or `file_skipped`) was skipped because permission to
+	/// read it was denied.
+	pub fn file_denied(&self, name: &str) {
+		let mut summary = self.summary.lock().unwrap();
+		if summary.denied_files.len() < MAX_LISTED_FILES {
+			summary.denied_files.push(name.to_string());
+		}
+		summary.denied_files_count += 1;
+	}
+
+	/// Record that the source at `path` didn't exist, and was skipped.
+	pub fn source_missing(&self, path: &str) {
+		let mut summary = self.summary.lock().unwrap();
+		if summary.missing_sources.len() < MAX_LISTED_FILES {
+			summary.missing_sources.push(path.to_string());
+		}
+		summary.missing_sources_count += 1;
+	}
+
+	/// Record that an error walking the sources (already recorded with
+	/// `file_skipped`) may have left out everything beneath a directory.
+	pub fn walk_error(&self) {
+		self.summary.lock().unwrap().walk_errors += 1;
+	}
+
+	/// Record that a file is being retried after a transient error reading it.
+	pub fn read_retried(&self) {
+		self.summary.lock().unwrap().read_retries += 1;
+	}
+
+	/// Record that a file was checksummed after being retried.
+	pub fn file_retried(&self) {
+		self.summary.lock().unwrap().retried_files += 1;
+	}
+
+	/// Record that a changed file was changing as it was backed up, described
+	/// by `detail`, so its archived contents might not match its checksum.
+	pub fn file_unstable(&self, name: &str, detail: &str) {
+		if let Some(ref events) = self.events {
+			events.file_unstable(name, detail);
+		}
+		let mut summary = self.summary.lock().unwrap();
+		if summary.unstable_files.len() < MAX_LISTED_FILES {
+			summary.unstable_files.push(name.to_string());
+		}
+		summary.unstable_files_count += 1;
+	}
+
+	/// Record that the file at `path` was skipped without being checksummed,
+	/// for `reason`, described in more detail by `detail` (e.g. the error
+	/// opening it).
+	pub fn file_skipped(&self, path: &str, reason: Skip, detail: &str) {
+		if let Some(ref events) = self.events {
+			events.file_skipped(path, reason);
+		}
+		if let Some(ref skipped) = self.skipped {
+			skipped.lock().unwrap().push((path.to_string(), detail.to_string()));
+		}
+		let mut summary = self.summary.lock().unwrap();
+		// Unreadable files are failures, listed with those which failed as
+		// they were read.
+		if let Skip::Unreadable = reason {
+			if summary.failed_files.len() < MAX_LISTED_FILES {
+				summary.failed_files.push(path.to_string());
+			}
+			summary.failed_files_count += 1;
+		}
+		match reason {
+			Skip::Unreadable => summary.skipped.unreadable += 1,
+			Skip::Excluded => summary.skipped.excluded += 1,
+			Skip::Special => summary.skipped.special += 1,
+		}
+		if summary.skipped_files.len() < MAX_LISTED_FILES {
+			summary.skipped_files.push(SkippedFile { path: path.to_string(), reason: reason.name().to_string() });
+		}
+		summary.skipped_files_count += 1;
+	}
+
+	/// Record how the run checksums and archives files.
+	pub fn settings(&self, hash_algorithm: &str, compression: CompressionSummary) {
+		let mut summary = self.summary.lock().unwrap();
+		summary.hash_algorithm = hash_algorithm.to_string();
+		summary.compression = compression;
+	}
+
+	/// Record that the run has finished, with the given exit status and error.
+	pub fn finished(&self, exit_status: i32, error: Option<String>) {
+		let elapsed = self.started.lock().unwrap().elapsed();
+		log_fields!(LogLevel::Info, {seconds: elapsed.as_secs_f64()}, "Finished in {}", human_duration(elapsed));
+		let mut summary = self.summary.lock().unwrap();
+		summary.finished = format_timestamp(SystemTime::now());
+		summary.total_seconds = elapsed.as_secs_f64();
+		summary.exit_status = exit_status;
+		summary.error = error;
+		if let Some(ref events) = self.events {
+			if let Some(ref error) = summary.error {
+				events.error(None, error);
+			}
+			events.summary(&summary);
+		}
+	}
+
+	/// Record the number of files found changed (or new) and unchanged since
+	/// the old checksums.
+	pub fn compared(&self, changed: u64, unchanged: u64) {
+		let mut summary = self.summary.lock().unwrap();
+		summary.changed_files += changed;
+		summary.unchanged_files += unchanged;
+	}
+
+	/// Record where the archive is written, and whether this is a dry run.
+	pub fn destination(&self, destination: &str, dry_run: bool) {
+		let mut summary = self.summary.lock().unwrap();
+		summary.destination = destination.to_string();
+		summary.dry_run = dry_run;
+	}
+
+	/// Record time spent in a phase, which has now ended.
+	pub fn elapsed(&self, phase: Phase, elapsed: Duration) {
+		log_fields!(LogLevel::Info, {phase: phase.name(), state: "end", seconds: elapsed.as_secs_f64()},
+			"Finished {} in {}", phase.description(), human_duration(elapsed));
+		if let Some(ref events) = self.events {
+			events.phase_ended(phase, elapsed.as_secs_f64());
+		}
+		let mut summary = self.summary.lock().unwrap();
+		summary.phase(phase).seconds += elapsed.as_secs_f64();
+	}
+
+	/// Time a phase consisting of `f`, recording that it has started and then
+	/// how long it took.
+	pub fn time<T, F>(&self, phase: Phase, f: F) -> T where F: FnOnce() -> T {
+		self.phase_started(phase);
+		let started = Instant::now();
+		let result = f();
+		self.elapsed(phase, started.elapsed());
+		result
+	}
+
+	/// Record the size of the archive written.
+	pub fn archive_written(&self, bytes: u64) {
+		self.summary.lock().unwrap().archive_bytes_written = bytes;
+	}
+
+	/// Record the limit on the rate at which files were read, and the average
+	/// rate achieved.
+	pub fn throttled(&self, limit: u64, average: f64) {
+		let mut summary = self.summary.lock().unwrap();
+		summary.bwlimit = limit;
+		summary.bwlimit_average = average;
+	}
+
+	/// Log every file skipped, with why, as a single block at info level, if
+	/// they've been kept (see `list_skipped`).
+	pub fn log_skipped(&self) {
+		let skipped = match self.skipped {
+			Some(ref skipped) => skipped.lock().unwrap(),
+			None => return
+		};
+		if skipped.is_empty() {
+			info!("No files were skipped");
+			return
+		}
+		let lines : Vec<String> = skipped.iter()
+			.map(|(path, detail)| format!("\t{} ({})", path, detail))
+			.collect();
+		info!("Skipped {} files:\n{}", skipped.len(), lines.join("\n"));
+	}
+
+	/// Write every file skipped to `out`, one per line, followed by a tab and
+	/// why, if they've been kept (see `list_skipped`).
+	///
+	/// # Errors
+	///
+	/// This function will return an error if `out` can't be written to.
+	pub fn write_skipped<W: Write>(&self, mut out: W) -> io::Result<()> {
+		if let Some(ref skipped) = self.skipped {
+			for (path, detail) in skipped.lock().unwrap().iter() {
+				writeln!(out, "{}\t{}", path, detail)?;
+			}
+		}
+		out.flush()
+	}
+
+	/// A count which increases whenever the run makes progress, so a stalled
+	/// run can be told from a busy one by checking it periodically.
+	pub fn activity(&self) -> usize {
+		self.activity.load(Ordering::Relaxed)
+	}
+
+	/// Get the statistics gathered so far.
+	pub fn summary(&self) -> Summary {
+		self.summary.lock().unwrap().clone()
+	}
+
+	/// Describe what the run is doing, in a line for people to read: the
+	/// current phase and file, the files and bytes done in that phase, and how
+	/// long it and the whole run have taken.
+	pub fn status(&self) -> String {
+		let current = self.current.lock().unwrap();
+		let total = human_duration(self.started.lock().unwrap().elapsed());
+		let phase = match current.phase {
+			Some(phase) => phase,
+			None => return format!("starting, {} elapsed", total)
+		};
+		let (files, bytes) = {
+			let mut summary = self.summary.lock().unwrap();
+			let done = summary.phase(phase);
+			(done.files, done.bytes)
+		};
+		let elapsed = current.started[phase as usize].map_or(Duration::from_secs(0), |s| s.elapsed());
+		let rate = if elapsed.as_secs_f64() > 0.0 { bytes as f64 / elapsed.as_secs_f64() } else { 0.0 };
+		let mut status = format!("{}, {} files ({}) in {}, {}/s, {} elapsed",
+			phase.description(), files, human_bytes(bytes), human_duration(elapsed),
+			human_bytes(rate as u64), total);
+		if !current.file.is_empty() {
+			status.push_str(&format!("; current file {}", current.file));
+		}
+		status
+	}
+}
+
+impl Summary {
+	fn phase(&mut self, phase: Phase) -> &mut PhaseSummary {
+		match phase {
+			Phase::LoadChecksums => &mut self.load_checksums,
+			Phase::Checksum => &mut self.checksum,
+			Phase::SaveChecksums => &mut self.save_checksums,
+			Phase::Archive => &mut self.archive,
+		}
+	}
+
+	/// Log the statistics, as a single block at info level (with files which
+	/// couldn't be read listed separately, at warning level).
+	pub fn log(&self) {
+		let checksum = &self.checksum;
+		let archive = &self.archive;
+		let skipped = &self.skipped;
+		let mut lines = vec![
+			"Summary:".to_string(),
+			format!("\tScanned:     {} files, {} checksummed in {} ({}/s)",
+				checksum.files, human_bytes(checksum.bytes),
+				human_duration(Duration::from_secs_f64(checksum.seconds)),
+				human_bytes(checksum.rate(checksum.bytes) as u64)),
+			format!("\tUnchanged:   {} files", self.unchanged_files),
+			format!("\tChanged/new: {} files", self.changed_files),
+			format!("\tSkipped:     {} unreadable, {} excluded, {} special",
+				skipped.unreadable, skipped.excluded, skipped.special),
+		];
+		if self.dry_run {
+			lines.push(format!("\tWould archive: {} files ({}), about {} compressed",
+				archive.files, human_bytes(archive.bytes), human_bytes(self.archive_bytes_written)));
+		} else {
+			lines.push(format!("\tArchived:    {} files ({}) in {} ({}/s read), {} compressed ({}/s written)",
+				archive.files, human_bytes(archive.bytes),
+				human_duration(Duration::from_secs_f64(archive.seconds)),
+				human_bytes(archive.rate(archive.bytes) as u64),
+				human_bytes(self.archive_bytes_written),
+				human_bytes(archive.rate(self.archive_bytes_written) as u64)));
+		}
+		if self.read_retries > 0 {
+			lines.push(format!("\tRetried:     {} files read after retrying, {} retries in all",
+				self.retried_files, self.read_retries));
+		}
+		if self.bwlimit > 0 {
+			lines.push(format!("\tBandwidth:   limited to {}/s, averaging {}/s",
+				human_bytes(self.bwlimit), human_bytes(self.bwlimit_average as u64)));
+		}
+		lines.push(format!("\tChecksums:   loaded in {}, saved in {}",
+			human_duration(Duration::from_secs_f64(self.load_checksums.seconds)),
+			human_duration(Duration::from_secs_f64(self.save_checksums.seconds))));
+		lines.push(format!("\tDestination: {}", self.destination));
+		lines.push(format!("\tTotal time:  {}", human_duration(Duration::from_secs_f64(self.total_seconds))));
+		log_fields!(LogLevel::Info, {summary: true}, "{}", lines.join("\n"));
+		if !self.failed_files.is_empty() {
+			let more = self.failed_files_count - self.failed_files.len() as u64;
+			warn!("Skipped {} files which couldn't be read:\n\t{}{}",
+				self.failed_files_count, self.failed_files.join("\n\t"),
+				if more > 0 { format!("\n\t(and {} more)", more) } else { String::new() });
+		}
+		if !self.missing_sources.is_empty() {
+			let more = self.missing_sources_count - self.missing_sources.len() as u64;
+			warn!("Skipped {} sources which didn't exist:\n\t{}{}",
+				self.missing_sources_count, self.missing_sources.join("\n\t"),
+				if more > 0 { format!("\n\t(and {} more)", more) } else { String::new() });
+		}
+		if self.walk_errors > 0 {
+			warn!("{} errors walking the sources (listed above); files beneath those directories weren't backed up",
+				self.walk_errors);
+		}
+		if self.denied_files_count > 0 {
+			let first : Vec<&str> = self.denied_files.iter().take(DENIED_LISTED_FILES).map(|f| &f[..]).collect();
+			let hint = if self.denied_files_count >= DENIED_HINT_FILES {
+				concat!("; to back them up, run as a user who can read them (e.g. root), ",
+					"or use --strict to fail rather than skip them")
+			} else {
+				""
+			};
+			warn!("{} files skipped due to permissions, first few: {}{}",
+				self.denied_files_count, first.join(", "), hint);
+		}
+		if !self.unstable_files.is_empty() {
+			let more = self.unstable_files_count - self.unstable_files.len() as u64;
+			warn!("{} files changed while being backed up, and should be backed up again when they're not in use:\n\t{}{}",
+				self.unstable_files_count, self.unstable_files.join("\n\t"),
+				if more > 0 { format!("\n\t(and {} more)", more) } else { String::new() });
+		}
+	}
+
+	/// Format the statistics as JSON.
+	pub fn to_json(&self) -> String {
+		// Encoding plain numbers can't fail.
+		serde_json::to_string(self).unwrap()
+	}
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use stats::Stats;
+
+/// The write end of the pipe through which the SIGUSR1 handler wakes the
+/// thread writing status snapshots, or -1 before it's set up.
+#[cfg(unix)]
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Handle SIGUSR1 by writing a snapshot of what the run is doing (see
+/// `Stats::status`) to stderr, for checking on long unattended runs.
+///
+/// Formatting the snapshot isn't async-signal-safe, so the handler only wakes
+/// a thread, through a pipe, which writes it. If the pipe can't be created, a
+/// warning is logged and the signal is left alone.
+///
+/// On platforms other than Unix, which have no SIGUSR1, this does nothing.
+#[cfg(unix)]
+pub fn install(stats: Arc<Stats>) {
+	use libc;
+	use std::io;
+	use std::thread;
+	extern "C" fn handle(_: libc::c_int) {
+		// Only async-signal-safe operations are allowed here.
+		let fd = WAKE_FD.load(Ordering::SeqCst);
+		if fd >= 0 {
+			let byte = 0u8;
+			unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+		}
+	}
+	let mut fds = [0 as libc::c_int; 2];
+	if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+		warn!("Couldn't set up status snapshots on SIGUSR1: {}", io::Error::last_os_error());
+		return
+	}
+	WAKE_FD.store(fds[1], Ordering::SeqCst);
+	thread::spawn(move || {
+		let mut byte = 0u8;
+		loop {
+			let read = unsafe { libc::read(fds[0], &mut byte as *mut u8 as *mut libc::c_void, 1) };
+			if read == 1 {
+				write_status(&stats);
+			} else if read < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+				continue
+			} else {
+				break
+			}
+		}
+	});
+	let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+	unsafe { libc::signal(libc::SIGUSR1, handler) };
+}
+
+#[cfg(not(unix))]
+pub fn install(_stats: Arc<Stats>) {
+}
+
+/// Write a status snapshot to stderr, clearing the progress line first, if
+/// one is displayed (it's redrawn with the next update).
+#[cfg(unix)]
+fn write_status(stats: &Stats) {
+	use progress;
+	use std::io::{self, Write};
+	progress::clear_line();
+	let _ = writeln!(io::stderr(), "Status: {}", stats.status());
+}
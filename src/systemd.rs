@@ -0,0 +1,134 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use stats::Stats;
+
+/// How often to update the service's status.
+const STATUS_INTERVAL_MS: u64 = 5000;
+
+/// Notifications to systemd about the state of the run (see `sd_notify(3)`),
+/// for running as a service with `Type=notify`.
+///
+/// systemd passes the socket to notify in `NOTIFY_SOCKET`, and the watchdog
+/// interval, if the service has one, in `WATCHDOG_USEC`. Only supported on
+/// Linux; elsewhere, there's never a socket to notify.
+pub struct Notifier {
+	#[cfg(target_os = "linux")]
+	socket: ::std::os::unix::net::UnixDatagram,
+	/// How often to ping the watchdog, if the service has one.
+	watchdog: Option<Duration>,
+	/// Set once the run is stopping, to end periodic updates.
+	stopping: AtomicBool,
+}
+
+impl Notifier {
+	/// Connect to the socket in `NOTIFY_SOCKET`, if set. If it can't be
+	/// reached, a warning is logged, and the run carries on without notifying.
+	#[cfg(target_os = "linux")]
+	pub fn from_env() -> Option<Notifier> {
+		use std::env;
+		use std::os::linux::net::SocketAddrExt;
+		use std::os::unix::net::{SocketAddr, UnixDatagram};
+		let path = match env::var("NOTIFY_SOCKET") {
+			Ok(path) => path,
+			Err(_) => return None
+		};
+		// Names starting with @ are in the abstract namespace.
+		let socket = UnixDatagram::unbound().and_then(|socket| {
+			let addr = if path.starts_with('@') {
+				SocketAddr::from_abstract_name(&path.as_bytes()[1 ..])
+			} else {
+				SocketAddr::from_pathname(&path)
+			};
+			addr.and_then(|addr| socket.connect_addr(&addr)).map(|()| socket)
+		});
+		match socket {
+			Ok(socket) => Some(Notifier {
+				socket,
+				watchdog: watchdog_interval(),
+				stopping: AtomicBool::new(false),
+			}),
+			Err(e) => {
+				warn!("Couldn't connect to systemd's notification socket {}: {}", path, e);
+				None
+			}
+		}
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	pub fn from_env() -> Option<Notifier> {
+		None
+	}
+
+	/// Tell systemd the service has started.
+	pub fn ready(&self) {
+		self.send("READY=1");
+	}
+
+	/// Tell systemd the service is stopping, and end periodic updates.
+	pub fn stopping(&self) {
+		self.stopping.store(true, Ordering::SeqCst);
+		self.send("STOPPING=1");
+	}
+
+	/// Update the service's status with what the run is doing (see
+	/// `Stats::status`) every few seconds, and ping the watchdog, if any, as
+	/// long as the run is making progress (see `Stats::activity`), until the
+	/// run is stopping.
+	pub fn start_updates(notifier: Arc<Notifier>, stats: Arc<Stats>) {
+		let status_interval = Duration::from_millis(STATUS_INTERVAL_MS);
+		let interval = notifier.watchdog.map_or(status_interval, |w| ::std::cmp::min(w, status_interval));
+		thread::spawn(move || {
+			let mut last_activity = stats.activity();
+			let mut since_status = status_interval;
+			loop {
+				if notifier.stopping.load(Ordering::SeqCst) {
+					break
+				}
+				if since_status >= status_interval {
+					notifier.send(&format!("STATUS={}", stats.status()));
+					since_status = Duration::from_secs(0);
+				}
+				let activity = stats.activity();
+				if notifier.watchdog.is_some() && activity != last_activity {
+					notifier.send("WATCHDOG=1");
+				}
+				last_activity = activity;
+				thread::sleep(interval);
+				since_status += interval;
+			}
+		});
+	}
+
+	/// Send a notification, ignoring failures: systemd going away doesn't stop
+	/// the run.
+	#[cfg(target_os = "linux")]
+	fn send(&self, state: &str) {
+		trace!("Notifying systemd: {}", state);
+		let _ = self.socket.send(state.as_bytes());
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	fn send(&self, _state: &str) {
+	}
+}
+
+/// How often to ping the watchdog: half the interval systemd gives in
+/// `WATCHDOG_USEC`, if it's for this process (`WATCHDOG_PID` is unset or this
+/// process's pid).
+#[cfg(target_os = "linux")]
+fn watchdog_interval() -> Option<Duration> {
+	use std::env;
+	use std::process;
+	if let Ok(pid) = env::var("WATCHDOG_PID") {
+		if pid.parse::<u32>().ok() != Some(process::id()) {
+			return None;
+		}
+	}
+	match env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok()) {
+		Some(usec) if usec > 0 => Some(Duration::from_micros(usec / 2)),
+		_ => None
+	}
+}
@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A limit on the rate at which bytes are read, shared between threads.
+///
+/// This is a token bucket, starting empty and holding up to a second's worth of
+/// bytes: reads take bytes from the bucket, and once it's empty, readers sleep
+/// until enough bytes have been added back to cover what they've read.
+#[derive(Debug)]
+pub struct Throttle {
+	rate: u64,
+	state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+	available: f64,
+	last_fill: Instant,
+	started: Option<Instant>,
+	total: u64,
+}
+
+impl Throttle {
+	/// Create a throttle limiting reads to `rate` bytes per second.
+	pub fn new(rate: u64) -> Throttle {
+		Throttle {
+			rate,
+			state: Mutex::new(State {
+				available: 0.0,
+				last_fill: Instant::now(),
+				started: None,
+				total: 0,
+			}),
+		}
+	}
+
+	/// The limit, in bytes per second.
+	pub fn rate(&self) -> u64 {
+		self.rate
+	}
+
+	/// Record that some bytes have been read, sleeping if they exceed the
+	/// limit.
+	pub fn consume(&self, bytes: u64) {
+		let wait = {
+			let mut state = self.state.lock().unwrap();
+			let now = Instant::now();
+			if state.started.is_none() {
+				state.started = Some(now);
+			}
+			state.total += bytes;
+			let refill = now.duration_since(state.last_fill).as_secs_f64() * self.rate as f64;
+			state.available = (state.available + refill).min(self.rate as f64) - bytes as f64;
+			state.last_fill = now;
+			// Readers sleep off any shortfall themselves, so the bucket can go
+			// negative while several of them are waiting.
+			if state.available < 0.0 {
+				Duration::from_secs_f64(-state.available / self.rate as f64)
+			} else {
+				Duration::from_secs(0)
+			}
+		};
+		if wait > Duration::from_secs(0) {
+			thread::sleep(wait);
+		}
+	}
+
+	/// The average rate at which bytes have been read since the first read, in
+	/// bytes per second.
+	pub fn average_rate(&self) -> f64 {
+		let state = self.state.lock().unwrap();
+		match state.started {
+			Some(started) => {
+				let elapsed = started.elapsed().as_secs_f64();
+				if elapsed > 0.0 { state.total as f64 / elapsed } else { 0.0 }
+			},
+			None => 0.0
+		}
+	}
+}
+
+/// Parse a number of bytes, with an optional `K`, `M`, `G`, or `T` suffix
+/// for binary multiples (so `50M` is 50 MiB).
+///
+/// Returns `None` if `size` isn't of this form.
+pub fn parse_size(size: &str) -> Option<u64> {
+	let size = size.trim();
+	let (number, multiplier) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+		Some('K') => (&size[.. size.len() - 1], 1 << 10),
+		Some('M') => (&size[.. size.len() - 1], 1 << 20),
+		Some('G') => (&size[.. size.len() - 1], 1 << 30),
+		Some('T') => (&size[.. size.len() - 1], 1 << 40),
+		_ => (size, 1),
+	};
+	number.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier))
+}
@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cancel;
+
+/// A change to the watched files.
+#[derive(Debug)]
+pub enum Change {
+	/// Something at the path was created, written, moved or removed.
+	Path(PathBuf),
+	/// The kernel's queue of events overflowed, so changes have been lost, and
+	/// everything must be checked again.
+	Overflow,
+}
+
+/// Notifications of changes to files, through inotify (see `inotify(7)`).
+///
+/// Directories are watched recursively, and directories created or moved
+/// beneath them are watched as they appear. A file is watched through its
+/// directory, so it's still watched when replaced by renaming another file
+/// over it, as editors do; changes to the other files in the directory are
+/// reported too.
+///
+/// Only supported on Linux.
+pub struct Watcher {
+	#[cfg(target_os = "linux")]
+	fd: ::libc::c_int,
+	/// The directory each watch descriptor is for.
+	watches: HashMap<i32, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+	/// Start watching nothing.
+	///
+	/// # Errors
+	///
+	/// This function will return an error if an inotify instance can't be
+	/// created (e.g. because of the limit on instances per user).
+	pub fn new() -> io::Result<Watcher> {
+		use libc;
+		let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(Watcher { fd, watches: HashMap::new() })
+	}
+
+	/// Watch a path: a directory and everything beneath it, or a file.
+	/// Directories beneath it which can't be watched are logged and left out.
+	///
+	/// # Errors
+	///
+	/// This function will return an error if the path itself can't be watched.
+	pub fn add(&mut self, path: &Path) -> io::Result<()> {
+		use libc;
+		use walkdir::WalkDir;
+		if !path.symlink_metadata()?.is_dir() {
+			return match path.parent() {
+				Some(dir) => self.add_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { dir }),
+				None => Ok(())
+			}
+		}
+		self.add_dir(path)?;
+		for entry in WalkDir::new(path).min_depth(1) {
+			// Changes beneath a directory which can't be walked aren't seen.
+			let dir = match entry {
+				Ok(ref entry) if !entry.file_type().is_dir() => continue,
+				Ok(entry) => entry,
+				Err(e) => {
+					warn!("Couldn't watch everything beneath {} for changes: {}", path.display(), e);
+					continue
+				}
+			};
+			if let Err(e) = self.add_dir(dir.path()) {
+				warn!("Couldn't watch {} for changes: {}{}", dir.path().display(), e,
+					if e.raw_os_error() == Some(libc::ENOSPC) {
+						" (see fs.inotify.max_user_watches)"
+					} else {
+						""
+					});
+			}
+		}
+		Ok(())
+	}
+
+	/// Wait up to `timeout` for changes, returning those which have happened,
+	/// if any. Returns early, with no changes, if interrupted by a signal.
+	///
+	/// # Errors
+	///
+	/// This function will return an error if the events can't be read.
+	pub fn wait(&mut self, timeout: Duration) -> io::Result<Vec<Change>> {
+		use libc;
+		use std::ffi::OsStr;
+		use std::os::unix::ffi::OsStrExt;
+		let mut poll = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+		let timeout_ms = timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis());
+		if unsafe { libc::poll(&mut poll, 1, timeout_ms as libc::c_int) } < 0 {
+			let e = io::Error::last_os_error();
+			return if e.kind() == io::ErrorKind::Interrupted { Ok(Vec::new()) } else { Err(e) };
+		}
+		let mut changes = Vec::new();
+		// Room for plenty of events at once; each is followed by its name,
+		// padded out to the event's alignment.
+		let mut buffer = vec![0u64; 8192];
+		loop {
+			let read = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void,
+				buffer.len() * mem::size_of::<u64>()) };
+			if read < 0 {
+				let e = io::Error::last_os_error();
+				match e.kind() {
+					io::ErrorKind::WouldBlock => return Ok(changes),
+					io::ErrorKind::Interrupted => continue,
+					_ => return Err(e)
+				}
+			}
+			let bytes = unsafe { ::std::slice::from_raw_parts(buffer.as_ptr() as *const u8, read as usize) };
+			let mut offset = 0;
+			while offset < bytes.len() {
+				let event = unsafe { &*(bytes[offset ..].as_ptr() as *const libc::inotify_event) };
+				let start = offset + mem::size_of::<libc::inotify_event>();
+				offset = start + event.len as usize;
+				if event.mask & libc::IN_Q_OVERFLOW != 0 {
+					changes.push(Change::Overflow);
+					continue
+				}
+				if event.mask & libc::IN_IGNORED != 0 {
+					self.watches.remove(&event.wd);
+					continue
+				}
+				let dir = match self.watches.get(&event.wd) {
+					Some(dir) => dir.clone(),
+					None => continue
+				};
+				// The name is padded with nulls.
+				let name = &bytes[start .. offset];
+				let name = &name[.. name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+				let path = if name.is_empty() { dir } else { dir.join(OsStr::from_bytes(name)) };
+				if event.mask & libc::IN_ISDIR != 0 {
+					if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+						if let Err(e) = self.add(&path) {
+							warn!("Couldn't watch {} for changes: {}", path.display(), e);
+						}
+					} else if event.mask & libc::IN_MOVED_FROM != 0 {
+						self.remove_beneath(&path);
+					}
+				}
+				changes.push(Change::Path(path));
+			}
+		}
+	}
+
+	/// Watch a single directory.
+	fn add_dir(&mut self, dir: &Path) -> io::Result<()> {
+		use libc;
+		use std::ffi::CString;
+		use std::os::unix::ffi::OsStrExt;
+		let mask = libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_CREATE | libc::IN_DELETE |
+			libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_DONT_FOLLOW | libc::IN_ONLYDIR;
+		let name = CString::new(dir.as_os_str().as_bytes())
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+		let wd = unsafe { libc::inotify_add_watch(self.fd, name.as_ptr(), mask) };
+		if wd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		trace!("Watching {} for changes", dir.display());
+		self.watches.insert(wd, dir.to_path_buf());
+		Ok(())
+	}
+
+	/// Stop watching a directory, and the directories beneath it, which has
+	/// been moved away, so they aren't reported under the old path.
+	fn remove_beneath(&mut self, path: &Path) {
+		use libc;
+		let removed : Vec<i32> = self.watches.iter()
+			.filter(|&(_, dir)| dir.starts_with(path))
+			.map(|(&wd, _)| wd)
+			.collect();
+		for wd in removed {
+			unsafe { libc::inotify_rm_watch(self.fd, wd) };
+			self.watches.remove(&wd);
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Watcher {
+	fn drop(&mut self) {
+		use libc;
+		unsafe { libc::close(self.fd) };
+	}
+}
+
+/// The paths beneath a set of sources which have changed since last taken,
+/// gathered on a thread of its own from a `Watcher`, for backing up the
+/// changes every so often rather than as soon as they happen.
+///
+/// If the kernel's queue of events overflows, or events can't be read, the
+/// sources themselves are recorded as changed, so everything is checked again.
+#[derive(Clone)]
+pub struct ChangeTracker {
+	changed: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl ChangeTracker {
+	/// Start gathering the changes `watcher` reports beneath `sources` (other
+	/// files in the directories of sources which are files are watched too,
+	/// and left out), until the run is asked to stop.
+	pub fn start(mut watcher: Watcher, sources: Vec<PathBuf>) -> ChangeTracker {
+		let tracker = ChangeTracker { changed: Arc::new(Mutex::new(HashSet::new())) };
+		let changed = tracker.changed.clone();
+		thread::spawn(move || {
+			while !cancel::cancelled() {
+				let changes = match watcher.wait(Duration::from_secs(1)) {
+					Ok(changes) => changes,
+					Err(e) => {
+						warn!("Error watching for changes: {}; checking all the files again", e);
+						changed.lock().unwrap().extend(sources.iter().cloned());
+						thread::sleep(Duration::from_secs(1));
+						continue
+					}
+				};
+				let mut changed = changed.lock().unwrap();
+				for change in changes {
+					match change {
+						Change::Overflow => {
+							warn!("Too many changes to keep track of; checking all the files again");
+							// Directories created since may have been missed.
+							for source in &sources {
+								if let Err(e) = watcher.add(source) {
+									warn!("Couldn't watch {} for changes: {}", source.display(), e);
+								}
+							}
+							changed.extend(sources.iter().cloned());
+						},
+						Change::Path(path) => if sources.iter().any(|source| path.starts_with(source)) {
+							trace!("Changed: {}", path.display());
+							changed.insert(path);
+						}
+					}
+				}
+			}
+		});
+		tracker
+	}
+
+	/// Take the paths which have changed since last taken.
+	pub fn take(&self) -> HashSet<PathBuf> {
+		mem::take(&mut *self.changed.lock().unwrap())
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+	pub fn new() -> io::Result<Watcher> {
+		Err(io::Error::new(io::ErrorKind::Other, "watching for changes is only supported on Linux"))
+	}
+
+	pub fn add(&mut self, _path: &Path) -> io::Result<()> {
+		Ok(())
+	}
+
+	pub fn wait(&mut self, _timeout: Duration) -> io::Result<Vec<Change>> {
+		Ok(Vec::new())
+	}
+}
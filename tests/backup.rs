@@ -0,0 +1,158 @@
+//! End-to-end tests for `backup` itself.
+
+mod common;
+
+use common::{backup, backup_ok, is_root, tree, TempDir};
+
+#[test]
+fn failed_archive_keeps_old_checksums() {
+	let modes = [&["--no-pipeline"][..], &["--archive-threads", "3"][..], &[][..]];
+	for mode in &modes {
+		let dir = TempDir::new("failed-archive");
+		dir.write("src/a", "a");
+		dir.write("src/b", "b");
+		backup_ok(dir.path(), &["-q", "--new-checksums", "checksums.txt", "--", "src", "full.tar.gz"]);
+		let before = dir.read("checksums.txt");
+		dir.write("src/a", "changed");
+		dir.write("src/c", "added");
+
+		let mut args = vec!["-q", "--old-checksums", "checksums.txt",
+			"--new-checksums", "checksums.txt"];
+		args.extend(*mode);
+		args.extend(&["--", "src", "missing/inc.tar.gz"]);
+		let output = backup(dir.path(), &args);
+		assert_eq!(output.status.code(), Some(3), "{:?}", mode);
+		assert_eq!(dir.read("checksums.txt"), before, "{:?} changed the checksums", mode);
+		let partial: Vec<String> = tree(dir.path()).into_keys()
+			.filter(|name| name.contains(".partial")).collect();
+		assert!(partial.is_empty(), "{:?} left {:?}", mode, partial);
+	}
+}
+
+#[test]
+fn source_spelling_doesnt_change_checksums() {
+	let dir = TempDir::new("source-spelling");
+	dir.write("photos/a.jpg", "a");
+	dir.write("photos/album/b.jpg", "b");
+	for (i, source) in ["photos", "./photos", "photos/", "./photos//"].iter().enumerate() {
+		backup_ok(dir.path(), &["-q", "--new-checksums", &format!("{}.txt", i),
+			"--", source, &format!("{}.tar.gz", i)]);
+	}
+	let first = dir.read("0.txt");
+	assert!(String::from_utf8_lossy(&first).contains("\tphotos/album/b.jpg\n"));
+	for i in 1 .. 4 {
+		assert_eq!(dir.read(&format!("{}.txt", i)), first, "source {}", i);
+	}
+}
+
+#[test]
+fn old_dotted_checksums_still_match() {
+	let dir = TempDir::new("dotted-checksums");
+	dir.write("photos/a.jpg", "a");
+	dir.write("photos/album/b.jpg", "b");
+	backup_ok(dir.path(), &["-q", "--new-checksums", "new.txt", "--", "photos", "full.tar.gz"]);
+	// Older versions kept the source's spelling in filenames.
+	let dotted = String::from_utf8(dir.read("new.txt")).unwrap().replace("\tphotos/", "\t./photos/");
+	dir.write("old.txt", &dotted);
+
+	let output = backup(dir.path(), &["-q", "--old-checksums", "old.txt",
+		"--new-checksums", "again.txt", "--", "photos", "inc.tar.gz"]);
+	assert_eq!(output.status.code(), Some(4), "{}", String::from_utf8_lossy(&output.stderr));
+	assert_eq!(dir.read("again.txt"), dir.read("new.txt"));
+}
+
+#[cfg(unix)]
+#[test]
+fn unlistable_directory_makes_the_run_partial() {
+	use std::fs::{self, Permissions};
+	use std::os::unix::fs::PermissionsExt;
+
+	if is_root() {
+		// Root can list the directory regardless.
+		return;
+	}
+	let dir = TempDir::new("unlistable-directory");
+	dir.write("src/readable", "readable");
+	dir.write("src/locked/hidden", "hidden");
+	fs::set_permissions(dir.join("src/locked"), Permissions::from_mode(0o000)).unwrap();
+	let output = backup(dir.path(), &["-q", "--stats-json", "stats.json",
+		"--new-checksums", "new.txt", "--", "src", "out.tar.gz"]);
+	fs::set_permissions(dir.join("src/locked"), Permissions::from_mode(0o755)).unwrap();
+
+	assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+	let stats = String::from_utf8(dir.read("stats.json")).unwrap();
+	assert!(stats.contains("\"walk_errors\":1"), "{}", stats);
+	let checksums = String::from_utf8(dir.read("new.txt")).unwrap();
+	assert!(checksums.contains("\tsrc/readable\n"));
+	assert!(!checksums.contains("hidden"));
+}
+
+#[test]
+fn sources_outside_the_root() {
+	let dir = TempDir::new("outside-root");
+	dir.write("home/me/photos/a.jpg", "a");
+	dir.write("tmp/other/b.txt", "b");
+	let other = dir.join("tmp/other");
+	let other = other.to_str().unwrap();
+	let args = |extra: &[&'static str]| {
+		let mut args = vec!["-q", "--source-root", "home/me", "--new-checksums", "new.txt"];
+		args.extend(extra);
+		args.extend(&["--", "photos", other, "out.tar.gz"]);
+		args
+	};
+
+	let output = backup(dir.path(), &args(&[]));
+	assert_eq!(output.status.code(), Some(3));
+	assert!(String::from_utf8_lossy(&output.stderr).contains("--allow-outside-root"));
+	assert!(!dir.join("new.txt").exists());
+
+	backup_ok(dir.path(), &args(&["--allow-outside-root"]));
+	let checksums = String::from_utf8(dir.read("new.txt")).unwrap();
+	assert!(checksums.contains("\tphotos/a.jpg\n"), "{}", checksums);
+	assert!(checksums.contains(&format!("\t@outside-root/{}/b.txt\n", other.trim_start_matches('/'))),
+		"{}", checksums);
+	// The archive never holds absolute names.
+	let listing = String::from_utf8(backup_ok(dir.path(), &["-q", "list", "out.tar.gz"]).stdout).unwrap();
+	assert!(listing.contains(&format!("@outside-root/{}/b.txt", other.trim_start_matches('/'))),
+		"{}", listing);
+}
+
+#[test]
+fn files_which_fail_to_archive_arent_embedded_as_backed_up() {
+	use std::fs;
+	use std::io::{BufRead, BufReader};
+	use std::process::{Command, Stdio};
+
+	let dir = TempDir::new("embedded-failures");
+	// Reading slowly (see --bwlimit), late is moved away once it's checksummed,
+	// well before early is archived, so that it can't be archived.
+	dir.write("src/early", &"x".repeat(1 << 20));
+	dir.write("src/late", "late");
+	let mut child = Command::new(env!("CARGO_BIN_EXE_backup"))
+		.current_dir(dir.path())
+		.args(["--output-checksums-to-archive", "--bwlimit", "512K",
+			"--", "src", "first.tar.gz"])
+		.env_remove("RUST_LOG")
+		.stderr(Stdio::piped())
+		.spawn().unwrap();
+	let mut log = String::new();
+	for line in BufReader::new(child.stderr.take().unwrap()).lines() {
+		let line = line.unwrap();
+		if line.contains("Finished walking and checksumming") {
+			fs::rename(dir.join("src/late"), dir.join("late")).unwrap();
+		}
+		log.push_str(&line);
+		log.push('\n');
+	}
+	let status = child.wait().unwrap();
+	fs::rename(dir.join("late"), dir.join("src/late")).unwrap();
+	assert_eq!(status.code(), Some(2), "{}", log);
+
+	// late is unchanged since it was checksummed, but wasn't backed up.
+	backup_ok(dir.path(), &["-q", "--since-archive", "first.tar.gz",
+		"--output-checksums-to-archive", "--", "src", "second.tar.gz"]);
+	let listing = backup_ok(dir.path(), &["list", "second.tar.gz"]);
+	let listing = String::from_utf8(listing.stdout).unwrap();
+	assert!(listing.lines().any(|line| line == "src/late"), "{}", listing);
+	assert!(!listing.lines().any(|line| line == "src/early"), "{}", listing);
+}
@@ -0,0 +1,29 @@
+//! End-to-end tests for `backup benchmark`.
+
+mod common;
+
+use common::{backup, TempDir};
+
+#[test]
+fn compares_buffer_sizes() {
+	let dir = TempDir::new("benchmark");
+	dir.write("src/a", "a");
+	dir.write("src/b", "b");
+
+	let output = backup(dir.path(), &["benchmark", "-q", "-x", "crc32c",
+		"--buffer-size", "64K,1M, 8M", "src"]);
+	assert!(output.status.success(), "{:?}", output);
+	let stdout = String::from_utf8(output.stdout).unwrap();
+	let read : Vec<&str> = stdout.lines().filter(|line| line.starts_with("Read ")).collect();
+	assert_eq!(read.len(), 3, "{}", stdout);
+	for (line, size) in read.iter().zip(&["64K", "1M", "8M"]) {
+		assert!(line.starts_with("Read 2 files "), "{}", line);
+		assert!(line.contains(&format!("--buffer-size {}:", size)), "{}", line);
+	}
+
+	// Every size is checked before anything is read.
+	let output = backup(dir.path(), &["benchmark", "-q", "-x", "crc32c",
+		"--buffer-size", "64K,lots", "src"]);
+	assert_eq!(output.status.code(), Some(3));
+	assert!(output.stdout.is_empty(), "{:?}", output);
+}
@@ -0,0 +1,117 @@
+//! Helpers for the end-to-end tests, which run the built binary against
+//! scratch directories.
+
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A scratch directory, removed (with everything in it) when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+	/// Create an empty scratch directory, named for the test using it.
+	pub fn new(name: &str) -> TempDir {
+		static COUNT: AtomicUsize = AtomicUsize::new(0);
+		let path = env::temp_dir().join(format!("backup-test-{}-{}-{}",
+			name, process::id(), COUNT.fetch_add(1, Ordering::SeqCst)));
+		let _ = fs::remove_dir_all(&path);
+		fs::create_dir_all(&path).unwrap();
+		TempDir(path)
+	}
+
+	/// Get the directory's path.
+	pub fn path(&self) -> &Path {
+		&self.0
+	}
+
+	/// Get the path of a file beneath the directory.
+	pub fn join(&self, name: &str) -> PathBuf {
+		self.0.join(name)
+	}
+
+	/// Write a file beneath the directory, creating its parent directories.
+	pub fn write(&self, name: &str, contents: &str) {
+		let path = self.join(name);
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		fs::write(path, contents).unwrap();
+	}
+
+	/// Read a file beneath the directory.
+	pub fn read(&self, name: &str) -> Vec<u8> {
+		fs::read(self.join(name)).unwrap()
+	}
+
+	/// Remove a file beneath the directory.
+	pub fn remove(&self, name: &str) {
+		fs::remove_file(self.join(name)).unwrap();
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
+/// Run the binary in `dir` with `args`.
+pub fn backup(dir: &Path, args: &[&str]) -> Output {
+	backup_with_log(dir, args, None)
+}
+
+/// Run the binary in `dir` with `args`, and with `RUST_LOG` set to
+/// `rust_log`, if given.
+pub fn backup_with_log(dir: &Path, args: &[&str], rust_log: Option<&str>) -> Output {
+	let mut command = Command::new(env!("CARGO_BIN_EXE_backup"));
+	command.current_dir(dir).args(args);
+	match rust_log {
+		Some(spec) => command.env("RUST_LOG", spec),
+		None => command.env_remove("RUST_LOG"),
+	};
+	command.output().unwrap()
+}
+
+/// Run the binary in `dir` with `args`, and check that it succeeds.
+pub fn backup_ok(dir: &Path, args: &[&str]) -> Output {
+	let output = backup(dir, args);
+	assert!(output.status.success(), "backup {:?} failed with {}: {}",
+		args, output.status, String::from_utf8_lossy(&output.stderr));
+	output
+}
+
+/// The regular files beneath a directory, by path relative to it (with `/`
+/// separators), with their contents.
+pub fn tree(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+	let mut files = BTreeMap::new();
+	add_tree(dir, dir, &mut files);
+	files
+}
+
+fn add_tree(root: &Path, dir: &Path, files: &mut BTreeMap<String, Vec<u8>>) {
+	for entry in fs::read_dir(dir).unwrap() {
+		let path = entry.unwrap().path();
+		if path.is_dir() {
+			add_tree(root, &path, files);
+		} else {
+			let name = path.strip_prefix(root).unwrap().to_str().unwrap().replace('\\', "/");
+			files.insert(name, fs::read(&path).unwrap());
+		}
+	}
+}
+
+/// Whether the tests are running as root, which can read files whatever their
+/// permissions.
+#[cfg(unix)]
+pub fn is_root() -> bool {
+	extern crate libc;
+	unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn is_root() -> bool {
+	false
+}
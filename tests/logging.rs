@@ -0,0 +1,124 @@
+//! End-to-end tests for the format of log messages.
+
+mod common;
+
+use common::{backup_ok, backup_with_log, TempDir};
+
+/// A name with quotes, a tab and a newline in it.
+const AWKWARD: &str = "src/say \"hi\"\tand\nbye";
+
+/// The checksum of `CONTENTS`.
+const CONTENTS: &str = "content";
+const CHECKSUM: &str = "040f06fd774092478d450774f5ba30c5da78acc8";
+
+/// Replace the value of each of the given fields in a JSON log line with a
+/// placeholder, since they vary from run to run (or edit to edit).
+fn redact_json(line: &str, keys: &[&str]) -> String {
+	let mut line = line.to_string();
+	for key in keys {
+		let field = format!("\"{}\":", key);
+		let start = line.find(&field).unwrap() + field.len();
+		let end = start + line[start ..].find([',', '}']).unwrap();
+		line.replace_range(start .. end, "_");
+	}
+	line
+}
+
+/// Replace the timestamp and line number in a human-readable log line with
+/// placeholders.
+fn redact_human(line: &str) -> String {
+	let (_, rest) = line.split_once(' ').unwrap();
+	let (location, rest) = rest.split_once("] ").unwrap();
+	let (file, _) = location.rsplit_once(':').unwrap();
+	format!("_ {}:_] {}", file, rest)
+}
+
+/// Log a dry run backing up a file with an awkward name in the given format,
+/// returning what was written to standard error and to the log file.
+fn dry_run(format: &str) -> (String, String) {
+	let dir = TempDir::new(&format!("logging-{}", format));
+	dir.write(AWKWARD, CONTENTS);
+	let output = backup_ok(dir.path(), &["--dry-run", "--log-format", format,
+		"--log-file", "log.txt", "--log-file-level", "info", "--", "src", "out.tar.gz"]);
+	(String::from_utf8(output.stderr).unwrap(), String::from_utf8(dir.read("log.txt")).unwrap())
+}
+
+#[test]
+fn json_lines() {
+	let (stderr, log) = dry_run("json");
+	assert_eq!(stderr, log);
+	let line = stderr.lines().find(|line| line.contains("\"change\"")).unwrap();
+	assert_eq!(redact_json(line, &["line", "timestamp"]), concat!(
+		r#"{"change":"added","checksum":"040f06fd774092478d450774f5ba30c5da78acc8","#,
+		r#""file":"src/main.rs","level":"INFO","line":_,"#,
+		r#""message":"[dry-run]\tA\tsrc/say \"hi\"\tand\nbye\t040f06fd774092478d450774f5ba30c5da78acc8\t7 B","#,
+		r#""module":"backup","path":"src/say \"hi\"\tand\nbye","timestamp":_}"#));
+	// Every line is a JSON object (so newlines in messages are escaped), with
+	// a UTC timestamp.
+	for line in stderr.lines() {
+		assert!(line.starts_with("{\"") && line.ends_with("Z\"}"), "{}", line);
+		assert_eq!(redact_json(line, &["timestamp"]).len(),
+			line.len() + 1 - "\"2020-01-01T00:00:00Z\"".len(), "{}", line);
+	}
+}
+
+#[test]
+fn human_lines() {
+	let (stderr, log) = dry_run("human");
+	assert_eq!(stderr, log);
+	let start = stderr.find("[dry-run]\tA").unwrap();
+	let start = stderr[.. start].rfind('\n').map_or(0, |i| i + 1);
+	let line: Vec<&str> = stderr[start ..].splitn(3, '\n').take(2).collect();
+	// The message is written as it is, newlines and all.
+	assert_eq!(redact_human(&line.join("\n")), format!(
+		"_ [backup src/main.rs:_] [INFO] [dry-run]\tA\t{}\t{}\t7 B", AWKWARD, CHECKSUM));
+	for line in stderr.lines().filter(|line| line.starts_with("20")) {
+		assert!(redact_human(line).starts_with("_ [backup"), "{}", line);
+	}
+}
+
+/// The modules and levels of the lines logged by a dry run with the given
+/// arguments and `RUST_LOG`, e.g. `backup::stats INFO`, without repeats.
+fn logged(args: &[&str], rust_log: Option<&str>) -> Vec<String> {
+	let dir = TempDir::new("log-levels");
+	dir.write("src/file", CONTENTS);
+	let mut all_args = args.to_vec();
+	all_args.extend(&["--dry-run", "--", "src", "out.tar.gz"]);
+	let output = backup_with_log(dir.path(), &all_args, rust_log);
+	assert!(output.status.success());
+	let mut logged: Vec<String> = String::from_utf8(output.stderr).unwrap().lines()
+		.filter(|line| line.starts_with("20"))
+		.map(|line| {
+			let mut fields = line.split_whitespace().skip(1);
+			let module = fields.next().unwrap().trim_start_matches('[');
+			let level = fields.nth(1).unwrap().trim_matches(|c| c == '[' || c == ']');
+			format!("{} {}", module, level)
+		})
+		.collect();
+	logged.sort();
+	logged.dedup();
+	logged
+}
+
+#[test]
+fn verbose_and_quiet() {
+	assert_eq!(logged(&[], None), ["backup INFO", "backup::stats INFO"]);
+	assert_eq!(logged(&["-v"], None), ["backup DEBUG", "backup INFO", "backup::stats INFO"]);
+	assert!(logged(&["-vv"], None).contains(&"backup::operations TRACE".to_string()));
+	// A dry run has nothing to warn about.
+	assert!(logged(&["-q"], None).is_empty());
+	assert!(logged(&["-q", "-vv"], None).is_empty());
+}
+
+#[test]
+fn rust_log_takes_precedence() {
+	let debug = logged(&[], Some("debug"));
+	assert!(debug.contains(&"backup DEBUG".to_string()));
+	assert!(!debug.contains(&"backup::operations TRACE".to_string()));
+	assert_eq!(logged(&["-q"], Some("debug")), debug);
+	assert_eq!(logged(&["-vv"], Some("debug")), debug);
+	assert!(logged(&["-vv"], Some("warn")).is_empty());
+	assert_eq!(logged(&[], Some("backup::operations=trace")), ["backup::operations TRACE"]);
+	// An empty RUST_LOG is ignored.
+	assert_eq!(logged(&["-v"], Some("")), logged(&["-v"], None));
+}
@@ -0,0 +1,57 @@
+//! End-to-end tests for `backup --low-memory`.
+
+mod common;
+
+use common::{backup, backup_ok, is_root, TempDir};
+
+#[test]
+fn matches_normal_backup() {
+	let dir = TempDir::new("low-memory");
+	for i in 0 .. 50 {
+		dir.write(&format!("src/dir {}/file\t{}", i % 7, i), &i.to_string());
+	}
+	backup_ok(dir.path(), &["-q", "--new-checksums", "old.txt", "--", "src", "old.tar.gz"]);
+	dir.write("src/dir 1/file\t1", "changed");
+	dir.write("src/added", "added");
+	dir.remove("src/dir 2/file\t2");
+
+	backup_ok(dir.path(), &["-q", "--old-checksums", "old.txt", "--new-checksums", "normal.txt",
+		"--", "src", "normal.tar.gz"]);
+	backup_ok(dir.path(), &["-q", "--low-memory", "--old-checksums", "old.txt",
+		"--new-checksums", "low.txt", "--", "src", "low.tar.gz"]);
+	assert_eq!(dir.read("low.txt"), dir.read("normal.txt"));
+	let listing = |archive: &str| backup_ok(dir.path(), &["-q", "list", archive]).stdout;
+	assert_eq!(listing("low.tar.gz"), listing("normal.tar.gz"));
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_files_make_the_run_partial() {
+	use std::fs::{self, Permissions};
+	use std::os::unix::fs::PermissionsExt;
+
+	if is_root() {
+		// Root can read the file regardless.
+		return;
+	}
+	let dir = TempDir::new("low-memory-unreadable");
+	dir.write("src/readable", "readable");
+	dir.write("src/unreadable", "unreadable");
+	fs::set_permissions(dir.join("src/unreadable"), Permissions::from_mode(0o000)).unwrap();
+
+	let output = backup(dir.path(), &["-q", "--low-memory", "--ignore-errors",
+		"--stats-json", "report.json", "--new-checksums", "new.txt", "--", "src", "out.tar.gz"]);
+	assert_eq!(output.status.code(), Some(2));
+	let checksums = String::from_utf8(dir.read("new.txt")).unwrap();
+	assert!(checksums.contains("src/readable"));
+	assert!(!checksums.contains("src/unreadable"));
+	let report = String::from_utf8(dir.read("report.json")).unwrap();
+	assert!(report.contains("\"unreadable\":1"), "{}", report);
+
+	// With --strict, nothing is written.
+	let output = backup(dir.path(), &["-q", "--low-memory", "--ignore-errors", "--strict",
+		"--new-checksums", "strict.txt", "--", "src", "strict.tar.gz"]);
+	assert_eq!(output.status.code(), Some(3));
+	assert!(!dir.join("strict.tar.gz").exists());
+	assert!(!dir.join("strict.txt").exists());
+}
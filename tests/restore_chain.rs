@@ -0,0 +1,76 @@
+//! End-to-end tests for `backup restore-chain`.
+
+mod common;
+
+use common::{backup, backup_ok, tree, TempDir};
+
+/// Back up `src` to `archive` in `dir`, embedding the checksums, and
+/// comparing against and saving the checksums in the given files.
+fn backup_to(dir: &TempDir, old: Option<&str>, new: &str, archive: &str, extra: &[&str]) {
+	let mut args = vec!["-q", "--output-checksums-to-archive", "--new-checksums", new];
+	if let Some(old) = old {
+		args.extend(&["--old-checksums", old]);
+	}
+	args.extend(extra);
+	args.extend(&["--", "src", archive]);
+	backup_ok(dir.path(), &args);
+}
+
+#[test]
+fn restores_full_and_incrementals() {
+	let dir = TempDir::new("restore-chain");
+	dir.write("src/kept", "kept");
+	dir.write("src/modified", "before");
+	dir.write("src/deleted", "deleted");
+	dir.write("src/sub/deleted-later", "deleted later");
+	backup_to(&dir, None, "1.txt", "full.tar.gz", &[]);
+
+	dir.write("src/modified", "after");
+	dir.write("src/sub/added", "added");
+	dir.remove("src/deleted");
+	backup_to(&dir, Some("1.txt"), "2.txt", "inc1.tar.gz", &[]);
+
+	dir.write("src/added-later", "added later");
+	dir.remove("src/sub/deleted-later");
+	backup_to(&dir, Some("2.txt"), "3.txt", "inc2.tar.gz", &[]);
+
+	backup_ok(dir.path(), &["-q", "restore-chain", "--full", "full.tar.gz",
+		"--incremental", "inc1.tar.gz", "--incremental", "inc2.tar.gz", "restored"]);
+	assert_eq!(tree(&dir.join("restored/src")), tree(&dir.join("src")));
+}
+
+#[test]
+fn dry_run_writes_nothing() {
+	let dir = TempDir::new("restore-chain-dry-run");
+	dir.write("src/file", "file");
+	backup_to(&dir, None, "1.txt", "full.tar.gz", &[]);
+	backup_ok(dir.path(), &["-q", "--dry-run", "restore-chain", "--full", "full.tar.gz", "restored"]);
+	assert!(!dir.join("restored").exists());
+}
+
+#[test]
+fn refuses_renamed_archives() {
+	let dir = TempDir::new("restore-chain-renamed");
+	dir.write("src/kept", "kept");
+	dir.write("src/modified", "before");
+	backup_to(&dir, None, "1.txt", "full.tar.gz", &[]);
+	dir.write("src/modified", "after");
+	backup_to(&dir, Some("1.txt"), "2.txt", "inc.tar.gz", &["--strip-components", "1"]);
+
+	let output = backup(dir.path(), &["-q", "restore-chain", "--full", "full.tar.gz",
+		"--incremental", "inc.tar.gz", "restored"]);
+	assert_eq!(output.status.code(), Some(3));
+	assert!(String::from_utf8_lossy(&output.stderr).contains("--strip-components"));
+	// Nothing is restored, let alone deleted.
+	assert!(!dir.join("restored").exists());
+}
+
+#[test]
+fn refuses_zip_archives() {
+	let dir = TempDir::new("restore-chain-zip");
+	dir.write("src/file", "file");
+	backup_to(&dir, None, "1.txt", "full.zip", &["--archive-format", "zip"]);
+	let output = backup(dir.path(), &["-q", "restore-chain", "--full", "full.zip", "restored"]);
+	assert_eq!(output.status.code(), Some(3));
+	assert!(String::from_utf8_lossy(&output.stderr).contains("zip archive"));
+}